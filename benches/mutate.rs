@@ -0,0 +1,64 @@
+//! Admission of a pod with 200 claims, all examined or capped by the volume scan limit
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use gravivol::{AdmissionReview, Controller, Options};
+use serde_json::{Value, json};
+
+const VOLUMES: usize = 200;
+
+fn review() -> Value {
+    let volumes: Vec<Value> = (0..VOLUMES)
+        .map(|i| {
+            let claim = format!("data-{i:03}");
+            json!({ "name": claim, "persistentVolumeClaim": { "claimName": claim } })
+        })
+        .collect();
+    json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "705ab4f5",
+            "namespace": "default",
+            "operation": "CREATE",
+            "object": {
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "generateName": "web-", "namespace": "default" },
+                "spec": { "containers": [{ "name": "web" }], "volumes": volumes },
+            },
+        },
+    })
+}
+
+fn bench_mutate(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let body = serde_json::to_vec(&review()).unwrap();
+
+    let mut group = c.benchmark_group("mutate");
+    for (name, volume_scan_limit) in [("all volumes", 0), ("scan limit 50", 50)] {
+        let controller = Controller::new("").with_options(Options {
+            volume_scan_limit,
+            ..Default::default()
+        });
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter_batched(
+                || serde_json::from_slice::<AdmissionReview>(&body).unwrap(),
+                |review| controller.mutate(review),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.bench_function("parse and mutate", |b| {
+        let controller = Controller::new("");
+        b.to_async(&runtime).iter(|| {
+            let review = serde_json::from_slice::<AdmissionReview>(&body).unwrap();
+            controller.mutate(review)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_mutate);
+criterion_main!(benches);
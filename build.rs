@@ -0,0 +1,50 @@
+//! Embeds the git commit, the rustc version and the build time, see src/build_info.rs
+
+use std::{env, path::Path, process::Command};
+
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+/// Trimmed stdout of a successful command
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !stdout.trim().is_empty()).then(|| stdout.trim().to_owned())
+}
+
+fn main() {
+    // Outside a git checkout, e.g. in a source archive, the commit is unknown
+    let commit = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    if commit.is_some() {
+        for path in [".git/HEAD", ".git/refs/heads", ".git/packed-refs"] {
+            if Path::new(path).exists() {
+                println!("cargo:rerun-if-changed={path}");
+            }
+        }
+    }
+    println!(
+        "cargo:rustc-env=GRAVIVOL_GIT_COMMIT={}",
+        commit.as_deref().unwrap_or("unknown")
+    );
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    println!(
+        "cargo:rustc-env=GRAVIVOL_RUSTC_VERSION={}",
+        output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_owned())
+    );
+
+    // Reproducible builds set the time
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| OffsetDateTime::from_unix_timestamp(epoch).ok())
+        .unwrap_or_else(OffsetDateTime::now_utc);
+    println!(
+        "cargo:rustc-env=GRAVIVOL_BUILT_AT={}",
+        built_at
+            .replace_nanosecond(0)
+            .expect("Valid nanosecond")
+            .format(&Rfc3339)
+            .expect("Formattable build time")
+    );
+}
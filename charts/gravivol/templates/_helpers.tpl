@@ -60,3 +60,18 @@ Create the name of the service account to use
 {{- default "default" .Values.serviceAccount.name }}
 {{- end }}
 {{- end }}
+
+{{/*
+Comma separated addresses with the port, from a list of addresses or a single one.
+*/}}
+{{- define "gravivol.bind" -}}
+{{- $addresses := index . 0 }}
+{{- if not (kindIs "slice" $addresses) }}
+{{- $addresses = list $addresses }}
+{{- end }}
+{{- $binds := list }}
+{{- range $addresses }}
+{{- $binds = append $binds (printf "%s:%v" . (index $ 1)) }}
+{{- end }}
+{{- join "," $binds }}
+{{- end }}
@@ -0,0 +1,78 @@
+//! AdmissionReview documents through parsing and Controller::mutate, which must not panic and
+//! must only return patches applying to the object of the review
+//!
+//! Run with `cargo fuzz run mutate`, the first byte selects the controller.
+#![no_main]
+
+use std::{collections::HashSet, sync::LazyLock};
+
+use base64::prelude::*;
+use gravivol::{
+    AdmissionReview, Controller, Kind, Options,
+    controller::{FirstPodMode, PatchMode},
+};
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+
+const CONFIG: &str = "default/myvol1,default/data:preferred,default/logs:anti-affinity,\
+                      apps/myvol1:spread,default/a-claim-name-that-is-much-too-long-for-the-name-part-of-a-label-key";
+
+static CONTROLLERS: LazyLock<[Controller; 3]> = LazyLock::new(|| {
+    [
+        Controller::new(CONFIG),
+        Controller::new(CONFIG).with_options(Options {
+            patch_mode: PatchMode::Both,
+            guarded_patch: true,
+            scheduling_gate: true,
+            stamp_annotation: true,
+            first_pod: FirstPodMode::Anchor,
+            group_by_owner: true,
+            replace_affinity_namespaces: HashSet::from(["default".to_owned()]),
+            ..Default::default()
+        }),
+        Controller::new(CONFIG).with_options(Options {
+            kinds: HashSet::from([
+                Kind::Pod,
+                Kind::Deployment,
+                Kind::StatefulSet,
+                Kind::DaemonSet,
+                Kind::Job,
+                Kind::CronJob,
+            ]),
+            label_pvcs: true,
+            patch_mode: PatchMode::Affinity,
+            ..Default::default()
+        }),
+    ]
+});
+
+static RUNTIME: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+});
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&index, json)) = data.split_first() else {
+        return;
+    };
+    let controller = &CONTROLLERS[usize::from(index) % CONTROLLERS.len()];
+    let Ok(document) = serde_json::from_slice::<Value>(json) else {
+        return;
+    };
+    let Ok(review) = serde_json::from_value::<AdmissionReview>(document.clone()) else {
+        return;
+    };
+    let Ok(mutated) = RUNTIME.block_on(controller.mutate(review)) else {
+        return;
+    };
+    let Some(encoded) = mutated.response().and_then(|response| response.patch.as_ref()) else {
+        return;
+    };
+    let patch: json_patch::Patch =
+        serde_json::from_slice(&BASE64_STANDARD.decode(encoded).unwrap()).unwrap();
+    let mut object = document["request"]["object"].clone();
+    if let Err(err) = json_patch::patch(&mut object, &patch) {
+        panic!("{err}: {patch:?} on {}", document["request"]["object"]);
+    }
+});
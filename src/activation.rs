@@ -0,0 +1,86 @@
+//! Sockets passed by systemd on socket activation, see sd_listen_fds(3)
+
+use std::{
+    env, io,
+    net::TcpListener,
+    os::fd::{FromRawFd, RawFd},
+    process,
+};
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Number of sockets passed to the process with the given pid, 0 if not socket activated
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<usize> {
+    // Inherited by a child of the activated process otherwise
+    if listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) != Some(pid) {
+        return Ok(0);
+    }
+    let Some(listen_fds) = listen_fds else {
+        return Ok(0);
+    };
+    listen_fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS must be a number but is '{listen_fds}'"),
+        )
+    })
+}
+
+/// TCP sockets passed by systemd, empty if not socket activated
+///
+/// The variables of systemd are removed, so that they are not inherited.
+///
+/// # Safety
+///
+/// Changes the environment, so no other thread may be running yet.
+pub unsafe fn listeners() -> io::Result<Vec<TcpListener>> {
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    );
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        // SAFETY: single threaded as required of the caller
+        unsafe { env::remove_var(name) };
+    }
+    (0..count?)
+        .map(|index| {
+            let fd = LISTEN_FDS_START + index as RawFd;
+            // SAFETY: systemd passed the descriptor to this process, nothing else owns it
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // Unix and UDP sockets have no TCP address
+            listener.local_addr().map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("Socket {fd} passed by systemd is no TCP listener: {err}"),
+                )
+            })?;
+            // Not inherited by processes started later on
+            // SAFETY: fcntl with F_SETFD only changes flags of the owned descriptor
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42).unwrap(), 0);
+        assert_eq!(
+            listen_fds(Some("42"), Some("two"), 42)
+                .unwrap_err()
+                .to_string(),
+            "LISTEN_FDS must be a number but is 'two'"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Periodic audit of running pods that escaped mutation
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
+use kube::{
+    Api, Client, ResourceExt,
+    api::{ListParams, ObjectList},
+};
+
+use crate::{controller::Controller, events::EventRecorder, metrics::Metrics};
+
+/// Pods listed per request, so that big clusters are listed in pages
+const PAGE_SIZE: u32 = 500;
+
+/// Periodically reports running pods that lack the labels of their claims
+///
+/// Only run by the leader, see [crate::leader::while_leading], so that replicas do not scan
+/// twice. Pods are reported by metric, log and optionally event, never evicted or modified.
+pub async fn run(
+    client: Client,
+    controller: Arc<Controller>,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+    events: Option<Arc<EventRecorder>>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match audit(&client, &controller, events.as_deref()).await {
+            Ok(counts) => metrics.set_unmutated_pods(&counts),
+            Err(err) => tracing::error!("Cannot list pods to audit: {err}"),
+        }
+    }
+}
+
+/// Number of unmutated pods by namespace
+async fn audit(
+    client: &Client,
+    controller: &Controller,
+    events: Option<&EventRecorder>,
+) -> Result<HashMap<String, i64>, kube::Error> {
+    let pods: Api<Pod> = Api::all(client.clone());
+    let mut params = ListParams::default()
+        .fields("status.phase=Running")
+        .limit(PAGE_SIZE);
+    let (mut counts, mut scanned) = (HashMap::new(), 0);
+    loop {
+        let page: ObjectList<Pod> = pods.list(&params).await?;
+        for pod in &page.items {
+            scanned += 1;
+            let (namespace, name) = (pod.namespace().unwrap_or_default(), pod.name_any());
+            let missing = match serde_json::to_value(pod) {
+                Ok(value) => controller
+                    .missing_claim_labels(&value, true)
+                    .await
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
+            let claims = match missing {
+                Ok(claims) if !claims.is_empty() => claims,
+                Ok(_) => continue,
+                Err(err) => {
+                    tracing::debug!("Cannot audit pod {namespace}/{name}: {err}");
+                    continue;
+                }
+            };
+            tracing::warn!(
+                namespace,
+                pod = name,
+                claims = claims.join(","),
+                "Pod {namespace}/{name} lacks the labels of claims {}, it was not mutated",
+                claims.join(", ")
+            );
+            if let Some(events) = events {
+                let reference = ObjectReference {
+                    api_version: Some("v1".to_owned()),
+                    kind: Some("Pod".to_owned()),
+                    namespace: Some(namespace.clone()),
+                    name: Some(name),
+                    uid: pod.uid(),
+                    ..ObjectReference::default()
+                };
+                events.pod_unmutated(&reference, &claims).await;
+            }
+            *counts.entry(namespace).or_default() += 1;
+        }
+        match page.metadata.continue_ {
+            Some(token) if !token.is_empty() => params = params.continue_token(&token),
+            _ => break,
+        }
+    }
+    tracing::info!(
+        "Audited {scanned} running pods, {} lack the labels of their claims",
+        counts.values().sum::<i64>()
+    );
+    Ok(counts)
+}
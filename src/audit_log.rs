@@ -0,0 +1,309 @@
+//! Append-only JSON Lines log of the decisions, for a record independent of log retention
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use json_patch::PatchOperation;
+use serde::Serialize;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::mpsc::{self, Receiver, Sender, error::TrySendError},
+};
+
+use crate::{decisions::Decision, metrics::Metrics};
+
+/// Decisions waiting to be written before further ones are dropped
+const CAPACITY: usize = 4096;
+
+/// One line of the log, the decision with the operations of its patch
+#[derive(Serialize)]
+struct Line<'a> {
+    #[serde(flatten)]
+    decision: &'a Decision,
+    patch: &'a [PatchOperation],
+}
+
+/// Queues decisions for a thread writing them to the file, never blocking admission
+pub struct AuditLog {
+    sender: Sender<Decision>,
+    metrics: Arc<Metrics>,
+}
+
+impl AuditLog {
+    /// Opens the file for appending and starts writing to it
+    ///
+    /// The file is reopened on SIGUSR1, e.g. after logrotate moved it, and renamed to
+    /// `<path>.1` once it reaches max_bytes, unless 0.
+    pub fn start(path: &str, max_bytes: u64, metrics: Arc<Metrics>) -> io::Result<AuditLog> {
+        let (log, receiver) = AuditLog::new(CAPACITY, metrics.clone());
+        let writer = Writer::open(PathBuf::from(path), max_bytes, metrics)?;
+        let mut reopen = signal(SignalKind::user_defined1())?;
+        let requested = writer.reopen.clone();
+        tokio::spawn(async move {
+            while reopen.recv().await.is_some() {
+                tracing::info!("Got SIGUSR1, reopening the audit log");
+                requested.store(true, Ordering::Relaxed);
+            }
+        });
+        std::thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || writer.run(receiver))?;
+        Ok(log)
+    }
+
+    pub(crate) fn new(capacity: usize, metrics: Arc<Metrics>) -> (AuditLog, Receiver<Decision>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (AuditLog { sender, metrics }, receiver)
+    }
+
+    /// Queues the decision, dropping and counting it if the writer falls behind
+    pub fn record(&self, decision: &Decision) {
+        match self.sender.try_send(decision.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(decision)) => {
+                tracing::warn!("Audit log is behind, dropped decision {}", decision.uid);
+                self.metrics.audit_log_dropped("overflow");
+            }
+            Err(TrySendError::Closed(decision)) => {
+                tracing::error!("Audit log is closed, dropped decision {}", decision.uid);
+                self.metrics.audit_log_dropped("error");
+            }
+        }
+    }
+}
+
+/// The file and how much was written to it
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    file: BufWriter<File>,
+    size: u64,
+    /// Set on SIGUSR1, the file is reopened before the next line
+    reopen: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Writer {
+    fn open(path: PathBuf, max_bytes: u64, metrics: Arc<Metrics>) -> io::Result<Writer> {
+        let (file, size) = Writer::append(&path)?;
+        Ok(Writer {
+            path,
+            max_bytes,
+            file,
+            size,
+            reopen: Arc::new(AtomicBool::new(false)),
+            metrics,
+        })
+    }
+
+    fn append(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((BufWriter::new(file), size))
+    }
+
+    /// Writes the decisions as they arrive, flushing whenever none is waiting
+    fn run(mut self, mut receiver: Receiver<Decision>) {
+        while let Some(decision) = receiver.blocking_recv() {
+            self.write_or_count(&decision);
+            while let Ok(decision) = receiver.try_recv() {
+                self.write_or_count(&decision);
+            }
+            if let Err(err) = self.file.flush() {
+                tracing::error!("Cannot write audit log {}: {err}", self.path.display());
+            }
+        }
+    }
+
+    fn write_or_count(&mut self, decision: &Decision) {
+        if let Err(err) = self.write(decision) {
+            tracing::error!(
+                "Cannot write decision {} to audit log {}: {err}",
+                decision.uid,
+                self.path.display()
+            );
+            self.metrics.audit_log_dropped("error");
+        }
+    }
+
+    fn write(&mut self, decision: &Decision) -> io::Result<()> {
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            self.reopen()?;
+        }
+        if self.max_bytes > 0 && self.size >= self.max_bytes {
+            self.rotate()?;
+        }
+        let mut line = serde_json::to_vec(&Line {
+            decision,
+            patch: &decision.patch,
+        })?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        (self.file, self.size) = Writer::append(&self.path)?;
+        Ok(())
+    }
+
+    /// Renames the file to `<path>.1`, replacing the previous one, and starts a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, &rotated)?;
+        tracing::info!("Rotated audit log {}", self.path.display());
+        self.reopen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_patch::Patch;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::metrics::SkipReason;
+
+    /// A file of a test in the temporary directory, removed with its rotation on drop
+    struct TestFile(PathBuf);
+
+    impl TestFile {
+        fn new(name: &str) -> TestFile {
+            let path =
+                std::env::temp_dir().join(format!("gravivol-{name}-{}.jsonl", std::process::id()));
+            let _ = fs::remove_file(&path);
+            TestFile(path)
+        }
+
+        fn lines(&self, suffix: &str) -> Vec<Value> {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let mut rotated = self.0.clone().into_os_string();
+            rotated.push(".1");
+            let _ = fs::remove_file(rotated);
+        }
+    }
+
+    fn patched() -> Decision {
+        let patch: Patch = serde_json::from_value(json!([{
+            "op": "add",
+            "path": "/metadata/labels",
+            "value": { "default.gravivol.fonona.net/data": "true" },
+        }]))
+        .unwrap();
+        let mut decision = Decision {
+            uid: "705ab4f5".to_owned(),
+            namespace: "default".to_owned(),
+            name: "web-".to_owned(),
+            claims: vec!["data".to_owned()],
+            patch_ops: 1,
+            patch: patch.0,
+            ..Default::default()
+        };
+        decision.stamp();
+        decision
+    }
+
+    #[test]
+    fn test_line_schema() {
+        let file = TestFile::new("audit-log");
+        let mut writer = Writer::open(file.0.clone(), 0, Arc::new(Metrics::new())).unwrap();
+        writer.write(&patched()).unwrap();
+        writer
+            .write(&Decision {
+                uid: "9c1e77d0".to_owned(),
+                namespace: "apps".to_owned(),
+                name: "worker".to_owned(),
+                skip_reason: Some(SkipReason::NoVolumes),
+                ..Default::default()
+            })
+            .unwrap();
+        writer.file.flush().unwrap();
+
+        let lines = file.lines("");
+        assert_eq!(lines.len(), 2);
+        let keys = |line: &Value| {
+            let mut keys: Vec<String> = line.as_object().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            keys(&lines[0]),
+            [
+                "claims",
+                "name",
+                "namespace",
+                "patch",
+                "patchOps",
+                "timestamp",
+                "uid"
+            ]
+        );
+        assert!(lines[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(
+            lines[0]["patch"],
+            json!([{
+                "op": "add",
+                "path": "/metadata/labels",
+                "value": { "default.gravivol.fonona.net/data": "true" },
+            }])
+        );
+        assert_eq!(lines[1]["skipReason"], "no_volumes");
+        assert!(lines[1].get("dryRun").is_none());
+        assert_eq!(lines[1]["patch"], json!([]));
+    }
+
+    #[test]
+    fn test_rotate_and_reopen() {
+        let file = TestFile::new("audit-log-rotate");
+        let mut writer = Writer::open(file.0.clone(), 1, Arc::new(Metrics::new())).unwrap();
+        writer.write(&patched()).unwrap();
+        writer.write(&patched()).unwrap();
+        writer.file.flush().unwrap();
+        assert_eq!(file.lines(".1").len(), 1);
+        assert_eq!(file.lines("").len(), 1);
+
+        // Moved away like by logrotate, the next line goes to a new file
+        fs::remove_file(&file.0).unwrap();
+        writer.reopen.store(true, Ordering::Relaxed);
+        writer.max_bytes = 0;
+        writer.write(&patched()).unwrap();
+        writer.file.flush().unwrap();
+        assert_eq!(file.lines("").len(), 1);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let metrics = Arc::new(Metrics::new());
+        let (log, _receiver) = AuditLog::new(1, metrics.clone());
+        log.record(&patched());
+        log.record(&patched());
+        assert!(
+            metrics
+                .encode()
+                .contains("gravivol_audit_log_dropped_total{reason=\"overflow\"} 1\n")
+        );
+    }
+}
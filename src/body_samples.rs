@@ -0,0 +1,242 @@
+//! Request bodies kept for /debug/bodies, sampled by uid and with the env of containers redacted
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+/// Replaces the values of environment variables, which often carry credentials
+const REDACTED: &str = "<redacted>";
+
+/// A request body as received, but with redacted env values
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    /// RFC 3339 time the request was received
+    pub timestamp: String,
+    pub uid: String,
+    pub body: Value,
+    /// Size of the redacted body as JSON, counted against the limit of the spool
+    #[serde(skip)]
+    bytes: usize,
+}
+
+/// Only the uid of a request, read before deciding whether to keep the whole body
+#[derive(Deserialize)]
+struct Envelope {
+    request: Option<EnvelopeRequest>,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeRequest {
+    #[serde(default)]
+    uid: String,
+}
+
+/// Sampled bodies up to a total size, dropping the oldest ones for new ones
+pub struct BodySamples {
+    /// Share of the uids sampled, 0 to 1
+    rate: f64,
+    /// Uids starting with one of them are always sampled
+    uid_prefixes: Vec<String>,
+    max_bytes: usize,
+    spool: Mutex<(VecDeque<Sample>, usize)>,
+}
+
+impl BodySamples {
+    pub fn new(rate: f64, uid_prefixes: Vec<String>, max_bytes: usize) -> BodySamples {
+        BodySamples {
+            rate,
+            uid_prefixes,
+            max_bytes,
+            spool: Mutex::new((VecDeque::new(), 0)),
+        }
+    }
+
+    /// Whether the request is sampled, the same for every retry of a request
+    fn sampled(&self, uid: &str) -> bool {
+        self.uid_prefixes
+            .iter()
+            .any(|prefix| uid.starts_with(prefix.as_str()))
+            || (hash(uid.as_bytes()) as f64 / u64::MAX as f64) < self.rate
+    }
+
+    /// Keeps the body if its uid is sampled, true if it was kept
+    ///
+    /// Bodies that are not JSON cannot be redacted and are never kept, neither are those larger
+    /// than the spool.
+    pub fn offer(&self, body: &[u8]) -> bool {
+        let Ok(Envelope {
+            request: Some(EnvelopeRequest { uid }),
+        }) = serde_json::from_slice(body)
+        else {
+            return false;
+        };
+        if !self.sampled(&uid) {
+            return false;
+        }
+        let Ok(mut body) = serde_json::from_slice::<Value>(body) else {
+            return false;
+        };
+        redact(&mut body);
+        let bytes = serde_json::to_vec(&body).map_or(usize::MAX, |json| json.len());
+        if bytes > self.max_bytes {
+            tracing::debug!("Body of request {uid} has {bytes} bytes, too large to keep");
+            return false;
+        }
+        tracing::debug!("Keeping body of request {uid}");
+        let (samples, size) = &mut *self.spool.lock().unwrap();
+        while *size + bytes > self.max_bytes {
+            match samples.pop_front() {
+                Some(oldest) => *size -= oldest.bytes,
+                None => break,
+            }
+        }
+        *size += bytes;
+        samples.push_back(Sample {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            uid,
+            body,
+            bytes,
+        });
+        true
+    }
+
+    /// Newest first
+    pub fn recent(&self) -> Vec<Sample> {
+        self.spool.lock().unwrap().0.iter().rev().cloned().collect()
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds unlike the hasher of the standard library
+///
+/// The high bits of FNV-1a are skewed for similar inputs like uids, so they are mixed with the
+/// finalizer of MurmurHash3 before comparing them against the rate.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Replaces the values of the env of all containers, in pods, templates and old objects alike
+///
+/// References with valueFrom and envFrom only name secrets and config maps and are kept.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match (key.as_str(), value) {
+                    (
+                        "containers" | "initContainers" | "ephemeralContainers",
+                        Value::Array(containers),
+                    ) => {
+                        for container in containers {
+                            let env = container.get_mut("env").and_then(Value::as_array_mut);
+                            for variable in env.into_iter().flatten() {
+                                if let Some(value) = variable.get_mut("value") {
+                                    *value = Value::String(REDACTED.to_owned());
+                                }
+                            }
+                        }
+                    }
+                    (_, value) => redact(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn body(uid: &str, env: &str) -> Vec<u8> {
+        json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": uid,
+                "object": {
+                    "kind": "Deployment",
+                    "spec": { "template": { "spec": {
+                        "initContainers": [{ "name": "init", "env": [{ "name": "TOKEN", "value": env }] }],
+                        "containers": [{
+                            "name": "web",
+                            "env": [
+                                { "name": "PASSWORD", "value": env },
+                                { "name": "KEY", "valueFrom": { "secretKeyRef": { "name": "web", "key": "key" } } },
+                            ],
+                        }],
+                    } } },
+                },
+            },
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_sampled() {
+        let samples = BodySamples::new(0.25, vec!["debug-".to_owned()], 1024);
+        let uids: Vec<String> = (0..4000).map(|i| format!("{i:08x}")).collect();
+        let sampled = uids.iter().filter(|uid| samples.sampled(uid)).count();
+        assert!((800..1200).contains(&sampled), "{sampled}");
+        assert!(samples.sampled("debug-705ab4f5"));
+        assert!(!BodySamples::new(0.0, Vec::new(), 1024).sampled("705ab4f5"));
+        assert!(BodySamples::new(1.0, Vec::new(), 1024).sampled("705ab4f5"));
+    }
+
+    #[test]
+    fn test_redact() {
+        let samples = BodySamples::new(1.0, Vec::new(), 1024 * 1024);
+        assert!(samples.offer(&body("705ab4f5", "s3cr3t")));
+        assert!(!samples.offer(b"{\"request\":"));
+
+        let recent = samples.recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].uid, "705ab4f5");
+        let text = recent[0].body.to_string();
+        assert!(!text.contains("s3cr3t"), "{text}");
+        let spec = &recent[0].body["request"]["object"]["spec"]["template"]["spec"];
+        assert_eq!(spec["initContainers"][0]["env"][0]["value"], REDACTED);
+        assert_eq!(spec["containers"][0]["env"][0]["name"], "PASSWORD");
+        assert_eq!(spec["containers"][0]["env"][0]["value"], REDACTED);
+        assert_eq!(
+            spec["containers"][0]["env"][1]["valueFrom"]["secretKeyRef"]["name"],
+            "web"
+        );
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let size = serde_json::from_slice::<Value>(&body("00000001", REDACTED))
+            .unwrap()
+            .to_string()
+            .len();
+        let samples = BodySamples::new(1.0, Vec::new(), 2 * size + size / 2);
+        for uid in ["00000001", "00000002", "00000003"] {
+            assert!(samples.offer(&body(uid, REDACTED)));
+        }
+        let uids: Vec<String> = samples
+            .recent()
+            .into_iter()
+            .map(|sample| sample.uid)
+            .collect();
+        assert_eq!(uids, ["00000003", "00000002"]);
+
+        let small = BodySamples::new(1.0, Vec::new(), size - 1);
+        assert!(!small.offer(&body("00000001", REDACTED)));
+        assert!(small.recent().is_empty());
+    }
+}
@@ -0,0 +1,24 @@
+//! Which build is running, embedded by build.rs
+
+use serde::Serialize;
+
+/// Crate version and git commit, e.g. "0.1.1+3e363d7a1b2c"
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GRAVIVOL_GIT_COMMIT"));
+
+/// Served on /version
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Abbreviated commit hash, "unknown" when built outside a git checkout
+    pub git_commit: &'static str,
+    pub rustc_version: &'static str,
+    /// RFC 3339 time of the build, or of SOURCE_DATE_EPOCH
+    pub built_at: &'static str,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_commit: env!("GRAVIVOL_GIT_COMMIT"),
+    rustc_version: env!("GRAVIVOL_RUSTC_VERSION"),
+    built_at: env!("GRAVIVOL_BUILT_AT"),
+};
@@ -0,0 +1,328 @@
+//! Lookups of claims, volumes, pods and storage classes in the Kubernetes API, cached for a while
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use k8s_openapi::api::{
+    core::v1::{PersistentVolume, PersistentVolumeClaim, Pod},
+    storage::v1::StorageClass,
+};
+use kube::{Api, Client, api::ListParams};
+
+use crate::metrics::Metrics;
+
+/// Why a lookup failed, e.g. an error of the Kubernetes API
+pub type LookupError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Lookups of objects in the Kubernetes API
+///
+/// Lookups for dry run requests must not have side effects, e.g. they are not cached.
+#[async_trait]
+pub trait Cluster: Send + Sync {
+    /// The claim or None if it does not exist
+    async fn get_claim(
+        &self,
+        namespace: &str,
+        name: &str,
+        dry_run: bool,
+    ) -> Result<Option<PersistentVolumeClaim>, LookupError>;
+
+    /// The persistent volume or None if it does not exist
+    async fn get_volume(
+        &self,
+        name: &str,
+        dry_run: bool,
+    ) -> Result<Option<PersistentVolume>, LookupError>;
+
+    /// Whether pods matching the label selector exist in the namespace
+    async fn has_pods(
+        &self,
+        namespace: &str,
+        label_selector: &str,
+        dry_run: bool,
+    ) -> Result<bool, LookupError>;
+
+    /// The storage class, the default class if name is None, None if it does not exist
+    async fn get_storage_class(
+        &self,
+        name: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Option<StorageClass>, LookupError>;
+}
+
+/// Annotation of the default storage class of the cluster
+const DEFAULT_CLASS_ANNOTATION: &str = "storageclass.kubernetes.io/is-default-class";
+
+/// Storage classes rarely change and are cached long
+const STORAGE_CLASS_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Phase of the claim, e.g. "Pending" or "Bound", None if it does not exist or has no status
+pub fn claim_phase(claim: Option<&PersistentVolumeClaim>) -> Option<&str> {
+    claim?.status.as_ref()?.phase.as_deref()
+}
+
+/// Pods come and go, so their lookups are only cached briefly
+const POD_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Claims that are not bound yet or do not exist usually change shortly after
+const UNBOUND_CLAIM_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Values that expire after a time, the one expiring first is evicted when full
+///
+/// Shared by all workers, hits, misses and evictions are counted in the metrics.
+struct TtlCache<V> {
+    /// Label of the cache in the metrics, e.g. "claims"
+    name: &'static str,
+    ttl: Duration,
+    capacity: usize,
+    /// Values by key with their expiry
+    entries: Mutex<HashMap<String, (Instant, V)>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<V: Clone> TtlCache<V> {
+    fn new(
+        name: &'static str,
+        ttl: Duration,
+        capacity: usize,
+        metrics: Arc<Metrics>,
+    ) -> TtlCache<V> {
+        TtlCache {
+            name,
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let value = match entries.get(key) {
+            Some((expiry, value)) if Instant::now() < *expiry => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        self.metrics.cache_lookup(self.name, value.is_some());
+        value
+    }
+
+    fn insert(&self, key: String, value: V) {
+        self.insert_for(key, value, self.ttl);
+    }
+
+    /// Inserts a value that expires earlier than the others
+    fn insert_for(&self, key: String, value: V, ttl: Duration) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (expiry, _)| now < *expiry);
+        if entries.len() >= self.capacity
+            && !entries.contains_key(&key)
+            && let Some(first) = entries
+                .iter()
+                .min_by_key(|(_, (expiry, _))| *expiry)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&first);
+            self.metrics.cache_evicted(self.name);
+        }
+        entries.insert(key, (now + ttl.min(self.ttl), value));
+    }
+}
+
+/// Cluster backed by the Kubernetes API, caching the results
+pub struct KubeCluster {
+    client: Client,
+    /// Claims, negative results and claims that are not bound only briefly
+    claims: TtlCache<Option<PersistentVolumeClaim>>,
+    volumes: TtlCache<Option<PersistentVolume>>,
+    pods: TtlCache<bool>,
+    /// By name, the default class by the empty name
+    storage_classes: TtlCache<Option<StorageClass>>,
+    metrics: Arc<Metrics>,
+}
+
+impl KubeCluster {
+    /// Caches up to capacity objects of each kind for the ttl, pods and storage classes shorter
+    /// and longer
+    pub fn new(
+        client: Client,
+        ttl: Duration,
+        capacity: usize,
+        metrics: Arc<Metrics>,
+    ) -> KubeCluster {
+        KubeCluster {
+            client,
+            claims: TtlCache::new("claims", ttl, capacity, metrics.clone()),
+            volumes: TtlCache::new("volumes", ttl, capacity, metrics.clone()),
+            pods: TtlCache::new("pods", POD_CACHE_TTL.min(ttl), capacity, metrics.clone()),
+            storage_classes: TtlCache::new(
+                "storage_classes",
+                STORAGE_CLASS_CACHE_TTL.max(ttl),
+                capacity,
+                metrics.clone(),
+            ),
+            metrics,
+        }
+    }
+}
+
+#[async_trait]
+impl Cluster for KubeCluster {
+    async fn get_claim(
+        &self,
+        namespace: &str,
+        name: &str,
+        dry_run: bool,
+    ) -> Result<Option<PersistentVolumeClaim>, LookupError> {
+        let key = format!("{namespace}/{name}");
+        if let Some(claim) = self.claims.get(&key) {
+            return Ok(claim);
+        }
+        let api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), namespace);
+        let claim = {
+            let _timer = self.metrics.time_lookup("claim");
+            api.get_opt(name).await?
+        };
+        if !dry_run {
+            if claim_phase(claim.as_ref()) == Some("Bound") {
+                self.claims.insert(key, claim.clone());
+            } else {
+                self.claims
+                    .insert_for(key, claim.clone(), UNBOUND_CLAIM_CACHE_TTL);
+            }
+        }
+        Ok(claim)
+    }
+
+    async fn get_volume(
+        &self,
+        name: &str,
+        dry_run: bool,
+    ) -> Result<Option<PersistentVolume>, LookupError> {
+        if let Some(volume) = self.volumes.get(name) {
+            return Ok(volume);
+        }
+        let api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let volume = {
+            let _timer = self.metrics.time_lookup("volume");
+            api.get_opt(name).await?
+        };
+        if !dry_run {
+            self.volumes.insert(name.to_owned(), volume.clone());
+        }
+        Ok(volume)
+    }
+
+    async fn has_pods(
+        &self,
+        namespace: &str,
+        label_selector: &str,
+        dry_run: bool,
+    ) -> Result<bool, LookupError> {
+        let key = format!("{namespace}/{label_selector}");
+        if let Some(has_pods) = self.pods.get(&key) {
+            return Ok(has_pods);
+        }
+        let api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let pods = {
+            let _timer = self.metrics.time_lookup("pods");
+            api.list_metadata(&ListParams::default().labels(label_selector).limit(1))
+                .await?
+        };
+        let has_pods = !pods.items.is_empty();
+        if !dry_run {
+            self.pods.insert(key, has_pods);
+        }
+        Ok(has_pods)
+    }
+
+    async fn get_storage_class(
+        &self,
+        name: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Option<StorageClass>, LookupError> {
+        let key = name.unwrap_or_default();
+        if let Some(class) = self.storage_classes.get(key) {
+            return Ok(class);
+        }
+        let api: Api<StorageClass> = Api::all(self.client.clone());
+        let _timer = self.metrics.time_lookup("storage_class");
+        let class = match name {
+            Some(name) => api.get_opt(name).await?,
+            None => api
+                .list(&ListParams::default())
+                .await?
+                .items
+                .into_iter()
+                .find(|class| {
+                    class
+                        .metadata
+                        .annotations
+                        .as_ref()
+                        .and_then(|annotations| annotations.get(DEFAULT_CLASS_ANNOTATION))
+                        .is_some_and(|value| value == "true")
+                }),
+        };
+        if !dry_run {
+            self.storage_classes.insert(key.to_owned(), class.clone());
+        }
+        Ok(class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_cache() {
+        let metrics = Arc::new(Metrics::new());
+        let cache = TtlCache::new("claims", Duration::from_millis(50), 10, metrics.clone());
+        cache.insert("a".to_owned(), 1);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("b"), None);
+        cache.insert_for("b".to_owned(), 2, Duration::ZERO);
+        assert_eq!(cache.get("b"), None);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("a"), None);
+        let text = metrics.encode();
+        assert!(text.contains("gravivol_cache_lookups_total{cache=\"claims\",result=\"hit\"} 1\n"));
+        assert!(
+            text.contains("gravivol_cache_lookups_total{cache=\"claims\",result=\"miss\"} 3\n")
+        );
+    }
+
+    #[test]
+    fn test_ttl_cache_capacity() {
+        let metrics = Arc::new(Metrics::new());
+        let cache = TtlCache::new("claims", Duration::from_secs(60), 2, metrics.clone());
+        cache.insert("a".to_owned(), 1);
+        cache.insert("b".to_owned(), 2);
+        cache.insert("b".to_owned(), 3);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert("c".to_owned(), 4);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(3));
+        assert_eq!(cache.get("c"), Some(4));
+        assert!(
+            metrics
+                .encode()
+                .contains("gravivol_cache_evictions_total{cache=\"claims\"} 1\n")
+        );
+
+        let disabled = TtlCache::new("claims", Duration::from_secs(60), 0, metrics);
+        disabled.insert("a".to_owned(), 1);
+        assert_eq!(disabled.get("a"), None);
+    }
+}
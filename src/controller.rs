@@ -1,20 +1,72 @@
-use std::collections::{HashMap, HashSet};
+//! Decides the patch of an admission request from the claims of the pod
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use base64::{Engine, prelude::BASE64_STANDARD};
-use json_patch::diff;
-use serde::{Deserialize, Serialize};
+use json_patch::{Patch, PatchOperation, TestOperation, diff, jsonptr::PointerBuf};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use serde::{Deserialize, Deserializer, Serialize, de};
 use serde_json::{Value, json};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::{
+    audit_log::AuditLog,
+    cluster::{Cluster, LookupError, claim_phase},
+    decisions::{Decision, Decisions},
+    events::EventRecorder,
+    gate::{GATE_LABEL, SCHEDULING_GATE},
+    metrics::{Metrics, Phase, SkipReason},
+    namespaces::{Namespaces, selector_matches},
+    rate_limit::RateLimiter,
+};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct Metadata {
     #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     generate_name: Option<String>,
+    /// Empty for pod templates
+    #[serde(
+        default,
+        deserialize_with = "null_as_default",
+        skip_serializing_if = "String::is_empty"
+    )]
     namespace: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     labels: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner_references: Option<Vec<OwnerReference>>,
+}
+
+/// Explicit null like a missing field, as sent by some clients
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// The metadata of an object, empty if it has none
+fn object_metadata(object: &Value) -> Result<Metadata, serde_json::Error> {
+    Option::<Metadata>::deserialize(&object["metadata"]).map(Option::unwrap_or_default)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OwnerReference {
+    kind: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    controller: Option<bool>,
 }
 
 impl Metadata {
@@ -27,6 +79,215 @@ impl Metadata {
             format!("{}/unknown", self.namespace)
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.generate_name.is_none()
+            && self.namespace.is_empty()
+            && self.labels.is_none()
+            && self.annotations.is_none()
+            && self.owner_references.is_none()
+    }
+
+    /// Name of the top-level controller, a ReplicaSet resolved to its Deployment by the template hash
+    pub fn top_level_owner(&self) -> Option<String> {
+        let owner = self
+            .owner_references
+            .as_ref()?
+            .iter()
+            .find(|owner| owner.controller == Some(true))?;
+        let template_hash = self
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get("pod-template-hash"));
+        if owner.kind == "ReplicaSet"
+            && let Some(template_hash) = template_hash
+            && let Some(deployment) = owner.name.strip_suffix(&format!("-{template_hash}"))
+        {
+            return Some(deployment.to_owned());
+        }
+        Some(owner.name.to_owned())
+    }
+
+    pub fn is_owned_by(&self, kind: &str) -> bool {
+        self.owner_references
+            .iter()
+            .flatten()
+            .any(|owner| owner.kind == kind)
+    }
+
+    pub fn get_annotation(&self, key: &str) -> Option<&str> {
+        self.annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(key))
+            .map(|value| value.as_str())
+    }
+}
+
+const ROLE_ANNOTATION: &str = "gravivol.fonona.net/role";
+const MUTATED_ANNOTATION: &str = "gravivol.fonona.net/mutated";
+const VERSION_ANNOTATION: &str = "gravivol.fonona.net/version";
+const MANAGED_LABEL: &str = "gravivol.fonona.net/managed";
+
+const PATCH_ANNOTATION: &str = "gravivol.fonona.net/patch";
+const TOPOLOGY_KEY_ANNOTATION: &str = "gravivol.fonona.net/topology-key";
+const CLAIMS_ANNOTATION: &str = "gravivol.fonona.net/claims";
+const DEFAULT_TOPOLOGY_KEY: &str = "kubernetes.io/hostname";
+/// Suffix of the prefix of the claim labels, after the namespace
+const DEFAULT_LABEL_DOMAIN: &str = "gravivol.fonona.net";
+/// Set by the kubelet on the mirror pods of static pods
+const MIRROR_ANNOTATION: &str = "kubernetes.io/config.mirror";
+
+/// Which parts of the mutation a pod receives
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchMode {
+    /// Labels only, e.g. for the anchor pod other pods follow
+    Labels,
+    /// Affinity only, pointing at labels applied elsewhere
+    Affinity,
+    /// Labels and affinity
+    Both,
+}
+
+impl PatchMode {
+    /// The mode of the name, labels, affinity or both, None if unknown
+    pub fn from_name(name: &str) -> Option<PatchMode> {
+        match name {
+            "labels" => Some(PatchMode::Labels),
+            "affinity" => Some(PatchMode::Affinity),
+            "both" => Some(PatchMode::Both),
+            _ => None,
+        }
+    }
+
+    /// The mode requested by the role or patch annotation of the pod
+    fn from_metadata(
+        metadata: &Metadata,
+        default: PatchMode,
+        warnings: &mut Vec<String>,
+    ) -> PatchMode {
+        let from_role = match metadata.get_annotation(ROLE_ANNOTATION) {
+            None => None,
+            Some("anchor") => Some(PatchMode::Labels),
+            Some("follower") => Some(PatchMode::Affinity),
+            Some(other) => {
+                tracing::warn!(
+                    "Unknown value '{}' for annotation {}, ignoring it",
+                    other,
+                    ROLE_ANNOTATION
+                );
+                warnings.push(warning(&format!(
+                    "unknown value '{other}' for annotation {ROLE_ANNOTATION} ignored"
+                )));
+                None
+            }
+        };
+        let from_patch = match metadata.get_annotation(PATCH_ANNOTATION) {
+            None => None,
+            Some(name) => {
+                let mode = PatchMode::from_name(name);
+                if mode.is_none() {
+                    tracing::warn!(
+                        "Unknown value '{}' for annotation {}, ignoring it",
+                        name,
+                        PATCH_ANNOTATION
+                    );
+                    warnings.push(warning(&format!(
+                        "unknown value '{name}' for annotation {PATCH_ANNOTATION} ignored"
+                    )));
+                }
+                mode
+            }
+        };
+        from_role.or(from_patch).unwrap_or(default)
+    }
+
+    fn adds_labels(&self) -> bool {
+        *self != PatchMode::Affinity
+    }
+
+    fn adds_affinity(&self) -> bool {
+        *self != PatchMode::Labels
+    }
+}
+
+/// The API server truncates longer warnings
+const MAX_WARNING_LENGTH: usize = 120;
+
+/// Creates an admission warning, truncated to the length the API server accepts
+///
+/// Names taken from the object may contain any character. All but printable ASCII are escaped,
+/// e.g. \u{1f4be}, so that the warning is safe to send as a header and to truncate.
+fn warning(message: &str) -> String {
+    let mut escaped = String::with_capacity(message.len() + 10);
+    // Length of the characters fitting before "...", never cutting an escape in half
+    let mut fitting = 0;
+    for c in format!("gravivol: {message}").chars() {
+        if c == ' ' || c.is_ascii_graphic() {
+            escaped.push(c);
+        } else {
+            escaped.extend(c.escape_default());
+        }
+        if escaped.len() <= MAX_WARNING_LENGTH - 3 {
+            fitting = escaped.len();
+        }
+    }
+    if escaped.len() > MAX_WARNING_LENGTH {
+        escaped.truncate(fitting);
+        escaped.push_str("...");
+    }
+    escaped
+}
+
+/// Maximum length of the name part of a label key
+const MAX_LABEL_NAME_LENGTH: usize = 63;
+/// Maximum length of the prefix part of a label key
+const MAX_LABEL_PREFIX_LENGTH: usize = 253;
+
+/// Whether the key is a label key: an optional DNS subdomain prefix and a name
+pub fn is_valid_label_key(key: &str) -> bool {
+    let (prefix, name) = match key.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, key),
+    };
+    let valid_name = !name.is_empty()
+        && name.len() <= MAX_LABEL_NAME_LENGTH
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let valid_prefix = prefix.is_none_or(|prefix| {
+        prefix.len() <= MAX_LABEL_PREFIX_LENGTH
+            && prefix.split('.').all(|part| {
+                !part.is_empty()
+                    && part.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+                    && part.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            })
+    });
+    valid_name && valid_prefix
+}
+
+/// The topology key requested by the annotation of the pod, if valid
+fn topology_key_from_metadata(metadata: &Metadata, warnings: &mut Vec<String>) -> Option<String> {
+    let topology_key = metadata.get_annotation(TOPOLOGY_KEY_ANNOTATION)?;
+    if is_valid_label_key(topology_key) {
+        Some(topology_key.to_owned())
+    } else {
+        tracing::warn!(
+            "Invalid value '{}' for annotation {}, ignoring it",
+            topology_key,
+            TOPOLOGY_KEY_ANNOTATION
+        );
+        warnings.push(warning(&format!(
+            "invalid label key '{topology_key}' for annotation {TOPOLOGY_KEY_ANNOTATION} ignored"
+        )));
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -36,12 +297,92 @@ struct Label {
 }
 
 impl Label {
-    pub fn from_pvc(pvc: &Pvc) -> Label {
+    pub fn from_pvc(pvc: &Pvc, label_domain: &str) -> Label {
         Label {
-            key: format!("{}.gravivol.fonona.net/{}", pvc.namespace, pvc.claim_name),
+            key: format!("{}.{label_domain}/{}", pvc.namespace, pvc.claim_name),
             value: "true".to_string(),
         }
     }
+
+    /// Whether the claim name can be the name part of a label key
+    ///
+    /// The names of claims are DNS subdomains, but a pod may mount a claim by any name.
+    pub fn is_valid_for_claim(claim_name: &str) -> bool {
+        !claim_name.contains('/') && is_valid_label_key(claim_name)
+    }
+
+    /// Whether the key is one of the claim labels created by gravivol
+    pub fn is_claim_key(key: &str, label_domain: &str) -> bool {
+        key.split_once('/').is_some_and(|(prefix, _)| {
+            prefix
+                .strip_suffix(label_domain)
+                .is_some_and(|namespace| namespace.ends_with('.'))
+        })
+    }
+}
+
+/// Short descriptions of the required pod affinity terms of the pod, e.g. "app=db@kubernetes.io/hostname"
+fn required_affinity_terms(pod: &PodTemplate) -> Vec<String> {
+    let Some(affinity) = &pod.spec.affinity else {
+        return Vec::new();
+    };
+    affinity["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|term| {
+            let selector = &term["labelSelector"];
+            let labels = selector["matchLabels"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(key, value)| format!("{key}={}", value.as_str().unwrap_or_default()));
+            let expressions = selector["matchExpressions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|expression| {
+                    format!(
+                        "{} {}",
+                        expression["key"].as_str().unwrap_or_default(),
+                        expression["operator"].as_str().unwrap_or_default()
+                    )
+                });
+            format!(
+                "{}@{}",
+                labels.chain(expressions).collect::<Vec<String>>().join(","),
+                term["topologyKey"].as_str().unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+/// Topology key of the first required pod affinity term already selecting one of the claim labels
+fn existing_claim_term_topology_key<'a>(
+    pod: &'a PodTemplate,
+    claim_keys: &[String],
+) -> Option<&'a str> {
+    let terms = pod.spec.affinity.as_ref()?["podAffinity"]
+        ["requiredDuringSchedulingIgnoredDuringExecution"]
+        .as_array()?;
+    terms
+        .iter()
+        .find(|term| {
+            let selector = &term["labelSelector"];
+            let label_keys = selector["matchLabels"]
+                .as_object()
+                .into_iter()
+                .flat_map(|labels| labels.keys().map(String::as_str));
+            let expression_keys = selector["matchExpressions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|expression| expression["key"].as_str());
+            label_keys
+                .chain(expression_keys)
+                .any(|key| claim_keys.iter().any(|claim_key| claim_key == key))
+        })
+        .map(|term| term["topologyKey"].as_str().unwrap_or_default())
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -57,620 +398,7133 @@ struct Volume {
     persistent_volume_claim: Option<PersistentVolumeClaim>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct Spec {
     #[serde(skip_serializing_if = "Option::is_none")]
     volumes: Option<Vec<Volume>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     affinity: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_selector: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topology_spread_constraints: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheduling_gates: Option<Vec<Value>>,
 }
 
+/// A pod or the pod template of a workload
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Pod {
-    kind: String,
-    api_version: String,
+struct PodTemplate {
+    // Skipped if empty so that the patch adds it as a whole when it does not exist
+    #[serde(
+        default,
+        deserialize_with = "null_as_default",
+        skip_serializing_if = "Metadata::is_empty"
+    )]
     metadata: Metadata,
+    /// Empty if missing, e.g. sent by a broken client, then there are no claims to patch for
+    #[serde(default, deserialize_with = "null_as_default")]
     spec: Spec,
 }
 
+impl PodTemplate {
+    /// A copy without the volumes, which no patch changes, sparing to copy and compare them
+    fn without_volumes(&self) -> PodTemplate {
+        PodTemplate {
+            metadata: self.metadata.clone(),
+            spec: Spec {
+                volumes: None,
+                affinity: self.spec.affinity.clone(),
+                node_selector: self.spec.node_selector.clone(),
+                topology_spread_constraints: self.spec.topology_spread_constraints.clone(),
+                scheduling_gates: self.spec.scheduling_gates.clone(),
+            },
+        }
+    }
+}
+
+/// The kinds of objects whose pods gravivol can mutate
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum Kind {
+    /// A pod itself
+    Pod,
+    /// The pod template of a Deployment
+    Deployment,
+    /// The pod template of a StatefulSet
+    StatefulSet,
+    /// The pod template of a DaemonSet
+    DaemonSet,
+    /// The pod template of a Job
+    Job,
+    /// The pod template of the job template of a CronJob
+    CronJob,
+}
+
+impl Kind {
+    /// The kind of the name, e.g. Deployment, None if not handled
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "Pod" => Some(Kind::Pod),
+            "Deployment" => Some(Kind::Deployment),
+            "StatefulSet" => Some(Kind::StatefulSet),
+            "DaemonSet" => Some(Kind::DaemonSet),
+            "Job" => Some(Kind::Job),
+            "CronJob" => Some(Kind::CronJob),
+            _ => None,
+        }
+    }
+
+    fn from_object(api_version: &str, kind: &str) -> Option<Kind> {
+        Kind::from_name(kind).filter(|kind| kind.api_version() == api_version)
+    }
+
+    fn api_version(&self) -> &'static str {
+        match self {
+            Kind::Pod => "v1",
+            Kind::Deployment | Kind::StatefulSet | Kind::DaemonSet => "apps/v1",
+            Kind::Job | Kind::CronJob => "batch/v1",
+        }
+    }
+
+    /// JSON pointer to the pod (template) within the object
+    fn template_path(&self) -> &'static str {
+        match self {
+            Kind::Pod => "",
+            Kind::Deployment | Kind::StatefulSet | Kind::DaemonSet | Kind::Job => "/spec/template",
+            Kind::CronJob => "/spec/jobTemplate/spec/template",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// Access modes of a PersistentVolumeClaim
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+// Named as in the Kubernetes API
+#[allow(clippy::enum_variant_names)]
+pub enum AccessMode {
+    /// Mounted read-write by the pods of a single node
+    ReadWriteOnce,
+    /// Mounted read-only by the pods of any node
+    ReadOnlyMany,
+    /// Mounted read-write by the pods of any node
+    ReadWriteMany,
+    /// Mounted read-write by a single pod
+    ReadWriteOncePod,
+}
+
+impl AccessMode {
+    /// The access mode of the name, e.g. ReadWriteOnce, None if unknown
+    pub fn from_name(name: &str) -> Option<AccessMode> {
+        match name {
+            "ReadWriteOnce" => Some(AccessMode::ReadWriteOnce),
+            "ReadOnlyMany" => Some(AccessMode::ReadOnlyMany),
+            "ReadWriteMany" => Some(AccessMode::ReadWriteMany),
+            "ReadWriteOncePod" => Some(AccessMode::ReadWriteOncePod),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct Request {
     uid: String,
-    object: Pod,
+    /// Missing for cluster-scoped objects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// Missing if the object only has a generateName
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Null for DELETE, if the webhook is registered for it
+    #[serde(default)]
+    object: Option<Value>,
+    /// The object before an UPDATE or DELETE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    old_object: Option<Value>,
+    /// CREATE, UPDATE, DELETE or CONNECT
+    #[serde(default)]
+    operation: String,
+    /// Set for kubectl --dry-run=server, nothing but the response must be affected
+    #[serde(default)]
+    dry_run: bool,
+    /// Who sent the request, e.g. the ReplicaSet controller for the pods of a Deployment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_info: Option<UserInfo>,
+}
+
+/// The requesting user as authenticated by the API server
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct UserInfo {
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    groups: Vec<String>,
 }
 
+/// The answer to an admission request
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Response {
-    uid: String,
-    allowed: bool,
+pub struct Response {
+    /// Uid of the request answered
+    pub uid: String,
+    /// False if the object is refused, e.g. when failing closed
+    pub allowed: bool,
+    /// JSONPatch if there is a patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch_type: Option<String>,
+    /// The JSON patch of the object, base64 encoded
     #[serde(skip_serializing_if = "Option::is_none")]
-    patch_type: Option<String>,
+    pub patch: Option<String>,
+    /// Shown to the client, e.g. by kubectl, each prefixed with "gravivol: "
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Why the request was refused or could not be processed
     #[serde(skip_serializing_if = "Option::is_none")]
-    patch: Option<String>,
+    pub status: Option<Status>,
+    /// The API server prefixes the keys with the webhook name gravivol.fonona.net
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub audit_annotations: HashMap<String, String>,
 }
 
+/// Result details of an admission, shown to the user by the API server
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct AdmissionReview {
-    pub api_version: String,
-    kind: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    request: Option<Request>,
+pub struct Status {
+    /// HTTP status code, e.g. 400 for an object that cannot be read
+    pub code: u16,
+    /// Human readable description
+    pub message: String,
+    /// Machine readable description, e.g. BadRequest
     #[serde(skip_serializing_if = "Option::is_none")]
-    response: Option<Response>,
+    pub reason: Option<String>,
 }
 
-fn create_patch(pod: &Pod, pvcs: Vec<String>) -> String {
-    let labels = pvcs
-        .iter()
-        .map(|p| {
-            Label::from_pvc(&Pvc {
-                namespace: pod.metadata.namespace.to_owned(),
-                claim_name: p.to_owned(),
-            })
-        })
-        .collect::<Vec<Label>>();
-
-    let mut new_pod = pod.to_owned();
-
-    // Add labels to metadata
-    if new_pod.metadata.labels.is_none() {
-        new_pod.metadata.labels = Some(HashMap::new())
-    }
-    if let Some(new_labels) = &mut new_pod.metadata.labels {
-        for label in &labels {
-            new_labels.insert(label.key.to_owned(), label.value.to_owned());
+impl Status {
+    fn internal_error(message: String) -> Status {
+        Status {
+            code: 500,
+            message,
+            reason: Some("InternalError".to_owned()),
         }
     }
 
-    // Add affinity
-    if new_pod.spec.affinity.is_none() {
-        new_pod.spec.affinity = Some(Value::Null);
-    }
-    if let Some(affinity) = &mut new_pod.spec.affinity {
-        if affinity["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"].is_null() {
-            affinity["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"] = json!([]);
-        }
-        if let Value::Array(the_array) =
-            &mut affinity["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
-        {
-            let mut entry = json!({
-                "labelSelector": {
-                    "matchLabels": {
-                    }
-                },
-                "topologyKey": "kubernetes.io/hostname",
-            });
-            for label in &labels {
-                entry["labelSelector"]["matchLabels"][&label.key] =
-                    Value::String(label.value.to_owned());
-            }
-            the_array.push(entry);
+    fn forbidden(message: String) -> Status {
+        Status {
+            code: 403,
+            message,
+            reason: Some("Forbidden".to_owned()),
         }
     }
 
-    let original_pod = serde_json::to_value(pod).expect("Cannot serialize pod");
-    let patched_pod = serde_json::to_value(new_pod).expect("Cannot serialize new pod");
-
-    let result_patch =
-        serde_json::to_string(&diff(&original_pod, &patched_pod)).expect("Cannot serialize patch");
-
-    log::debug!("Patch: {result_patch}");
-    result_patch
+    fn bad_request(message: String) -> Status {
+        Status {
+            code: 400,
+            message,
+            reason: Some("BadRequest".to_owned()),
+        }
+    }
 }
 
-#[derive(Eq, Hash, PartialEq)]
-struct Pvc {
-    namespace: String,
-    claim_name: String,
+/// Why a review could not be answered as usual
+#[derive(Debug, thiserror::Error)]
+pub enum ControllerError {
+    /// The body is not an AdmissionReview, e.g. it is not JSON or too large
+    #[error("{0}")]
+    InvalidReview(String),
+    /// The review has neither request nor response
+    #[error("no request in AdmissionReview")]
+    MissingRequest,
+    /// The object lacks fields of its kind or has them of the wrong type
+    #[error("cannot read {object}: {source}")]
+    InvalidObject {
+        /// Kind and name of the object
+        object: String,
+        /// Why it cannot be read
+        source: serde_json::Error,
+    },
+    /// The workload has no pod template at its usual path
+    #[error("{0} has no pod template")]
+    MissingTemplate(String),
+    /// The object could not be patched, a bug
+    #[error("could not create the patch for claims {claims}: {source}")]
+    PatchBuild {
+        /// Comma separated claims of the patch
+        claims: String,
+        /// Why it could not be created
+        source: serde_json::Error,
+    },
+    /// The patch could not be written as JSON, a bug
+    #[error("cannot serialize the patch of {object}: {source}")]
+    Serialization {
+        /// Kind and name of the object
+        object: String,
+        /// Why it could not be written
+        source: serde_json::Error,
+    },
+    /// Processing panicked, took too long or broke an invariant
+    #[error("{0}")]
+    Internal(String),
 }
 
-impl Pvc {
-    fn from_config_entry(entry: &str) -> Option<Pvc> {
-        let entry_parts: Vec<&str> = entry.split('/').collect();
-        if entry_parts.len() == 2 {
-            Some(Pvc {
-                namespace: entry_parts[0].to_owned(),
-                claim_name: entry_parts[1].to_owned(),
-            })
-        } else {
-            log::error!(
-                "Config entry is not in the format <namespace>/<claim name> : {}",
-                entry
-            );
-            None
+impl ControllerError {
+    /// Bad request if the review or its object cannot be read, else an internal error
+    fn status(&self) -> Status {
+        let message = format!("gravivol: {self}");
+        match self {
+            ControllerError::InvalidReview(_)
+            | ControllerError::MissingRequest
+            | ControllerError::InvalidObject { .. }
+            | ControllerError::MissingTemplate(_) => Status::bad_request(message),
+            ControllerError::PatchBuild { .. }
+            | ControllerError::Serialization { .. }
+            | ControllerError::Internal(_) => Status::internal_error(message),
+        }
+    }
+
+    fn invalid_object(object: &str) -> impl FnOnce(serde_json::Error) -> ControllerError {
+        move |source| ControllerError::InvalidObject {
+            object: object.to_owned(),
+            source,
         }
     }
 }
 
-pub struct Controller {
-    // If set is empty, all PVCs will be handled
-    pvcs_to_handle: HashSet<Pvc>,
+/// The uid of the request in a body, also if it is not a valid review, e.g. truncated
+pub fn request_uid(body: &[u8]) -> Option<String> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(value) => value["request"]["uid"].as_str().map(str::to_owned),
+        Err(_) => find_uid(body),
+    }
 }
 
-impl Controller {
-    /// config is comma separated string with PVCs to consider
-    pub fn new(config: &str) -> Controller {
-        let mut pvcs = HashSet::new();
-        for config_entry in config.split(',') {
-            if !config_entry.is_empty()
-                && let Some(pvc) = Pvc::from_config_entry(config_entry)
-            {
-                pvcs.insert(pvc);
+/// Best effort search for request.uid in a body that is not valid JSON, scanned without parsing
+///
+/// Only the uid of the request is taken, not those of owner references in the object, which
+/// come first if the keys are sorted.
+fn find_uid(body: &[u8]) -> Option<String> {
+    // Keys of the enclosing objects and arrays, empty for the top level and array items
+    let mut keys: Vec<String> = Vec::new();
+    let (mut string, mut escaped) = (None::<Vec<u8>>, false);
+    let (mut last, mut key) = (None, None);
+    for &byte in body {
+        if let Some(current) = &mut string {
+            match byte {
+                _ if escaped => {
+                    escaped = false;
+                    current.push(byte);
+                }
+                b'\\' => escaped = true,
+                b'"' => {
+                    let value = String::from_utf8_lossy(current).into_owned();
+                    string = None;
+                    if key.as_deref() == Some("uid") && keys == ["", "request"] {
+                        return Some(value);
+                    }
+                    last = Some(value);
+                }
+                _ => current.push(byte),
             }
+            continue;
         }
-        Controller {
-            pvcs_to_handle: pvcs,
+        match byte {
+            b'"' => string = Some(Vec::new()),
+            b':' => key = last.take(),
+            b'{' | b'[' => keys.push(key.take().unwrap_or_default()),
+            b'}' | b']' => {
+                keys.pop();
+            }
+            b',' => key = None,
+            _ => {}
         }
     }
+    None
+}
 
-    fn pvc_needs_handling(&self, namespace: &str, claim_name: &str) -> bool {
-        let pvc = Pvc {
-            namespace: namespace.to_owned(),
-            claim_name: claim_name.to_owned(),
-        };
-        if self.pvcs_to_handle.is_empty() {
-            true
-        } else {
-            self.pvcs_to_handle.contains(&pvc)
+/// Whether objects and arrays nest deeper than max_depth in the JSON, scanned without parsing
+pub fn exceeds_depth(json: &[u8], max_depth: usize) -> bool {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
         }
     }
+    false
+}
 
-    pub fn mutate(
-        &self,
-        review: AdmissionReview,
-    ) -> Result<AdmissionReview, Box<dyn std::error::Error>> {
-        if let Some(request) = review.request {
-            let mut pvcs_found: Vec<String> = Vec::new();
-            let mut review = AdmissionReview {
-                api_version: review.api_version.clone(),
-                kind: review.kind.clone(),
-                request: None,
-                response: Some(Response {
-                    uid: request.uid.clone(),
-                    allowed: true,
-                    patch_type: None,
-                    patch: None,
-                }),
-            };
+/// Number of elements of a JSON array, skipped over without building them, 0 for null
+#[derive(Default)]
+struct ElementCount(usize);
 
-            if &request.object.kind != "Pod" {
-                log::error!("Object is not a Pod but {}", &request.object.kind);
-                return Ok(review);
-            }
+impl<'de> Deserialize<'de> for ElementCount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CountVisitor;
 
-            log::info!(
-                "Got review request for pod {}",
-                request.object.metadata.get_display_name(),
-            );
+        impl<'de> de::Visitor<'de> for CountVisitor {
+            type Value = ElementCount;
 
-            // Extract PVCs
-            if let Some(volumes) = &request.object.spec.volumes {
-                for vol in volumes {
-                    if let Some(pvc) = &vol.persistent_volume_claim
-                        && self
-                            .pvc_needs_handling(&request.object.metadata.namespace, &pvc.claim_name)
-                    {
-                        log::info!(
-                            "Pod {} uses matching PVC {}",
-                            request.object.metadata.get_display_name(),
-                            pvc.claim_name
-                        );
-                        pvcs_found.push(pvc.claim_name.to_owned());
-                    }
-                }
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array or null")
             }
 
-            if !pvcs_found.is_empty() {
-                let patch = create_patch(&request.object, pvcs_found);
-
-                let mut response = review.response.unwrap();
-                response.patch_type = Some("JSONPatch".to_owned());
-                response.patch = Some(BASE64_STANDARD.encode(patch.as_bytes()));
-                review.response = Some(response);
-                log::info!(
-                    "Created patch for pod {}",
-                    request.object.metadata.get_display_name()
-                );
-            } else {
-                log::info!(
-                    "No patch required for pod {}",
-                    request.object.metadata.get_display_name()
-                );
+            fn visit_unit<E: de::Error>(self) -> Result<ElementCount, E> {
+                Ok(ElementCount(0))
             }
 
-            Ok(review)
-        } else {
-            Err("No request in AdmissionReview found!".into())
+            fn visit_seq<A: de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ElementCount, A::Error> {
+                let mut count = 0;
+                while seq.next_element::<de::IgnoredAny>()?.is_some() {
+                    count += 1;
+                }
+                Ok(ElementCount(count))
+            }
         }
+
+        deserializer.deserialize_any(CountVisitor)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use json_patch::{Patch, patch};
-    use serde_json::json;
-
-    use super::*;
-
-    #[test]
-    fn test_pod_no_volumes() {
-        let data = json!(
-        {
-            "apiVersion": "admission.k8s.io/v1",
-            "kind": "AdmissionReview",
-            "request": {
-                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
-                "object": {
-                    "kind": "Pod",
-                    "apiVersion": "v1",
-                    "metadata": {
-                        "generateName": "bla-6b47d48686-",
-                        "namespace": "default",
-                        "creationTimestamp": null,
-                        "labels": {
-                            "app.kubernetes.io/instance": "bla",
-                            "app.kubernetes.io/managed-by": "Helm",
-                            "app.kubernetes.io/name": "bla",
-                            "app.kubernetes.io/version": "1.16.0"
-                        }
-                    },
-                    "spec": {}
-                }
-            }
-        });
-        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
-        let controller = Controller::new("");
-        let response = controller.mutate(review).unwrap();
+/// The fields leading from an object to the volumes of its pod (template), others are skipped
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct VolumePath {
+    api_version: Option<String>,
+    kind: Option<String>,
+    spec: Option<Box<VolumePath>>,
+    template: Option<Box<VolumePath>>,
+    job_template: Option<Box<VolumePath>>,
+    volumes: ElementCount,
+}
 
-        assert_eq!(response.api_version, "admission.k8s.io/v1");
-        assert_eq!(response.kind, "AdmissionReview");
-        match response.response {
-            Some(res) => {
-                assert_eq!(res.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
-                assert!(res.allowed);
-                assert_eq!(res.patch, None);
-                assert_eq!(res.patch_type, None);
+impl VolumePath {
+    fn count(&self, template_path: &str) -> usize {
+        let mut node = self;
+        for field in template_path.split('/').skip(1).chain(["spec"]) {
+            let next = match field {
+                "spec" => &node.spec,
+                "template" => &node.template,
+                "jobTemplate" => &node.job_template,
+                _ => &None,
+            };
+            match next {
+                Some(next) => node = next,
+                None => return 0,
             }
-            None => panic!("Expected Some(response)"),
         }
+        node.volumes.0
     }
+}
 
-    #[test]
-    fn test_pod_with_matching_and_non_matching_pvc() {
-        let config = "default/myvol1,foo/myvol2";
+#[derive(Deserialize)]
+struct VolumeRequest {
+    object: Option<VolumePath>,
+}
+
+#[derive(Deserialize)]
+struct VolumeReview {
+    request: Option<VolumeRequest>,
+}
+
+/// Volumes of the pod or pod template of the requested object in the AdmissionReview, 0 for
+/// other objects, counted without building the review, None if it cannot be parsed
+pub fn count_volumes(json: &[u8]) -> Option<usize> {
+    let review: VolumeReview = serde_json::from_slice(json).ok()?;
+    let Some(object) = review.request.and_then(|request| request.object) else {
+        return Some(0);
+    };
+    let kind = Kind::from_object(
+        object.api_version.as_deref().unwrap_or_default(),
+        object.kind.as_deref().unwrap_or_default(),
+    );
+    Some(kind.map_or(0, |kind| object.count(kind.template_path())))
+}
+
+/// Versions of AdmissionReview the webhook understands, the response mirrors the one of the request
+const ADMISSION_API_VERSIONS: [&str; 2] = ["admission.k8s.io/v1", "admission.k8s.io/v1beta1"];
+
+/// An admission.k8s.io AdmissionReview, sent by the API server with a request and returned
+/// with the response
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionReview {
+    /// admission.k8s.io/v1, or v1beta1 for old API servers
+    pub api_version: String,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<Request>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<Response>,
+}
+
+impl AdmissionReview {
+    /// Uid of the request, or of the response, None for a review without either
+    pub fn uid(&self) -> Option<&str> {
+        match (&self.request, &self.response) {
+            (Some(request), _) => Some(&request.uid),
+            (None, Some(response)) => Some(&response.uid),
+            (None, None) => None,
+        }
+    }
+
+    /// The response, None before the review is answered
+    pub fn response(&self) -> Option<&Response> {
+        self.response.as_ref()
+    }
+
+    /// Whether the response patches the object
+    pub fn has_patch(&self) -> bool {
+        self.response
+            .as_ref()
+            .is_some_and(|response| response.patch.is_some())
+    }
+
+    /// Namespace and name of the requested object, empty if unknown
+    fn object_names(&self) -> (&str, &str) {
+        let request = self.request.as_ref();
+        // Only the old object is known on DELETE
+        let object =
+            request.and_then(|request| request.object.as_ref().or(request.old_object.as_ref()));
+        let metadata = |key| {
+            object
+                .and_then(|object| object["metadata"][key].as_str())
+                .unwrap_or_default()
+        };
+        // Pods of controllers only have a generateName on creation
+        let name = match metadata("name") {
+            "" => request
+                .and_then(|request| request.name.as_deref())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| metadata("generateName")),
+            name => name,
+        };
+        let namespace = request
+            .and_then(|request| request.namespace.as_deref())
+            .unwrap_or_else(|| metadata("namespace"));
+        (namespace, name)
+    }
+
+    /// Span tagging the logs of the request with its uid, namespace and object name, and with
+    /// the matching claims once known
+    pub fn span(&self) -> tracing::Span {
+        let (namespace, name) = self.object_names();
+        tracing::info_span!(
+            "admission",
+            uid = %self.uid().unwrap_or_default(),
+            %namespace,
+            object = %name,
+            claims = tracing::field::Empty,
+            claim_count = tracing::field::Empty,
+            patch_bytes = tracing::field::Empty
+        )
+    }
+
+    /// Adds a warning shown to the client, e.g. by kubectl
+    pub fn add_warning(&mut self, warning: String) {
+        if let Some(response) = &mut self.response {
+            response.warnings.push(warning);
+        }
+    }
+
+    /// Refuses a review of an unsupported kind or version, true if it was refused
+    fn refuse_unsupported(&mut self) -> bool {
+        if self.kind == "AdmissionReview"
+            && ADMISSION_API_VERSIONS.contains(&self.api_version.as_str())
+        {
+            return false;
+        }
+        tracing::error!("Unsupported review {} {}", self.api_version, self.kind);
+        let message = format!(
+            "gravivol does not support {} {}, expected AdmissionReview of {}",
+            self.api_version,
+            self.kind,
+            ADMISSION_API_VERSIONS.join(" or ")
+        );
+        if let Some(response) = &mut self.response {
+            response.allowed = false;
+            response.status = Some(Status::bad_request(message));
+        }
+        true
+    }
+
+    /// Allows the object whatever the failure mode, e.g. when shedding load
+    pub fn allowed(mut self) -> AdmissionReview {
+        if let Some(response) = &mut self.response {
+            response.allowed = true;
+        }
+        self
+    }
+}
+
+/// Creates the patch adding the managed label to a PersistentVolumeClaim
+fn create_claim_patch(metadata: &Metadata) -> serde_json::Result<Patch> {
+    let mut new_metadata = metadata.to_owned();
+    new_metadata
+        .labels
+        .get_or_insert_with(HashMap::new)
+        .insert(MANAGED_LABEL.to_owned(), "true".to_owned());
+
+    Ok(diff(
+        &nest_at_path("/metadata", serde_json::to_value(metadata)?),
+        &nest_at_path("/metadata", serde_json::to_value(new_metadata)?),
+    ))
+}
+
+/// Puts the value at the position of the JSON pointer path into an otherwise empty document
+fn nest_at_path(path: &str, value: Value) -> Value {
+    path.rsplit('/')
+        .filter(|token| !token.is_empty())
+        .fold(value, |value, token| json!({ token: value }))
+}
+
+impl Spec {
+    /// Names of all claims mounted by the pod
+    fn claim_names(&self) -> Vec<&str> {
+        self.volumes
+            .iter()
+            .flatten()
+            .filter_map(|volume| volume.persistent_volume_claim.as_ref())
+            .map(|pvc| pvc.claim_name.as_str())
+            .collect()
+    }
+}
+
+/// Keeps only the claims named in the claims annotation of the pod, if present
+fn restrict_to_annotated_claims(
+    pod: &PodTemplate,
+    claims: &mut Vec<String>,
+    warnings: &mut Vec<String>,
+) {
+    let Some(annotation) = pod.metadata.get_annotation(CLAIMS_ANNOTATION) else {
+        return;
+    };
+    let annotated: Vec<&str> = annotation
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    let mounted = pod.spec.claim_names();
+    for name in &annotated {
+        if !mounted.contains(name) {
+            tracing::warn!("Claim {name} of annotation {CLAIMS_ANNOTATION} is not mounted");
+            warnings.push(warning(&format!(
+                "claim {name} named in annotation {CLAIMS_ANNOTATION} is not mounted"
+            )));
+        }
+    }
+    claims.retain(|claim| annotated.contains(&claim.as_str()));
+}
+
+/// What create_patch adds to a pod
+#[derive(Debug, Default)]
+struct Mutation {
+    namespace: String,
+    /// All matched claims, recorded in the annotation
+    claims: Vec<String>,
+    /// Claims the pod gets labels for
+    label_claims: Vec<String>,
+    /// Claims the pod affinity term selects
+    affinity_claims: Vec<String>,
+    /// Claims selected by topology spread constraints, with the parameters of their constraint
+    spread_claims: Vec<(String, SpreadOptions)>,
+    /// Required node selector terms of the volumes bound to the claims
+    node_terms: Vec<Value>,
+    /// Claims selected by preferred pod affinity terms, one term each, with their weights
+    preferred_claims: Vec<(String, u32)>,
+    /// Claims selected by preferred pod anti-affinity terms, one term each, with their weights
+    anti_affinity_claims: Vec<(String, u32)>,
+    /// Topology key of the pod affinity term
+    topology_key: String,
+    /// Domain of the claim label keys, after the namespace
+    label_domain: String,
+    /// The request is a dry run, lookups must not have side effects
+    dry_run: bool,
+    /// Label selector of the anchor pods a gated pod waits for
+    gate_selector: Option<String>,
+    /// Label values of claims other than "true"
+    label_values: HashMap<String, String>,
+    /// Workload whose name prefixes the claim in the label keys
+    owner: Option<String>,
+    /// The pod affinity term replaces the existing required pod affinity terms
+    replace_affinity: bool,
+}
+
+impl Mutation {
+    fn new(namespace: &str, claims: &[String], mode: PatchMode) -> Mutation {
+        Mutation {
+            namespace: namespace.to_owned(),
+            claims: claims.to_vec(),
+            label_claims: if mode.adds_labels() {
+                claims.to_vec()
+            } else {
+                Vec::new()
+            },
+            affinity_claims: if mode.adds_affinity() {
+                claims.to_vec()
+            } else {
+                Vec::new()
+            },
+            spread_claims: Vec::new(),
+            node_terms: Vec::new(),
+            preferred_claims: Vec::new(),
+            anti_affinity_claims: Vec::new(),
+            topology_key: DEFAULT_TOPOLOGY_KEY.to_owned(),
+            label_domain: DEFAULT_LABEL_DOMAIN.to_owned(),
+            dry_run: false,
+            gate_selector: None,
+            label_values: HashMap::new(),
+            owner: None,
+            replace_affinity: false,
+        }
+    }
+
+    /// Pod affinity term selecting the pods labelled for the claims
+    fn affinity_term(&self, claims: &[String]) -> Value {
+        let mut term = json!({
+            "labelSelector": {
+                "matchLabels": {
+                }
+            },
+            "topologyKey": self.topology_key,
+        });
+        for label in self.labels(claims) {
+            term["labelSelector"]["matchLabels"][&label.key] = Value::String(label.value);
+        }
+        term
+    }
+
+    /// Label selector for the pods labelled for the claims
+    fn label_selector(&self, claims: &[String]) -> String {
+        self.labels(claims)
+            .iter()
+            .map(|label| format!("{}={}", label.key, label.value))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    fn labels(&self, claims: &[String]) -> Vec<Label> {
+        claims
+            .iter()
+            .map(|p| {
+                let mut label = Label::from_pvc(
+                    &Pvc {
+                        namespace: self.namespace.to_owned(),
+                        claim_name: p.to_owned(),
+                    },
+                    &self.label_domain,
+                );
+                if let Some(value) = self.label_values.get(p) {
+                    label.value = value.to_owned();
+                }
+                if let Some(owner) = &self.owner {
+                    label.key = format!("{}.{}/{owner}.{p}", self.namespace, self.label_domain);
+                }
+                label
+            })
+            .collect()
+    }
+}
+
+/// Combines node selector terms: the result matches a node if it matches one term of each list
+fn merge_node_terms(terms: &[Value], other_terms: &[Value]) -> Vec<Value> {
+    let mut merged = Vec::new();
+    for term in terms {
+        for other_term in other_terms {
+            let mut merged_term = json!({});
+            for field in ["matchExpressions", "matchFields"] {
+                let requirements: Vec<Value> = [term, other_term]
+                    .iter()
+                    .filter_map(|t| t[field].as_array())
+                    .flatten()
+                    .cloned()
+                    .collect();
+                if !requirements.is_empty() {
+                    merged_term[field] = Value::Array(requirements);
+                }
+            }
+            merged.push(merged_term);
+        }
+    }
+    merged
+}
+
+/// The hostname if the terms only allow a single host
+fn single_hostname(terms: &[Value]) -> Option<&str> {
+    let [term] = terms else {
+        return None;
+    };
+    if term.get("matchFields").is_some() {
+        return None;
+    }
+    let [expression] = term["matchExpressions"].as_array()?.as_slice() else {
+        return None;
+    };
+    let [value] = expression["values"].as_array()?.as_slice() else {
+        return None;
+    };
+    if expression["key"] == "kubernetes.io/hostname" && expression["operator"] == "In" {
+        value.as_str()
+    } else {
+        None
+    }
+}
+
+/// The member of an object, added as null if missing, turning null into an empty object
+///
+/// None if the value is of another type, which the API server never sends but a client
+/// posting to the webhook directly might.
+fn member<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    if value.is_null() {
+        *value = json!({});
+    }
+    value
+        .as_object_mut()
+        .map(|object| object.entry(key).or_insert(Value::Null))
+}
+
+/// Error for a part of the pod that is not of the type of the Kubernetes API
+fn malformed(field: &str) -> serde_json::Error {
+    serde::de::Error::custom(format!("{field} of the pod is malformed"))
+}
+
+/// Restricts the pod to the nodes matching the terms
+fn add_node_placement(pod: &mut PodTemplate, terms: &[Value]) -> serde_json::Result<()> {
+    if let Some(hostname) = single_hostname(terms) {
+        let node_selector = pod.spec.node_selector.get_or_insert_with(HashMap::new);
+        if node_selector
+            .get("kubernetes.io/hostname")
+            .is_none_or(|existing| existing == hostname)
+        {
+            node_selector.insert("kubernetes.io/hostname".to_owned(), hostname.to_owned());
+            return Ok(());
+        }
+    }
+
+    let affinity = pod.spec.affinity.get_or_insert(Value::Null);
+    let required = member(affinity, "nodeAffinity")
+        .and_then(|node_affinity| {
+            member(
+                node_affinity,
+                "requiredDuringSchedulingIgnoredDuringExecution",
+            )
+        })
+        .ok_or_else(|| malformed("nodeAffinity"))?;
+    let existing_terms = required["nodeSelectorTerms"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    *member(required, "nodeSelectorTerms").ok_or_else(|| malformed("nodeAffinity"))? =
+        if existing_terms.is_empty() {
+            Value::Array(terms.to_vec())
+        } else {
+            Value::Array(merge_node_terms(&existing_terms, terms))
+        };
+    Ok(())
+}
+
+/// Appends a preferred term per claim to the field of the affinity, podAffinity or
+/// podAntiAffinity, the highest weight first
+fn add_weighted_terms(
+    affinity: &mut Value,
+    field: &str,
+    claims: &[(String, u32)],
+    mutation: &Mutation,
+) -> serde_json::Result<()> {
+    let mut claims = claims.to_vec();
+    claims.sort_by(|(_, weight), (_, other_weight)| other_weight.cmp(weight));
+    let preferred = member(affinity, field)
+        .and_then(|affinity| member(affinity, "preferredDuringSchedulingIgnoredDuringExecution"))
+        .ok_or_else(|| malformed(field))?;
+    if preferred.is_null() {
+        *preferred = json!([]);
+    }
+    let preferred = preferred.as_array_mut().ok_or_else(|| malformed(field))?;
+    for (claim, weight) in claims {
+        preferred.push(json!({
+            "weight": weight,
+            "podAffinityTerm": mutation.affinity_term(&[claim]),
+        }));
+    }
+    Ok(())
+}
+
+/// Creates the patch for the pod template found at path in the object
+fn create_patch(
+    pod: &PodTemplate,
+    path: &str,
+    mutation: &Mutation,
+    options: &Options,
+) -> serde_json::Result<Patch> {
+    let pod = pod.without_volumes();
+    let mut new_pod = pod.clone();
+
+    // Add labels to metadata
+    if !mutation.label_claims.is_empty() {
+        let new_labels = new_pod.metadata.labels.get_or_insert_with(HashMap::new);
+        for label in mutation.labels(&mutation.label_claims) {
+            new_labels.insert(label.key, label.value);
+        }
+    }
+
+    // Add annotations naming the claims
+    if options.stamp_annotation {
+        let annotations = new_pod
+            .metadata
+            .annotations
+            .get_or_insert_with(HashMap::new);
+        annotations.insert(MUTATED_ANNOTATION.to_owned(), mutation.claims.join(","));
+        annotations.insert(
+            VERSION_ANNOTATION.to_owned(),
+            env!("CARGO_PKG_VERSION").to_owned(),
+        );
+    }
+
+    // Add affinity
+    if !mutation.affinity_claims.is_empty() {
+        let affinity = new_pod.spec.affinity.get_or_insert(Value::Null);
+        let required = member(affinity, "podAffinity")
+            .and_then(|pod_affinity| {
+                member(
+                    pod_affinity,
+                    "requiredDuringSchedulingIgnoredDuringExecution",
+                )
+            })
+            .ok_or_else(|| malformed("podAffinity"))?;
+        if required.is_null() || mutation.replace_affinity {
+            *required = json!([]);
+        }
+        required
+            .as_array_mut()
+            .ok_or_else(|| malformed("podAffinity"))?
+            .push(mutation.affinity_term(&mutation.affinity_claims));
+    }
+
+    // Add preferred affinity and anti-affinity
+    if !mutation.preferred_claims.is_empty() {
+        let affinity = new_pod.spec.affinity.get_or_insert(Value::Null);
+        add_weighted_terms(
+            affinity,
+            "podAffinity",
+            &mutation.preferred_claims,
+            mutation,
+        )?;
+    }
+    if !mutation.anti_affinity_claims.is_empty() {
+        let affinity = new_pod.spec.affinity.get_or_insert(Value::Null);
+        add_weighted_terms(
+            affinity,
+            "podAntiAffinity",
+            &mutation.anti_affinity_claims,
+            mutation,
+        )?;
+    }
+
+    // Add topology spread constraints, one selecting the claims of each set of parameters
+    let mut spreads: Vec<(&SpreadOptions, Vec<String>)> = Vec::new();
+    for (claim, spread) in &mutation.spread_claims {
+        match spreads
+            .iter_mut()
+            .find(|(parameters, _)| *parameters == spread)
+        {
+            Some((_, claims)) => claims.push(claim.clone()),
+            None => spreads.push((spread, vec![claim.clone()])),
+        }
+    }
+    for (spread, claims) in spreads {
+        let mut constraint = json!({
+            "maxSkew": spread.max_skew,
+            "topologyKey": spread.topology_key,
+            "whenUnsatisfiable": spread.when_unsatisfiable.as_str(),
+            "labelSelector": {
+                "matchLabels": {
+                }
+            },
+        });
+        for label in mutation.labels(&claims) {
+            constraint["labelSelector"]["matchLabels"][&label.key] = Value::String(label.value);
+        }
+        new_pod
+            .spec
+            .topology_spread_constraints
+            .get_or_insert_with(Vec::new)
+            .push(constraint);
+    }
+
+    // Add scheduling gate, removed by the gate controller once an anchor pod is scheduled
+    if let Some(gate_selector) = &mutation.gate_selector {
+        new_pod
+            .metadata
+            .labels
+            .get_or_insert_with(HashMap::new)
+            .insert(GATE_LABEL.to_owned(), "true".to_owned());
+        new_pod
+            .metadata
+            .annotations
+            .get_or_insert_with(HashMap::new)
+            .insert(GATE_LABEL.to_owned(), gate_selector.to_owned());
+        let gates = new_pod.spec.scheduling_gates.get_or_insert_with(Vec::new);
+        if !gates.iter().any(|gate| gate["name"] == SCHEDULING_GATE) {
+            gates.push(json!({ "name": SCHEDULING_GATE }));
+        }
+    }
+
+    // Add node placement of the bound volumes
+    if !mutation.node_terms.is_empty() {
+        add_node_placement(&mut new_pod, &mutation.node_terms)?;
+    }
+
+    let original_pod = nest_at_path(path, serde_json::to_value(&pod)?);
+    let patched_pod = nest_at_path(path, serde_json::to_value(new_pod)?);
+
+    Ok(diff(&original_pod, &patched_pod))
+}
+
+/// Prepends test operations asserting the parts of the object the patch relies on, so that
+/// the API server rejects the patch instead of clobbering changes made after the review
+///
+/// An added member or array element relies on the whole parent, which also covers the absence
+/// of the member and the length of the array. Replaced and removed values are tested directly.
+/// A member of a metadata or spec that exists, e.g. the labels or the affinity, is tested itself,
+/// so that unrelated changes do not fail the patch. JSON Patch cannot test that a member is
+/// absent, so adding a missing one tests the whole metadata or spec instead.
+fn guard_patch(object: &Value, patch: Patch) -> Patch {
+    let mut guarded_paths: Vec<PointerBuf> = Vec::new();
+    for operation in &patch.0 {
+        let path = match operation {
+            PatchOperation::Add(add) => match add.path.parent() {
+                Some(parent)
+                    if parent.last().is_some_and(|token| {
+                        matches!(token.decoded().as_ref(), "metadata" | "spec")
+                    }) && object.pointer(add.path.as_str()).is_some() =>
+                {
+                    Some(add.path.clone())
+                }
+                parent => parent.map(|parent| parent.to_buf()),
+            },
+            PatchOperation::Replace(replace) => Some(replace.path.clone()),
+            PatchOperation::Remove(remove) => Some(remove.path.clone()),
+            _ => None,
+        };
+        if let Some(path) = path
+            && !guarded_paths.contains(&path)
+        {
+            guarded_paths.push(path);
+        }
+    }
+
+    let mut operations: Vec<PatchOperation> = guarded_paths
+        .into_iter()
+        .filter_map(|path| {
+            let value = object.pointer(path.as_str())?.clone();
+            Some(PatchOperation::Test(TestOperation { path, value }))
+        })
+        .collect();
+    operations.extend(patch.0);
+    Patch(operations)
+}
+
+#[derive(Debug, Eq, Hash, PartialEq)]
+struct Pvc {
+    namespace: String,
+    claim_name: String,
+}
+
+/// Valid weights of preferred scheduling terms
+const PREFERRED_WEIGHTS: std::ops::RangeInclusive<u32> = 1..=100;
+
+/// How pods using a configured claim are placed
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClaimMode {
+    /// Pod affinity to the other pods using the claim
+    Affinity,
+    /// Pod affinity replacing the required pod affinity terms of the pod, for claims gravivol
+    /// is authoritative for
+    ReplaceAffinity,
+    /// Preferred pod affinity with the weight, the configured base weight if None
+    Preferred(Option<u32>),
+    /// Preferred pod anti-affinity with the weight, spreading the pods using the claim
+    AntiAffinity(Option<u32>),
+    /// Topology spread constraint over the pods using the claim
+    Spread(SpreadOverrides),
+}
+
+/// Parameters of the topology spread constraint of a claim, the configured [SpreadOptions] if
+/// None
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpreadOverrides {
+    /// Difference of matching pods allowed between topology domains
+    pub max_skew: Option<u32>,
+    /// Whether pods exceeding the skew are not scheduled or scheduled anyway
+    pub when_unsatisfiable: Option<WhenUnsatisfiable>,
+    /// Label of the nodes whose values are the topology domains
+    pub topology_key: Option<String>,
+}
+
+impl SpreadOverrides {
+    /// Parses the parameters of a config entry in any order, e.g. "2:topology.kubernetes.io/zone"
+    fn parse(parameters: &str) -> Option<SpreadOverrides> {
+        let mut overrides = SpreadOverrides::default();
+        for parameter in parameters.split(':') {
+            let duplicate = if let Ok(max_skew) = parameter.parse::<u32>() {
+                max_skew == 0 || overrides.max_skew.replace(max_skew).is_some()
+            } else if let Some(when_unsatisfiable) = WhenUnsatisfiable::from_name(parameter) {
+                overrides
+                    .when_unsatisfiable
+                    .replace(when_unsatisfiable)
+                    .is_some()
+            } else if is_valid_label_key(parameter) {
+                overrides
+                    .topology_key
+                    .replace(parameter.to_owned())
+                    .is_some()
+            } else {
+                true
+            };
+            if duplicate {
+                return None;
+            }
+        }
+        Some(overrides)
+    }
+
+    /// The configured parameters with these overridden
+    fn apply(&self, spread: &SpreadOptions) -> SpreadOptions {
+        SpreadOptions {
+            max_skew: self.max_skew.unwrap_or(spread.max_skew),
+            when_unsatisfiable: self.when_unsatisfiable.unwrap_or(spread.when_unsatisfiable),
+            topology_key: self
+                .topology_key
+                .clone()
+                .unwrap_or_else(|| spread.topology_key.clone()),
+        }
+    }
+}
+
+impl ClaimMode {
+    /// Parses the mode of a config entry, e.g. "spread", "spread:2:ScheduleAnyway" or
+    /// "preferred:90"
+    fn from_name(name: &str) -> Option<ClaimMode> {
+        let parse_weight = |weight: &str| {
+            weight
+                .parse()
+                .ok()
+                .filter(|weight| PREFERRED_WEIGHTS.contains(weight))
+        };
+        match name.split_once(':') {
+            None => match name {
+                "affinity" => Some(ClaimMode::Affinity),
+                "preferred" => Some(ClaimMode::Preferred(None)),
+                "anti-affinity" => Some(ClaimMode::AntiAffinity(None)),
+                "spread" => Some(ClaimMode::Spread(SpreadOverrides::default())),
+                _ => None,
+            },
+            Some(("affinity", "replace")) => Some(ClaimMode::ReplaceAffinity),
+            Some(("spread", parameters)) => {
+                SpreadOverrides::parse(parameters).map(ClaimMode::Spread)
+            }
+            Some(("preferred", weight)) => {
+                parse_weight(weight).map(|weight| ClaimMode::Preferred(Some(weight)))
+            }
+            Some(("anti-affinity", weight)) => {
+                parse_weight(weight).map(|weight| ClaimMode::AntiAffinity(Some(weight)))
+            }
+            Some(_) => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ClaimMode::Affinity | ClaimMode::ReplaceAffinity => "affinity",
+            ClaimMode::Preferred(_) => "preferred",
+            ClaimMode::AntiAffinity(_) => "anti-affinity",
+            ClaimMode::Spread(_) => "spread",
+        }
+    }
+}
+
+impl Pvc {
+    fn new(namespace: &str, claim_name: &str) -> Pvc {
+        Pvc {
+            namespace: namespace.to_owned(),
+            claim_name: claim_name.to_owned(),
+        }
+    }
+
+    /// Parses an entry of the format <namespace>/<claim name>[:<mode>]
+    fn from_config_entry(entry: &str) -> Option<(Pvc, ClaimMode)> {
+        let (pvc_part, mode) = match entry.split_once(':') {
+            Some((pvc_part, mode_name)) => (pvc_part, ClaimMode::from_name(mode_name)),
+            None => (entry, Some(ClaimMode::Affinity)),
+        };
+        let entry_parts: Vec<&str> = pvc_part.split('/').collect();
+        if let (2, Some(mode)) = (entry_parts.len(), mode) {
+            Some((
+                Pvc {
+                    namespace: entry_parts[0].to_owned(),
+                    claim_name: entry_parts[1].to_owned(),
+                },
+                mode,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Seconds between warnings about objects of the same unhandled kind
+const UNHANDLED_KIND_LOG_INTERVAL_SECS: f64 = 60.0;
+
+const CONFIG_ENTRY_FORMAT: &str = "<namespace>/<claim name>[:affinity[:replace]|preferred[:<weight 1-100>]|anti-affinity[:<weight 1-100>]|spread[:<max skew>][:DoNotSchedule|ScheduleAnyway][:<topology key>]]";
+
+/// Number of entries in the config by the name of their mode, 0 for modes without entries
+pub fn config_entries_by_mode(config: &str) -> Result<BTreeMap<&'static str, usize>, String> {
+    validate_config(config)?;
+    let mut entries: BTreeMap<&'static str, usize> = [
+        ClaimMode::Affinity,
+        ClaimMode::Preferred(None),
+        ClaimMode::AntiAffinity(None),
+        ClaimMode::Spread(SpreadOverrides::default()),
+    ]
+    .iter()
+    .map(|mode| (mode.as_str(), 0))
+    .collect();
+    for (_, mode) in config.split(',').filter_map(Pvc::from_config_entry) {
+        *entries.entry(mode.as_str()).or_default() += 1;
+    }
+    Ok(entries)
+}
+
+/// Fails with the entries of the comma separated config that cannot be parsed
+pub fn validate_config(config: &str) -> Result<(), String> {
+    let invalid: Vec<&str> = config
+        .split(',')
+        .filter(|entry| !entry.is_empty() && Pvc::from_config_entry(entry).is_none())
+        .collect();
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "config entries not in the format {CONFIG_ENTRY_FORMAT}: {}",
+            invalid.join(",")
+        ))
+    }
+}
+
+/// Behaviour of the controller besides the PVCs to handle
+#[derive(Clone, Debug, Serialize)]
+pub struct Options {
+    /// Annotate mutated pods with the claims and the gravivol version
+    pub stamp_annotation: bool,
+    /// Kinds of objects to mutate
+    pub kinds: HashSet<Kind>,
+    /// Label the handled PersistentVolumeClaim objects themselves
+    pub label_pvcs: bool,
+    /// Parts of the mutation pods get unless overridden by annotation
+    pub patch_mode: PatchMode,
+    /// Placement derived from the node affinity of the bound volumes
+    pub node_affinity: NodeAffinityMode,
+    /// Handling of pods that have no peer pod to be placed with yet
+    pub first_pod: FirstPodMode,
+    /// Precede the patch with test operations for the values it relies on
+    pub guarded_patch: bool,
+    /// Leave pods owned by a DaemonSet alone, they are pinned to their node anyway
+    pub skip_daemonsets: bool,
+    /// Most volumes of a pod examined for claims, the rest are ignored with a warning, 0 for all
+    pub volume_scan_limit: usize,
+    /// Largest patch returned as JSON, larger ones are dropped with a warning, 0 for no limit
+    pub max_patch_bytes: usize,
+    /// Topology spread constraint for claims configured with the spread mode
+    pub spread: SpreadOptions,
+    /// Weight of preferred pod affinity terms for claims without a configured weight
+    pub preferred_weight: u32,
+    /// Weight of pod anti-affinity terms for claims without a configured weight
+    pub anti_affinity_weight: u32,
+    /// Gate follower pods until an anchor pod is scheduled
+    pub scheduling_gate: bool,
+    /// Value of the labels for the claims
+    pub label_value: LabelValue,
+    /// Include the owning workload in the label keys, so that its claims only group its pods
+    pub group_by_owner: bool,
+    /// Namespaces in which the pod affinity term replaces the required ones of the pod
+    pub replace_affinity_namespaces: HashSet<String>,
+    /// Only claims with one of these access modes are handled, any claim if empty
+    pub access_modes: HashSet<AccessMode>,
+    /// Only claims of storage classes with one of these provisioners are handled, any if empty
+    pub provisioners: HashSet<String>,
+    /// Claims of storage classes with one of these provisioners are not handled
+    pub provisioners_deny: HashSet<String>,
+    /// Handling of the pod affinity for claims that are not bound yet
+    pub unbound_claims: UnboundClaimMode,
+    /// Whether requests that cannot be processed are admitted
+    pub failure_mode: FailureMode,
+    /// Only pods in namespaces matching it are mutated, all if None
+    pub namespace_selector: Option<LabelSelector>,
+    /// How pods lacking the labels of their claims are answered on validation
+    pub validate_mode: ValidateMode,
+}
+
+/// How requests are answered that cannot be processed
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    /// Admit the object unchanged
+    Open,
+    /// Deny the object
+    Closed,
+}
+
+impl FailureMode {
+    /// The mode of the name, open or closed, None if unknown
+    pub fn from_name(name: &str) -> Option<FailureMode> {
+        match name {
+            "open" => Some(FailureMode::Open),
+            "closed" => Some(FailureMode::Closed),
+            _ => None,
+        }
+    }
+}
+
+/// How pods failing validation are answered
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidateMode {
+    /// Refuse the pod
+    Deny,
+    /// Allow the pod with a warning
+    Warn,
+}
+
+impl ValidateMode {
+    /// The mode of the name, deny or warn, None if unknown
+    pub fn from_name(name: &str) -> Option<ValidateMode> {
+        match name {
+            "deny" => Some(ValidateMode::Deny),
+            "warn" => Some(ValidateMode::Warn),
+            _ => None,
+        }
+    }
+
+    /// The name of the mode
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidateMode::Deny => "deny",
+            ValidateMode::Warn => "warn",
+        }
+    }
+}
+
+/// Value of the label a pod gets for a claim
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelValue {
+    /// The string true
+    True,
+    /// The UID of the claim, distinguishing recreated claims of the same name
+    Uid,
+}
+
+impl LabelValue {
+    /// The value of the name, true or uid, None if unknown
+    pub fn from_name(name: &str) -> Option<LabelValue> {
+        match name {
+            "true" => Some(LabelValue::True),
+            "uid" => Some(LabelValue::Uid),
+            _ => None,
+        }
+    }
+}
+
+/// Parameters of the topology spread constraint
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SpreadOptions {
+    /// Difference of matching pods allowed between topology domains
+    pub max_skew: u32,
+    /// Whether pods exceeding the skew are not scheduled or scheduled anyway
+    pub when_unsatisfiable: WhenUnsatisfiable,
+    /// Label of the nodes whose values are the topology domains
+    pub topology_key: String,
+}
+
+impl Default for SpreadOptions {
+    fn default() -> Self {
+        SpreadOptions {
+            max_skew: 1,
+            when_unsatisfiable: WhenUnsatisfiable::DoNotSchedule,
+            topology_key: DEFAULT_TOPOLOGY_KEY.to_owned(),
+        }
+    }
+}
+
+/// Handling of a pod that would exceed the skew of a topology spread constraint
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum WhenUnsatisfiable {
+    /// Keep the pod pending
+    DoNotSchedule,
+    /// Schedule the pod, preferring domains reducing the skew
+    ScheduleAnyway,
+}
+
+impl WhenUnsatisfiable {
+    /// The value of the name, e.g. DoNotSchedule, None if unknown
+    pub fn from_name(name: &str) -> Option<WhenUnsatisfiable> {
+        match name {
+            "DoNotSchedule" => Some(WhenUnsatisfiable::DoNotSchedule),
+            "ScheduleAnyway" => Some(WhenUnsatisfiable::ScheduleAnyway),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            WhenUnsatisfiable::DoNotSchedule => "DoNotSchedule",
+            WhenUnsatisfiable::ScheduleAnyway => "ScheduleAnyway",
+        }
+    }
+}
+
+impl Options {
+    /// The settings of the enabled features that look up objects in the Kubernetes API
+    pub fn api_features(&self) -> Vec<&'static str> {
+        [
+            (
+                self.node_affinity != NodeAffinityMode::Off,
+                "GRAVIVOL_NODE_AFFINITY",
+            ),
+            (self.first_pod != FirstPodMode::Off, "GRAVIVOL_FIRST_POD"),
+            (self.scheduling_gate, "GRAVIVOL_SCHEDULING_GATE"),
+            (self.label_value == LabelValue::Uid, "GRAVIVOL_LABEL_VALUE"),
+            (!self.access_modes.is_empty(), "GRAVIVOL_ACCESS_MODES"),
+            (
+                self.unbound_claims != UnboundClaimMode::Off,
+                "GRAVIVOL_UNBOUND_CLAIMS",
+            ),
+            (!self.provisioners.is_empty(), "GRAVIVOL_PROVISIONERS"),
+            (
+                !self.provisioners_deny.is_empty(),
+                "GRAVIVOL_PROVISIONERS_DENY",
+            ),
+            (
+                self.namespace_selector.is_some(),
+                "GRAVIVOL_NAMESPACE_SELECTOR",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, setting)| enabled.then_some(setting))
+        .collect()
+    }
+}
+
+/// How pods are handled when no pod with the labels of their affinity term exists yet
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirstPodMode {
+    /// Always add the required pod affinity
+    Off,
+    /// No pod affinity, the pod becomes the anchor for the following pods
+    Anchor,
+    /// Only a preferred pod affinity
+    Preferred,
+}
+
+impl FirstPodMode {
+    /// The mode of the name, off, anchor or preferred, None if unknown
+    pub fn from_name(name: &str) -> Option<FirstPodMode> {
+        match name {
+            "off" => Some(FirstPodMode::Off),
+            "anchor" => Some(FirstPodMode::Anchor),
+            "preferred" => Some(FirstPodMode::Preferred),
+            _ => None,
+        }
+    }
+}
+
+/// How claims are handled that are not bound to a volume yet
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnboundClaimMode {
+    /// Like bound claims
+    Off,
+    /// No pod affinity for the claim, only the label
+    Skip,
+    /// A preferred pod affinity for the claim
+    Preferred,
+}
+
+impl UnboundClaimMode {
+    /// The mode of the name, off, skip or preferred, None if unknown
+    pub fn from_name(name: &str) -> Option<UnboundClaimMode> {
+        match name {
+            "off" => Some(UnboundClaimMode::Off),
+            "skip" => Some(UnboundClaimMode::Skip),
+            "preferred" => Some(UnboundClaimMode::Preferred),
+            _ => None,
+        }
+    }
+}
+
+/// How the node affinity of bound volumes is used
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeAffinityMode {
+    /// Only the pod affinity
+    Off,
+    /// In addition to the pod affinity
+    Add,
+    /// Instead of the pod affinity
+    Replace,
+}
+
+impl NodeAffinityMode {
+    /// The mode of the name, off, add or replace, None if unknown
+    pub fn from_name(name: &str) -> Option<NodeAffinityMode> {
+        match name {
+            "off" => Some(NodeAffinityMode::Off),
+            "add" => Some(NodeAffinityMode::Add),
+            "replace" => Some(NodeAffinityMode::Replace),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            stamp_annotation: true,
+            kinds: HashSet::from([Kind::Pod]),
+            label_pvcs: false,
+            patch_mode: PatchMode::Both,
+            node_affinity: NodeAffinityMode::Off,
+            first_pod: FirstPodMode::Off,
+            guarded_patch: false,
+            skip_daemonsets: true,
+            volume_scan_limit: 256,
+            max_patch_bytes: 256 * 1024,
+            spread: SpreadOptions::default(),
+            preferred_weight: 100,
+            anti_affinity_weight: 100,
+            scheduling_gate: false,
+            label_value: LabelValue::True,
+            group_by_owner: false,
+            replace_affinity_namespaces: HashSet::new(),
+            access_modes: HashSet::new(),
+            provisioners: HashSet::new(),
+            provisioners_deny: HashSet::new(),
+            unbound_claims: UnboundClaimMode::Off,
+            failure_mode: FailureMode::Open,
+            namespace_selector: None,
+            validate_mode: ValidateMode::Deny,
+        }
+    }
+}
+
+/// A setting of a [ControllerBuilder] that cannot be used
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ConfigError {
+    /// The namespace of a claim is not a DNS label
+    #[error("namespace of claim {namespace}/{claim} is not a valid namespace name")]
+    Namespace {
+        /// Namespace of the claim
+        namespace: String,
+        /// Name of the claim
+        claim: String,
+    },
+    /// The name of a claim cannot be the name part of a label key
+    #[error("name of claim {namespace}/{claim} cannot be used in a label")]
+    Claim {
+        /// Namespace of the claim
+        namespace: String,
+        /// Name of the claim
+        claim: String,
+    },
+    /// The weight of the mode of a claim is not within 1-100
+    #[error("weight {weight} of claim {namespace}/{claim} is not within 1-100")]
+    Weight {
+        /// Namespace of the claim
+        namespace: String,
+        /// Name of the claim
+        claim: String,
+        /// The configured weight
+        weight: u32,
+    },
+    /// The max skew of the spread mode of a claim is 0
+    #[error("max skew of claim {namespace}/{claim} must be positive")]
+    MaxSkew {
+        /// Namespace of the claim
+        namespace: String,
+        /// Name of the claim
+        claim: String,
+    },
+    /// The topology key, of the builder or of the spread mode of a claim, is not a label key
+    #[error("topology key must be a label key but is '{0}'")]
+    TopologyKey(String),
+    /// The label domain is not a DNS subdomain, or too long to be prefixed with a namespace
+    #[error("label domain must be a DNS subdomain but is '{0}'")]
+    LabelDomain(String),
+    /// An ignored namespace is not a DNS label
+    #[error("ignored namespace '{0}' is not a valid namespace name")]
+    IgnoredNamespace(String),
+}
+
+impl ConfigError {
+    /// The setting that cannot be used, e.g. topology_key
+    pub fn field(&self) -> &'static str {
+        match self {
+            ConfigError::Namespace { .. } => "namespace",
+            ConfigError::Claim { .. } => "claim",
+            ConfigError::Weight { .. } => "weight",
+            ConfigError::MaxSkew { .. } => "max_skew",
+            ConfigError::TopologyKey(_) => "topology_key",
+            ConfigError::LabelDomain(_) => "label_domain",
+            ConfigError::IgnoredNamespace(_) => "ignore_namespace",
+        }
+    }
+}
+
+/// Whether the name is a DNS label, like the names of namespaces
+fn is_dns_label(name: &str) -> bool {
+    name.len() <= MAX_LABEL_NAME_LENGTH
+        && !name.contains('.')
+        && is_valid_label_key(&format!("{name}/{name}"))
+}
+
+/// Configures a [Controller] in code instead of with the comma separated config
+///
+/// ```
+/// use gravivol::{Controller, controller::ClaimMode};
+///
+/// let controller = Controller::builder()
+///     .add_pvc("default", "data")
+///     .add_pvc_with_mode("default", "logs", ClaimMode::Preferred(Some(50)))
+///     .topology_key("topology.kubernetes.io/zone")
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ControllerBuilder {
+    /// In the order added, the default mode if None
+    pvcs: Vec<(Pvc, Option<ClaimMode>)>,
+    excluded_pvcs: Vec<Pvc>,
+    default_mode: ClaimMode,
+    topology_key: String,
+    label_domain: String,
+    ignored_namespaces: Vec<String>,
+    options: Options,
+}
+
+impl Default for ControllerBuilder {
+    fn default() -> Self {
+        ControllerBuilder {
+            pvcs: Vec::new(),
+            excluded_pvcs: Vec::new(),
+            default_mode: ClaimMode::Affinity,
+            topology_key: DEFAULT_TOPOLOGY_KEY.to_owned(),
+            label_domain: DEFAULT_LABEL_DOMAIN.to_owned(),
+            ignored_namespaces: Vec::new(),
+            options: Options::default(),
+        }
+    }
+}
+
+impl ControllerBuilder {
+    /// Handles all claims with pod affinity unless claims are added
+    pub fn new() -> ControllerBuilder {
+        ControllerBuilder::default()
+    }
+
+    /// Handles the claim with the default mode, only added claims are handled once one is
+    pub fn add_pvc(mut self, namespace: &str, claim: &str) -> ControllerBuilder {
+        self.pvcs.push((Pvc::new(namespace, claim), None));
+        self
+    }
+
+    /// Handles the claim with the mode, like the entry `<namespace>/<claim>:<mode>` of the config
+    pub fn add_pvc_with_mode(
+        mut self,
+        namespace: &str,
+        claim: &str,
+        mode: ClaimMode,
+    ) -> ControllerBuilder {
+        self.pvcs.push((Pvc::new(namespace, claim), Some(mode)));
+        self
+    }
+
+    /// Never handles the claim, even if added or all claims are handled
+    pub fn exclude_pvc(mut self, namespace: &str, claim: &str) -> ControllerBuilder {
+        self.excluded_pvcs.push(Pvc::new(namespace, claim));
+        self
+    }
+
+    /// Mode of the claims added without one, and of all claims if none is added
+    pub fn affinity_mode(mut self, mode: ClaimMode) -> ControllerBuilder {
+        self.default_mode = mode;
+        self
+    }
+
+    /// Topology key of the pod affinity terms unless overridden by annotation,
+    /// kubernetes.io/hostname by default
+    pub fn topology_key(mut self, topology_key: &str) -> ControllerBuilder {
+        self.topology_key = topology_key.to_owned();
+        self
+    }
+
+    /// Domain of the claim labels, `<namespace>.<domain>/<claim>`, gravivol.fonona.net by
+    /// default
+    ///
+    /// Pods labelled with another domain are not co-located with those labelled with this one.
+    pub fn label_domain(mut self, label_domain: &str) -> ControllerBuilder {
+        self.label_domain = label_domain.to_owned();
+        self
+    }
+
+    /// Leaves the pods of the namespace alone, like a namespace not matching the namespace
+    /// selector
+    pub fn ignore_namespace(mut self, namespace: &str) -> ControllerBuilder {
+        self.ignored_namespaces.push(namespace.to_owned());
+        self
+    }
+
+    /// Replaces the default options
+    pub fn options(mut self, options: Options) -> ControllerBuilder {
+        self.options = options;
+        self
+    }
+
+    /// The controller, Err with the first setting that cannot be used
+    pub fn build(self) -> Result<Controller, ConfigError> {
+        if !is_valid_label_key(&self.topology_key) {
+            return Err(ConfigError::TopologyKey(self.topology_key));
+        }
+        // The longest namespace must fit into the prefix of the label keys
+        let longest = Pvc::new(&"n".repeat(MAX_LABEL_NAME_LENGTH), "claim");
+        if !is_valid_label_key(&Label::from_pvc(&longest, &self.label_domain).key) {
+            return Err(ConfigError::LabelDomain(self.label_domain));
+        }
+        let modes = self.pvcs.iter().map(|(pvc, mode)| (pvc, mode.as_ref()));
+        let excluded = self.excluded_pvcs.iter().map(|pvc| (pvc, None));
+        for (pvc, mode) in modes.chain(excluded) {
+            let (namespace, claim) = (pvc.namespace.clone(), pvc.claim_name.clone());
+            if !is_dns_label(&namespace) {
+                return Err(ConfigError::Namespace { namespace, claim });
+            }
+            if !Label::is_valid_for_claim(&claim) {
+                return Err(ConfigError::Claim { namespace, claim });
+            }
+            match mode {
+                Some(
+                    ClaimMode::Preferred(Some(weight)) | ClaimMode::AntiAffinity(Some(weight)),
+                ) if !PREFERRED_WEIGHTS.contains(weight) => {
+                    return Err(ConfigError::Weight {
+                        namespace,
+                        claim,
+                        weight: *weight,
+                    });
+                }
+                Some(ClaimMode::Spread(overrides)) => {
+                    if overrides.max_skew == Some(0) {
+                        return Err(ConfigError::MaxSkew { namespace, claim });
+                    }
+                    if let Some(topology_key) = &overrides.topology_key
+                        && !is_valid_label_key(topology_key)
+                    {
+                        return Err(ConfigError::TopologyKey(topology_key.clone()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(namespace) = self
+            .ignored_namespaces
+            .iter()
+            .find(|namespace| !is_dns_label(namespace))
+        {
+            return Err(ConfigError::IgnoredNamespace(namespace.clone()));
+        }
+        Ok(self.into_controller())
+    }
+
+    /// The controller without validating the settings
+    fn into_controller(self) -> Controller {
+        let default_mode = self.default_mode;
+        Controller {
+            pvcs_to_handle: self
+                .pvcs
+                .into_iter()
+                .map(|(pvc, mode)| (pvc, mode.unwrap_or_else(|| default_mode.clone())))
+                .collect(),
+            excluded_pvcs: self.excluded_pvcs.into_iter().collect(),
+            default_mode,
+            topology_key: self.topology_key,
+            label_domain: self.label_domain,
+            ignored_namespaces: self.ignored_namespaces.into_iter().collect(),
+            options: self.options,
+            cluster: None,
+            metrics: Arc::new(Metrics::new()),
+            decisions: None,
+            audit_log: None,
+            events: None,
+            namespaces: None,
+            unhandled_kinds: RateLimiter::new(1.0 / UNHANDLED_KIND_LOG_INTERVAL_SECS, 1),
+        }
+    }
+}
+
+/// Answers admission reviews of pods and pod templates with patches co-locating them by their claims
+pub struct Controller {
+    // If map is empty, all PVCs will be handled with the default mode
+    pvcs_to_handle: HashMap<Pvc, ClaimMode>,
+    /// Never handled, even if all PVCs are
+    excluded_pvcs: HashSet<Pvc>,
+    default_mode: ClaimMode,
+    /// Of the pod affinity terms unless overridden by annotation
+    topology_key: String,
+    label_domain: String,
+    /// Pods in them are skipped like those in namespaces not selected
+    ignored_namespaces: HashSet<String>,
+    options: Options,
+    cluster: Option<Arc<dyn Cluster>>,
+    metrics: Arc<Metrics>,
+    decisions: Option<Arc<Decisions>>,
+    audit_log: Option<Arc<AuditLog>>,
+    events: Option<Arc<EventRecorder>>,
+    namespaces: Option<Arc<dyn Namespaces>>,
+    /// Limits the warnings about unhandled kinds, sent on every request if misregistered
+    unhandled_kinds: RateLimiter,
+}
+
+impl Controller {
+    /// config is comma separated string with PVCs to consider
+    ///
+    /// Entries that cannot be parsed are logged and ignored. Unlike by
+    /// [ControllerBuilder::build], the claims are not validated, pods mounting claims whose
+    /// names cannot be used in a label get a warning instead.
+    pub fn new(config: &str) -> Controller {
+        let mut builder = Controller::builder();
+        for config_entry in config.split(',') {
+            if config_entry.is_empty() {
+                continue;
+            }
+            match Pvc::from_config_entry(config_entry) {
+                Some((pvc, mode)) => builder.pvcs.push((pvc, Some(mode))),
+                None => tracing::error!(
+                    "Config entry is not in the format {CONFIG_ENTRY_FORMAT} : {config_entry}"
+                ),
+            }
+        }
+        builder.into_controller()
+    }
+
+    /// Configures a controller in code, see [ControllerBuilder]
+    pub fn builder() -> ControllerBuilder {
+        ControllerBuilder::new()
+    }
+
+    /// Replaces the default options
+    pub fn with_options(mut self, options: Options) -> Controller {
+        self.options = options;
+        self
+    }
+
+    /// Records into metrics shared with other controllers
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Controller {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The metrics recorded into
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Records the decision on each request into a history shared with other controllers
+    pub fn with_decisions(mut self, decisions: Arc<Decisions>) -> Controller {
+        self.decisions = Some(decisions);
+        self
+    }
+
+    /// Writes the decision on each request with its patch to the audit log
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Controller {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Creates an event on each pod that got a patch
+    pub fn with_events(mut self, events: Arc<EventRecorder>) -> Controller {
+        self.events = Some(events);
+        self
+    }
+
+    /// Enables the namespace selector, matched against the labels of the namespaces
+    pub fn with_namespaces(mut self, namespaces: Arc<dyn Namespaces>) -> Controller {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    /// Enables lookups in the Kubernetes API
+    pub fn with_cluster(mut self, cluster: Arc<dyn Cluster>) -> Controller {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Required node selector terms of the volume bound to the claim, None if not bound
+    async fn volume_node_terms(
+        cluster: &dyn Cluster,
+        namespace: &str,
+        claim_name: &str,
+        dry_run: bool,
+    ) -> Result<Option<Vec<Value>>, LookupError> {
+        let volume_name = cluster
+            .get_claim(namespace, claim_name, dry_run)
+            .await?
+            .and_then(|claim| claim.spec)
+            .and_then(|spec| spec.volume_name);
+        let Some(volume_name) = volume_name else {
+            return Ok(None);
+        };
+        let terms = cluster
+            .get_volume(&volume_name, dry_run)
+            .await?
+            .and_then(|volume| volume.spec)
+            .and_then(|spec| spec.node_affinity)
+            .and_then(|node_affinity| node_affinity.required)
+            .map(|required| required.node_selector_terms);
+        match terms {
+            Some(terms) if !terms.is_empty() => Ok(Some(
+                terms
+                    .iter()
+                    .map(serde_json::to_value)
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    /// The owning workload to group by if it fits into the label keys of all claims
+    fn owner(
+        &self,
+        kind: Kind,
+        metadata: &Metadata,
+        claims: &[String],
+        warnings: &mut Vec<String>,
+    ) -> Option<String> {
+        // The pods of a workload are owned by it
+        let owner = match kind {
+            Kind::Pod => metadata.top_level_owner()?,
+            _ => metadata.name.to_owned()?,
+        };
+        if let Some(claim) = claims
+            .iter()
+            .find(|claim| owner.len() + 1 + claim.len() > MAX_LABEL_NAME_LENGTH)
+        {
+            tracing::warn!(
+                "Owner {owner} and claim {claim} are too long for a label, not grouping by owner"
+            );
+            warnings.push(warning(&format!(
+                "owner {owner} and claim {claim} are too long for a label, not grouped by owner"
+            )));
+            return None;
+        }
+        Some(owner)
+    }
+
+    /// Drops the claims that are being deleted, e.g. waiting for the pvc-protection finalizer
+    ///
+    /// Pods co-located by them would never get their volume. Claims that cannot be looked up are
+    /// kept.
+    async fn filter_terminating(
+        &self,
+        namespace: &str,
+        claims: &mut Vec<String>,
+        display_name: &str,
+        dry_run: bool,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        let mut kept = Vec::new();
+        for claim in claims.drain(..) {
+            match cluster.get_claim(namespace, &claim, dry_run).await {
+                Ok(Some(found)) if found.metadata.deletion_timestamp.is_some() => {
+                    tracing::debug!(
+                        "{display_name} uses PVC {claim} which is being deleted, skipped"
+                    );
+                    self.metrics.terminating_claim();
+                    warnings.push(warning(&format!(
+                        "claim {claim} is being deleted, not co-located"
+                    )));
+                }
+                Ok(_) => kept.push(claim),
+                Err(err) => {
+                    tracing::warn!("Cannot look up PVC {claim} of {display_name}: {err}");
+                    self.metrics.lookup_failed("terminating");
+                    kept.push(claim);
+                }
+            }
+        }
+        *claims = kept;
+    }
+
+    /// Drops the claims without one of the configured access modes
+    ///
+    /// Claims that cannot be looked up are kept, as if no access modes were configured.
+    async fn filter_access_modes(
+        &self,
+        namespace: &str,
+        claims: &mut Vec<String>,
+        display_name: &str,
+        dry_run: bool,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        if self.options.access_modes.is_empty() {
+            return;
+        }
+
+        let mut kept = Vec::new();
+        for claim in claims.drain(..) {
+            let access_modes = cluster
+                .get_claim(namespace, &claim, dry_run)
+                .await
+                .map(|found| {
+                    found
+                        .and_then(|found| found.spec)
+                        .and_then(|spec| spec.access_modes)
+                });
+            match access_modes {
+                Ok(Some(access_modes))
+                    if !access_modes.iter().any(|mode| {
+                        AccessMode::from_name(mode)
+                            .is_some_and(|mode| self.options.access_modes.contains(&mode))
+                    }) =>
+                {
+                    tracing::debug!(
+                        "{display_name} uses PVC {claim} with access modes {}, skipped",
+                        access_modes.join(",")
+                    );
+                    warnings.push(warning(&format!(
+                        "claim {claim} has access modes {}, not co-located",
+                        access_modes.join(",")
+                    )));
+                }
+                Ok(Some(_)) => kept.push(claim),
+                Ok(None) => {
+                    tracing::debug!(
+                        "PVC {claim} of {display_name} not found or without access modes, handling it as configured"
+                    );
+                    kept.push(claim);
+                }
+                Err(err) => {
+                    tracing::warn!("Cannot look up PVC {claim} of {display_name}: {err}");
+                    self.metrics.lookup_failed("access_modes");
+                    warnings.push(warning(&format!(
+                        "cannot look up the access modes of claim {claim}, handling it as configured"
+                    )));
+                    kept.push(claim);
+                }
+            }
+        }
+        *claims = kept;
+    }
+
+    /// Provisioner of the storage class of the claim, None if the claim or class does not exist
+    ///
+    /// A claim without storage class name gets the default class, one with the empty name none.
+    async fn claim_provisioner(
+        cluster: &dyn Cluster,
+        namespace: &str,
+        claim_name: &str,
+        dry_run: bool,
+    ) -> Result<Option<String>, LookupError> {
+        let Some(claim) = cluster.get_claim(namespace, claim_name, dry_run).await? else {
+            return Ok(None);
+        };
+        let class_name = claim.spec.and_then(|spec| spec.storage_class_name);
+        if class_name.as_deref() == Some("") {
+            return Ok(Some(String::new()));
+        }
+        Ok(cluster
+            .get_storage_class(class_name.as_deref(), dry_run)
+            .await?
+            .map(|class| class.provisioner))
+    }
+
+    /// Drops the claims whose storage class provisioner is not allowed or denied
+    ///
+    /// Claims that cannot be looked up are kept, as if no provisioners were configured.
+    async fn filter_provisioners(
+        &self,
+        namespace: &str,
+        claims: &mut Vec<String>,
+        display_name: &str,
+        dry_run: bool,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        if self.options.provisioners.is_empty() && self.options.provisioners_deny.is_empty() {
+            return;
+        }
+
+        let mut kept = Vec::new();
+        for claim in claims.drain(..) {
+            match Controller::claim_provisioner(cluster.as_ref(), namespace, &claim, dry_run).await
+            {
+                Ok(Some(provisioner))
+                    if self.options.provisioners_deny.contains(&provisioner)
+                        || !self.options.provisioners.is_empty()
+                            && !self.options.provisioners.contains(&provisioner) =>
+                {
+                    let provisioner = if provisioner.is_empty() {
+                        "no provisioner"
+                    } else {
+                        &provisioner
+                    };
+                    tracing::debug!(
+                        "{display_name} uses PVC {claim} of provisioner {provisioner}, skipped"
+                    );
+                    warnings.push(warning(&format!(
+                        "claim {claim} has storage of {provisioner}, not co-located"
+                    )));
+                }
+                Ok(Some(_)) => kept.push(claim),
+                Ok(None) => {
+                    tracing::debug!(
+                        "PVC {claim} of {display_name} or its storage class not found, handling it as configured"
+                    );
+                    kept.push(claim);
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Cannot look up the storage class of PVC {claim} of {display_name}: {err}"
+                    );
+                    self.metrics.lookup_failed("provisioner");
+                    warnings.push(warning(&format!(
+                        "cannot look up the provisioner of claim {claim}, handling it as configured"
+                    )));
+                    kept.push(claim);
+                }
+            }
+        }
+        *claims = kept;
+    }
+
+    /// Uses the UIDs of the claims as label values, so that pods of recreated claims differ
+    async fn resolve_label_values(
+        &self,
+        mutation: &mut Mutation,
+        display_name: &str,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        if self.options.label_value != LabelValue::Uid {
+            return;
+        }
+
+        for claim in mutation.claims.clone() {
+            let uid = cluster
+                .get_claim(&mutation.namespace, &claim, mutation.dry_run)
+                .await
+                .map(|found| found.and_then(|found| found.metadata.uid));
+            match uid {
+                Ok(Some(uid)) => {
+                    let value = uid.chars().take(MAX_LABEL_NAME_LENGTH).collect();
+                    mutation.label_values.insert(claim, value);
+                }
+                Ok(None) => {
+                    tracing::warn!(
+                        "PVC {claim} of {display_name} not found, labelling it with true"
+                    );
+                }
+                Err(err) => {
+                    tracing::warn!("Cannot look up PVC {claim} of {display_name}: {err}");
+                    warnings.push(warning(&format!(
+                        "cannot look up claim {claim}, using label value true instead of its UID"
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Drops or relaxes the pod affinity for claims that are not bound yet
+    ///
+    /// Claims that cannot be looked up keep their pod affinity.
+    async fn check_claim_phases(
+        &self,
+        mutation: &mut Mutation,
+        display_name: &str,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        if self.options.unbound_claims == UnboundClaimMode::Off
+            || mutation.affinity_claims.is_empty()
+        {
+            return;
+        }
+
+        for claim in std::mem::take(&mut mutation.affinity_claims) {
+            let claim_found = cluster
+                .get_claim(&mutation.namespace, &claim, mutation.dry_run)
+                .await;
+            let phase = match &claim_found {
+                Ok(found) => match claim_phase(found.as_ref()) {
+                    Some("Bound") => {
+                        mutation.affinity_claims.push(claim);
+                        continue;
+                    }
+                    Some(phase) => phase.to_owned(),
+                    None if found.is_none() => "not found".to_owned(),
+                    None => "Pending".to_owned(),
+                },
+                Err(err) => {
+                    tracing::warn!("Cannot look up PVC {claim} of {display_name}: {err}");
+                    self.metrics.lookup_failed("phase");
+                    warnings.push(warning(&format!(
+                        "cannot look up the phase of claim {claim}, adding the required pod affinity"
+                    )));
+                    mutation.affinity_claims.push(claim);
+                    continue;
+                }
+            };
+            match self.options.unbound_claims {
+                UnboundClaimMode::Preferred => {
+                    tracing::debug!(
+                        "PVC {claim} of {display_name} is {phase}, adding preferred pod affinity"
+                    );
+                    warnings.push(warning(&format!(
+                        "claim {claim} is {phase}, not bound, adding a preferred pod affinity only"
+                    )));
+                    mutation
+                        .preferred_claims
+                        .push((claim, self.options.preferred_weight));
+                }
+                UnboundClaimMode::Skip | UnboundClaimMode::Off => {
+                    tracing::debug!(
+                        "PVC {claim} of {display_name} is {phase}, not adding pod affinity"
+                    );
+                    warnings.push(warning(&format!(
+                        "claim {claim} is {phase}, not bound, no pod affinity added"
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Moves claims whose volumes pin the pod to nodes from pod affinity to node affinity
+    async fn add_volume_placement(
+        &self,
+        mutation: &mut Mutation,
+        display_name: &str,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        if self.options.node_affinity == NodeAffinityMode::Off
+            || mutation.affinity_claims.is_empty()
+        {
+            return;
+        }
+
+        let mut node_terms: Option<Vec<Value>> = None;
+        let mut affinity_claims = Vec::new();
+        for claim in &mutation.affinity_claims {
+            match Controller::volume_node_terms(
+                cluster.as_ref(),
+                &mutation.namespace,
+                claim,
+                mutation.dry_run,
+            )
+            .await
+            {
+                Ok(Some(terms)) => {
+                    tracing::debug!("Volume of PVC {claim} of {display_name} has node affinity");
+                    node_terms = Some(match node_terms {
+                        Some(existing) => merge_node_terms(&existing, &terms),
+                        None => terms,
+                    });
+                    if self.options.node_affinity == NodeAffinityMode::Add {
+                        affinity_claims.push(claim.to_owned());
+                    }
+                }
+                Ok(None) => {
+                    tracing::debug!(
+                        "PVC {claim} of {display_name} is not bound to a volume with node affinity"
+                    );
+                    affinity_claims.push(claim.to_owned());
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Cannot look up the volume of PVC {claim} of {display_name}: {err}"
+                    );
+                    warnings.push(warning(&format!(
+                        "cannot look up the volume of claim {claim}, using pod affinity only"
+                    )));
+                    affinity_claims.push(claim.to_owned());
+                }
+            }
+        }
+        mutation.affinity_claims = affinity_claims;
+        mutation.node_terms = node_terms.unwrap_or_default();
+    }
+
+    /// Relaxes the pod affinity if no peer pod carrying the selected labels exists yet
+    async fn check_peer_pods(
+        &self,
+        mutation: &mut Mutation,
+        display_name: &str,
+        warnings: &mut Vec<String>,
+    ) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        if self.options.first_pod == FirstPodMode::Off || mutation.affinity_claims.is_empty() {
+            return;
+        }
+
+        let label_selector = mutation.label_selector(&mutation.affinity_claims);
+        match cluster
+            .has_pods(&mutation.namespace, &label_selector, mutation.dry_run)
+            .await
+        {
+            Ok(true) => tracing::debug!("Found peer pod for {display_name}"),
+            Ok(false) => {
+                tracing::debug!(
+                    "No peer pod for {display_name} found, first pod mode is {:?}",
+                    self.options.first_pod
+                );
+                match self.options.first_pod {
+                    FirstPodMode::Anchor => mutation.affinity_claims.clear(),
+                    FirstPodMode::Preferred => {
+                        for claim in std::mem::take(&mut mutation.affinity_claims) {
+                            mutation
+                                .preferred_claims
+                                .push((claim, self.options.preferred_weight));
+                        }
+                    }
+                    FirstPodMode::Off => {}
+                }
+            }
+            Err(err) => {
+                tracing::warn!("Cannot look up peer pods of {display_name}: {err}");
+                warnings.push(warning(
+                    "cannot look up peer pods, adding the required pod affinity",
+                ));
+            }
+        }
+    }
+
+    fn pvc_needs_handling(&self, namespace: &str, claim_name: &str) -> bool {
+        let pvc = Pvc {
+            namespace: namespace.to_owned(),
+            claim_name: claim_name.to_owned(),
+        };
+        if self.excluded_pvcs.contains(&pvc) {
+            false
+        } else if self.pvcs_to_handle.is_empty() {
+            true
+        } else {
+            self.pvcs_to_handle.contains_key(&pvc)
+        }
+    }
+
+    fn claim_mode(&self, namespace: &str, claim_name: &str) -> ClaimMode {
+        let pvc = Pvc {
+            namespace: namespace.to_owned(),
+            claim_name: claim_name.to_owned(),
+        };
+        self.pvcs_to_handle
+            .get(&pvc)
+            .cloned()
+            .unwrap_or_else(|| self.default_mode.clone())
+    }
+
+    /// Adds the managed label to a configured claim
+    fn label_claim(
+        &self,
+        metadata: &Metadata,
+        response: &mut Response,
+    ) -> Result<(), ControllerError> {
+        let display_name = format!("PersistentVolumeClaim {}", metadata.get_display_name());
+        match &metadata.name {
+            Some(name) if self.pvc_needs_handling(&metadata.namespace, name) => {
+                let patch =
+                    create_claim_patch(metadata).map_err(|source| ControllerError::PatchBuild {
+                        claims: name.to_owned(),
+                        source,
+                    })?;
+                let patch = serde_json::to_string(&patch).map_err(|source| {
+                    ControllerError::Serialization {
+                        object: display_name.clone(),
+                        source,
+                    }
+                })?;
+                tracing::debug!(patch, "Patch of {display_name}");
+                response.patch_type = Some("JSONPatch".to_owned());
+                response.patch = Some(BASE64_STANDARD.encode(patch.as_bytes()));
+                tracing::debug!("Created patch for {display_name}");
+            }
+            _ => tracing::debug!("No patch required for {display_name}"),
+        }
+        Ok(())
+    }
+
+    /// Review answering a request body that could not be processed, None if it has no request uid
+    pub fn failure_review(&self, body: &[u8], message: &str) -> Option<AdmissionReview> {
+        let mut review = self.empty_review(body)?;
+        if let Some(response) = &mut review.response {
+            response.allowed = self.options.failure_mode == FailureMode::Open;
+            response.status = Some(Status::internal_error(format!("gravivol: {message}")));
+        }
+        Some(review)
+    }
+
+    /// Review answering a request body with the error, allowed with a warning unless failing
+    /// closed
+    pub fn error_review(&self, body: &[u8], err: &ControllerError) -> Option<AdmissionReview> {
+        let mut review = self.empty_review(body)?;
+        if let Some(response) = &mut review.response {
+            self.fail(response, err);
+        }
+        Some(review)
+    }
+
+    /// Answers with the status of the error, allowing the object unchanged with a warning when
+    /// failing open and denying it otherwise
+    fn fail(&self, response: &mut Response, err: &ControllerError) {
+        response.allowed = self.options.failure_mode == FailureMode::Open;
+        response.status = Some(err.status());
+        if response.allowed {
+            response
+                .warnings
+                .push(warning(&format!("{err}, the object was not mutated")));
+        }
+    }
+
+    /// Allowing review with the uid and API version of the request body, None without uid
+    fn empty_review(&self, body: &[u8]) -> Option<AdmissionReview> {
+        let uid = request_uid(body)?;
+        let parsed: Option<Value> = serde_json::from_slice(body).ok();
+        let api_version = parsed
+            .as_ref()
+            .and_then(|value| value["apiVersion"].as_str())
+            .filter(|api_version| ADMISSION_API_VERSIONS.contains(api_version))
+            .unwrap_or(ADMISSION_API_VERSIONS[0]);
+        Some(AdmissionReview {
+            api_version: api_version.to_owned(),
+            kind: "AdmissionReview".to_owned(),
+            request: None,
+            response: Some(Response {
+                uid,
+                allowed: true,
+                patch_type: None,
+                patch: None,
+                warnings: Vec::new(),
+                status: None,
+                audit_annotations: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Whether the namespace is ignored or does not match the namespace selector
+    ///
+    /// A namespace that is not known yet, e.g. just created, is handled as selected with a
+    /// warning, like a failed lookup.
+    fn namespace_excluded(&self, namespace: &str, warnings: &mut Vec<String>) -> bool {
+        if self.ignored_namespaces.contains(namespace) {
+            return true;
+        }
+        let (Some(selector), Some(namespaces)) =
+            (&self.options.namespace_selector, &self.namespaces)
+        else {
+            return false;
+        };
+        match namespaces.get(namespace) {
+            Some(metadata) => !selector_matches(selector, &metadata.labels),
+            None => {
+                tracing::warn!("Namespace {namespace} is not known yet, handling it as selected");
+                self.metrics.lookup_failed("namespace");
+                warnings.push(warning(&format!(
+                    "namespace {namespace} is not known yet, handling it as selected"
+                )));
+                false
+            }
+        }
+    }
+
+    /// Claims of the pod configured for co-location that pass the checks on the claims
+    async fn matched_claims(
+        &self,
+        namespace: &str,
+        pod: &PodTemplate,
+        display_name: &str,
+        dry_run: bool,
+        warnings: &mut Vec<String>,
+    ) -> Vec<String> {
+        let mut pvcs_found = self.configured_claims(namespace, pod, display_name, warnings);
+        self.check_claims(namespace, &mut pvcs_found, display_name, dry_run, warnings)
+            .await;
+        pvcs_found
+    }
+
+    /// Claims of the pod configured for co-location, without looking anything up
+    fn configured_claims(
+        &self,
+        namespace: &str,
+        pod: &PodTemplate,
+        display_name: &str,
+        warnings: &mut Vec<String>,
+    ) -> Vec<String> {
+        let volumes = pod.spec.volumes.as_deref().unwrap_or_default();
+        let limit = match self.options.volume_scan_limit {
+            0 => volumes.len(),
+            limit => limit.min(volumes.len()),
+        };
+        let mut pvcs_found = Vec::with_capacity(limit);
+        let mut unconfigured = Vec::new();
+        tracing::info_span!("match").in_scope(|| {
+            if limit < volumes.len() {
+                tracing::warn!(
+                    "{} has {} volumes, only the first {limit} are examined for claims",
+                    display_name,
+                    volumes.len()
+                );
+                warnings.push(warning(&format!(
+                    "pod has {} volumes, only the first {limit} were examined for claims",
+                    volumes.len()
+                )));
+            }
+            for vol in &volumes[..limit] {
+                let Some(pvc) = &vol.persistent_volume_claim else {
+                    continue;
+                };
+                if !self.pvc_needs_handling(namespace, &pvc.claim_name) {
+                    tracing::debug!(
+                        "{} uses PVC {} which is not configured",
+                        display_name,
+                        pvc.claim_name
+                    );
+                    unconfigured.push(&pvc.claim_name);
+                } else if pvc.claim_name.chars().count() > MAX_LABEL_NAME_LENGTH {
+                    tracing::warn!(
+                        "{} uses matching PVC {} but its name is too long for a label",
+                        display_name,
+                        pvc.claim_name
+                    );
+                    warnings.push(warning(&format!(
+                        "claim name longer than {MAX_LABEL_NAME_LENGTH} characters cannot be used in a label, skipped: {}",
+                        pvc.claim_name
+                    )));
+                } else if !Label::is_valid_for_claim(&pvc.claim_name) {
+                    tracing::warn!(
+                        "{} uses matching PVC {:?} but its name is not valid in a label",
+                        display_name,
+                        pvc.claim_name
+                    );
+                    warnings.push(warning(&format!(
+                        "claim name cannot be used in a label, skipped: {}",
+                        pvc.claim_name
+                    )));
+                } else {
+                    tracing::debug!("{} uses matching PVC {}", display_name, pvc.claim_name);
+                    pvcs_found.push(pvc.claim_name.to_owned());
+                }
+            }
+            // Only next to co-located claims, most pods mount claims of no concern to gravivol
+            if !pvcs_found.is_empty() {
+                for claim in unconfigured {
+                    warnings.push(warning(&format!(
+                        "claim {claim} is not configured for co-location"
+                    )));
+                }
+            }
+            restrict_to_annotated_claims(pod, &mut pvcs_found, warnings);
+        });
+        pvcs_found
+    }
+
+    /// Drops the claims failing a check that looks them up in the Kubernetes API
+    async fn check_claims(
+        &self,
+        namespace: &str,
+        pvcs_found: &mut Vec<String>,
+        display_name: &str,
+        dry_run: bool,
+        warnings: &mut Vec<String>,
+    ) {
+        self.filter_terminating(namespace, pvcs_found, display_name, dry_run, warnings)
+            .await;
+        self.filter_access_modes(namespace, pvcs_found, display_name, dry_run, warnings)
+            .await;
+        self.filter_provisioners(namespace, pvcs_found, display_name, dry_run, warnings)
+            .await;
+    }
+
+    /// Why no claim of the pod was matched, distinguishing the steps of [Controller::matched_claims]
+    fn no_claims_reason(&self, namespace: &str, pod: &PodTemplate) -> SkipReason {
+        let mounted = pod.spec.claim_names();
+        if mounted.is_empty() {
+            return SkipReason::NoVolumes;
+        }
+        let configured: Vec<&str> = mounted
+            .into_iter()
+            .filter(|claim| {
+                self.pvc_needs_handling(namespace, claim) && Label::is_valid_for_claim(claim)
+            })
+            .collect();
+        if configured.is_empty() {
+            return SkipReason::NoClaims;
+        }
+        match pod.metadata.get_annotation(CLAIMS_ANNOTATION) {
+            Some(annotation)
+                if !annotation
+                    .split(',')
+                    .any(|name| configured.contains(&name.trim())) =>
+            {
+                SkipReason::OptedOut
+            }
+            _ => SkipReason::ClaimsFiltered,
+        }
+    }
+
+    /// Claims of a running pod whose labels the pod should carry but does not
+    ///
+    /// Matched like on admission, e.g. for pods created while the webhook was unavailable.
+    pub async fn missing_claim_labels(
+        &self,
+        pod: &Value,
+        dry_run: bool,
+    ) -> Result<Vec<String>, ControllerError> {
+        let pod = PodTemplate::deserialize(pod).map_err(ControllerError::invalid_object("pod"))?;
+        let metadata = &pod.metadata;
+        let uses_configured_claim = pod.spec.volumes.iter().flatten().any(|volume| {
+            volume
+                .persistent_volume_claim
+                .as_ref()
+                .is_some_and(|pvc| self.pvc_needs_handling(&metadata.namespace, &pvc.claim_name))
+        });
+        // Skipped on admission as well, and pods in affinity mode get no labels
+        if !uses_configured_claim
+            || metadata.get_annotation(MIRROR_ANNOTATION).is_some()
+            || self.namespace_excluded(&metadata.namespace, &mut Vec::new())
+            || (self.options.skip_daemonsets && metadata.is_owned_by("DaemonSet"))
+            || !PatchMode::from_metadata(metadata, self.options.patch_mode, &mut Vec::new())
+                .adds_labels()
+        {
+            return Ok(Vec::new());
+        }
+        let display_name = format!("{} {}", Kind::Pod, metadata.get_display_name());
+        let claims = self
+            .matched_claims(
+                &metadata.namespace,
+                &pod,
+                &display_name,
+                dry_run,
+                &mut Vec::new(),
+            )
+            .await;
+        let labeled: HashSet<&str> = metadata
+            .labels
+            .iter()
+            .flatten()
+            .map(|(key, _)| key.as_str())
+            .filter(|key| Label::is_claim_key(key, &self.label_domain))
+            .filter_map(|key| key.split_once('/').map(|(_, name)| name))
+            .collect();
+        Ok(claims
+            .into_iter()
+            // The label of a claim is prefixed with the owner when grouping by owner
+            .filter(|claim| {
+                !labeled.iter().any(|name| {
+                    name == claim
+                        || name
+                            .strip_suffix(claim.as_str())
+                            .is_some_and(|prefix| prefix.ends_with('.'))
+                })
+            })
+            .collect())
+    }
+
+    fn skip(&self, decision: &mut Decision, reason: SkipReason) {
+        self.metrics.skipped(reason);
+        decision.skip_reason = Some(reason);
+    }
+
+    /// Answers the review, with a patch if the object mounts claims configured for co-location
+    ///
+    /// Objects that need no patch are allowed unchanged. Fails if the review has no request or its
+    /// object cannot be read, the error then answers the review with [Controller::error_review].
+    pub async fn mutate(
+        &self,
+        review: AdmissionReview,
+    ) -> Result<AdmissionReview, ControllerError> {
+        let (namespace, name) = review.object_names();
+        let mut decision = Decision {
+            uid: review.uid().unwrap_or_default().to_owned(),
+            namespace: namespace.to_owned(),
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        let recorded = review.request.is_some();
+        let started = Instant::now();
+        let result = self.decide(review, &mut decision).await;
+        if recorded {
+            decision.log(
+                started.elapsed(),
+                result
+                    .as_ref()
+                    .err()
+                    .map(|err| err as &dyn std::error::Error),
+            );
+            decision.stamp();
+            // A dry run changes nothing, so it leaves no record
+            if let Some(audit_log) = &self.audit_log
+                && !decision.dry_run
+            {
+                audit_log.record(&decision);
+            }
+            if let Some(decisions) = &self.decisions {
+                decisions.record(decision);
+            }
+        }
+        result
+    }
+
+    /// Answers the review, noting what was decided
+    async fn decide(
+        &self,
+        review: AdmissionReview,
+        decision: &mut Decision,
+    ) -> Result<AdmissionReview, ControllerError> {
+        if let Some(request) = review.request {
+            self.metrics.admission_requests.inc();
+            let mut review = AdmissionReview {
+                api_version: review.api_version.clone(),
+                kind: review.kind.clone(),
+                request: None,
+                response: Some(Response {
+                    uid: request.uid.clone(),
+                    allowed: true,
+                    patch_type: None,
+                    patch: None,
+                    warnings: Vec::new(),
+                    status: None,
+                    audit_annotations: HashMap::new(),
+                }),
+            };
+            let mut warnings = Vec::new();
+            decision.operation = request.operation.clone();
+            decision.dry_run = request.dry_run;
+            if request.dry_run {
+                self.metrics.dry_runs.inc();
+            }
+            if let Some(user_info) = &request.user_info {
+                decision.user = user_info.username.clone();
+                decision.groups = user_info.groups.clone();
+            }
+            self.metrics.operation(&request.operation);
+
+            if review.refuse_unsupported() {
+                return Ok(review);
+            }
+            let Some(object) = &request.object else {
+                tracing::debug!("{} request has no object, skipped", request.operation);
+                self.skip(decision, SkipReason::NoObject);
+                return Ok(review);
+            };
+
+            let object_kind = object["kind"].as_str().unwrap_or_default();
+            let object_api_version = object["apiVersion"].as_str().unwrap_or_default();
+            if self.options.label_pvcs
+                && object_kind == "PersistentVolumeClaim"
+                && object_api_version == "v1"
+            {
+                let metadata: Metadata = object_metadata(object)
+                    .map_err(ControllerError::invalid_object("PersistentVolumeClaim"))?;
+                if let Some(response) = &mut review.response {
+                    self.label_claim(&metadata, response)?;
+                }
+                return Ok(review);
+            }
+            let kind = match Kind::from_object(object_api_version, object_kind) {
+                Some(kind) if self.options.kinds.contains(&kind) => kind,
+                _ => {
+                    self.unhandled_kind(object_api_version, object_kind);
+                    self.skip(decision, SkipReason::UnhandledKind);
+                    let message = warning(&format!(
+                        "objects of kind {object_api_version} {object_kind} are not handled, check the rules of the webhook configuration"
+                    ));
+                    decision.warnings = vec![message.clone()];
+                    if let Some(response) = &mut review.response {
+                        response.warnings.push(message);
+                    }
+                    return Ok(review);
+                }
+            };
+
+            let mut metadata: Metadata = object_metadata(object).map_err(
+                ControllerError::invalid_object(&format!("metadata of {kind}")),
+            )?;
+            // The object of a CREATE may lack both, the request always names them if known
+            if metadata.namespace.is_empty() {
+                metadata.namespace = request.namespace.clone().unwrap_or_default();
+            }
+            if metadata.name.is_none() {
+                metadata.name = request.name.clone().filter(|name| !name.is_empty());
+            }
+            let display_name = format!("{} {}", kind, metadata.get_display_name());
+            let pod: PodTemplate = match object.pointer(kind.template_path()) {
+                Some(template) => PodTemplate::deserialize(template)
+                    .map_err(ControllerError::invalid_object(&display_name))?,
+                None => return Err(ControllerError::MissingTemplate(display_name)),
+            };
+
+            tracing::debug!("Got review request for {display_name}");
+
+            if metadata.get_annotation(MIRROR_ANNOTATION).is_some() {
+                // The kubelet keeps running the static pod regardless of its mirror
+                tracing::debug!("{display_name} is a mirror pod, skipped");
+                self.skip(decision, SkipReason::MirrorPod);
+                return Ok(review);
+            }
+
+            if self.namespace_excluded(&metadata.namespace, &mut warnings) {
+                tracing::debug!("{display_name} is in a namespace not selected, skipped");
+                self.skip(decision, SkipReason::NamespaceExcluded);
+                return Ok(review);
+            }
+
+            let mut pvcs_found = {
+                let _timer = self.metrics.time_phase(Phase::Match);
+                self.configured_claims(&metadata.namespace, &pod, &display_name, &mut warnings)
+            };
+            // Summed over the checks and the lookups for the patch, not read if not timed
+            let mut lookups = Duration::ZERO;
+            let started = self.metrics.phase_timing().then(Instant::now);
+            self.check_claims(
+                &metadata.namespace,
+                &mut pvcs_found,
+                &display_name,
+                request.dry_run,
+                &mut warnings,
+            )
+            .await;
+            lookups += started.map_or(Duration::ZERO, |started| started.elapsed());
+            let span = tracing::Span::current();
+            // Signed, as OpenTelemetry exports unsigned values as strings
+            span.record("claim_count", pvcs_found.len() as i64);
+            if !pvcs_found.is_empty() {
+                span.record("claims", tracing::field::display(pvcs_found.join(",")));
+            }
+            decision.claims = pvcs_found.clone();
+            self.metrics.claims_matched(pvcs_found.len());
+
+            if !pvcs_found.is_empty()
+                && self.options.skip_daemonsets
+                && metadata.is_owned_by("DaemonSet")
+            {
+                tracing::debug!("{display_name} is owned by a DaemonSet, skipped");
+                self.skip(decision, SkipReason::DaemonSet);
+                warnings.push(warning(
+                    "pods owned by a DaemonSet are not co-located with other pods",
+                ));
+            } else if !pvcs_found.is_empty()
+                && pod.metadata.get_annotation(MUTATED_ANNOTATION)
+                    == Some(pvcs_found.join(",").as_str())
+            {
+                tracing::debug!("{display_name} has already been mutated");
+                self.skip(decision, SkipReason::AlreadyMutated);
+            } else if !pvcs_found.is_empty() {
+                let mode =
+                    PatchMode::from_metadata(&pod.metadata, self.options.patch_mode, &mut warnings);
+                tracing::debug!("{display_name} gets patch mode {mode:?}");
+                let mut mutation = Mutation::new(&metadata.namespace, &pvcs_found, mode);
+                mutation.topology_key = self.topology_key.clone();
+                mutation.label_domain = self.label_domain.clone();
+                mutation.dry_run = request.dry_run;
+                if self.options.group_by_owner {
+                    mutation.owner = self.owner(kind, &metadata, &pvcs_found, &mut warnings);
+                }
+                if let Some(topology_key) = topology_key_from_metadata(&pod.metadata, &mut warnings)
+                {
+                    mutation.topology_key = topology_key;
+                }
+                let mut replace_affinity = self
+                    .options
+                    .replace_affinity_namespaces
+                    .contains(&metadata.namespace);
+                for claim in std::mem::take(&mut mutation.affinity_claims) {
+                    match self.claim_mode(&metadata.namespace, &claim) {
+                        ClaimMode::Affinity => mutation.affinity_claims.push(claim),
+                        ClaimMode::ReplaceAffinity => {
+                            replace_affinity = true;
+                            mutation.affinity_claims.push(claim);
+                        }
+                        ClaimMode::Preferred(weight) => mutation
+                            .preferred_claims
+                            .push((claim, weight.unwrap_or(self.options.preferred_weight))),
+                        ClaimMode::AntiAffinity(weight) => mutation
+                            .anti_affinity_claims
+                            .push((claim, weight.unwrap_or(self.options.anti_affinity_weight))),
+                        ClaimMode::Spread(overrides) => mutation
+                            .spread_claims
+                            .push((claim, overrides.apply(&self.options.spread))),
+                    }
+                }
+                let started = self.metrics.phase_timing().then(Instant::now);
+                self.resolve_label_values(&mut mutation, &display_name, &mut warnings)
+                    .await;
+                self.check_claim_phases(&mut mutation, &display_name, &mut warnings)
+                    .await;
+                self.add_volume_placement(&mut mutation, &display_name, &mut warnings)
+                    .await;
+                self.check_peer_pods(&mut mutation, &display_name, &mut warnings)
+                    .await;
+                lookups += started.map_or(Duration::ZERO, |started| started.elapsed());
+                mutation.replace_affinity = replace_affinity;
+                if mutation.replace_affinity && !mutation.affinity_claims.is_empty() {
+                    let dropped = required_affinity_terms(&pod);
+                    if !dropped.is_empty() {
+                        tracing::debug!(
+                            "Replacing required pod affinity terms of {display_name}: {}",
+                            dropped.join(" ")
+                        );
+                        warnings.push(warning(&format!(
+                            "replaced required pod affinity terms: {}",
+                            dropped.join(" ")
+                        )));
+                    }
+                } else if !mutation.affinity_claims.is_empty()
+                    && let Some(topology_key) = existing_claim_term_topology_key(
+                        &pod,
+                        &mutation
+                            .labels(&mutation.affinity_claims)
+                            .into_iter()
+                            .map(|label| label.key)
+                            .collect::<Vec<_>>(),
+                    )
+                {
+                    if topology_key == mutation.topology_key {
+                        tracing::debug!(
+                            "{display_name} already has a pod affinity term for claims"
+                        );
+                    } else {
+                        tracing::warn!(
+                            "{display_name} has a pod affinity term for claims with topology key {topology_key}, conflicting with {}",
+                            mutation.topology_key
+                        );
+                        self.metrics.conflicts.inc();
+                        warnings.push(warning(&format!(
+                            "existing pod affinity term for claims has topology key {topology_key}, not adding one"
+                        )));
+                    }
+                    mutation.affinity_claims.clear();
+                }
+                // Only followers are gated, anchors must get scheduled to open the gate
+                if self.options.scheduling_gate
+                    && mutation.label_claims.is_empty()
+                    && !mutation.affinity_claims.is_empty()
+                {
+                    mutation.gate_selector =
+                        Some(mutation.label_selector(&mutation.affinity_claims));
+                }
+                let claims = pvcs_found.join(",");
+                let Some(mut response) = review.response.take() else {
+                    return Err(ControllerError::Internal(format!(
+                        "review of {display_name} lost its response"
+                    )));
+                };
+                let built = tracing::info_span!("patch_build").in_scope(|| {
+                    let _timer = self.metrics.time_phase(Phase::PatchBuild);
+                    create_patch(&pod, kind.template_path(), &mutation, &self.options)
+                        .map(|patch| {
+                            if self.options.guarded_patch {
+                                guard_patch(object, patch)
+                            } else {
+                                patch
+                            }
+                        })
+                        .map_err(|source| ControllerError::PatchBuild {
+                            claims: claims.clone(),
+                            source,
+                        })
+                        .and_then(|patch| match serde_json::to_string(&patch) {
+                            Ok(json) => Ok((json, patch)),
+                            Err(source) => Err(ControllerError::Serialization {
+                                object: display_name.clone(),
+                                source,
+                            }),
+                        })
+                });
+                match built {
+                    Ok((patch, operations))
+                        if self.options.max_patch_bytes > 0
+                            && patch.len() > self.options.max_patch_bytes =>
+                    {
+                        // Something went wrong, and the patched object could exceed the
+                        // object size limit of etcd
+                        tracing::error!(
+                            "Patch of {display_name} has {} operations in {} bytes, more than {}, admitted without it",
+                            operations.len(),
+                            patch.len(),
+                            self.options.max_patch_bytes
+                        );
+                        self.skip(decision, SkipReason::PatchTooLarge);
+                        response.warnings.push(warning(&format!(
+                            "patch of {} bytes is larger than {} bytes, the object was not mutated",
+                            patch.len(),
+                            self.options.max_patch_bytes
+                        )));
+                    }
+                    Ok((patch, operations)) => {
+                        tracing::Span::current().record("patch_bytes", patch.len() as i64);
+                        decision.patch_ops = operations.len();
+                        if self.audit_log.is_some() {
+                            // The tests guarding the patch may copy large parts of the object
+                            decision.patch = operations
+                                .0
+                                .into_iter()
+                                .filter(|operation| !matches!(operation, PatchOperation::Test(_)))
+                                .collect();
+                        }
+                        tracing::debug!(patch, "Patch of {display_name}");
+                        response.patch_type = Some("JSONPatch".to_owned());
+                        response.patch = Some(BASE64_STANDARD.encode(patch.as_bytes()));
+                        response.audit_annotations = HashMap::from([
+                            (
+                                "matched-claims".to_owned(),
+                                pvcs_found
+                                    .iter()
+                                    .map(|claim| format!("{}/{}", metadata.namespace, claim))
+                                    .collect::<Vec<String>>()
+                                    .join(","),
+                            ),
+                            ("patch-ops".to_owned(), decision.patch_ops.to_string()),
+                        ]);
+                        if request.dry_run {
+                            tracing::debug!("Created patch for dry run of {display_name}");
+                        } else {
+                            tracing::debug!("Created patch for {display_name}");
+                            self.metrics.mutated(&metadata.namespace);
+                            for claim in &pvcs_found {
+                                self.metrics.claim_matched(&metadata.namespace, claim);
+                            }
+                            if let Some(events) = &self.events
+                                && kind == Kind::Pod
+                            {
+                                match metadata.name.as_deref().or(request.name.as_deref()) {
+                                    Some(name) => {
+                                        events.pod_mutated(&metadata.namespace, name, &pvcs_found)
+                                    }
+                                    None => tracing::debug!(
+                                        "{display_name} has no name yet, not creating an event"
+                                    ),
+                                }
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        // Admitted without the patch unless failing closed
+                        tracing::error!("Cannot create patch for {display_name}: {err}");
+                        self.metrics.errors.inc();
+                        self.fail(&mut response, &err);
+                    }
+                }
+                review.response = Some(response);
+            } else {
+                tracing::debug!("No patch required for {display_name}");
+                self.skip(decision, self.no_claims_reason(&metadata.namespace, &pod));
+            }
+            self.metrics.observe_phase(Phase::Lookup, lookups);
+
+            if let Some(response) = &mut review.response {
+                // Followed by the warning of a patch that could not be created
+                warnings.append(&mut response.warnings);
+                decision.warnings = warnings.clone();
+                response.warnings = warnings;
+            }
+
+            Ok(review)
+        } else {
+            Err(ControllerError::MissingRequest)
+        }
+    }
+
+    /// Logs an object of a kind not handled, at most once per kind and interval
+    ///
+    /// A webhook configuration sending other kinds, e.g. ReplicaSets, is harmless but would
+    /// otherwise log each of its requests.
+    fn unhandled_kind(&self, api_version: &str, kind: &str) {
+        if self
+            .unhandled_kinds
+            .try_acquire(&format!("{api_version} {kind}"))
+        {
+            tracing::warn!(
+                "Objects of kind {api_version} {kind} are not handled, check the rules of the webhook configuration"
+            );
+        } else {
+            tracing::debug!("Object of kind {api_version} {kind} is not handled");
+        }
+    }
+
+    /// Answers a validating review, refusing pods that lack the labels of their claims
+    ///
+    /// Pods are matched like by [Controller::missing_claim_labels], e.g. pods created while the
+    /// mutating webhook was down or outside its namespaceSelector. Other objects are allowed.
+    pub async fn validate(
+        &self,
+        review: AdmissionReview,
+    ) -> Result<AdmissionReview, ControllerError> {
+        let Some(mut request) = review.request else {
+            return Err(ControllerError::MissingRequest);
+        };
+        let mut review = AdmissionReview {
+            api_version: review.api_version,
+            kind: review.kind,
+            request: None,
+            response: Some(Response {
+                uid: request.uid.clone(),
+                allowed: true,
+                patch_type: None,
+                patch: None,
+                warnings: Vec::new(),
+                status: None,
+                audit_annotations: HashMap::new(),
+            }),
+        };
+        let Some(object) = &mut request.object else {
+            return Ok(review);
+        };
+        if review.refuse_unsupported() || object["kind"] != "Pod" || object["apiVersion"] != "v1" {
+            return Ok(review);
+        }
+        // The namespace of the request if the object does not carry one yet
+        if let (Some(namespace), Some(metadata)) =
+            (&request.namespace, object["metadata"].as_object_mut())
+        {
+            metadata
+                .entry("namespace")
+                .or_insert_with(|| Value::String(namespace.clone()));
+        }
+        let missing = self.missing_claim_labels(object, request.dry_run).await?;
+        if missing.is_empty() {
+            return Ok(review);
+        }
+        let metadata: Metadata =
+            object_metadata(object).map_err(ControllerError::invalid_object("metadata of Pod"))?;
+        let labels: Vec<String> = missing
+            .iter()
+            .map(|claim| {
+                Label::from_pvc(
+                    &Pvc {
+                        namespace: metadata.namespace.clone(),
+                        claim_name: claim.clone(),
+                    },
+                    &self.label_domain,
+                )
+                .key
+            })
+            .collect();
+        let message = format!(
+            "pod {} mounts claims {} without the labels {} of their co-location, it bypassed the mutating webhook",
+            metadata.get_display_name(),
+            missing.join(", "),
+            labels.join(", ")
+        );
+        tracing::warn!("Validation of pod failed: {message}");
+        self.metrics
+            .validation_failed(self.options.validate_mode.as_str());
+        if let Some(response) = &mut review.response {
+            match self.options.validate_mode {
+                ValidateMode::Deny => {
+                    response.allowed = false;
+                    response.status = Some(Status::forbidden(format!("gravivol: {message}")));
+                }
+                ValidateMode::Warn => response.warnings.push(warning(&message)),
+            }
+        }
+        Ok(review)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_patch::{Patch, patch};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+
+    /// The controller of the config, created from it and with the builder
+    fn controllers(config: &str) -> [Controller; 2] {
+        let mut builder = Controller::builder();
+        for entry in config.split(',').filter(|entry| !entry.is_empty()) {
+            builder = match entry.split_once(':') {
+                Some((claim, mode)) => {
+                    let (namespace, claim) = claim.split_once('/').unwrap();
+                    builder.add_pvc_with_mode(namespace, claim, ClaimMode::from_name(mode).unwrap())
+                }
+                None => {
+                    let (namespace, claim) = entry.split_once('/').unwrap();
+                    builder.add_pvc(namespace, claim)
+                }
+            };
+        }
+        [Controller::new(config), builder.build().unwrap()]
+    }
+
+    #[tokio::test]
+    async fn test_degenerate_pods() {
+        let [from_config, built] = controllers("default/data");
+        let claim = json!([{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }]);
+        for (name, metadata, spec) in [
+            (
+                "no spec",
+                json!({ "name": "web", "namespace": "default" }),
+                None,
+            ),
+            (
+                "null spec",
+                json!({ "name": "web", "namespace": "default" }),
+                Some(Value::Null),
+            ),
+            (
+                "null volumes",
+                json!({ "name": "web", "namespace": "default" }),
+                Some(json!({ "volumes": null, "containers": [{ "name": "web" }] })),
+            ),
+            (
+                "null claim",
+                json!({ "name": "web", "namespace": "default" }),
+                Some(json!({ "volumes": [{ "name": "data", "persistentVolumeClaim": null }] })),
+            ),
+            (
+                "null affinity and node selector",
+                json!({ "name": "web", "namespace": "default" }),
+                Some(json!({ "affinity": null, "nodeSelector": null, "volumes": [] })),
+            ),
+            (
+                "empty metadata",
+                json!({}),
+                Some(json!({ "volumes": claim })),
+            ),
+            (
+                "null metadata",
+                Value::Null,
+                Some(json!({ "volumes": claim })),
+            ),
+            (
+                "null fields of metadata",
+                json!({
+                    "name": null,
+                    "generateName": "web-",
+                    "namespace": null,
+                    "labels": null,
+                    "annotations": null,
+                    "ownerReferences": null,
+                }),
+                Some(json!({ "volumes": claim })),
+            ),
+        ] {
+            let mut pod = json!({ "apiVersion": "v1", "kind": "Pod", "metadata": metadata });
+            if let Some(spec) = spec {
+                pod["spec"] = spec;
+            }
+            for controller in [&from_config, &built] {
+                let (_, response) = mutate_pod_with(controller, &pod).await;
+                assert!(response.allowed, "{name}");
+                assert!(response.patch.is_none(), "{name}");
+                assert!(response.status.is_none(), "{name}: {:?}", response.status);
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let error = |builder: ControllerBuilder| builder.build().err().unwrap();
+        for (error, field, message) in [
+            (
+                error(Controller::builder().add_pvc("Default", "data")),
+                "namespace",
+                "namespace of claim Default/data is not a valid namespace name",
+            ),
+            (
+                error(Controller::builder().exclude_pvc("default", "data/1")),
+                "claim",
+                "name of claim default/data/1 cannot be used in a label",
+            ),
+            (
+                error(Controller::builder().add_pvc_with_mode(
+                    "default",
+                    "data",
+                    ClaimMode::AntiAffinity(Some(0)),
+                )),
+                "weight",
+                "weight 0 of claim default/data is not within 1-100",
+            ),
+            (
+                error(Controller::builder().topology_key("zone key")),
+                "topology_key",
+                "topology key must be a label key but is 'zone key'",
+            ),
+            (
+                error(Controller::builder().label_domain("Example.com")),
+                "label_domain",
+                "label domain must be a DNS subdomain but is 'Example.com'",
+            ),
+            (
+                error(Controller::builder().ignore_namespace("")),
+                "ignore_namespace",
+                "ignored namespace '' is not a valid namespace name",
+            ),
+        ] {
+            assert_eq!(error.field(), field);
+            assert_eq!(error.to_string(), message);
+        }
+        // No room left for the namespace
+        let long_domain = format!("{}.example.com", "a".repeat(180));
+        assert_eq!(
+            Controller::builder()
+                .label_domain(&long_domain)
+                .build()
+                .err(),
+            Some(ConfigError::LabelDomain(long_domain))
+        );
+        assert!(Controller::builder().build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_builder_exclude_and_ignore() {
+        let controller = Controller::builder()
+            .exclude_pvc("default", "scratch")
+            .ignore_namespace("kube-system")
+            .options(Options {
+                stamp_annotation: false,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let (patched_pod, _) =
+            mutate_pod_with(&controller, &pod_with_claims(&["data", "scratch"])).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/data": "true" })
+        );
+
+        let mut pod = pod_with_claims(&["data"]);
+        pod["metadata"]["namespace"] = json!("kube-system");
+        let (_, response) = mutate_pod_with(&controller, &pod).await;
+        assert!(response.patch.is_none());
+        assert!(
+            controller
+                .metrics()
+                .encode()
+                .contains("gravivol_pods_skipped_total{reason=\"namespace_excluded\"} 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_topology_key_and_label_domain() {
+        let controller = Controller::builder()
+            .add_pvc("default", "data")
+            .topology_key("topology.kubernetes.io/zone")
+            .label_domain("colocation.example.com")
+            .options(Options {
+                stamp_annotation: false,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod_with_claims(&["data"])).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.colocation.example.com/data": "true" })
+        );
+        assert_eq!(
+            patched_pod["spec"]["affinity"],
+            json!({
+                "podAffinity": {
+                    "requiredDuringSchedulingIgnoredDuringExecution": [{
+                        "labelSelector": {
+                            "matchLabels": { "default.colocation.example.com/data": "true" }
+                        },
+                        "topologyKey": "topology.kubernetes.io/zone",
+                    }]
+                }
+            })
+        );
+        // Recognized as the labels of the claims
+        assert!(
+            controller
+                .missing_claim_labels(&patched_pod, true)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_affinity_mode() {
+        let controller = Controller::builder()
+            .add_pvc("default", "data")
+            .add_pvc_with_mode("default", "logs", ClaimMode::Affinity)
+            .affinity_mode(ClaimMode::Preferred(Some(30)))
+            .options(Options {
+                stamp_annotation: false,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let (patched_pod, _) =
+            mutate_pod_with(&controller, &pod_with_claims(&["data", "logs"])).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"],
+            json!({
+                "podAffinity": {
+                    "requiredDuringSchedulingIgnoredDuringExecution": [{
+                        "labelSelector": {
+                            "matchLabels": { "default.gravivol.fonona.net/logs": "true" }
+                        },
+                        "topologyKey": "kubernetes.io/hostname",
+                    }],
+                    "preferredDuringSchedulingIgnoredDuringExecution": [{
+                        "podAffinityTerm": {
+                            "labelSelector": {
+                                "matchLabels": { "default.gravivol.fonona.net/data": "true" }
+                            },
+                            "topologyKey": "kubernetes.io/hostname",
+                        },
+                        "weight": 30,
+                    }],
+                }
+            })
+        );
+
+        // All claims without added ones
+        let controller = Controller::builder()
+            .affinity_mode(ClaimMode::Spread(SpreadOverrides::default()))
+            .build()
+            .unwrap();
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod_with_claims(&["data"])).await;
+        assert!(patched_pod["spec"]["affinity"].is_null());
+        assert_eq!(
+            patched_pod["spec"]["topologySpreadConstraints"][0]["labelSelector"],
+            json!({ "matchLabels": { "default.gravivol.fonona.net/data": "true" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_volume_scan_limit() {
+        let claims: Vec<String> = (0..200).map(|i| format!("data-{i:03}")).collect();
+        let claims: Vec<&str> = claims.iter().map(String::as_str).collect();
+        let pod = pod_with_claims(&claims);
+        let controller = Controller::new("").with_options(Options {
+            volume_scan_limit: 50,
+            ..Default::default()
+        });
+        let (patched_pod, response) = mutate_pod_with(&controller, &pod).await;
+
+        assert!(response.allowed);
+        assert_eq!(
+            response.warnings,
+            ["gravivol: pod has 200 volumes, only the first 50 were examined for claims"]
+        );
+        let labels = patched_pod["metadata"]["labels"].as_object().unwrap();
+        assert_eq!(labels.len(), 50);
+        assert!(labels.contains_key("default.gravivol.fonona.net/data-049"));
+        assert!(!labels.contains_key("default.gravivol.fonona.net/data-050"));
+
+        let controller = Controller::new("").with_options(Options {
+            volume_scan_limit: 0,
+            ..Default::default()
+        });
+        let (patched_pod, response) = mutate_pod_with(&controller, &pod).await;
+        assert!(response.warnings.is_empty());
+        assert_eq!(
+            patched_pod["metadata"]["labels"].as_object().unwrap().len(),
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_patch_bytes() {
+        let logs = crate::logging::CapturedLogs::default();
+        let _subscriber = tracing::dispatcher::set_default(&crate::logging::dispatch(
+            crate::logging::LogFormat::Json,
+            tracing_subscriber::EnvFilter::new("info"),
+            logs.clone(),
+            false,
+            None,
+        ));
+        let claims: Vec<String> = (0..100).map(|i| format!("data-{i:03}")).collect();
+        let claims: Vec<&str> = claims.iter().map(String::as_str).collect();
+        let mut pod = pod_with_claims(&claims);
+        pod["metadata"]["name"] = json!("web");
+        let controller = Controller::new("").with_options(Options {
+            max_patch_bytes: 4096,
+            ..Default::default()
+        });
+        let (patched_pod, response) = mutate_pod_with(&controller, &pod).await;
+
+        assert!(response.allowed);
+        assert!(response.patch.is_none());
+        assert_eq!(patched_pod, pod);
+        assert_eq!(response.warnings.len(), 1);
+        assert!(
+            response.warnings[0].starts_with("gravivol: patch of ")
+                && response.warnings[0]
+                    .ends_with(" bytes is larger than 4096 bytes, the object was not mutated"),
+            "{:?}",
+            response.warnings
+        );
+        let lines: Vec<Value> = logs
+            .lines()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let error = lines.iter().find(|line| line["level"] == "ERROR").unwrap();
+        assert!(
+            error["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Patch of Pod default/web has 3 operations in "),
+            "{error}"
+        );
+        assert_eq!(lines.last().unwrap()["skip_reason"], "patch_too_large");
+        assert!(
+            controller
+                .metrics()
+                .encode()
+                .contains("gravivol_pods_skipped_total{reason=\"patch_too_large\"} 1\n")
+        );
+
+        // Below the default limit
+        let (_, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert!(response.patch.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_pod_no_volumes() {
+        let data = json!(
+        {
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": {
+                    "kind": "Pod",
+                    "apiVersion": "v1",
+                    "metadata": {
+                        "generateName": "bla-6b47d48686-",
+                        "namespace": "default",
+                        "creationTimestamp": null,
+                        "labels": {
+                            "app.kubernetes.io/instance": "bla",
+                            "app.kubernetes.io/managed-by": "Helm",
+                            "app.kubernetes.io/name": "bla",
+                            "app.kubernetes.io/version": "1.16.0"
+                        }
+                    },
+                    "spec": {}
+                }
+            }
+        });
+        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
+        let controller = Controller::new("");
+        let response = controller.mutate(review).await.unwrap();
+
+        assert_eq!(response.api_version, "admission.k8s.io/v1");
+        assert_eq!(response.kind, "AdmissionReview");
+        match response.response {
+            Some(res) => {
+                assert_eq!(res.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
+                assert!(res.allowed);
+                assert_eq!(res.patch, None);
+                assert_eq!(res.patch_type, None);
+            }
+            None => panic!("Expected Some(response)"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pod_with_matching_and_non_matching_pvc() {
+        let config = "default/myvol1,foo/myvol2";
+
+        let pod = json!({
+            "kind": "Pod",
+            "apiVersion": "v1",
+            "metadata": {
+                "generateName": "bla-6b47d48686-",
+                "namespace": "default",
+                "creationTimestamp": null,
+                "labels": {
+                    "app.kubernetes.io/instance": "bla",
+                    "app.kubernetes.io/managed-by": "Helm",
+                    "app.kubernetes.io/name": "bla",
+                    "app.kubernetes.io/version": "1.16.0"
+                }
+            },
+            "spec": {
+                "volumes": [
+                    {
+                        "name": "somename",
+                        "persistentVolumeClaim": {
+                            "claimName": "myvol1"
+                        }
+                    },
+                    {
+                        "name": "othername",
+                        "persistentVolumeClaim": {
+                            "claimName": "myvol2"
+                        }
+                    }
+                ]
+            }
+        });
+
+        let expected_patched_pod = json!({
+            "kind": "Pod",
+            "apiVersion": "v1",
+            "metadata": {
+                "generateName": "bla-6b47d48686-",
+                "namespace": "default",
+                "creationTimestamp": null,
+                "annotations": {
+                    "gravivol.fonona.net/mutated": "myvol1",
+                    "gravivol.fonona.net/version": env!("CARGO_PKG_VERSION"),
+                },
+                "labels": {
+                    "app.kubernetes.io/instance": "bla",
+                    "app.kubernetes.io/managed-by": "Helm",
+                    "app.kubernetes.io/name": "bla",
+                    "app.kubernetes.io/version": "1.16.0",
+                     "default.gravivol.fonona.net/myvol1": "true",
+                }
+            },
+            "spec": {
+                "volumes": [
+                    {
+                        "name": "somename",
+                        "persistentVolumeClaim": {
+                            "claimName": "myvol1"
+                        }
+                    },
+                    {
+                        "name": "othername",
+                        "persistentVolumeClaim": {
+                            "claimName": "myvol2"
+                        }
+                    }
+                ],
+                "affinity": {
+                    "podAffinity": {
+                        "requiredDuringSchedulingIgnoredDuringExecution": [
+                        {
+                            "labelSelector": {
+                                "matchLabels": {
+                                    "default.gravivol.fonona.net/myvol1": "true",
+                                }
+                            },
+                            "topologyKey": "kubernetes.io/hostname",
+                        }]
+                    }
+                }
+            }
+        });
+
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": pod.clone(),
+            }
+        });
+        for controller in controllers(config) {
+            let review: AdmissionReview =
+                serde_json::from_value(data.clone()).expect("Failed to parse JSON");
+            let response = controller.mutate(review).await.unwrap();
+            let mut pod = pod.clone();
+
+            assert_eq!(response.api_version, "admission.k8s.io/v1");
+            assert_eq!(response.kind, "AdmissionReview");
+            match response.response {
+                Some(res) => {
+                    assert_eq!(res.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
+                    assert_eq!(res.patch_type, Some("JSONPatch".to_owned()));
+                    let patch_string = String::from_utf8(
+                        BASE64_STANDARD
+                            .decode(res.patch.expect("No patch in response"))
+                            .expect("Cannot decode base64"),
+                    )
+                    .expect("Invalid UTF-8");
+                    let patch_json: Patch =
+                        serde_json::from_str(&patch_string).expect("Cannot parse JSON patch");
+                    patch(&mut pod, &patch_json).expect("Patch failed");
+                    assert_eq!(pod, expected_patched_pod);
+                }
+                None => panic!("Expected Some(response)"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_config() {
+        let config = "";
+
+        let pod = json!({
+            "kind": "Pod",
+            "apiVersion": "v1",
+            "metadata": {
+                "generateName": "bla-6b47d48686-",
+                "namespace": "default",
+                "creationTimestamp": null,
+                "labels": {
+                    "app.kubernetes.io/instance": "bla",
+                    "app.kubernetes.io/managed-by": "Helm",
+                    "app.kubernetes.io/name": "bla",
+                    "app.kubernetes.io/version": "1.16.0"
+                }
+            },
+            "spec": {
+                "volumes": [
+                    {
+                        "name": "somename",
+                        "persistentVolumeClaim": {
+                            "claimName": "myvol1"
+                        }
+                    }
+                ]
+            }
+        });
+
+        let expected_patched_pod = json!({
+            "kind": "Pod",
+            "apiVersion": "v1",
+            "metadata": {
+                "generateName": "bla-6b47d48686-",
+                "namespace": "default",
+                "creationTimestamp": null,
+                "annotations": {
+                    "gravivol.fonona.net/mutated": "myvol1",
+                    "gravivol.fonona.net/version": env!("CARGO_PKG_VERSION"),
+                },
+                "labels": {
+                    "app.kubernetes.io/instance": "bla",
+                    "app.kubernetes.io/managed-by": "Helm",
+                    "app.kubernetes.io/name": "bla",
+                    "app.kubernetes.io/version": "1.16.0",
+                    "default.gravivol.fonona.net/myvol1": "true",
+                }
+            },
+            "spec": {
+                "volumes": [
+                    {
+                        "name": "somename",
+                        "persistentVolumeClaim": {
+                            "claimName": "myvol1"
+                        }
+                    }
+                ],
+                "affinity": {
+                    "podAffinity": {
+                        "requiredDuringSchedulingIgnoredDuringExecution": [
+                        {
+                            "labelSelector": {
+                                "matchLabels": {
+                                    "default.gravivol.fonona.net/myvol1": "true",
+                                }
+                            },
+                            "topologyKey": "kubernetes.io/hostname",
+                        }]
+                    }
+                }
+            }
+        });
+
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": pod.clone(),
+            }
+        });
+        for controller in controllers(config) {
+            let review: AdmissionReview =
+                serde_json::from_value(data.clone()).expect("Failed to parse JSON");
+            let response = controller.mutate(review).await.unwrap();
+            let mut pod = pod.clone();
+
+            assert_eq!(response.api_version, "admission.k8s.io/v1");
+            assert_eq!(response.kind, "AdmissionReview");
+            match response.response {
+                Some(res) => {
+                    assert_eq!(res.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
+                    assert_eq!(res.patch_type, Some("JSONPatch".to_owned()));
+                    let patch_string = String::from_utf8(
+                        BASE64_STANDARD
+                            .decode(res.patch.expect("No patch in response"))
+                            .expect("Cannot decode base64"),
+                    )
+                    .expect("Invalid UTF-8");
+                    let patch_json: Patch =
+                        serde_json::from_str(&patch_string).expect("Cannot parse JSON patch");
+                    patch(&mut pod, &patch_json).expect("Patch failed");
+                    assert_eq!(pod, expected_patched_pod);
+                }
+                None => panic!("Expected Some(response)"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_create_patch_pure_pod() {
+        let pod_before = json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "namespace": "foo"
+            },
+            "spec": {
+                "containers": [
+                    {
+                        "name": "my-container",
+                        "image": "nginx"
+                    }
+                ]
+            }
+        });
+
+        let pvcs = vec!["myvol1".to_owned(), "myvol2".to_owned()];
+
+        let mut pod_after = pod_before.to_owned();
+
+        pod_after["metadata"]["labels"] = json!({
+            "foo.gravivol.fonona.net/myvol1": "true",
+            "foo.gravivol.fonona.net/myvol2": "true",
+        });
+
+        pod_after["spec"]["affinity"] = json!({
+            "podAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [
+                {
+                    "labelSelector": {
+                        "matchLabels": {
+                            "foo.gravivol.fonona.net/myvol1": "true",
+                            "foo.gravivol.fonona.net/myvol2": "true",
+                        }
+                    },
+                    "topologyKey": "kubernetes.io/hostname",
+                }]
+            }
+        });
+
+        let no_stamp = Options {
+            stamp_annotation: false,
+            ..Default::default()
+        };
+        let pod: PodTemplate = serde_json::from_value(pod_before.to_owned()).unwrap();
+        let created_patch = create_patch(
+            &pod,
+            "",
+            &Mutation::new("foo", &pvcs, PatchMode::Both),
+            &no_stamp,
+        )
+        .unwrap();
+
+        let mut pod_patched = pod_before.to_owned();
+        patch(&mut pod_patched, &created_patch).unwrap();
+
+        assert_eq!(pod_patched, pod_after);
+    }
+
+    #[test]
+    fn test_create_patch_existing_labels_and_affinity() {
+        let pod_before = json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "namespace": "my-namespace",
+                "labels": {
+                    "mylabel1": "myvalue1"
+                }
+            },
+            "spec": {
+                "containers": [
+                    {
+                        "name": "my-container",
+                        "image": "nginx"
+                    }
+                ],
+                "affinity": {
+                    "podAffinity": {
+                        "requiredDuringSchedulingIgnoredDuringExecution": [
+                        {
+                            "labelSelector": {
+                                "matchLabels": {
+                                    "somelabel": "somevalue",
+                                }
+                            },
+                            "topologyKey": "somekey",
+                        }]
+                    }
+                }
+            }
+        });
+
+        let pvcs = vec!["myvol1".to_owned(), "myvol2".to_owned()];
+
+        let mut pod_after = pod_before.to_owned();
+
+        pod_after["metadata"]["labels"]["my-namespace.gravivol.fonona.net/myvol1"] =
+            Value::String("true".to_owned());
+        pod_after["metadata"]["labels"]["my-namespace.gravivol.fonona.net/myvol2"] =
+            Value::String("true".to_owned());
+
+        if let Value::Array(the_array) = &mut pod_after["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+        {
+            the_array.push(json!({
+                "labelSelector": {
+                    "matchLabels": {
+                        "my-namespace.gravivol.fonona.net/myvol1": "true",
+                        "my-namespace.gravivol.fonona.net/myvol2": "true",
+                    }
+                },
+                "topologyKey": "kubernetes.io/hostname",
+            }));
+        }
+
+        let no_stamp = Options {
+            stamp_annotation: false,
+            ..Default::default()
+        };
+        let pod: PodTemplate = serde_json::from_value(pod_before.to_owned()).unwrap();
+        let created_patch = create_patch(
+            &pod,
+            "",
+            &Mutation::new("my-namespace", &pvcs, PatchMode::Both),
+            &no_stamp,
+        )
+        .unwrap();
+
+        let mut pod_patched = pod_before.to_owned();
+        patch(&mut pod_patched, &created_patch).unwrap();
+
+        assert_eq!(pod_patched, pod_after);
+    }
+
+    /// Sends the pod through mutate and returns the patched pod, the same for both controllers
+    async fn mutate_pod(config: &str, pod: &Value) -> Value {
+        let [from_config, built] = controllers(config);
+        let patched_pod = mutate_pod_with(&from_config, pod).await.0;
+        assert_eq!(mutate_pod_with(&built, pod).await.0, patched_pod);
+        patched_pod
+    }
+
+    /// Sends the pod through mutate and returns the patched pod and the response
+    async fn mutate_pod_with(controller: &Controller, pod: &Value) -> (Value, Response) {
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": pod,
+            }
+        });
+        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
+        let response = controller
+            .mutate(review)
+            .await
+            .unwrap()
+            .response
+            .expect("Expected Some(response)");
+
+        let mut patched_pod = pod.to_owned();
+        if let Some(encoded_patch) = &response.patch {
+            let patch_json: Patch =
+                serde_json::from_slice(&BASE64_STANDARD.decode(encoded_patch).unwrap()).unwrap();
+            patch(&mut patched_pod, &patch_json).expect("Patch failed");
+        }
+        (patched_pod, response)
+    }
+
+    /// Cluster returning fixed objects
+    #[derive(Default)]
+    struct StubCluster {
+        claims: HashMap<String, Value>,
+        volumes: HashMap<String, Value>,
+        /// By name, the default class by the empty name
+        storage_classes: HashMap<String, Value>,
+        /// Label selectors that select existing pods
+        pod_selectors: HashSet<String>,
+        fail: bool,
+        /// Number of lookups whose results may be cached
+        cacheable_lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    impl StubCluster {
+        fn lookup(&self, dry_run: bool) -> Result<(), LookupError> {
+            if self.fail {
+                return Err("connection refused".into());
+            }
+            if !dry_run {
+                self.cacheable_lookups
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Cluster for StubCluster {
+        async fn get_claim(
+            &self,
+            namespace: &str,
+            name: &str,
+            dry_run: bool,
+        ) -> Result<Option<k8s_openapi::api::core::v1::PersistentVolumeClaim>, LookupError>
+        {
+            self.lookup(dry_run)?;
+            Ok(self
+                .claims
+                .get(&format!("{namespace}/{name}"))
+                .map(|claim| serde_json::from_value(claim.clone()).unwrap()))
+        }
+
+        async fn get_volume(
+            &self,
+            name: &str,
+            dry_run: bool,
+        ) -> Result<Option<k8s_openapi::api::core::v1::PersistentVolume>, LookupError> {
+            self.lookup(dry_run)?;
+            Ok(self
+                .volumes
+                .get(name)
+                .map(|volume| serde_json::from_value(volume.clone()).unwrap()))
+        }
+
+        async fn has_pods(
+            &self,
+            namespace: &str,
+            label_selector: &str,
+            dry_run: bool,
+        ) -> Result<bool, LookupError> {
+            self.lookup(dry_run)?;
+            Ok(self
+                .pod_selectors
+                .contains(&format!("{namespace}/{label_selector}")))
+        }
+
+        async fn get_storage_class(
+            &self,
+            name: Option<&str>,
+            dry_run: bool,
+        ) -> Result<Option<k8s_openapi::api::storage::v1::StorageClass>, LookupError> {
+            self.lookup(dry_run)?;
+            Ok(self
+                .storage_classes
+                .get(name.unwrap_or_default())
+                .map(|class| serde_json::from_value(class.clone()).unwrap()))
+        }
+    }
+
+    fn stub_cluster_with_bound_volume(node_terms: Value) -> StubCluster {
+        StubCluster {
+            claims: HashMap::from([(
+                "default/myvol1".to_owned(),
+                json!({
+                    "metadata": { "name": "myvol1", "namespace": "default" },
+                    "spec": { "volumeName": "pv-1" },
+                }),
+            )]),
+            volumes: HashMap::from([(
+                "pv-1".to_owned(),
+                json!({
+                    "metadata": { "name": "pv-1" },
+                    "spec": {
+                        "nodeAffinity": {
+                            "required": { "nodeSelectorTerms": node_terms }
+                        }
+                    },
+                }),
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roles() {
+        let expected_labels = json!({
+            "default.gravivol.fonona.net/myvol1": "true",
+        });
+        let expected_affinity = json!({
+            "podAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [
+                {
+                    "labelSelector": {
+                        "matchLabels": {
+                            "default.gravivol.fonona.net/myvol1": "true",
+                        }
+                    },
+                    "topologyKey": "kubernetes.io/hostname",
+                }]
+            }
+        });
+
+        for (role, with_labels, with_affinity) in [
+            (None, true, true),
+            (Some("anchor"), true, false),
+            (Some("follower"), false, true),
+        ] {
+            let mut pod = json!({
+                "kind": "Pod",
+                "apiVersion": "v1",
+                "metadata": {
+                    "namespace": "default",
+                },
+                "spec": {
+                    "volumes": [
+                        {
+                            "name": "somename",
+                            "persistentVolumeClaim": {
+                                "claimName": "myvol1"
+                            }
+                        }
+                    ]
+                }
+            });
+            if let Some(role) = role {
+                pod["metadata"]["annotations"] = json!({ "gravivol.fonona.net/role": role });
+            }
+
+            let patched_pod = mutate_pod("default/myvol1", &pod).await;
+
+            let labels = &patched_pod["metadata"]["labels"];
+            let affinity = &patched_pod["spec"]["affinity"];
+            if with_labels {
+                assert_eq!(labels, &expected_labels, "role {role:?}");
+            } else {
+                assert!(labels.is_null(), "role {role:?}");
+            }
+            if with_affinity {
+                assert_eq!(affinity, &expected_affinity, "role {role:?}");
+            } else {
+                assert!(affinity.is_null(), "role {role:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warnings_for_skipped_claims() {
+        let long_claim = "a".repeat(64);
+        let config = format!("default/myvol1,default/{long_claim}");
+
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": {
+                    "kind": "Pod",
+                    "apiVersion": "v1",
+                    "metadata": {
+                        "namespace": "default",
+                    },
+                    "spec": {
+                        "volumes": [
+                            {
+                                "name": "vol1",
+                                "persistentVolumeClaim": { "claimName": "myvol1" }
+                            },
+                            {
+                                "name": "vol2",
+                                "persistentVolumeClaim": { "claimName": "myvol2" }
+                            },
+                            {
+                                "name": "vol3",
+                                "persistentVolumeClaim": { "claimName": long_claim }
+                            }
+                        ]
+                    }
+                }
+            }
+        });
+        let mut unrelated = data.clone();
+        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
+        let response = Controller::new(&config)
+            .mutate(review)
+            .await
+            .unwrap()
+            .response
+            .expect("Expected Some(response)");
+
+        assert!(response.patch.is_some());
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: claim name longer than 63 characters cannot be used in a label, skipped: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa...".to_owned(),
+                "gravivol: claim myvol2 is not configured for co-location".to_owned(),
+            ]
+        );
+        assert!(response.warnings.iter().all(|w| w.chars().count() <= 120));
+
+        // No warnings for a pod with only claims gravivol is not concerned with
+        unrelated["request"]["object"]["spec"]["volumes"]
+            .as_array_mut()
+            .unwrap()
+            .retain(|volume| volume["name"] == "vol2");
+        let review: AdmissionReview = serde_json::from_value(unrelated).unwrap();
+        let response = Controller::new(&config)
+            .mutate(review)
+            .await
+            .unwrap()
+            .response
+            .unwrap();
+        assert!(response.patch.is_none());
+        assert!(response.warnings.is_empty(), "{:?}", response.warnings);
+
+        // Rejected up front when built
+        assert_eq!(
+            Controller::builder()
+                .add_pvc("default", "myvol1")
+                .add_pvc("default", &long_claim)
+                .build()
+                .err(),
+            Some(ConfigError::Claim {
+                namespace: "default".to_owned(),
+                claim: long_claim,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_ascii_claims() {
+        let long_claim = "数据".repeat(40);
+        let mut pod = pod_with_claims(&["data", "💾", "数据", "data\nlogs", &long_claim]);
+        pod["metadata"]["name"] = json!("web");
+        pod["metadata"]["annotations"] = json!({
+            "gravivol.fonona.net/claims": "data,💾,数据,data\nlogs,ログ",
+        });
+        let controller = Controller::new("");
+        let (patched_pod, response) = mutate_pod_with(&controller, &pod).await;
+
+        assert!(response.allowed);
+        serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(patched_pod.clone()).unwrap();
+        let labels = patched_pod["metadata"]["labels"].as_object().unwrap();
+        assert_eq!(
+            labels.keys().collect::<Vec<_>>(),
+            ["default.gravivol.fonona.net/data"]
+        );
+        assert!(labels.keys().all(|key| is_valid_label_key(key)));
+        assert_eq!(
+            response.warnings,
+            [
+                "gravivol: claim name cannot be used in a label, skipped: \\u{1f4be}",
+                "gravivol: claim name cannot be used in a label, skipped: \\u{6570}\\u{636e}",
+                "gravivol: claim name cannot be used in a label, skipped: data\\nlogs",
+                "gravivol: claim name longer than 63 characters cannot be used in a label, skipped: \\u{6570}\\u{636e}\\u{6570}\\u{636e}...",
+                "gravivol: claim \\u{30ed}\\u{30b0} named in annotation gravivol.fonona.net/claims is not mounted",
+            ]
+        );
+        assert!(
+            response
+                .warnings
+                .iter()
+                .all(|warning| warning.len() <= MAX_WARNING_LENGTH && warning.is_ascii())
+        );
+        let audit = &response.audit_annotations["matched-claims"];
+        assert_eq!(audit, "default/data");
+    }
+
+    #[test]
+    fn test_warning_truncation() {
+        let message = warning(&"数".repeat(100));
+        // Cut before the escape that does not fit
+        assert_eq!(message, format!("gravivol: {}...", "\\u{6570}".repeat(13)));
+        assert_eq!(warning("é"), "gravivol: \\u{e9}");
+    }
+
+    #[test]
+    fn test_status_serialization() {
+        let mut response = Response {
+            uid: "26973DA1-B488-4F59-B062-461C6BDCAD83".to_owned(),
+            allowed: true,
+            patch_type: None,
+            patch: None,
+            warnings: Vec::new(),
+            status: None,
+            audit_annotations: HashMap::new(),
+        };
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "allowed": true,
+            })
+        );
+
+        response.status = Some(Status::internal_error(
+            "gravivol could not create the patch for claims myvol1".to_owned(),
+        ));
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "allowed": true,
+                "status": {
+                    "code": 500,
+                    "message": "gravivol could not create the patch for claims myvol1",
+                    "reason": "InternalError",
+                }
+            })
+        );
+    }
 
+    #[tokio::test]
+    async fn test_audit_annotations() {
         let mut pod = json!({
             "kind": "Pod",
             "apiVersion": "v1",
             "metadata": {
-                "generateName": "bla-6b47d48686-",
                 "namespace": "default",
-                "creationTimestamp": null,
-                "labels": {
-                    "app.kubernetes.io/instance": "bla",
-                    "app.kubernetes.io/managed-by": "Helm",
-                    "app.kubernetes.io/name": "bla",
-                    "app.kubernetes.io/version": "1.16.0"
-                }
             },
             "spec": {
                 "volumes": [
                     {
-                        "name": "somename",
-                        "persistentVolumeClaim": {
-                            "claimName": "myvol1"
-                        }
+                        "name": "vol1",
+                        "persistentVolumeClaim": { "claimName": "myvol1" }
                     },
                     {
-                        "name": "othername",
-                        "persistentVolumeClaim": {
-                            "claimName": "myvol2"
-                        }
+                        "name": "vol2",
+                        "persistentVolumeClaim": { "claimName": "myvol2" }
                     }
                 ]
             }
         });
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": pod.clone(),
+            }
+        });
+        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
+        let response =
+            serde_json::to_value(Controller::new("").mutate(review).await.unwrap()).unwrap();
 
-        let expected_patched_pod = json!({
+        // labels, annotations and affinity are added as a whole
+        assert_eq!(
+            response["response"]["auditAnnotations"],
+            json!({
+                "matched-claims": "default/myvol1,default/myvol2",
+                "patch-ops": "3",
+            })
+        );
+
+        pod["spec"]["volumes"] = json!([]);
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                "object": pod,
+            }
+        });
+        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
+        let response =
+            serde_json::to_value(Controller::new("").mutate(review).await.unwrap()).unwrap();
+        assert!(response["response"].get("auditAnnotations").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stamp_annotation() {
+        let pod = json!({
             "kind": "Pod",
             "apiVersion": "v1",
             "metadata": {
-                "generateName": "bla-6b47d48686-",
                 "namespace": "default",
-                "creationTimestamp": null,
-                "labels": {
-                    "app.kubernetes.io/instance": "bla",
-                    "app.kubernetes.io/managed-by": "Helm",
-                    "app.kubernetes.io/name": "bla",
-                    "app.kubernetes.io/version": "1.16.0",
-                     "default.gravivol.fonona.net/myvol1": "true",
-                }
+                "annotations": {
+                    "someannotation": "somevalue",
+                },
             },
             "spec": {
                 "volumes": [
                     {
-                        "name": "somename",
-                        "persistentVolumeClaim": {
-                            "claimName": "myvol1"
-                        }
+                        "name": "vol1",
+                        "persistentVolumeClaim": { "claimName": "myvol1" }
                     },
                     {
-                        "name": "othername",
-                        "persistentVolumeClaim": {
-                            "claimName": "myvol2"
-                        }
+                        "name": "vol2",
+                        "persistentVolumeClaim": { "claimName": "myvol2" }
                     }
-                ],
-                "affinity": {
-                    "podAffinity": {
-                        "requiredDuringSchedulingIgnoredDuringExecution": [
-                        {
-                            "labelSelector": {
-                                "matchLabels": {
-                                    "default.gravivol.fonona.net/myvol1": "true",
-                                }
-                            },
-                            "topologyKey": "kubernetes.io/hostname",
-                        }]
+                ]
+            }
+        });
+
+        let patched_pod = mutate_pod("", &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["annotations"],
+            json!({
+                "someannotation": "somevalue",
+                "gravivol.fonona.net/mutated": "myvol1,myvol2",
+                "gravivol.fonona.net/version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+
+        // A second admission of the mutated pod does not change it again
+        assert_eq!(mutate_pod("", &patched_pod).await, patched_pod);
+    }
+
+    #[tokio::test]
+    async fn test_deployment() {
+        let deployment = json!({
+            "kind": "Deployment",
+            "apiVersion": "apps/v1",
+            "metadata": {
+                "name": "bla",
+                "namespace": "default",
+            },
+            "spec": {
+                "replicas": 2,
+                "template": {
+                    "metadata": {
+                        "labels": {
+                            "app": "bla",
+                        }
+                    },
+                    "spec": {
+                        "volumes": [
+                            {
+                                "name": "vol1",
+                                "persistentVolumeClaim": { "claimName": "myvol1" }
+                            }
+                        ]
                     }
                 }
             }
         });
-
         let data = json!({
             "apiVersion": "admission.k8s.io/v1",
             "kind": "AdmissionReview",
             "request": {
                 "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
-                "object": pod.clone(),
+                "object": deployment.clone(),
             }
         });
-        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
-        let controller = Controller::new(config);
-        let response = controller.mutate(review).unwrap();
 
-        assert_eq!(response.api_version, "admission.k8s.io/v1");
-        assert_eq!(response.kind, "AdmissionReview");
-        match response.response {
-            Some(res) => {
-                assert_eq!(res.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
-                assert_eq!(res.patch_type, Some("JSONPatch".to_owned()));
-                let patch_string = String::from_utf8(
-                    BASE64_STANDARD
-                        .decode(res.patch.expect("No patch in response"))
-                        .expect("Cannot decode base64"),
+        for controller in controllers("default/myvol1") {
+            // Only pods are handled by default
+            let review: AdmissionReview =
+                serde_json::from_value(data.clone()).expect("Failed to parse JSON");
+            let response = controller.mutate(review).await.unwrap().response.unwrap();
+            assert_eq!(response.patch, None);
+
+            let controller = controller.with_options(Options {
+                stamp_annotation: false,
+                kinds: HashSet::from([Kind::Pod, Kind::Deployment]),
+                ..Default::default()
+            });
+            let review: AdmissionReview =
+                serde_json::from_value(data.clone()).expect("Failed to parse JSON");
+            let response = controller.mutate(review).await.unwrap().response.unwrap();
+            let patch_json: Patch =
+                serde_json::from_slice(&BASE64_STANDARD.decode(response.patch.unwrap()).unwrap())
+                    .unwrap();
+            assert!(
+                patch_json
+                    .iter()
+                    .all(|op| op.path().to_string().starts_with("/spec/template/"))
+            );
+
+            let mut patched = deployment.clone();
+            patch(&mut patched, &patch_json).expect("Patch failed");
+            let mut expected = deployment.clone();
+            expected["spec"]["template"]["metadata"]["labels"]["default.gravivol.fonona.net/myvol1"] =
+                json!("true");
+            expected["spec"]["template"]["spec"]["affinity"] = json!({
+                "podAffinity": {
+                    "requiredDuringSchedulingIgnoredDuringExecution": [
+                    {
+                        "labelSelector": {
+                            "matchLabels": {
+                                "default.gravivol.fonona.net/myvol1": "true",
+                            }
+                        },
+                        "topologyKey": "kubernetes.io/hostname",
+                    }]
+                }
+            });
+            assert_eq!(patched, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_and_cronjob() {
+        let template = json!({
+            "spec": {
+                "restartPolicy": "Never",
+                "volumes": [
+                    {
+                        "name": "cache",
+                        "persistentVolumeClaim": { "claimName": "cache" }
+                    }
+                ]
+            }
+        });
+        let job = json!({
+            "kind": "Job",
+            "apiVersion": "batch/v1",
+            "metadata": {
+                "name": "bla",
+                "namespace": "default",
+            },
+            "spec": {
+                "template": template.clone(),
+            }
+        });
+        let cronjob = json!({
+            "kind": "CronJob",
+            "apiVersion": "batch/v1",
+            "metadata": {
+                "name": "bla",
+                "namespace": "default",
+            },
+            "spec": {
+                "schedule": "*/5 * * * *",
+                "jobTemplate": {
+                    "spec": {
+                        "template": template,
+                    }
+                }
+            }
+        });
+        let expected_affinity = json!({
+            "podAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [
+                {
+                    "labelSelector": {
+                        "matchLabels": {
+                            "default.gravivol.fonona.net/cache": "true",
+                        }
+                    },
+                    "topologyKey": "kubernetes.io/hostname",
+                }]
+            }
+        });
+        let controller = Controller::new("").with_options(Options {
+            stamp_annotation: false,
+            kinds: HashSet::from([Kind::Job, Kind::CronJob]),
+            ..Default::default()
+        });
+        let mutate_object = async |object: &Value| {
+            let data = json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                    "object": object,
+                }
+            });
+            let review: AdmissionReview = serde_json::from_value(data).unwrap();
+            let response = controller.mutate(review).await.unwrap().response.unwrap();
+            let mut patched = object.to_owned();
+            if let Some(encoded_patch) = response.patch {
+                let patch_json: Patch =
+                    serde_json::from_slice(&BASE64_STANDARD.decode(encoded_patch).unwrap())
+                        .unwrap();
+                patch(&mut patched, &patch_json).expect("Patch failed");
+            }
+            patched
+        };
+
+        let patched_job = mutate_object(&job).await;
+        let template = &patched_job["spec"]["template"];
+        assert_eq!(
+            template["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/cache": "true" })
+        );
+        assert_eq!(template["spec"]["affinity"], expected_affinity);
+
+        let patched_cronjob = mutate_object(&cronjob).await;
+        let template = &patched_cronjob["spec"]["jobTemplate"]["spec"]["template"];
+        assert_eq!(
+            template["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/cache": "true" })
+        );
+        assert_eq!(template["spec"]["affinity"], expected_affinity);
+
+        // Without volumes in the template the CronJob is not touched
+        let mut cronjob_without_volumes = cronjob.clone();
+        cronjob_without_volumes["spec"]["jobTemplate"]["spec"]["template"]["spec"]
+            .as_object_mut()
+            .unwrap()
+            .remove("volumes");
+        assert_eq!(
+            mutate_object(&cronjob_without_volumes).await,
+            cronjob_without_volumes
+        );
+    }
+
+    #[tokio::test]
+    async fn test_label_pvc() {
+        let claim = json!({
+            "kind": "PersistentVolumeClaim",
+            "apiVersion": "v1",
+            "metadata": {
+                "name": "myvol1",
+                "namespace": "default",
+            },
+            "spec": {
+                "accessModes": ["ReadWriteOnce"],
+                "resources": { "requests": { "storage": "1Gi" } }
+            }
+        });
+        // The response of the built controller, the same as of the one from the config
+        let mutate_claim = async |config: &str, label_pvcs: bool, claim: &Value| {
+            let mut responses = Vec::new();
+            for controller in controllers(config) {
+                let data = json!({
+                    "apiVersion": "admission.k8s.io/v1",
+                    "kind": "AdmissionReview",
+                    "request": {
+                        "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                        "object": claim,
+                    }
+                });
+                let review: AdmissionReview = serde_json::from_value(data).unwrap();
+                let controller = controller.with_options(Options {
+                    label_pvcs,
+                    ..Default::default()
+                });
+                responses.push(controller.mutate(review).await.unwrap().response.unwrap());
+            }
+            let built = responses.pop().unwrap();
+            assert_eq!(responses[0].patch, built.patch);
+            built
+        };
+
+        let response = mutate_claim("default/myvol1", true, &claim).await;
+        let patch_json: Patch =
+            serde_json::from_slice(&BASE64_STANDARD.decode(response.patch.unwrap()).unwrap())
+                .unwrap();
+        let mut patched = claim.clone();
+        patch(&mut patched, &patch_json).expect("Patch failed");
+        assert_eq!(
+            patched["metadata"]["labels"],
+            json!({ "gravivol.fonona.net/managed": "true" })
+        );
+
+        assert_eq!(
+            mutate_claim("default/myvol2", true, &claim).await.patch,
+            None
+        );
+        assert_eq!(
+            mutate_claim("default/myvol1", false, &claim).await.patch,
+            None
+        );
+    }
+
+    fn patch_ops(mode: PatchMode) -> Vec<(String, String)> {
+        let pod: PodTemplate = serde_json::from_value(json!({
+            "metadata": {
+                "namespace": "default",
+            },
+            "spec": {}
+        }))
+        .unwrap();
+        let options = Options {
+            stamp_annotation: false,
+            ..Default::default()
+        };
+        let created_patch = create_patch(
+            &pod,
+            "",
+            &Mutation::new("default", &["myvol1".to_owned()], mode),
+            &options,
+        )
+        .unwrap();
+        serde_json::from_value::<Vec<Value>>(serde_json::to_value(created_patch).unwrap())
+            .unwrap()
+            .iter()
+            .map(|op| {
+                (
+                    op["op"].as_str().unwrap().to_owned(),
+                    op["path"].as_str().unwrap().to_owned(),
                 )
-                .expect("Invalid UTF-8");
-                let patch_json: Patch =
-                    serde_json::from_str(&patch_string).expect("Cannot parse JSON patch");
-                patch(&mut pod, &patch_json).expect("Patch failed");
-                assert_eq!(pod, expected_patched_pod);
-            }
-            None => panic!("Expected Some(response)"),
-        }
+            })
+            .collect()
     }
 
     #[test]
-    fn test_empty_config() {
-        let config = "";
+    fn test_patch_mode_labels() {
+        assert_eq!(
+            patch_ops(PatchMode::Labels),
+            vec![("add".to_owned(), "/metadata/labels".to_owned())]
+        );
+    }
 
-        let mut pod = json!({
+    #[test]
+    fn test_patch_mode_affinity() {
+        assert_eq!(
+            patch_ops(PatchMode::Affinity),
+            vec![("add".to_owned(), "/spec/affinity".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_patch_mode_both() {
+        assert_eq!(
+            patch_ops(PatchMode::Both),
+            vec![
+                ("add".to_owned(), "/metadata/labels".to_owned()),
+                ("add".to_owned(), "/spec/affinity".to_owned())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patch_mode_annotation() {
+        let pod = json!({
             "kind": "Pod",
             "apiVersion": "v1",
             "metadata": {
-                "generateName": "bla-6b47d48686-",
                 "namespace": "default",
-                "creationTimestamp": null,
-                "labels": {
-                    "app.kubernetes.io/instance": "bla",
-                    "app.kubernetes.io/managed-by": "Helm",
-                    "app.kubernetes.io/name": "bla",
-                    "app.kubernetes.io/version": "1.16.0"
-                }
+                "annotations": {
+                    "gravivol.fonona.net/patch": "labels",
+                },
             },
             "spec": {
                 "volumes": [
                     {
-                        "name": "somename",
-                        "persistentVolumeClaim": {
-                            "claimName": "myvol1"
-                        }
+                        "name": "vol1",
+                        "persistentVolumeClaim": { "claimName": "myvol1" }
                     }
                 ]
             }
         });
 
-        let expected_patched_pod = json!({
+        let patched_pod = mutate_pod("", &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+        assert!(patched_pod["spec"]["affinity"].is_null());
+    }
+
+    fn pod_with_claims(claims: &[&str]) -> Value {
+        json!({
             "kind": "Pod",
             "apiVersion": "v1",
             "metadata": {
-                "generateName": "bla-6b47d48686-",
                 "namespace": "default",
-                "creationTimestamp": null,
-                "labels": {
-                    "app.kubernetes.io/instance": "bla",
-                    "app.kubernetes.io/managed-by": "Helm",
-                    "app.kubernetes.io/name": "bla",
-                    "app.kubernetes.io/version": "1.16.0",
+            },
+            "spec": {
+                "volumes": claims
+                    .iter()
+                    .map(|claim| json!({
+                        "name": claim,
+                        "persistentVolumeClaim": { "claimName": claim }
+                    }))
+                    .collect::<Vec<Value>>()
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_node_affinity_single_hostname() {
+        let cluster = stub_cluster_with_bound_volume(json!([{
+            "matchExpressions": [{
+                "key": "kubernetes.io/hostname",
+                "operator": "In",
+                "values": ["node-1"],
+            }]
+        }]));
+        let controller = Controller::new("")
+            .with_options(Options {
+                stamp_annotation: false,
+                node_affinity: NodeAffinityMode::Replace,
+                ..Default::default()
+            })
+            .with_cluster(Arc::new(cluster));
+
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod_with_claims(&["myvol1"])).await;
+        assert_eq!(
+            patched_pod["spec"]["nodeSelector"],
+            json!({ "kubernetes.io/hostname": "node-1" })
+        );
+        assert!(patched_pod["spec"]["affinity"].is_null());
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_node_affinity_terms_in_addition() {
+        let terms = json!([
+            {
+                "matchExpressions": [{
+                    "key": "topology.kubernetes.io/zone",
+                    "operator": "In",
+                    "values": ["zone-a", "zone-b"],
+                }]
+            }
+        ]);
+        let cluster = stub_cluster_with_bound_volume(terms.clone());
+        let controller = Controller::new("")
+            .with_options(Options {
+                stamp_annotation: false,
+                node_affinity: NodeAffinityMode::Add,
+                ..Default::default()
+            })
+            .with_cluster(Arc::new(cluster));
+
+        // myvol2 is not bound and placed by pod affinity only
+        let (patched_pod, _) =
+            mutate_pod_with(&controller, &pod_with_claims(&["myvol1", "myvol2"])).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["nodeAffinity"],
+            json!({
+                "requiredDuringSchedulingIgnoredDuringExecution": {
+                    "nodeSelectorTerms": terms
+                }
+            })
+        );
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                [0]["labelSelector"]["matchLabels"],
+            json!({
+                "default.gravivol.fonona.net/myvol1": "true",
+                "default.gravivol.fonona.net/myvol2": "true",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_node_affinity_lookup_failure() {
+        let controller = Controller::new("")
+            .with_options(Options {
+                stamp_annotation: false,
+                node_affinity: NodeAffinityMode::Replace,
+                ..Default::default()
+            })
+            .with_cluster(Arc::new(StubCluster {
+                fail: true,
+                ..Default::default()
+            }));
+
+        let (patched_pod, response) =
+            mutate_pod_with(&controller, &pod_with_claims(&["myvol1"])).await;
+        assert!(patched_pod["spec"]["nodeSelector"].is_null());
+        assert!(!patched_pod["spec"]["affinity"]["podAffinity"].is_null());
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: cannot look up the volume of claim myvol1, using pod affinity only"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_modes() {
+        let claim = |name: &str, access_modes: &[&str]| {
+            (
+                format!("default/{name}"),
+                json!({
+                    "metadata": { "name": name, "namespace": "default" },
+                    "spec": { "accessModes": access_modes },
+                }),
+            )
+        };
+        let controller = |fail| {
+            Controller::new("")
+                .with_options(Options {
+                    stamp_annotation: false,
+                    access_modes: HashSet::from([
+                        AccessMode::ReadWriteOnce,
+                        AccessMode::ReadWriteOncePod,
+                    ]),
+                    ..Default::default()
+                })
+                .with_cluster(Arc::new(StubCluster {
+                    claims: HashMap::from([
+                        claim("rwo", &["ReadWriteOnce"]),
+                        claim("rwop", &["ReadWriteOncePod"]),
+                        claim("nfs", &["ReadWriteMany", "ReadOnlyMany"]),
+                    ]),
+                    fail,
+                    ..Default::default()
+                }))
+        };
+
+        // Claims that do not exist yet are handled as configured
+        let (patched_pod, response) = mutate_pod_with(
+            &controller(false),
+            &pod_with_claims(&["rwo", "nfs", "rwop", "pending"]),
+        )
+        .await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({
+                "default.gravivol.fonona.net/rwo": "true",
+                "default.gravivol.fonona.net/rwop": "true",
+                "default.gravivol.fonona.net/pending": "true",
+            })
+        );
+        assert_eq!(
+            response.warnings,
+            vec!["gravivol: claim nfs has access modes ReadWriteMany,ReadOnlyMany, not co-located"]
+        );
+
+        let nfs_only = controller(false);
+        let (patched_pod, response) = mutate_pod_with(&nfs_only, &pod_with_claims(&["nfs"])).await;
+        assert!(response.patch.is_none());
+        assert!(patched_pod["spec"]["affinity"].is_null());
+        assert!(
+            nfs_only
+                .metrics()
+                .encode()
+                .contains("gravivol_pods_skipped_total{reason=\"claims_filtered\"} 1\n")
+        );
+
+        // A failed lookup falls back to the configured claims
+        let failing = controller(true);
+        let (patched_pod, response) = mutate_pod_with(&failing, &pod_with_claims(&["nfs"])).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/nfs": "true" })
+        );
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: cannot look up the access modes of claim nfs, handling it as configured"
+            ]
+        );
+        assert!(
+            failing
+                .metrics()
+                .encode()
+                .contains("gravivol_lookup_failures_total{check=\"access_modes\"} 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_terminating_claims() {
+        let controller = Controller::new("")
+            .with_options(Options {
+                stamp_annotation: false,
+                ..Default::default()
+            })
+            .with_cluster(Arc::new(StubCluster {
+                claims: HashMap::from([(
+                    "default/old".to_owned(),
+                    json!({
+                        "metadata": {
+                            "name": "old",
+                            "namespace": "default",
+                            "deletionTimestamp": "2026-10-16T12:00:00Z",
+                            "finalizers": ["kubernetes.io/pvc-protection"],
+                        },
+                    }),
+                )]),
+                ..Default::default()
+            }));
+
+        let (patched_pod, response) =
+            mutate_pod_with(&controller, &pod_with_claims(&["old", "new"])).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/new": "true" })
+        );
+        assert_eq!(
+            response.warnings,
+            vec!["gravivol: claim old is being deleted, not co-located"]
+        );
+        assert!(
+            controller
+                .metrics()
+                .encode()
+                .contains("gravivol_terminating_claims_total 1\n")
+        );
+
+        // No patch if it was the only claim
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod_with_claims(&["old"])).await;
+        assert!(patched_pod["metadata"]["labels"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_skip_reasons() {
+        for controller in controllers("default/data") {
+            let skipped = |reason: &str| {
+                let line = format!("gravivol_pods_skipped_total{{reason=\"{reason}\"}} ");
+                controller
+                    .metrics()
+                    .encode()
+                    .lines()
+                    .find_map(|sample| sample.strip_prefix(line.as_str())?.parse::<u64>().ok())
+                    .unwrap_or_default()
+            };
+
+            mutate_pod_with(&controller, &pod_with_claims(&[])).await;
+            assert_eq!(skipped("no_volumes"), 1);
+
+            mutate_pod_with(&controller, &pod_with_claims(&["logs"])).await;
+            assert_eq!(skipped("no_claims"), 1);
+
+            let mut opted_out = pod_with_claims(&["data", "logs"]);
+            opted_out["metadata"]["annotations"] = json!({ CLAIMS_ANNOTATION: "logs" });
+            mutate_pod_with(&controller, &opted_out).await;
+            assert_eq!(skipped("opted_out"), 1);
+
+            let mut mirror = pod_with_claims(&["data"]);
+            mirror["metadata"]["annotations"] = json!({ MIRROR_ANNOTATION: "3c2d9a1b7e5f" });
+            mutate_pod_with(&controller, &mirror).await;
+            assert_eq!(skipped("mirror_pod"), 1);
+
+            let (patched_pod, response) =
+                mutate_pod_with(&controller, &pod_with_claims(&["data"])).await;
+            assert!(response.patch.is_some());
+            mutate_pod_with(&controller, &patched_pod).await;
+            assert_eq!(skipped("already_mutated"), 1);
+
+            let text = controller.metrics().encode();
+            assert!(text.contains("gravivol_mutations_total{namespace=\"default\"} 1\n"));
+            // Only the patched and the already mutated pod, the others were skipped before
+            assert!(text.contains("gravivol_matched_claims_total 2\n"));
+        }
+    }
+
+    /// A pod of a Deployment as sent by the API server of a 1.31 cluster
+    const CREATE_REVIEW: &str = r#"{
+        "kind": "AdmissionReview",
+        "apiVersion": "admission.k8s.io/v1",
+        "request": {
+            "uid": "b0d5ab4e-5d0c-4a4f-9c3e-7d3e0f2a61c9",
+            "kind": {"group": "", "version": "v1", "kind": "Pod"},
+            "resource": {"group": "", "version": "v1", "resource": "pods"},
+            "requestKind": {"group": "", "version": "v1", "kind": "Pod"},
+            "requestResource": {"group": "", "version": "v1", "resource": "pods"},
+            "namespace": "default",
+            "operation": "CREATE",
+            "userInfo": {
+                "username": "system:serviceaccount:kube-system:replicaset-controller",
+                "uid": "3f8e2a41-6c1d-4b7e-a0f5-92d4c8e1b7a3",
+                "groups": ["system:serviceaccounts", "system:serviceaccounts:kube-system", "system:authenticated"]
+            },
+            "object": {
+                "kind": "Pod",
+                "apiVersion": "v1",
+                "metadata": {
+                    "generateName": "web-7c5b9d8f4-",
+                    "creationTimestamp": null,
+                    "labels": {"app": "web", "pod-template-hash": "7c5b9d8f4"},
+                    "ownerReferences": [{
+                        "apiVersion": "apps/v1",
+                        "kind": "ReplicaSet",
+                        "name": "web-7c5b9d8f4",
+                        "uid": "d2a1c6e8-0b4f-4e3a-8c7d-5f9e1a2b3c4d",
+                        "controller": true,
+                        "blockOwnerDeletion": true
+                    }],
+                    "managedFields": [{
+                        "manager": "kube-controller-manager",
+                        "operation": "Update",
+                        "apiVersion": "v1",
+                        "time": "2024-11-05T09:14:27Z",
+                        "fieldsType": "FieldsV1",
+                        "fieldsV1": {"f:metadata": {"f:generateName": {}}}
+                    }]
+                },
+                "spec": {
+                    "volumes": [
+                        {"name": "data", "persistentVolumeClaim": {"claimName": "data"}},
+                        {"name": "kube-api-access-9xk2p", "projected": {"sources": [{"serviceAccountToken": {"expirationSeconds": 3607, "path": "token"}}], "defaultMode": 420}}
+                    ],
+                    "containers": [{
+                        "name": "web",
+                        "image": "nginx:1.27",
+                        "resources": {},
+                        "volumeMounts": [{"name": "data", "mountPath": "/data"}],
+                        "terminationMessagePath": "/dev/termination-log",
+                        "terminationMessagePolicy": "File",
+                        "imagePullPolicy": "IfNotPresent"
+                    }],
+                    "restartPolicy": "Always",
+                    "terminationGracePeriodSeconds": 30,
+                    "dnsPolicy": "ClusterFirst",
+                    "serviceAccountName": "default",
+                    "serviceAccount": "default",
+                    "securityContext": {},
+                    "schedulerName": "default-scheduler",
+                    "enableServiceLinks": true,
+                    "preemptionPolicy": "PreemptLowerPriority"
+                },
+                "status": {}
+            },
+            "oldObject": null,
+            "dryRun": false,
+            "options": {"kind": "CreateOptions", "apiVersion": "meta.k8s.io/v1"}
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn test_request_fields() {
+        let review: AdmissionReview = serde_json::from_str(CREATE_REVIEW).unwrap();
+        let request = review.request.as_ref().unwrap();
+        assert_eq!(request.uid, "b0d5ab4e-5d0c-4a4f-9c3e-7d3e0f2a61c9");
+        assert_eq!(request.namespace.as_deref(), Some("default"));
+        assert_eq!(request.name, None);
+        assert_eq!(request.operation, "CREATE");
+        assert!(!request.dry_run);
+        assert!(request.old_object.is_none());
+        let user_info = request.user_info.as_ref().unwrap();
+        assert_eq!(
+            user_info.username,
+            "system:serviceaccount:kube-system:replicaset-controller"
+        );
+        assert_eq!(user_info.groups.len(), 3);
+        assert_eq!(review.object_names(), ("default", "web-7c5b9d8f4-"));
+
+        for controller in controllers("default/data") {
+            let logs = crate::logging::CapturedLogs::default();
+            let _subscriber = tracing::dispatcher::set_default(&crate::logging::dispatch(
+                crate::logging::LogFormat::Json,
+                tracing_subscriber::EnvFilter::new("info"),
+                logs.clone(),
+                false,
+                None,
+            ));
+            // Matched by the namespace of the request, the object does not carry it
+            let review: AdmissionReview = serde_json::from_str(CREATE_REVIEW).unwrap();
+            let response = controller.mutate(review).await.unwrap().response.unwrap();
+            assert!(response.patch.is_some());
+            let line: Value = serde_json::from_str(&logs.lines()[0]).unwrap();
+            assert_eq!(
+                line["user"],
+                "system:serviceaccount:kube-system:replicaset-controller"
+            );
+            assert_eq!(
+                line["groups"],
+                "system:serviceaccounts,system:serviceaccounts:kube-system,system:authenticated"
+            );
+            assert_eq!(line["outcome"], "patched");
+            assert!(
+                controller
+                    .metrics()
+                    .encode()
+                    .contains("gravivol_admission_operations_total{operation=\"CREATE\"} 1\n")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unhandled_kinds() {
+        for controller in controllers("default/data") {
+            let logs = crate::logging::CapturedLogs::default();
+            let _subscriber = tracing::dispatcher::set_default(&crate::logging::dispatch(
+                crate::logging::LogFormat::Json,
+                tracing_subscriber::EnvFilter::new("info"),
+                logs.clone(),
+                false,
+                None,
+            ));
+            for (api_version, kind) in [
+                ("apps/v1", "ReplicaSet"),
+                ("apps/v1", "ReplicaSet"),
+                ("apps/v1", "Deployment"),
+                ("v1", "Pod"),
+            ] {
+                let mut object = pod_with_claims(&["data"]);
+                object["apiVersion"] = json!(api_version);
+                object["kind"] = json!(kind);
+                let (_, response) = mutate_pod_with(&controller, &object).await;
+                assert!(response.allowed);
+                if kind == "Pod" {
+                    assert!(response.patch.is_some());
+                    assert!(response.warnings.is_empty());
+                } else {
+                    assert!(response.patch.is_none());
+                    assert_eq!(
+                        response.warnings,
+                        [format!(
+                            "gravivol: objects of kind {api_version} {kind} are not handled, check the rules of the webhook configuration"
+                        )]
+                    );
+                }
+            }
+
+            // Logged once per kind, not for every request
+            let warnings: Vec<Value> = logs
+                .lines()
+                .iter()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                .filter(|line| line["level"] == "WARN")
+                .collect();
+            assert_eq!(warnings.len(), 2, "{warnings:#?}");
+            assert_eq!(
+                warnings[0]["message"],
+                "Objects of kind apps/v1 ReplicaSet are not handled, check the rules of the webhook configuration"
+            );
+            assert_eq!(
+                warnings[1]["message"],
+                "Objects of kind apps/v1 Deployment are not handled, check the rules of the webhook configuration"
+            );
+            assert!(!logs.lines().iter().any(|line| line.contains("\"ERROR\"")));
+            assert!(
+                controller
+                    .metrics()
+                    .encode()
+                    .contains("gravivol_pods_skipped_total{reason=\"unhandled_kind\"} 3\n")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decision_log() {
+        for controller in controllers("default/data") {
+            let logs = crate::logging::CapturedLogs::default();
+            let _subscriber = tracing::dispatcher::set_default(&crate::logging::dispatch(
+                crate::logging::LogFormat::Json,
+                tracing_subscriber::EnvFilter::new("info"),
+                logs.clone(),
+                false,
+                None,
+            ));
+            for (uid, claims) in [
+                ("705ab4f5", ["data", "logs"]),
+                ("9c1e77d0", ["logs", "tmp"]),
+            ] {
+                let mut pod = pod_with_claims(&claims);
+                pod["metadata"]["name"] = json!("web");
+                let review: AdmissionReview = serde_json::from_value(json!({
+                    "apiVersion": "admission.k8s.io/v1",
+                    "kind": "AdmissionReview",
+                    "request": { "uid": uid, "operation": "CREATE", "object": pod },
+                }))
+                .unwrap();
+                controller.mutate(review).await.unwrap();
+            }
+
+            let lines: Vec<Value> = logs
+                .lines()
+                .iter()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(lines.len(), 2, "{lines:#?}");
+            let patched = &lines[0];
+            assert_eq!(patched["level"], "INFO");
+            assert_eq!(patched["message"], "Admission of default/web patched");
+            assert_eq!(patched["uid"], "705ab4f5");
+            assert_eq!(patched["namespace"], "default");
+            assert_eq!(patched["name"], "web");
+            assert_eq!(patched["operation"], "CREATE");
+            assert_eq!(patched["outcome"], "patched");
+            assert_eq!(patched["claims"], "data");
+            assert!(patched["patch_ops"].as_u64().unwrap() > 0);
+            assert_eq!(patched["dry_run"], false);
+            assert!(patched["duration_ms"].as_f64().unwrap() > 0.0);
+            assert!(patched.get("skip_reason").is_none());
+            assert!(patched.get("error").is_none());
+
+            let skipped = &lines[1];
+            assert_eq!(skipped["message"], "Admission of default/web skipped");
+            assert_eq!(skipped["uid"], "9c1e77d0");
+            assert_eq!(skipped["outcome"], "skipped");
+            assert_eq!(skipped["claims"], "");
+            assert_eq!(skipped["skip_reason"], "no_claims");
+            assert_eq!(skipped["patch_ops"], 0);
+        }
+    }
+
+    /// Namespaces of a test, known with their labels
+    struct FixedNamespaces(HashMap<&'static str, &'static str>);
+
+    impl Namespaces for FixedNamespaces {
+        fn get(&self, name: &str) -> Option<Arc<crate::namespaces::NamespaceMetadata>> {
+            self.0.get(name).map(|team| {
+                Arc::new(crate::namespaces::NamespaceMetadata {
+                    labels: [("team".to_owned(), team.to_string())].into(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_namespace_selector() {
+        let controller = Controller::new("")
+            .with_options(Options {
+                stamp_annotation: false,
+                namespace_selector: Some(
+                    serde_json::from_value(json!({ "matchLabels": { "team": "storage" } }))
+                        .unwrap(),
+                ),
+                ..Default::default()
+            })
+            .with_namespaces(Arc::new(FixedNamespaces(HashMap::from([
+                ("default", "storage"),
+                ("web", "frontend"),
+            ]))));
+        let pod_in = |namespace: &str| {
+            let mut pod = pod_with_claims(&["data"]);
+            pod["metadata"]["namespace"] = json!(namespace);
+            pod
+        };
+
+        let (patched_pod, response) = mutate_pod_with(&controller, &pod_in("default")).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/data": "true" })
+        );
+        assert!(response.warnings.is_empty());
+
+        let (_, response) = mutate_pod_with(&controller, &pod_in("web")).await;
+        assert!(response.patch.is_none());
+        assert!(response.warnings.is_empty());
+        assert!(
+            controller
+                .metrics()
+                .encode()
+                .contains("gravivol_pods_skipped_total{reason=\"namespace_excluded\"} 1\n")
+        );
+
+        // Not known yet, e.g. created a moment ago
+        let (patched_pod, response) = mutate_pod_with(&controller, &pod_in("new")).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "new.gravivol.fonona.net/data": "true" })
+        );
+        assert_eq!(
+            response.warnings,
+            vec!["gravivol: namespace new is not known yet, handling it as selected"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unbound_claims() {
+        let claim = |name: &str, phase: &str| {
+            (
+                format!("default/{name}"),
+                json!({
+                    "metadata": { "name": name, "namespace": "default" },
+                    "status": { "phase": phase },
+                }),
+            )
+        };
+        let controller = |unbound_claims, fail| {
+            Controller::new("")
+                .with_options(Options {
+                    stamp_annotation: false,
+                    unbound_claims,
+                    ..Default::default()
+                })
+                .with_cluster(Arc::new(StubCluster {
+                    claims: HashMap::from([claim("bound", "Bound"), claim("pending", "Pending")]),
+                    fail,
+                    ..Default::default()
+                }))
+        };
+        let pod = pod_with_claims(&["bound", "pending"]);
+        let term = |claim: &str| {
+            json!({
+                "labelSelector": {
+                    "matchLabels": { format!("default.gravivol.fonona.net/{claim}"): "true" }
+                },
+                "topologyKey": "kubernetes.io/hostname",
+            })
+        };
+
+        let (patched_pod, response) =
+            mutate_pod_with(&controller(UnboundClaimMode::Skip, false), &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"],
+            json!({ "requiredDuringSchedulingIgnoredDuringExecution": [term("bound")] })
+        );
+        // Still labelled, so that later pods follow once it is bound
+        assert_eq!(
+            patched_pod["metadata"]["labels"]["default.gravivol.fonona.net/pending"],
+            "true"
+        );
+        assert_eq!(
+            response.warnings,
+            vec!["gravivol: claim pending is Pending, not bound, no pod affinity added"]
+        );
+
+        let (patched_pod, response) =
+            mutate_pod_with(&controller(UnboundClaimMode::Preferred, false), &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"],
+            json!({
+                "requiredDuringSchedulingIgnoredDuringExecution": [term("bound")],
+                "preferredDuringSchedulingIgnoredDuringExecution": [{
+                    "weight": 100,
+                    "podAffinityTerm": term("pending"),
+                }],
+            })
+        );
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: claim pending is Pending, not bound, adding a preferred pod affinity only"
+            ]
+        );
+
+        let (patched_pod, response) = mutate_pod_with(
+            &controller(UnboundClaimMode::Skip, false),
+            &pod_with_claims(&["missing"]),
+        )
+        .await;
+        assert!(patched_pod["spec"]["affinity"].is_null());
+        assert_eq!(
+            response.warnings,
+            vec!["gravivol: claim missing is not found, not bound, no pod affinity added"]
+        );
+
+        // A failed lookup keeps the required pod affinity
+        let failing = controller(UnboundClaimMode::Skip, true);
+        let (patched_pod, _) = mutate_pod_with(&failing, &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                [0]["labelSelector"]["matchLabels"],
+            json!({
+                "default.gravivol.fonona.net/bound": "true",
+                "default.gravivol.fonona.net/pending": "true",
+            })
+        );
+        assert!(
+            failing
+                .metrics()
+                .encode()
+                .contains("gravivol_lookup_failures_total{check=\"phase\"} 2\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provisioners() {
+        let claim = |name: &str, spec: Value| {
+            (
+                format!("default/{name}"),
+                json!({ "metadata": { "name": name, "namespace": "default" }, "spec": spec }),
+            )
+        };
+        let class = |name: &str, provisioner: &str| {
+            (
+                name.to_owned(),
+                json!({ "metadata": { "name": name }, "provisioner": provisioner }),
+            )
+        };
+        let controller = |allow: &[&str], deny: &[&str], fail| {
+            Controller::new("")
+                .with_options(Options {
+                    stamp_annotation: false,
+                    provisioners: allow.iter().map(|p| p.to_string()).collect(),
+                    provisioners_deny: deny.iter().map(|p| p.to_string()).collect(),
+                    ..Default::default()
+                })
+                .with_cluster(Arc::new(StubCluster {
+                    claims: HashMap::from([
+                        claim("local", json!({ "storageClassName": "local-path" })),
+                        claim("nfs", json!({ "storageClassName": "nfs-client" })),
+                        claim("default", json!({})),
+                        claim("static", json!({ "storageClassName": "" })),
+                    ]),
+                    storage_classes: HashMap::from([
+                        class("local-path", "rancher.io/local-path"),
+                        class("nfs-client", "nfs.csi.k8s.io"),
+                        class("", "openebs.io/local"),
+                    ]),
+                    fail,
+                    ..Default::default()
+                }))
+        };
+        let labels = |patched_pod: &Value| {
+            let mut claims: Vec<String> = patched_pod["metadata"]["labels"]
+                .as_object()
+                .map(|labels| {
+                    labels
+                        .keys()
+                        .map(|key| key.rsplit('/').next().unwrap().to_owned())
+                        .collect()
+                })
+                .unwrap_or_default();
+            claims.sort();
+            claims
+        };
+        let pod = pod_with_claims(&["local", "nfs", "default", "static", "unknown"]);
+
+        // The claim without class gets the default class of openebs.io/local
+        let (patched_pod, response) = mutate_pod_with(
+            &controller(&["rancher.io/local-path", "openebs.io/local"], &[], false),
+            &pod,
+        )
+        .await;
+        assert_eq!(labels(&patched_pod), ["default", "local", "unknown"]);
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: claim nfs has storage of nfs.csi.k8s.io, not co-located",
+                "gravivol: claim static has storage of no provisioner, not co-located",
+            ]
+        );
+
+        let (patched_pod, _) =
+            mutate_pod_with(&controller(&[], &["nfs.csi.k8s.io"], false), &pod).await;
+        assert_eq!(
+            labels(&patched_pod),
+            ["default", "local", "static", "unknown"]
+        );
+
+        // A failed lookup falls back to the configured claims
+        let failing = controller(&["rancher.io/local-path"], &[], true);
+        let (patched_pod, response) = mutate_pod_with(&failing, &pod_with_claims(&["nfs"])).await;
+        assert_eq!(labels(&patched_pod), ["nfs"]);
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: cannot look up the provisioner of claim nfs, handling it as configured"
+            ]
+        );
+        assert!(
+            failing
+                .metrics()
+                .encode()
+                .contains("gravivol_lookup_failures_total{check=\"provisioner\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn test_merge_node_terms() {
+        let terms = vec![
+            json!({ "matchExpressions": [{ "key": "a", "operator": "Exists" }] }),
+            json!({ "matchExpressions": [{ "key": "b", "operator": "Exists" }] }),
+        ];
+        let other_terms = vec![
+            json!({ "matchFields": [{ "key": "metadata.name", "operator": "In", "values": ["n"] }] }),
+        ];
+        assert_eq!(
+            merge_node_terms(&terms, &other_terms),
+            vec![
+                json!({
+                    "matchExpressions": [{ "key": "a", "operator": "Exists" }],
+                    "matchFields": [{ "key": "metadata.name", "operator": "In", "values": ["n"] }],
+                }),
+                json!({
+                    "matchExpressions": [{ "key": "b", "operator": "Exists" }],
+                    "matchFields": [{ "key": "metadata.name", "operator": "In", "values": ["n"] }],
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_first_pod() {
+        let required_term = json!([{
+            "labelSelector": {
+                "matchLabels": {
                     "default.gravivol.fonona.net/myvol1": "true",
                 }
             },
-            "spec": {
-                "volumes": [
-                    {
-                        "name": "somename",
-                        "persistentVolumeClaim": {
-                            "claimName": "myvol1"
-                        }
-                    }
-                ],
-                "affinity": {
+            "topologyKey": "kubernetes.io/hostname",
+        }]);
+        let controller = |first_pod, pod_selectors: &[&str]| {
+            Controller::new("")
+                .with_options(Options {
+                    stamp_annotation: false,
+                    first_pod,
+                    ..Default::default()
+                })
+                .with_cluster(Arc::new(StubCluster {
+                    pod_selectors: pod_selectors.iter().map(|s| s.to_string()).collect(),
+                    ..Default::default()
+                }))
+        };
+        let pod = pod_with_claims(&["myvol1"]);
+
+        // Without peer pod the pod becomes the anchor
+        let (patched_pod, _) = mutate_pod_with(&controller(FirstPodMode::Anchor, &[]), &pod).await;
+        assert!(patched_pod["spec"]["affinity"].is_null());
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+
+        // or gets a preferred pod affinity
+        let (patched_pod, _) =
+            mutate_pod_with(&controller(FirstPodMode::Preferred, &[]), &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"],
+            json!({
+                "preferredDuringSchedulingIgnoredDuringExecution": [{
+                    "weight": 100,
+                    "podAffinityTerm": required_term[0],
+                }]
+            })
+        );
+
+        // With peer pod the affinity is required
+        let (patched_pod, _) = mutate_pod_with(
+            &controller(
+                FirstPodMode::Preferred,
+                &["default/default.gravivol.fonona.net/myvol1=true"],
+            ),
+            &pod,
+        )
+        .await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"],
+            required_term
+        );
+    }
+
+    /// The operations of the guarded patch for the pod
+    async fn guarded_patch_ops(pod: &Value) -> Value {
+        let controller = Controller::new("").with_options(Options {
+            stamp_annotation: false,
+            guarded_patch: true,
+            ..Default::default()
+        });
+        let (_, response) = mutate_pod_with(&controller, pod).await;
+        serde_json::from_slice(&BASE64_STANDARD.decode(response.patch.unwrap()).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_guarded_patch_fresh_pod() {
+        let pod = pod_with_claims(&["myvol1"]);
+        let labels = json!({ "default.gravivol.fonona.net/myvol1": "true" });
+        assert_eq!(
+            guarded_patch_ops(&pod).await,
+            json!([
+                { "op": "test", "path": "/metadata", "value": pod["metadata"] },
+                { "op": "test", "path": "/spec", "value": pod["spec"] },
+                { "op": "add", "path": "/metadata/labels", "value": labels },
+                { "op": "add", "path": "/spec/affinity", "value": {
                     "podAffinity": {
-                        "requiredDuringSchedulingIgnoredDuringExecution": [
-                        {
-                            "labelSelector": {
-                                "matchLabels": {
-                                    "default.gravivol.fonona.net/myvol1": "true",
-                                }
-                            },
+                        "requiredDuringSchedulingIgnoredDuringExecution": [{
+                            "labelSelector": { "matchLabels": labels },
                             "topologyKey": "kubernetes.io/hostname",
                         }]
                     }
-                }
+                }},
+            ])
+        );
+
+        // Labels or an affinity gained after the review would be overwritten, so the patch fails
+        let guarded_patch: Patch = serde_json::from_value(guarded_patch_ops(&pod).await).unwrap();
+        let mut labeled_pod = pod.clone();
+        labeled_pod["metadata"]["labels"] = json!({ "app": "web" });
+        assert!(patch(&mut labeled_pod, &guarded_patch).is_err());
+        let mut affine_pod = pod.clone();
+        affine_pod["spec"]["affinity"] = json!({ "nodeAffinity": {} });
+        assert!(patch(&mut affine_pod, &guarded_patch).is_err());
+        patch(&mut pod.clone(), &guarded_patch).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_guarded_patch_existing_affinity() {
+        let existing_term = json!({
+            "labelSelector": { "matchLabels": { "app": "db" } },
+            "topologyKey": "kubernetes.io/hostname",
+        });
+        let mut pod = pod_with_claims(&["myvol1"]);
+        pod["metadata"]["labels"] = json!({ "app": "web" });
+        pod["spec"]["affinity"] = json!({
+            "podAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [existing_term]
+            }
+        });
+        let terms_path =
+            "/spec/affinity/podAffinity/requiredDuringSchedulingIgnoredDuringExecution";
+        assert_eq!(
+            guarded_patch_ops(&pod).await,
+            json!([
+                { "op": "test", "path": "/metadata/labels", "value": { "app": "web" } },
+                { "op": "test", "path": terms_path, "value": [existing_term] },
+                {
+                    "op": "add",
+                    "path": "/metadata/labels/default.gravivol.fonona.net~1myvol1",
+                    "value": "true",
+                },
+                { "op": "add", "path": format!("{terms_path}/1"), "value": {
+                    "labelSelector": {
+                        "matchLabels": { "default.gravivol.fonona.net/myvol1": "true" }
+                    },
+                    "topologyKey": "kubernetes.io/hostname",
+                }},
+            ])
+        );
+
+        // A change made after the review fails the patch
+        let mut changed_pod = pod.clone();
+        changed_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"] =
+            json!([]);
+        let guarded_patch: Patch = serde_json::from_value(guarded_patch_ops(&pod).await).unwrap();
+        assert!(patch(&mut changed_pod, &guarded_patch).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_pod() {
+        let mut pod = pod_with_claims(&["myvol1"]);
+        pod["metadata"]["annotations"] = json!({
+            "kubernetes.io/config.mirror": "3c2d9a1b7e5f",
+        });
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert!(response.allowed);
+        assert_eq!(response.patch, None);
+        assert_eq!(patched_pod, pod);
+    }
+
+    #[tokio::test]
+    async fn test_daemonset_pod() {
+        let mut pod = pod_with_claims(&["myvol1"]);
+        pod["metadata"]["ownerReferences"] = json!([{
+            "apiVersion": "apps/v1",
+            "kind": "DaemonSet",
+            "name": "node-agent",
+            "uid": "d9607e19-f88f-11e6-a518-42010a800195",
+            "controller": true,
+        }]);
+
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(response.patch, None);
+        assert_eq!(patched_pod, pod);
+        assert_eq!(
+            response.warnings,
+            vec!["gravivol: pods owned by a DaemonSet are not co-located with other pods"]
+        );
+
+        let controller = Controller::new("").with_options(Options {
+            skip_daemonsets: false,
+            ..Default::default()
+        });
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+        assert_eq!(
+            patched_pod["metadata"]["ownerReferences"],
+            pod["metadata"]["ownerReferences"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_claim_labels() {
+        for controller in controllers("default/myvol1,default/myvol2") {
+            missing_claim_labels(controller).await;
+        }
+    }
+
+    async fn missing_claim_labels(controller: Controller) {
+        let mut pod = pod_with_claims(&["myvol1", "myvol2", "other"]);
+        assert_eq!(
+            controller.missing_claim_labels(&pod, true).await.unwrap(),
+            vec!["myvol1", "myvol2"]
+        );
+
+        // Labeled directly or with the owner as prefix
+        pod["metadata"]["labels"] = json!({
+            "default.gravivol.fonona.net/myvol1": "true",
+            "default.gravivol.fonona.net/web.myvol2": "true",
+        });
+        assert!(
+            controller
+                .missing_claim_labels(&pod, true)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // Followers only get the affinity term
+        let mut follower = pod_with_claims(&["myvol1"]);
+        follower["metadata"]["annotations"] = json!({ "gravivol.fonona.net/role": "follower" });
+        assert!(
+            controller
+                .missing_claim_labels(&follower, true)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            controller
+                .missing_claim_labels(&pod_with_claims(&["other"]), true)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_is_valid_label_key() {
+        assert!(is_valid_label_key("kubernetes.io/hostname"));
+        assert!(is_valid_label_key("topology.kubernetes.io/zone"));
+        assert!(is_valid_label_key("rack"));
+        assert!(!is_valid_label_key(""));
+        assert!(!is_valid_label_key("/zone"));
+        assert!(!is_valid_label_key("Example.com/zone"));
+        assert!(!is_valid_label_key("example.com/zone/rack"));
+        assert!(!is_valid_label_key("zone-"));
+        assert!(!is_valid_label_key(&"a".repeat(64)));
+    }
+
+    #[tokio::test]
+    async fn test_topology_key_annotation() {
+        let mut pod = pod_with_claims(&["myvol1"]);
+        pod["metadata"]["annotations"] = json!({
+            "gravivol.fonona.net/topology-key": "topology.kubernetes.io/zone",
+        });
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                [0]["topologyKey"],
+            "topology.kubernetes.io/zone"
+        );
+        assert!(response.warnings.is_empty());
+
+        pod["metadata"]["annotations"]["gravivol.fonona.net/topology-key"] = json!("not a key");
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                [0]["topologyKey"],
+            "kubernetes.io/hostname"
+        );
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: invalid label key 'not a key' for annotation gravivol.fonona.net/topology-key ignored"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claims_annotation() {
+        let mut pod = pod_with_claims(&["myvol1", "myvol2", "myvol3"]);
+        pod["metadata"]["annotations"] = json!({
+            "gravivol.fonona.net/claims": "myvol1,myvol3",
+        });
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({
+                "default.gravivol.fonona.net/myvol1": "true",
+                "default.gravivol.fonona.net/myvol3": "true",
+            })
+        );
+        assert_eq!(
+            patched_pod["metadata"]["annotations"]["gravivol.fonona.net/mutated"],
+            "myvol1,myvol3"
+        );
+        assert!(response.warnings.is_empty());
+
+        pod["metadata"]["annotations"]["gravivol.fonona.net/claims"] = json!("myvol2, myvol4");
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol2": "true" })
+        );
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: claim myvol4 named in annotation gravivol.fonona.net/claims is not mounted"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_entry_modes() {
+        let (pvc, mode) = Pvc::from_config_entry("default/myvol1").unwrap();
+        assert_eq!(
+            (pvc.claim_name.as_str(), mode),
+            ("myvol1", ClaimMode::Affinity)
+        );
+        let (pvc, mode) = Pvc::from_config_entry("default/myvol2:spread").unwrap();
+        assert_eq!(
+            (pvc.claim_name.as_str(), mode),
+            ("myvol2", ClaimMode::Spread(SpreadOverrides::default()))
+        );
+        assert!(Pvc::from_config_entry("default/myvol3:nearby").is_none());
+        let (_, mode) = Pvc::from_config_entry("default/myvol4:preferred:90").unwrap();
+        assert_eq!(mode, ClaimMode::Preferred(Some(90)));
+        let (_, mode) = Pvc::from_config_entry("default/myvol5:preferred").unwrap();
+        assert_eq!(mode, ClaimMode::Preferred(None));
+        assert!(Pvc::from_config_entry("default/myvol6:preferred:0").is_none());
+        assert!(Pvc::from_config_entry("default/myvol6:preferred:101").is_none());
+        let (_, mode) = Pvc::from_config_entry("default/myvol7:anti-affinity:40").unwrap();
+        assert_eq!(mode, ClaimMode::AntiAffinity(Some(40)));
+        let (_, mode) = Pvc::from_config_entry("default/myvol8:affinity:replace").unwrap();
+        assert_eq!(mode, ClaimMode::ReplaceAffinity);
+        assert!(Pvc::from_config_entry("default/myvol8:preferred:replace").is_none());
+
+        // Spread parameters in any order
+        let (_, mode) =
+            Pvc::from_config_entry("default/myvol9:spread:topology.kubernetes.io/zone:2").unwrap();
+        assert_eq!(
+            mode,
+            ClaimMode::Spread(SpreadOverrides {
+                max_skew: Some(2),
+                when_unsatisfiable: None,
+                topology_key: Some("topology.kubernetes.io/zone".to_owned()),
+            })
+        );
+        let (_, mode) = Pvc::from_config_entry("default/myvol9:spread:ScheduleAnyway").unwrap();
+        assert_eq!(
+            mode,
+            ClaimMode::Spread(SpreadOverrides {
+                when_unsatisfiable: Some(WhenUnsatisfiable::ScheduleAnyway),
+                ..Default::default()
+            })
+        );
+        for entry in [
+            "default/myvol9:spread:0",
+            "default/myvol9:spread:1:2",
+            "default/myvol9:spread:not a key",
+            "default/myvol9:spread:",
+        ] {
+            assert!(Pvc::from_config_entry(entry).is_none(), "{entry}");
+        }
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config("").is_ok());
+        assert!(validate_config("default/myvol1,default/myvol2:spread,").is_ok());
+        let err = validate_config("default/myvol1,myvol2,default/myvol3:nearby").unwrap_err();
+        assert!(err.ends_with(": myvol2,default/myvol3:nearby"));
+    }
+
+    #[tokio::test]
+    async fn test_spread_mode() {
+        let existing_constraint = json!({
+            "maxSkew": 2,
+            "topologyKey": "topology.kubernetes.io/zone",
+            "whenUnsatisfiable": "ScheduleAnyway",
+            "labelSelector": { "matchLabels": { "app": "cache" } },
+        });
+        let mut pod = pod_with_claims(&["myvol1", "myvol2"]);
+        pod["spec"]["topologySpreadConstraints"] = json!([existing_constraint]);
+        for controller in controllers("default/myvol1,default/myvol2:spread") {
+            let controller = controller.with_options(Options {
+                stamp_annotation: false,
+                spread: SpreadOptions {
+                    max_skew: 1,
+                    when_unsatisfiable: WhenUnsatisfiable::ScheduleAnyway,
+                    topology_key: "kubernetes.io/hostname".to_owned(),
+                },
+                ..Default::default()
+            });
+
+            let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+            assert_eq!(
+                patched_pod["metadata"]["labels"],
+                json!({
+                    "default.gravivol.fonona.net/myvol1": "true",
+                    "default.gravivol.fonona.net/myvol2": "true",
+                })
+            );
+            assert_eq!(
+                patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                    [0]["labelSelector"],
+                json!({ "matchLabels": { "default.gravivol.fonona.net/myvol1": "true" } })
+            );
+            assert_eq!(
+                patched_pod["spec"]["topologySpreadConstraints"],
+                json!([
+                    existing_constraint,
+                    {
+                        "maxSkew": 1,
+                        "topologyKey": "kubernetes.io/hostname",
+                        "whenUnsatisfiable": "ScheduleAnyway",
+                        "labelSelector": {
+                            "matchLabels": { "default.gravivol.fonona.net/myvol2": "true" }
+                        },
+                    },
+                ])
+            );
+        }
+
+        // Claims of one namespace spread with different parameters, one constraint each
+        let pod = pod_with_claims(&["myvol1", "myvol2", "myvol3"]);
+        let config = "default/myvol1:spread,default/myvol2:spread:3:topology.kubernetes.io/zone,default/myvol3:spread";
+        for controller in controllers(config) {
+            let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+            assert_eq!(
+                patched_pod["spec"]["topologySpreadConstraints"],
+                json!([
+                    {
+                        "maxSkew": 1,
+                        "topologyKey": "kubernetes.io/hostname",
+                        "whenUnsatisfiable": "DoNotSchedule",
+                        "labelSelector": {
+                            "matchLabels": {
+                                "default.gravivol.fonona.net/myvol1": "true",
+                                "default.gravivol.fonona.net/myvol3": "true",
+                            }
+                        },
+                    },
+                    {
+                        "maxSkew": 3,
+                        "topologyKey": "topology.kubernetes.io/zone",
+                        "whenUnsatisfiable": "DoNotSchedule",
+                        "labelSelector": {
+                            "matchLabels": { "default.gravivol.fonona.net/myvol2": "true" }
+                        },
+                    },
+                ])
+            );
+        }
+        assert_eq!(
+            Controller::builder()
+                .add_pvc_with_mode(
+                    "default",
+                    "myvol1",
+                    ClaimMode::Spread(SpreadOverrides {
+                        topology_key: Some("not a key".to_owned()),
+                        ..Default::default()
+                    })
+                )
+                .build()
+                .err(),
+            Some(ConfigError::TopologyKey("not a key".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v1beta1_review() {
+        let data = json!({
+            "apiVersion": "admission.k8s.io/v1beta1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "0df28fbd-5f5f-11e8-bc74-36e6bb280816",
+                "kind": { "group": "", "version": "v1", "kind": "Pod" },
+                "resource": { "group": "", "version": "v1", "resource": "pods" },
+                "namespace": "default",
+                "operation": "CREATE",
+                "userInfo": {
+                    "username": "system:serviceaccount:kube-system:replicaset-controller",
+                    "uid": "a7e0ab33-5f29-11e8-8a3c-36e6bb280816",
+                    "groups": ["system:serviceaccounts", "system:authenticated"]
+                },
+                "object": pod_with_claims(&["myvol1"]),
+                "oldObject": null,
+                "dryRun": false
             }
         });
+        let review: AdmissionReview = serde_json::from_value(data).unwrap();
+        let review = Controller::new("").mutate(review).await.unwrap();
+        let response = serde_json::to_value(&review).unwrap();
+        assert_eq!(response["apiVersion"], "admission.k8s.io/v1beta1");
+        assert_eq!(response["kind"], "AdmissionReview");
+        assert_eq!(
+            response["response"]["uid"],
+            "0df28fbd-5f5f-11e8-bc74-36e6bb280816"
+        );
+        assert_eq!(response["response"]["allowed"], true);
+        assert_eq!(response["response"]["patchType"], "JSONPatch");
+    }
 
+    #[tokio::test]
+    async fn test_unknown_review_version() {
         let data = json!({
-            "apiVersion": "admission.k8s.io/v1",
+            "apiVersion": "admission.k8s.io/v2",
             "kind": "AdmissionReview",
             "request": {
                 "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
-                "object": pod.clone(),
+                "object": pod_with_claims(&["myvol1"]),
             }
         });
-        let review: AdmissionReview = serde_json::from_value(data).expect("Failed to parse JSON");
-        let controller = Controller::new(config);
-        let response = controller.mutate(review).unwrap();
+        let review: AdmissionReview = serde_json::from_value(data).unwrap();
+        let review = Controller::new("").mutate(review).await.unwrap();
+        assert_eq!(review.api_version, "admission.k8s.io/v2");
+        let response = review.response.unwrap();
+        assert!(!response.allowed);
+        assert_eq!(response.patch, None);
+        let status = response.status.unwrap();
+        assert_eq!(status.code, 400);
+        assert_eq!(
+            status.message,
+            "gravivol does not support admission.k8s.io/v2 AdmissionReview, expected AdmissionReview of admission.k8s.io/v1 or admission.k8s.io/v1beta1"
+        );
+    }
 
-        assert_eq!(response.api_version, "admission.k8s.io/v1");
-        assert_eq!(response.kind, "AdmissionReview");
-        match response.response {
-            Some(res) => {
-                assert_eq!(res.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
-                assert_eq!(res.patch_type, Some("JSONPatch".to_owned()));
-                let patch_string = String::from_utf8(
-                    BASE64_STANDARD
-                        .decode(res.patch.expect("No patch in response"))
-                        .expect("Cannot decode base64"),
-                )
-                .expect("Invalid UTF-8");
-                let patch_json: Patch =
-                    serde_json::from_str(&patch_string).expect("Cannot parse JSON patch");
-                patch(&mut pod, &patch_json).expect("Patch failed");
-                assert_eq!(pod, expected_patched_pod);
-            }
-            None => panic!("Expected Some(response)"),
+    #[tokio::test]
+    async fn test_dry_run() {
+        let cluster = Arc::new(stub_cluster_with_bound_volume(json!([])));
+        let controller = Controller::new("")
+            .with_options(Options {
+                node_affinity: NodeAffinityMode::Add,
+                ..Default::default()
+            })
+            .with_cluster(cluster.clone());
+        let review = |dry_run: bool| {
+            serde_json::from_value::<AdmissionReview>(json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                    "object": pod_with_claims(&["myvol1"]),
+                    "dryRun": dry_run,
+                }
+            }))
+            .unwrap()
+        };
+
+        let response = controller
+            .mutate(review(true))
+            .await
+            .unwrap()
+            .response
+            .unwrap();
+        assert!(response.patch.is_some());
+        assert_eq!(
+            response.audit_annotations,
+            HashMap::from([
+                ("matched-claims".to_owned(), "default/myvol1".to_owned()),
+                ("patch-ops".to_owned(), "3".to_owned()),
+            ])
+        );
+        assert_eq!(controller.metrics.dry_runs.get(), 1);
+        assert_eq!(
+            cluster
+                .cacheable_lookups
+                .load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+
+        let response = controller
+            .mutate(review(false))
+            .await
+            .unwrap()
+            .response
+            .unwrap();
+        assert!(response.patch.is_some());
+        assert!(!response.audit_annotations.is_empty());
+        assert_eq!(controller.metrics.dry_runs.get(), 1);
+        assert!(
+            cluster
+                .cacheable_lookups
+                .load(std::sync::atomic::Ordering::Relaxed)
+                > 0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_audit_log() {
+        let (audit_log, mut queued) = AuditLog::new(16, Arc::new(Metrics::new()));
+        let controller = Controller::new("default/myvol1").with_audit_log(Arc::new(audit_log));
+        let review = |dry_run: bool| {
+            serde_json::from_value::<AdmissionReview>(json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                    "object": pod_with_claims(&["myvol1"]),
+                    "dryRun": dry_run,
+                }
+            }))
+            .unwrap()
+        };
+
+        let response = controller
+            .mutate(review(true))
+            .await
+            .unwrap()
+            .response
+            .unwrap();
+        assert!(response.patch.is_some());
+        assert!(queued.try_recv().is_err());
+
+        controller.mutate(review(false)).await.unwrap();
+        let decision = queued.try_recv().unwrap();
+        assert_eq!(decision.uid, "26973DA1-B488-4F59-B062-461C6BDCAD83");
+        assert!(!decision.dry_run);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_without_patch() {
+        for controller in controllers("default/other") {
+            let review = serde_json::from_value::<AdmissionReview>(json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "26973DA1-B488-4F59-B062-461C6BDCAD83",
+                    "object": pod_with_claims(&["myvol1"]),
+                    "dryRun": true,
+                }
+            }))
+            .unwrap();
+            let response = controller.mutate(review).await.unwrap().response.unwrap();
+            assert!(response.patch.is_none());
+            assert_eq!(controller.metrics.dry_runs.get(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_weighted_preferred_terms() {
+        for controller in controllers(
+            "default/scratch:preferred:20,default/config:preferred,default/dataset:preferred:90",
+        ) {
+            let controller = controller.with_options(Options {
+                stamp_annotation: false,
+                preferred_weight: 50,
+                ..Default::default()
+            });
+            let pod = pod_with_claims(&["scratch", "config", "dataset"]);
+            let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+
+            let pod_affinity = &patched_pod["spec"]["affinity"]["podAffinity"];
+            assert!(pod_affinity["requiredDuringSchedulingIgnoredDuringExecution"].is_null());
+            let weights: Vec<(&str, u64)> =
+                pod_affinity["preferredDuringSchedulingIgnoredDuringExecution"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|term| {
+                        let labels = term["podAffinityTerm"]["labelSelector"]["matchLabels"]
+                            .as_object()
+                            .unwrap();
+                        assert_eq!(labels.len(), 1);
+                        (
+                            labels.keys().next().unwrap().as_str(),
+                            term["weight"].as_u64().unwrap(),
+                        )
+                    })
+                    .collect();
+            assert_eq!(
+                weights,
+                vec![
+                    ("default.gravivol.fonona.net/dataset", 90),
+                    ("default.gravivol.fonona.net/config", 50),
+                    ("default.gravivol.fonona.net/scratch", 20),
+                ]
+            );
         }
     }
 
+    fn pod_with_claim_term(topology_key: &str) -> Value {
+        let mut pod = pod_with_claims(&["myvol1"]);
+        pod["spec"]["affinity"] = json!({
+            "podAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [{
+                    "labelSelector": {
+                        "matchExpressions": [{
+                            "key": "default.gravivol.fonona.net/myvol1",
+                            "operator": "In",
+                            "values": ["true"],
+                        }]
+                    },
+                    "topologyKey": topology_key,
+                }]
+            }
+        });
+        pod
+    }
+
+    #[tokio::test]
+    async fn test_conflicting_affinity_term() {
+        let pod = pod_with_claim_term("topology.kubernetes.io/zone");
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(patched_pod["spec"]["affinity"], pod["spec"]["affinity"]);
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: existing pod affinity term for claims has topology key topology.kubernetes.io/zone, not adding one"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_affinity_term_already_present() {
+        let pod = pod_with_claim_term("kubernetes.io/hostname");
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        assert_eq!(patched_pod["spec"]["affinity"], pod["spec"]["affinity"]);
+        assert!(response.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_affinity_term_for_other_claim() {
+        let mut pod = pod_with_claim_term("topology.kubernetes.io/zone");
+        pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+            [0]["labelSelector"]["matchExpressions"][0]["key"] =
+            json!("default.gravivol.fonona.net/other");
+        let (patched_pod, response) = mutate_pod_with(&Controller::new(""), &pod).await;
+        let terms = patched_pod["spec"]["affinity"]["podAffinity"]
+            ["requiredDuringSchedulingIgnoredDuringExecution"]
+            .as_array()
+            .unwrap();
+        assert_eq!(terms.len(), 2);
+        assert_eq!(
+            terms[1]["labelSelector"]["matchLabels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+        assert_eq!(terms[1]["topologyKey"], "kubernetes.io/hostname");
+        assert!(response.warnings.is_empty());
+    }
+
     #[test]
-    fn test_create_patch_pure_pod() {
+    fn test_create_patch_scheduling_gate() {
         let pod_before = json!({
-            "apiVersion": "v1",
-            "kind": "Pod",
             "metadata": {
-                "namespace": "foo"
+                "labels": { "app": "web" },
             },
             "spec": {
-                "containers": [
-                    {
-                        "name": "my-container",
-                        "image": "nginx"
-                    }
-                ]
+                "schedulingGates": [{ "name": "example.com/other" }],
             }
         });
+        let mut mutation = Mutation::new("default", &["myvol1".to_owned()], PatchMode::Affinity);
+        mutation.gate_selector = Some("default.gravivol.fonona.net/myvol1=true".to_owned());
+        let options = Options {
+            stamp_annotation: false,
+            ..Default::default()
+        };
+        let pod: PodTemplate = serde_json::from_value(pod_before.clone()).unwrap();
+        let created_patch = create_patch(&pod, "", &mutation, &options).unwrap();
 
-        let pvcs = vec!["myvol1".to_owned(), "myvol2".to_owned()];
+        let mut pod_patched = pod_before.clone();
+        patch(&mut pod_patched, &created_patch).unwrap();
+        assert_eq!(
+            pod_patched["metadata"],
+            json!({
+                "labels": { "app": "web", "gravivol.fonona.net/awaiting-anchor": "true" },
+                "annotations": {
+                    "gravivol.fonona.net/awaiting-anchor": "default.gravivol.fonona.net/myvol1=true"
+                },
+            })
+        );
+        assert_eq!(
+            pod_patched["spec"]["schedulingGates"],
+            json!([
+                { "name": "example.com/other" },
+                { "name": "gravivol.fonona.net/awaiting-anchor" },
+            ])
+        );
+        assert!(
+            pod_patched["spec"]["affinity"]["podAffinity"]
+                ["requiredDuringSchedulingIgnoredDuringExecution"]
+                .is_array()
+        );
 
-        let mut pod_after = pod_before.to_owned();
+        // The gate is not added twice
+        let pod: PodTemplate = serde_json::from_value(pod_patched.clone()).unwrap();
+        let created_patch = create_patch(&pod, "", &mutation, &options).unwrap();
+        let mut pod_patched_twice = pod_patched.clone();
+        patch(&mut pod_patched_twice, &created_patch).unwrap();
+        assert_eq!(
+            pod_patched_twice["spec"]["schedulingGates"],
+            pod_patched["spec"]["schedulingGates"]
+        );
+    }
 
-        pod_after["metadata"]["labels"] = json!({
-            "foo.gravivol.fonona.net/myvol1": "true",
-            "foo.gravivol.fonona.net/myvol2": "true",
+    #[tokio::test]
+    async fn test_scheduling_gate_only_for_followers() {
+        let controller = Controller::new("").with_options(Options {
+            scheduling_gate: true,
+            ..Default::default()
         });
+        let mut pod = pod_with_claims(&["myvol1"]);
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+        assert!(patched_pod["spec"]["schedulingGates"].is_null());
 
-        pod_after["spec"]["affinity"] = json!({
-            "podAffinity": {
-                "requiredDuringSchedulingIgnoredDuringExecution": [
-                {
-                    "labelSelector": {
-                        "matchLabels": {
-                            "foo.gravivol.fonona.net/myvol1": "true",
-                            "foo.gravivol.fonona.net/myvol2": "true",
-                        }
-                    },
+        pod["metadata"]["annotations"] = json!({ "gravivol.fonona.net/role": "follower" });
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["schedulingGates"],
+            json!([{ "name": "gravivol.fonona.net/awaiting-anchor" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_label_value_uid() {
+        let uid = "8e5e1ab4-3f3a-4f8e-9d43-b5cbb3d0a3c1";
+        let controller = |fail: bool| {
+            Controller::new("")
+                .with_options(Options {
+                    label_value: LabelValue::Uid,
+                    ..Default::default()
+                })
+                .with_cluster(Arc::new(StubCluster {
+                    claims: HashMap::from([(
+                        "default/myvol1".to_owned(),
+                        json!({ "metadata": { "name": "myvol1", "uid": uid } }),
+                    )]),
+                    fail,
+                    ..Default::default()
+                }))
+        };
+        let pod = pod_with_claims(&["myvol1"]);
+
+        let (patched_pod, response) = mutate_pod_with(&controller(false), &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": uid })
+        );
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                [0]["labelSelector"],
+            json!({ "matchLabels": { "default.gravivol.fonona.net/myvol1": uid } })
+        );
+        assert!(response.warnings.is_empty());
+
+        let (patched_pod, response) = mutate_pod_with(&controller(true), &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/myvol1": "true" })
+        );
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: cannot look up claim myvol1, using label value true instead of its UID"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_by_owner() {
+        let controller = Controller::new("").with_options(Options {
+            stamp_annotation: false,
+            group_by_owner: true,
+            ..Default::default()
+        });
+        let pod_owned_by = |kind: &str, name: &str| {
+            let mut pod = pod_with_claims(&["cache"]);
+            pod["metadata"]["labels"] = json!({ "pod-template-hash": "5d4f8c7b9" });
+            pod["metadata"]["ownerReferences"] = json!([
+                { "kind": "ConfigMap", "name": "settings" },
+                { "kind": kind, "name": name, "controller": true },
+            ]);
+            pod
+        };
+
+        let (patched_pod, _) =
+            mutate_pod_with(&controller, &pod_owned_by("StatefulSet", "redis")).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"]["default.gravivol.fonona.net/redis.cache"],
+            "true"
+        );
+        let (patched_pod, _) =
+            mutate_pod_with(&controller, &pod_owned_by("ReplicaSet", "web-5d4f8c7b9")).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"]["default.gravivol.fonona.net/web.cache"],
+            "true"
+        );
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+                [0]["labelSelector"],
+            json!({ "matchLabels": { "default.gravivol.fonona.net/web.cache": "true" } })
+        );
+
+        // Without controller the key stays the same
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod_with_claims(&["cache"])).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/cache": "true" })
+        );
+        let mut pod = pod_with_claims(&["cache"]);
+        pod["metadata"]["ownerReferences"] = json!([{ "kind": "ConfigMap", "name": "settings" }]);
+        let (patched_pod, _) = mutate_pod_with(&controller, &pod).await;
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({ "default.gravivol.fonona.net/cache": "true" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replace_affinity() {
+        let other_affinity = json!({
+            "podAntiAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [{
+                    "labelSelector": { "matchLabels": { "app": "web" } },
                     "topologyKey": "kubernetes.io/hostname",
                 }]
-            }
+            },
+            "nodeAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": {
+                    "nodeSelectorTerms": [{
+                        "matchExpressions": [
+                            { "key": "disktype", "operator": "In", "values": ["ssd"] }
+                        ]
+                    }]
+                }
+            },
+        });
+        let user_term = json!({
+            "labelSelector": { "matchLabels": { "app": "db" } },
+            "topologyKey": "topology.kubernetes.io/zone",
+        });
+        let gravivol_term = json!({
+            "labelSelector": {
+                "matchLabels": { "default.gravivol.fonona.net/myvol1": "true" }
+            },
+            "topologyKey": "kubernetes.io/hostname",
+        });
+        let mut pod = pod_with_claims(&["myvol1"]);
+        pod["spec"]["affinity"] = other_affinity.clone();
+        pod["spec"]["affinity"]["podAffinity"] = json!({
+            "requiredDuringSchedulingIgnoredDuringExecution": [user_term]
         });
+        let controller = |namespaces: &[&str]| {
+            Controller::new("").with_options(Options {
+                replace_affinity_namespaces: namespaces.iter().map(|ns| ns.to_string()).collect(),
+                ..Default::default()
+            })
+        };
 
-        let pod: Pod = serde_json::from_value(pod_before.to_owned()).unwrap();
-        let created_patch: Patch = serde_json::from_str(&create_patch(&pod, pvcs)).unwrap();
+        // Appended by default
+        let (patched_pod, response) = mutate_pod_with(&controller(&["other"]), &pod).await;
+        assert_eq!(
+            patched_pod["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"],
+            json!([user_term, gravivol_term])
+        );
+        assert!(response.warnings.is_empty());
 
-        let mut pod_patched = pod_before.to_owned();
-        patch(&mut pod_patched, &created_patch).unwrap();
+        // Replaced in the configured namespaces
+        let (patched_pod, response) = mutate_pod_with(&controller(&["default"]), &pod).await;
+        let mut expected_affinity = other_affinity;
+        expected_affinity["podAffinity"] = json!({
+            "requiredDuringSchedulingIgnoredDuringExecution": [gravivol_term]
+        });
+        assert_eq!(patched_pod["spec"]["affinity"], expected_affinity);
+        assert_eq!(
+            response.warnings,
+            vec![
+                "gravivol: replaced required pod affinity terms: app=db@topology.kubernetes.io/zone"
+            ]
+        );
 
-        assert_eq!(pod_patched, pod_after);
+        // Replaced for a claim configured with affinity:replace, appended for another one
+        for (config, replaced) in [
+            ("default/myvol1:affinity:replace,default/myvol2", true),
+            ("default/myvol1,default/myvol2:affinity:replace", false),
+        ] {
+            for controller in controllers(config) {
+                let (replacing, response) = mutate_pod_with(&controller, &pod).await;
+                if replaced {
+                    assert_eq!(replacing["spec"]["affinity"], expected_affinity);
+                    assert_eq!(response.warnings.len(), 1);
+                } else {
+                    assert_eq!(
+                        replacing["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"],
+                        json!([user_term, gravivol_term])
+                    );
+                    assert!(response.warnings.is_empty());
+                }
+            }
+        }
     }
 
-    #[test]
-    fn test_create_patch_existing_labels_and_affinity() {
-        let pod_before = json!({
-            "apiVersion": "v1",
-            "kind": "Pod",
-            "metadata": {
-                "namespace": "my-namespace",
-                "labels": {
-                    "mylabel1": "myvalue1"
-                }
-            },
-            "spec": {
-                "containers": [
-                    {
-                        "name": "my-container",
-                        "image": "nginx"
-                    }
-                ],
-                "affinity": {
-                    "podAffinity": {
-                        "requiredDuringSchedulingIgnoredDuringExecution": [
-                        {
+    #[tokio::test]
+    async fn test_anti_affinity_mode() {
+        let options = Options {
+            stamp_annotation: false,
+            anti_affinity_weight: 80,
+            ..Default::default()
+        };
+        let [from_config, built] = controllers("default/myvol1,default/replicated:anti-affinity")
+            .map(|controller| controller.with_options(options.clone()));
+        let pod = pod_with_claims(&["myvol1", "replicated"]);
+        let (patched_pod, _) = mutate_pod_with(&from_config, &pod).await;
+        assert_eq!(mutate_pod_with(&built, &pod).await.0, patched_pod);
+
+        assert_eq!(
+            patched_pod["metadata"]["labels"],
+            json!({
+                "default.gravivol.fonona.net/myvol1": "true",
+                "default.gravivol.fonona.net/replicated": "true",
+            })
+        );
+        assert_eq!(
+            patched_pod["spec"]["affinity"],
+            json!({
+                "podAffinity": {
+                    "requiredDuringSchedulingIgnoredDuringExecution": [{
+                        "labelSelector": {
+                            "matchLabels": { "default.gravivol.fonona.net/myvol1": "true" }
+                        },
+                        "topologyKey": "kubernetes.io/hostname",
+                    }]
+                },
+                "podAntiAffinity": {
+                    "preferredDuringSchedulingIgnoredDuringExecution": [{
+                        "weight": 80,
+                        "podAffinityTerm": {
                             "labelSelector": {
-                                "matchLabels": {
-                                    "somelabel": "somevalue",
-                                }
+                                "matchLabels": { "default.gravivol.fonona.net/replicated": "true" }
                             },
-                            "topologyKey": "somekey",
-                        }]
-                    }
-                }
-            }
+                            "topologyKey": "kubernetes.io/hostname",
+                        }
+                    }]
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_failure_review() {
+        let controller = Controller::new("");
+        let review = controller
+            .failure_review(
+                br#"{"apiVersion": "admission.k8s.io/v1beta1", "request": {"uid": "abc", "object": 1}}"#,
+                "no pod",
+            )
+            .unwrap();
+        assert_eq!(review.api_version, "admission.k8s.io/v1beta1");
+        let response = review.response.unwrap();
+        assert_eq!(response.uid, "abc");
+        assert!(response.allowed);
+        assert_eq!(response.status.unwrap().message, "gravivol: no pod");
+
+        let closed = Controller::new("").with_options(Options {
+            failure_mode: FailureMode::Closed,
+            ..Default::default()
         });
+        let review = closed
+            .failure_review(br#"{"request": {"uid" : "def", "object": {"#, "broken")
+            .unwrap();
+        assert_eq!(review.api_version, "admission.k8s.io/v1");
+        let response = review.response.unwrap();
+        assert_eq!(response.uid, "def");
+        assert!(!response.allowed);
 
-        let pvcs = vec!["myvol1".to_owned(), "myvol2".to_owned()];
+        assert!(
+            controller
+                .failure_review(br#"{"kind": "AdmissionReview"}"#, "empty")
+                .is_none()
+        );
+        assert!(controller.failure_review(b"not json", "garbage").is_none());
+    }
 
-        let mut pod_after = pod_before.to_owned();
+    #[tokio::test]
+    async fn test_errors() {
+        for controller in controllers("default/data") {
+            let controller = controller.with_options(Options {
+                kinds: HashSet::from([Kind::Pod, Kind::Deployment]),
+                ..Default::default()
+            });
+            let mutate = |request: Value| {
+                let review: AdmissionReview = serde_json::from_value(json!({
+                    "apiVersion": "admission.k8s.io/v1",
+                    "kind": "AdmissionReview",
+                    "request": request,
+                }))
+                .unwrap();
+                controller.mutate(review)
+            };
+            let err = mutate(Value::Null).await.unwrap_err();
+            assert!(matches!(err, ControllerError::MissingRequest), "{err:?}");
+            let err = mutate(json!({
+                "uid": "705ab4f5",
+                "object": { "kind": "Pod", "apiVersion": "v1", "metadata": { "labels": 5 } },
+            }))
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(err, ControllerError::InvalidObject { .. }),
+                "{err:?}"
+            );
+            assert_eq!(err.status().code, 400);
+            let err = mutate(json!({
+                "uid": "705ab4f5",
+                "object": {
+                    "kind": "Deployment",
+                    "apiVersion": "apps/v1",
+                    "metadata": { "name": "web", "namespace": "default" },
+                },
+            }))
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(err, ControllerError::MissingTemplate(_)),
+                "{err:?}"
+            );
+            assert_eq!(err.status().code, 400);
+            let err =
+                ControllerError::Internal("request 705ab4f5 not processed within 5s".to_owned());
+            assert_eq!(err.status().code, 500);
 
-        pod_after["metadata"]["labels"]["my-namespace.gravivol.fonona.net/myvol1"] =
-            Value::String("true".to_owned());
-        pod_after["metadata"]["labels"]["my-namespace.gravivol.fonona.net/myvol2"] =
-            Value::String("true".to_owned());
+            let body = br#"{"request": {"uid": "705ab4f5", "object": {"kind": "Pod"}}}"#;
+            let response = controller
+                .error_review(body, &err)
+                .unwrap()
+                .response
+                .unwrap();
+            assert_eq!(response.uid, "705ab4f5");
+            assert!(response.allowed);
+            assert_eq!(
+                response.status.unwrap().message,
+                "gravivol: request 705ab4f5 not processed within 5s"
+            );
+            assert_eq!(
+                response.warnings,
+                [warning(
+                    "request 705ab4f5 not processed within 5s, the object was not mutated"
+                )]
+            );
+            let closed = Controller::new("").with_options(Options {
+                failure_mode: FailureMode::Closed,
+                ..Default::default()
+            });
+            let response = closed.error_review(body, &err).unwrap().response.unwrap();
+            assert!(!response.allowed);
+            assert!(response.warnings.is_empty());
+        }
+    }
 
-        if let Value::Array(the_array) = &mut pod_after["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
-        {
-            the_array.push(json!({
-                "labelSelector": {
-                    "matchLabels": {
-                        "my-namespace.gravivol.fonona.net/myvol1": "true",
-                        "my-namespace.gravivol.fonona.net/myvol2": "true",
+    #[test]
+    fn test_request_uid() {
+        assert_eq!(
+            request_uid(br#"{"request": {"uid": "705ab4f5", "object": 1}}"#).as_deref(),
+            Some("705ab4f5")
+        );
+        // Truncated with sorted keys, the owner reference comes before the uid of the request
+        let truncated = br#"{"apiVersion": "admission.k8s.io/v1", "request": {"kind": {}, "object": {"metadata": {"ownerReferences": [{"uid": "9c1e77d0"}], "name": "a\"uid\": \"x"}}, "operation": "CREATE", "uid": "705ab4f5", "userInfo": {"#;
+        assert_eq!(request_uid(truncated).as_deref(), Some("705ab4f5"));
+        assert_eq!(request_uid(br#"{"request": {"uid": 5, "object": {"#), None);
+        assert_eq!(request_uid(br#"{"uid": "705ab4f5", "request": {"#), None);
+        assert_eq!(request_uid(b"not json"), None);
+    }
+
+    #[test]
+    fn test_exceeds_depth() {
+        let json = br#"{"a": [{"b": "}]]]{{{\"["}, 1], "c": {}}"#;
+        assert!(!exceeds_depth(json, 3));
+        assert!(exceeds_depth(json, 2));
+        let nested = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert!(exceeds_depth(nested.as_bytes(), 64));
+        assert!(!exceeds_depth(b"\"[[[[\"", 1));
+    }
+
+    #[test]
+    fn test_count_volumes() {
+        let count = |object: Value| {
+            count_volumes(
+                json!({
+                    "apiVersion": "admission.k8s.io/v1",
+                    "kind": "AdmissionReview",
+                    "request": {
+                        "uid": "705ab4f5",
+                        "object": object,
+                        "oldObject": { "apiVersion": "v1", "kind": "Pod", "spec": { "volumes": [{}] } },
+                    }
+                })
+                .to_string()
+                .as_bytes(),
+            )
+        };
+        let volumes = json!([{ "name": "a", "persistentVolumeClaim": { "claimName": "a" } }, { "name": "b" }]);
+        assert_eq!(
+            count(json!({ "spec": { "volumes": volumes }, "apiVersion": "v1", "kind": "Pod" })),
+            Some(2)
+        );
+        assert_eq!(
+            count(json!({
+                "apiVersion": "batch/v1",
+                "kind": "CronJob",
+                "spec": { "jobTemplate": { "spec": { "template": { "spec": { "volumes": volumes } } } } }
+            })),
+            Some(2)
+        );
+        assert_eq!(
+            count(
+                json!({ "apiVersion": "apps/v1", "kind": "Deployment", "spec": { "volumes": volumes } })
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            count(
+                json!({ "apiVersion": "v1", "kind": "ConfigMap", "spec": { "volumes": volumes } })
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            count(json!({ "apiVersion": "v1", "kind": "Pod", "spec": {} })),
+            Some(0)
+        );
+        assert_eq!(
+            count(json!({ "apiVersion": "v1", "kind": "Pod", "spec": { "volumes": null } })),
+            Some(0)
+        );
+        assert_eq!(count(Value::Null), Some(0));
+        assert_eq!(
+            count(json!({ "apiVersion": "v1", "kind": "Pod", "spec": { "volumes": {} } })),
+            None
+        );
+        assert_eq!(count_volumes(b"{\"request\": "), None);
+    }
+
+    /// Claims of the generated pods, the last one does not fit into the name part of a label key
+    const FUZZ_CLAIMS: [&str; 4] = [
+        "myvol1",
+        "data",
+        "logs",
+        "a-claim-name-that-is-much-too-long-for-the-name-part-of-a-label-key",
+    ];
+
+    /// Keys of the generated objects, so that fields of pods meet values of unexpected types
+    const FUZZ_KEYS: [&str; 14] = [
+        "podAffinity",
+        "podAntiAffinity",
+        "nodeAffinity",
+        "requiredDuringSchedulingIgnoredDuringExecution",
+        "preferredDuringSchedulingIgnoredDuringExecution",
+        "nodeSelectorTerms",
+        "matchExpressions",
+        "labelSelector",
+        "topologyKey",
+        "key",
+        "values",
+        "name",
+        "claimName",
+        "persistentVolumeClaim",
+    ];
+
+    /// Number of controllers of [fuzz_controller]
+    const FUZZ_CONTROLLERS: usize = 4;
+
+    fn arb_json() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            prop::sample::select(vec![
+                "",
+                "default",
+                "myvol1",
+                "In",
+                "kubernetes.io/hostname",
+                "~/ü\""
+            ])
+            .prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 24, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+                prop::collection::btree_map(prop::sample::select(FUZZ_KEYS.to_vec()), inner, 0..4)
+                    .prop_map(|members| {
+                        Value::Object(
+                            members
+                                .into_iter()
+                                .map(|(key, value)| (key.to_owned(), value))
+                                .collect(),
+                        )
+                    }),
+            ]
+        })
+    }
+
+    /// Pods mounting some of the claims, with parts that are missing, null or of any type
+    fn arb_pod() -> impl Strategy<Value = Value> {
+        let volume = prop::sample::select(FUZZ_CLAIMS.to_vec()).prop_map(
+            |claim| json!({ "name": claim, "persistentVolumeClaim": { "claimName": claim } }),
+        );
+        let volumes = prop_oneof![
+            4 => prop::collection::vec(volume, 0..4).prop_map(Value::from),
+            1 => arb_json(),
+        ];
+        let labels = prop_oneof![
+            4 => prop::collection::btree_map(
+                prop::sample::select(vec![
+                    "app",
+                    "default.gravivol.fonona.net/myvol1",
+                    GATE_LABEL
+                ]),
+                prop::sample::select(vec!["", "true", "web"]),
+                0..3,
+            )
+            .prop_map(|labels| json!(labels)),
+            1 => arb_json(),
+        ];
+        let annotations = prop::collection::btree_map(
+            prop::sample::select(vec![
+                CLAIMS_ANNOTATION,
+                PATCH_ANNOTATION,
+                TOPOLOGY_KEY_ANNOTATION,
+                ROLE_ANNOTATION,
+                MUTATED_ANNOTATION,
+            ]),
+            prop::sample::select(vec![
+                "",
+                "myvol1",
+                "myvol1,data",
+                "labels",
+                "affinity",
+                "anchor",
+                "zone",
+                "a/b/c",
+            ]),
+            0..3,
+        )
+        .prop_map(|annotations| json!(annotations));
+        let affinity = prop_oneof![
+            Just(json!({ "podAffinity": {} })),
+            Just(
+                json!({ "podAffinity": { "requiredDuringSchedulingIgnoredDuringExecution": null } })
+            ),
+            Just(
+                json!({ "nodeAffinity": { "requiredDuringSchedulingIgnoredDuringExecution": {} } })
+            ),
+            arb_json(),
+        ];
+        (
+            prop::option::of(prop::sample::select(vec!["default", "apps", ""])),
+            prop::option::of(labels),
+            prop::option::of(annotations),
+            prop::option::of(volumes),
+            prop::option::of(affinity),
+            prop::option::weighted(0.2, arb_json()),
+        )
+            .prop_map(
+                |(namespace, labels, annotations, volumes, affinity, node_selector)| {
+                    let mut pod = json!({
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "generateName": "web-" },
+                        "spec": { "containers": [{ "name": "web" }] },
+                    });
+                    for (pointer, value) in [
+                        (
+                            "/metadata",
+                            namespace.map(|namespace| ("namespace", json!(namespace))),
+                        ),
+                        ("/metadata", labels.map(|labels| ("labels", labels))),
+                        (
+                            "/metadata",
+                            annotations.map(|annotations| ("annotations", annotations)),
+                        ),
+                        ("/spec", volumes.map(|volumes| ("volumes", volumes))),
+                        ("/spec", affinity.map(|affinity| ("affinity", affinity))),
+                        (
+                            "/spec",
+                            node_selector.map(|node_selector| ("nodeSelector", node_selector)),
+                        ),
+                    ] {
+                        if let Some((key, value)) = value {
+                            pod.pointer_mut(pointer).unwrap()[key] = value;
+                        }
                     }
+                    pod
                 },
-                "topologyKey": "kubernetes.io/hostname",
-            }));
+            )
+    }
+
+    /// Controllers with different configs and options, the last ones placing pods on the nodes
+    /// of their volumes
+    fn fuzz_controller(index: usize) -> Controller {
+        let config = "default/myvol1,default/data:preferred,default/logs:anti-affinity,\
+                      apps/myvol1:spread,default/a-claim-name-that-is-much-too-long-for-the-name-part-of-a-label-key";
+        let controller = Controller::new(config);
+        match index {
+            0 => controller,
+            1 => controller.with_options(Options {
+                patch_mode: PatchMode::Both,
+                guarded_patch: true,
+                scheduling_gate: true,
+                stamp_annotation: true,
+                first_pod: FirstPodMode::Anchor,
+                group_by_owner: true,
+                replace_affinity_namespaces: HashSet::from(["default".to_owned()]),
+                ..Default::default()
+            }),
+            _ => {
+                let terms = if index == 2 {
+                    json!([{ "matchExpressions": [{ "key": "kubernetes.io/hostname", "operator": "In", "values": ["node-1"] }] }])
+                } else {
+                    json!([{ "matchExpressions": [{ "key": "topology.kubernetes.io/zone", "operator": "In", "values": ["a"] }] }])
+                };
+                controller
+                    .with_options(Options {
+                        node_affinity: NodeAffinityMode::Add,
+                        guarded_patch: index == 3,
+                        ..Default::default()
+                    })
+                    .with_cluster(Arc::new(stub_cluster_with_bound_volume(terms)))
+            }
         }
+    }
+
+    /// Pods of the shape the API server sends, with the parts gravivol patches partly present
+    fn arb_valid_pod() -> impl Strategy<Value = Value> {
+        let volume = prop::sample::select(FUZZ_CLAIMS.to_vec()).prop_map(
+            |claim| json!({ "name": claim, "persistentVolumeClaim": { "claimName": claim } }),
+        );
+        let term = json!({
+            "labelSelector": { "matchLabels": { "app": "db" } },
+            "topologyKey": "kubernetes.io/hostname",
+        });
+        let node_terms = json!({
+            "nodeSelectorTerms": [{
+                "matchExpressions": [{ "key": "disk", "operator": "In", "values": ["ssd"] }],
+            }],
+        });
+        let affinity = prop::sample::select(vec![
+            json!({}),
+            json!({ "podAffinity": {} }),
+            json!({ "podAffinity": { "requiredDuringSchedulingIgnoredDuringExecution": [term] } }),
+            json!({ "podAffinity": { "preferredDuringSchedulingIgnoredDuringExecution": [
+                { "weight": 10, "podAffinityTerm": term },
+            ] } }),
+            json!({ "podAntiAffinity": { "requiredDuringSchedulingIgnoredDuringExecution": [term] } }),
+            json!({ "nodeAffinity": { "requiredDuringSchedulingIgnoredDuringExecution": node_terms } }),
+        ]);
+        let labels = prop_oneof![
+            prop::collection::btree_map(
+                prop::sample::select(vec!["app", "tier"]),
+                prop::sample::select(vec!["web", "db"]),
+                0..2,
+            )
+            .prop_map(|labels| json!(labels)),
+            Just(Value::Null),
+        ];
+        (
+            prop::option::of(labels),
+            prop::collection::vec(volume, 0..4),
+            prop::option::of(affinity),
+        )
+            .prop_map(|(labels, volumes, affinity)| {
+                let mut pod = json!({
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "generateName": "web-", "namespace": "default" },
+                    "spec": { "containers": [{ "name": "web" }], "volumes": volumes },
+                });
+                if let Some(labels) = labels {
+                    pod["metadata"]["labels"] = labels;
+                }
+                if let Some(affinity) = affinity {
+                    pod["spec"]["affinity"] = affinity;
+                }
+                pod
+            })
+    }
 
-        let pod: Pod = serde_json::from_value(pod_before.to_owned()).unwrap();
-        let created_patch: Patch = serde_json::from_str(&create_patch(&pod, pvcs)).unwrap();
+    /// Sends the review through mutate, failing if it panics or returns a patch that does not
+    /// apply to the object of the review, returning the patched object if patched
+    fn patched_object(
+        controller: &Controller,
+        review: &Value,
+    ) -> Result<Option<Value>, TestCaseError> {
+        let object = review["request"]["object"].clone();
+        let Ok(parsed) = serde_json::from_value::<AdmissionReview>(review.clone()) else {
+            return Ok(None);
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let Ok(mutated) = runtime.block_on(controller.mutate(parsed)) else {
+            return Ok(None);
+        };
+        let Some(encoded) = mutated.response.and_then(|response| response.patch) else {
+            return Ok(None);
+        };
+        let created: Patch =
+            serde_json::from_slice(&BASE64_STANDARD.decode(encoded).unwrap()).unwrap();
+        let mut patched = object.clone();
+        let applied = patch(&mut patched, &created);
+        prop_assert!(applied.is_ok(), "{applied:?}: {created:?} on {object}");
+        Ok(Some(patched))
+    }
 
-        let mut pod_patched = pod_before.to_owned();
-        patch(&mut pod_patched, &created_patch).unwrap();
+    fn review_of(pod: &Value) -> Value {
+        json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": { "uid": "705ab4f5", "namespace": "default", "operation": "CREATE", "object": pod },
+        })
+    }
 
-        assert_eq!(pod_patched, pod_after);
+    proptest! {
+        #[test]
+        fn prop_mutate_pods(pod in arb_pod(), index in 0..FUZZ_CONTROLLERS) {
+            patched_object(&fuzz_controller(index), &review_of(&pod))?;
+        }
+
+        #[test]
+        fn prop_patched_pods(pod in arb_valid_pod(), index in 0..FUZZ_CONTROLLERS) {
+            let Some(patched) = patched_object(&fuzz_controller(index), &review_of(&pod))? else {
+                return Ok(());
+            };
+            let parsed = serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(patched.clone());
+            prop_assert!(parsed.is_ok(), "{parsed:?}: {patched}");
+            let labels = patched["metadata"]["labels"].as_object().cloned().unwrap_or_default();
+            for key in labels.keys().filter(|key| Label::is_claim_key(key, DEFAULT_LABEL_DOMAIN)) {
+                let (_, name) = key.split_once('/').unwrap();
+                prop_assert!(name.len() <= MAX_LABEL_NAME_LENGTH, "{key}");
+            }
+            // The claims of the config mounted in the namespace, all labeled with the default options
+            if index == 0 {
+                let mounted = pod["spec"]["volumes"].as_array().unwrap();
+                for claim in &FUZZ_CLAIMS[..3] {
+                    if mounted.iter().any(|volume| volume["name"] == *claim) {
+                        let key = format!("default.gravivol.fonona.net/{claim}");
+                        prop_assert_eq!(&labels[&key], "true", "{}", patched);
+                    }
+                }
+            }
+            // The pod matches its own required terms for claims, or it could never be scheduled
+            // as the first of its claims
+            let required = &patched["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"];
+            for term in required.as_array().into_iter().flatten() {
+                let match_labels = term["labelSelector"]["matchLabels"].as_object();
+                for (key, value) in match_labels.into_iter().flatten() {
+                    if Label::is_claim_key(key, DEFAULT_LABEL_DOMAIN) {
+                        prop_assert_eq!(labels.get(key), Some(value), "{}", patched);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn prop_mutate_bytes(
+            pod in arb_pod(),
+            edits in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 0..6),
+            index in 0..FUZZ_CONTROLLERS,
+        ) {
+            let mut body = serde_json::to_vec(&review_of(&pod)).unwrap();
+            for (position, byte) in edits {
+                let position = position.index(body.len());
+                body[position] = byte;
+            }
+            exceeds_depth(&body, 64);
+            request_uid(&body);
+            if let Ok(review) = serde_json::from_slice::<Value>(&body) {
+                patched_object(&fuzz_controller(index), &review)?;
+            }
+        }
     }
 }
@@ -0,0 +1,172 @@
+//! Recent decisions of the controller, for /debug/mutations
+
+use std::{collections::VecDeque, error::Error, sync::Mutex, time::Duration};
+
+use json_patch::PatchOperation;
+use serde::Serialize;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use crate::metrics::SkipReason;
+
+/// What the controller decided for an admission request, without the object itself
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Decision {
+    /// RFC 3339 time of the decision
+    pub timestamp: String,
+    /// Uid of the request
+    pub uid: String,
+    /// Namespace of the object
+    pub namespace: String,
+    /// Name of the object, or its generateName
+    pub name: String,
+    /// CREATE or UPDATE, empty if the request did not say
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub operation: String,
+    /// Username of the requester, empty if the request did not say
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub user: String,
+    /// Groups of the requester
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Claims of the object configured for co-location
+    pub claims: Vec<String>,
+    /// Why the object was admitted unchanged, None if patched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<SkipReason>,
+    /// Warnings returned to the client
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Operations of the returned patch, 0 without patch
+    pub patch_ops: usize,
+    /// Whether the request was a dry run
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+    /// The operations changing the object, without the test operations guarding them, only
+    /// kept for the audit log
+    #[serde(skip)]
+    pub patch: Vec<PatchOperation>,
+}
+
+impl Decision {
+    /// Sets the time of the decision to now
+    pub fn stamp(&mut self) {
+        self.timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+    }
+
+    /// Logs the decision at info level, the one line of an admission request unless debug
+    /// level is enabled for the steps leading to it
+    pub fn log(&self, elapsed: Duration, error: Option<&dyn Error>) {
+        let outcome = if error.is_some() {
+            "failed"
+        } else if self.patch_ops > 0 {
+            "patched"
+        } else if self.skip_reason.is_some() {
+            "skipped"
+        } else {
+            "admitted"
+        };
+        tracing::info!(
+            uid = %self.uid,
+            namespace = %self.namespace,
+            name = %self.name,
+            operation = %self.operation,
+            user = %self.user,
+            groups = %self.groups.join(","),
+            outcome,
+            claims = %self.claims.join(","),
+            skip_reason = self.skip_reason.map(|reason| reason.as_str()),
+            warnings = (!self.warnings.is_empty()).then(|| self.warnings.join("; ")),
+            patch_ops = self.patch_ops,
+            dry_run = self.dry_run,
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            error = error.map(tracing::field::display),
+            "Admission of {}/{} {outcome}",
+            self.namespace,
+            self.name
+        );
+    }
+}
+
+/// Bounded history of decisions, shared by all workers
+pub struct Decisions {
+    capacity: usize,
+    entries: Mutex<VecDeque<Decision>>,
+}
+
+impl Decisions {
+    /// Keeps the last capacity decisions
+    pub fn new(capacity: usize) -> Decisions {
+        Decisions {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Adds the decision, stamped with the current time unless it is, dropping the oldest one
+    /// if full
+    pub fn record(&self, mut decision: Decision) {
+        if self.capacity == 0 {
+            return;
+        }
+        if decision.timestamp.is_empty() {
+            decision.stamp();
+        }
+        decision.patch = Vec::new();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(decision);
+    }
+
+    /// Newest first, only those in the namespace if given
+    pub fn recent(&self, namespace: Option<&str>) -> Vec<Decision> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|decision| namespace.is_none_or(|namespace| decision.namespace == namespace))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decision(uid: &str, namespace: &str) -> Decision {
+        Decision {
+            uid: uid.to_owned(),
+            namespace: namespace.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ring() {
+        let decisions = Decisions::new(2);
+        decisions.record(decision("1", "default"));
+        decisions.record(decision("2", "apps"));
+        decisions.record(decision("3", "default"));
+        let uids = |namespace| {
+            decisions
+                .recent(namespace)
+                .into_iter()
+                .map(|decision| decision.uid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(uids(None), ["3", "2"]);
+        assert_eq!(uids(Some("default")), ["3"]);
+        assert!(uids(Some("other")).is_empty());
+        assert!(!decisions.recent(None)[0].timestamp.is_empty());
+
+        let disabled = Decisions::new(0);
+        disabled.record(decision("1", "default"));
+        assert!(disabled.recent(None).is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+//! Kubernetes Events on the pods that got a patch
+
+use std::time::Duration;
+
+use k8s_openapi::{
+    api::{
+        core::v1::{ObjectReference, Pod},
+        events::v1::Event,
+    },
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+    chrono::{DateTime, Utc},
+};
+use kube::{Api, Client, api::PostParams};
+use tracing::Instrument;
+
+use crate::rate_limit::RateLimiter;
+
+/// Reason of the events, for kubectl get events --field-selector reason=VolumeColocation
+pub const EVENT_REASON: &str = "VolumeColocation";
+/// Reason of the events on pods the audit found without the labels of their claims
+pub const MISSING_REASON: &str = "MissingColocation";
+const REPORTING_CONTROLLER: &str = "gravivol.fonona.net/webhook";
+/// Seconds between events on the same pod, e.g. recreated under the same name in a loop
+const EVENT_INTERVAL_SECS: f64 = 60.0;
+/// Wait for the admitted pod to be stored, so that the event references its uid
+const POD_LOOKUP_DELAY: Duration = Duration::from_secs(2);
+
+/// Creates events in the background, at most one per pod and interval
+pub struct EventRecorder {
+    client: Client,
+    /// Reporting instance, the name of the gravivol pod
+    instance: String,
+    limiter: RateLimiter,
+}
+
+impl EventRecorder {
+    pub fn new(client: Client, instance: &str) -> EventRecorder {
+        EventRecorder {
+            client,
+            instance: instance.to_owned(),
+            limiter: RateLimiter::new(1.0 / EVENT_INTERVAL_SECS, 1),
+        }
+    }
+
+    /// Reports the claims the pod is co-located by, never failing the admission
+    pub fn pod_mutated(&self, namespace: &str, name: &str, claims: &[String]) {
+        if !self.limiter.try_acquire(&format!("{namespace}/{name}")) {
+            tracing::debug!("Not creating another event on pod {namespace}/{name} yet");
+            return;
+        }
+        let mut event = event(
+            namespace,
+            name,
+            "Normal",
+            EVENT_REASON,
+            format!(
+                "gravivol added co-location affinity for claims {}",
+                claims.join(", ")
+            ),
+            &self.instance,
+            Utc::now(),
+        );
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let events: Api<Event> = Api::namespaced(self.client.clone(), namespace);
+        let (name, display_name) = (name.to_owned(), format!("{namespace}/{name}"));
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(POD_LOOKUP_DELAY).await;
+                // kubectl describe only shows events with the uid of the pod
+                match pods.get_opt(&name).await {
+                    Ok(Some(pod)) => {
+                        if let Some(regarding) = &mut event.regarding {
+                            regarding.uid = pod.metadata.uid;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => tracing::debug!("Cannot look up pod {display_name}: {err}"),
+                }
+                if let Err(err) = events.create(&PostParams::default(), &event).await {
+                    tracing::warn!("Cannot create event on pod {display_name}: {err}");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Reports a stored pod that lacks the labels of the claims
+    pub async fn pod_unmutated(&self, pod: &ObjectReference, claims: &[String]) {
+        let (Some(namespace), Some(name)) = (&pod.namespace, &pod.name) else {
+            return;
+        };
+        if !self.limiter.try_acquire(&format!("{namespace}/{name}")) {
+            return;
+        }
+        let mut event = event(
+            namespace,
+            name,
+            "Warning",
+            MISSING_REASON,
+            format!(
+                "gravivol did not mutate the pod, it lacks the labels of claims {}",
+                claims.join(", ")
+            ),
+            &self.instance,
+            Utc::now(),
+        );
+        event.action = Some("Audit".to_owned());
+        event.regarding = Some(pod.clone());
+        let events: Api<Event> = Api::namespaced(self.client.clone(), namespace);
+        if let Err(err) = events.create(&PostParams::default(), &event).await {
+            tracing::warn!("Cannot create event on pod {namespace}/{name}: {err}");
+        }
+    }
+}
+
+/// Event on the pod of the given name
+fn event(
+    namespace: &str,
+    name: &str,
+    type_: &str,
+    reason: &str,
+    note: String,
+    instance: &str,
+    now: DateTime<Utc>,
+) -> Event {
+    Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{name}.")),
+            namespace: Some(namespace.to_owned()),
+            ..ObjectMeta::default()
+        },
+        event_time: Some(MicroTime(now)),
+        action: Some("Mutate".to_owned()),
+        reason: Some(reason.to_owned()),
+        note: Some(note),
+        type_: Some(type_.to_owned()),
+        regarding: Some(ObjectReference {
+            api_version: Some("v1".to_owned()),
+            kind: Some("Pod".to_owned()),
+            namespace: Some(namespace.to_owned()),
+            name: Some(name.to_owned()),
+            ..ObjectReference::default()
+        }),
+        reporting_controller: Some(REPORTING_CONTROLLER.to_owned()),
+        reporting_instance: Some(instance.to_owned()),
+        ..Event::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        let now = DateTime::from_timestamp(1_792_152_000, 0).unwrap();
+        let event = event(
+            "default",
+            "web-0",
+            "Normal",
+            EVENT_REASON,
+            "gravivol added co-location affinity for claims data, logs".to_owned(),
+            "gravivol-7d4b9c-x2x4z",
+            now,
+        );
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            json!({
+                "apiVersion": "events.k8s.io/v1",
+                "kind": "Event",
+                "metadata": { "generateName": "web-0.", "namespace": "default" },
+                "eventTime": "2026-10-16T12:00:00.000000Z",
+                "action": "Mutate",
+                "reason": "VolumeColocation",
+                "note": "gravivol added co-location affinity for claims data, logs",
+                "type": "Normal",
+                "regarding": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "namespace": "default",
+                    "name": "web-0",
+                },
+                "reportingController": "gravivol.fonona.net/webhook",
+                "reportingInstance": "gravivol-7d4b9c-x2x4z",
+            })
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use json_patch::Patch as JsonPatch;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    Api, Client, ResourceExt,
+    api::{ListParams, Patch, PatchParams},
+};
+use serde_json::json;
+
+/// Scheduling gate of follower pods waiting for a scheduled anchor pod
+pub const SCHEDULING_GATE: &str = "gravivol.fonona.net/awaiting-anchor";
+/// Label marking gated pods, the annotation of the same name holds the selector of the anchor pods
+pub const GATE_LABEL: &str = "gravivol.fonona.net/awaiting-anchor";
+
+/// Periodically removes the scheduling gate of pods whose anchor pod is scheduled
+///
+/// Only run by the leader, see [crate::leader::while_leading], so that replicas do not race.
+pub async fn run(client: Client, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = release_gated_pods(&client).await {
+            tracing::error!("Cannot list gated pods: {err}");
+        }
+    }
+}
+
+async fn release_gated_pods(client: &Client) -> Result<(), kube::Error> {
+    let all_pods: Api<Pod> = Api::all(client.clone());
+    for pod in all_pods
+        .list(&ListParams::default().labels(GATE_LABEL))
+        .await?
+    {
+        let (Some(namespace), Some(index)) = (pod.namespace(), gate_index(&pod)) else {
+            continue;
+        };
+        let name = pod.name_any();
+        let Some(selector) = pod.annotations().get(GATE_LABEL) else {
+            tracing::warn!("Gated pod {namespace}/{name} has no anchor selector");
+            continue;
+        };
+
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+        let anchors = match pods
+            .list_metadata(
+                &ListParams::default()
+                    .labels(selector)
+                    .fields("spec.nodeName!=")
+                    .limit(1),
+            )
+            .await
+        {
+            Ok(anchors) => anchors,
+            Err(err) => {
+                tracing::error!("Cannot look up anchor pods of {namespace}/{name}: {err}");
+                continue;
+            }
+        };
+        if anchors.items.is_empty() {
+            tracing::debug!("Pod {namespace}/{name} is still waiting for an anchor pod");
+            continue;
+        }
+
+        match pods
+            .patch(
+                &name,
+                &PatchParams::default(),
+                &Patch::<()>::Json(release_patch(index)),
+            )
+            .await
+        {
+            Ok(_) => tracing::info!("Removed scheduling gate of pod {namespace}/{name}"),
+            Err(err) => {
+                tracing::error!("Cannot remove scheduling gate of pod {namespace}/{name}: {err}")
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Position of the gravivol gate in the scheduling gates of the pod
+fn gate_index(pod: &Pod) -> Option<usize> {
+    pod.spec
+        .as_ref()?
+        .scheduling_gates
+        .as_ref()?
+        .iter()
+        .position(|gate| gate.name == SCHEDULING_GATE)
+}
+
+/// Removes the gate at the index and the gate label, failing if the gates changed meanwhile
+fn release_patch(index: usize) -> JsonPatch {
+    serde_json::from_value(json!([
+        {
+            "op": "test",
+            "path": format!("/spec/schedulingGates/{index}/name"),
+            "value": SCHEDULING_GATE,
+        },
+        { "op": "remove", "path": format!("/spec/schedulingGates/{index}") },
+        { "op": "remove", "path": "/metadata/labels/gravivol.fonona.net~1awaiting-anchor" },
+    ]))
+    .expect("Valid patch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_patch() {
+        let mut pod = json!({
+            "metadata": {
+                "labels": { "app": "web", "gravivol.fonona.net/awaiting-anchor": "true" }
+            },
+            "spec": {
+                "schedulingGates": [
+                    { "name": "example.com/other" },
+                    { "name": "gravivol.fonona.net/awaiting-anchor" },
+                ]
+            }
+        });
+        let typed_pod: Pod = serde_json::from_value(pod.clone()).unwrap();
+        let index = gate_index(&typed_pod).unwrap();
+        assert_eq!(index, 1);
+
+        json_patch::patch(&mut pod, &release_patch(index)).unwrap();
+        assert_eq!(
+            pod,
+            json!({
+                "metadata": { "labels": { "app": "web" } },
+                "spec": { "schedulingGates": [{ "name": "example.com/other" }] }
+            })
+        );
+        assert!(json_patch::patch(&mut pod, &release_patch(0)).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use rustls::pki_types::CertificateDer;
+use serde::Serialize;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use x509_parser::prelude::{ASN1Time, FromDer, X509Certificate};
+
+/// How long the API server may take to answer the readiness check
+const CLIENT_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Validity period of a certificate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Validity {
+    not_before: ASN1Time,
+    not_after: ASN1Time,
+}
+
+impl Validity {
+    pub fn from_der(cert: &CertificateDer) -> Result<Validity, String> {
+        let (_, cert) = X509Certificate::from_der(cert)
+            .map_err(|err| format!("cannot parse certificate: {err}"))?;
+        Ok(Validity {
+            not_before: cert.validity().not_before,
+            not_after: cert.validity().not_after,
+        })
+    }
+
+    pub fn not_after(&self) -> ASN1Time {
+        self.not_after
+    }
+
+    /// Seconds from now until the expiry, negative once expired
+    pub fn expires_in(&self, now: ASN1Time) -> i64 {
+        self.not_after.timestamp() - now.timestamp()
+    }
+
+    fn check(&self, now: ASN1Time) -> Result<(), String> {
+        if now < self.not_before {
+            Err(format!(
+                "certificate is not valid before {}",
+                self.not_before
+            ))
+        } else if now > self.not_after {
+            Err(format!("certificate expired at {}", self.not_after))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Result of a readiness check
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    /// Reported without failing readiness
+    Degraded {
+        message: String,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+impl From<Result<(), String>> for CheckStatus {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => CheckStatus::Ok,
+            Err(message) => CheckStatus::Failed { message },
+        }
+    }
+}
+
+/// Body of /readyz
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    /// "ready" if all checks pass, "degraded" if some are degraded, "unavailable" if one failed
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, CheckStatus>,
+    pub uptime_seconds: u64,
+    /// RFC 3339 time of the last config load
+    pub last_config_reload: Option<String>,
+    /// RFC 3339 time of the last admission request received
+    pub last_request: Option<String>,
+    /// RFC 3339 time of the last patch returned
+    pub last_mutation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate_not_after: Option<String>,
+    /// RFC 3339 time the caBundle of the registered webhook was last updated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_ca_bundle_sync: Option<String>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        !self
+            .checks
+            .values()
+            .any(|status| matches!(status, CheckStatus::Failed { .. }))
+    }
+}
+
+/// Time in RFC 3339, None if out of range
+pub fn format_time(time: OffsetDateTime) -> Option<String> {
+    time.format(&Rfc3339).ok()
+}
+
+/// State that decides whether the webhook can serve admission requests
+pub struct Health {
+    started: Instant,
+    /// None when serving without a certificate
+    certificate: RwLock<Option<Validity>>,
+    /// Error of the last config load
+    config_error: RwLock<Option<String>>,
+    config_loaded_at: RwLock<Option<OffsetDateTime>>,
+    ca_bundle_synced_at: RwLock<Option<OffsetDateTime>>,
+    /// Error of the last caBundle update, None after a successful one
+    ca_bundle_error: RwLock<Option<String>>,
+    client: Option<kube::Client>,
+    /// Set on termination, so that the endpoint is removed while requests drain
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub fn new(client: Option<kube::Client>) -> Health {
+        Health {
+            started: Instant::now(),
+            certificate: RwLock::default(),
+            config_error: RwLock::default(),
+            config_loaded_at: RwLock::default(),
+            ca_bundle_synced_at: RwLock::default(),
+            ca_bundle_error: RwLock::default(),
+            client,
+            shutting_down: AtomicBool::default(),
+        }
+    }
+
+    pub fn set_certificate(&self, validity: Validity) {
+        *self.certificate.write().unwrap() = Some(validity);
+    }
+
+    pub fn certificate(&self) -> Option<Validity> {
+        *self.certificate.read().unwrap()
+    }
+
+    pub fn set_config_result(&self, result: Result<(), String>) {
+        *self.config_error.write().unwrap() = result.err();
+        *self.config_loaded_at.write().unwrap() = Some(OffsetDateTime::now_utc());
+    }
+
+    /// Result of registering the webhook or updating its caBundle
+    pub fn set_ca_bundle_result(&self, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                *self.ca_bundle_error.write().unwrap() = None;
+                *self.ca_bundle_synced_at.write().unwrap() = Some(OffsetDateTime::now_utc());
+            }
+            Err(message) => *self.ca_bundle_error.write().unwrap() = Some(message),
+        }
+    }
+
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    /// Status of each check, the certificate only with TLS and the API server only when used
+    pub async fn checks(&self) -> BTreeMap<&'static str, CheckStatus> {
+        let mut checks = BTreeMap::new();
+        let shutdown = if self.shutting_down.load(Ordering::Relaxed) {
+            Err("shutting down".to_owned())
+        } else {
+            Ok(())
+        };
+        checks.insert("shutdown", shutdown.into());
+        if let Some(validity) = *self.certificate.read().unwrap() {
+            checks.insert("tls_cert", validity.check(ASN1Time::now()).into());
+        }
+        let config = match self.config_error.read().unwrap().clone() {
+            Some(message) => Err(message),
+            None => Ok(()),
+        };
+        checks.insert("config", config.into());
+        // The webhook keeps serving with a stale caBundle, the API server is what fails
+        if let Some(message) = self.ca_bundle_error.read().unwrap().clone() {
+            checks.insert("ca_bundle", CheckStatus::Degraded { message });
+        } else if self.ca_bundle_synced_at.read().unwrap().is_some() {
+            checks.insert("ca_bundle", CheckStatus::Ok);
+        }
+        if let Some(client) = &self.client {
+            let result = match tokio::time::timeout(
+                CLIENT_CHECK_TIMEOUT,
+                client.apiserver_version(),
+            )
+            .await
+            {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(format!("API server is not reachable: {err}")),
+                Err(_) => Err("API server did not answer in time".to_owned()),
+            };
+            checks.insert("kube_api", result.into());
+        }
+        checks
+    }
+
+    /// The checks with the state of the webhook, last_request and last_mutation as Unix time
+    ///
+    /// A webhook without requests stays ready, the API server may just not call it, see
+    /// [IdleWarning].
+    pub async fn readiness(
+        &self,
+        last_request: Option<i64>,
+        last_mutation: Option<i64>,
+    ) -> Readiness {
+        let format_unix = |timestamp: i64| {
+            OffsetDateTime::from_unix_timestamp(timestamp)
+                .ok()
+                .and_then(format_time)
+        };
+        let checks = self.checks().await;
+        let mut readiness = Readiness {
+            status: "ready",
+            checks,
+            uptime_seconds: self.started.elapsed().as_secs(),
+            last_config_reload: self.config_loaded_at.read().unwrap().and_then(format_time),
+            last_request: last_request.and_then(format_unix),
+            last_mutation: last_mutation.and_then(format_unix),
+            certificate_not_after: self
+                .certificate()
+                .map(|validity| validity.not_after().to_string()),
+            last_ca_bundle_sync: self
+                .ca_bundle_synced_at
+                .read()
+                .unwrap()
+                .and_then(format_time),
+        };
+        if !readiness.is_ready() {
+            readiness.status = "unavailable";
+        } else if readiness
+            .checks
+            .values()
+            .any(|status| matches!(status, CheckStatus::Degraded { .. }))
+        {
+            readiness.status = "degraded";
+        }
+        readiness
+    }
+}
+
+/// Warns when no admission request arrived for a while, e.g. as the webhook configuration
+/// points elsewhere or selects nothing, repeated after the same time while it lasts
+pub struct IdleWarning {
+    threshold: Duration,
+    /// Unix time the process started, it is not idle before the threshold passed
+    started: i64,
+    /// Unix time of the last warning
+    last: Option<i64>,
+}
+
+impl IdleWarning {
+    /// Between two checks of the time of the last request
+    pub const CHECK_EVERY: Duration = Duration::from_secs(60);
+
+    pub fn new(threshold: Duration) -> IdleWarning {
+        IdleWarning {
+            threshold,
+            started: OffsetDateTime::now_utc().unix_timestamp(),
+            last: None,
+        }
+    }
+
+    /// Warns if due, last_request as Unix time, 0 if there was none yet
+    pub fn check(&mut self, last_request: i64) {
+        let Some(idle) = self.due(OffsetDateTime::now_utc().unix_timestamp(), last_request) else {
+            return;
+        };
+        if last_request > 0 {
+            tracing::warn!(
+                "No admission request received for {} minutes, check the webhook configuration and its service",
+                idle / 60
+            );
+        } else {
+            tracing::warn!(
+                "No admission request received since the start {} minutes ago, check the webhook configuration and its service",
+                idle / 60
+            );
+        }
+    }
+
+    /// Seconds without requests to warn about at now, None if not idle or warned about lately
+    fn due(&mut self, now: i64, last_request: i64) -> Option<i64> {
+        let idle = now - last_request.max(self.started);
+        let threshold = self.threshold.as_secs() as i64;
+        if idle < threshold {
+            self.last = None;
+            return None;
+        }
+        match self.last {
+            Some(last) if now - last < threshold => None,
+            _ => {
+                self.last = Some(now);
+                Some(idle)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validity(not_before: i64, not_after: i64) -> Validity {
+        Validity {
+            not_before: ASN1Time::from_timestamp(not_before).unwrap(),
+            not_after: ASN1Time::from_timestamp(not_after).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_validity_check() {
+        let validity = validity(100, 200);
+        let at = |secs| ASN1Time::from_timestamp(secs).unwrap();
+        assert!(validity.check(at(150)).is_ok());
+        assert_eq!(
+            validity.check(at(50)).unwrap_err(),
+            "certificate is not valid before Jan  1 00:01:40 1970 +00:00"
+        );
+        assert_eq!(
+            validity.check(at(250)).unwrap_err(),
+            "certificate expired at Jan  1 00:03:20 1970 +00:00"
+        );
+    }
+
+    fn failed(message: &str) -> CheckStatus {
+        CheckStatus::Failed {
+            message: message.to_owned(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_checks() {
+        let health = Health::new(None);
+        assert_eq!(
+            health.checks().await,
+            BTreeMap::from([("config", CheckStatus::Ok), ("shutdown", CheckStatus::Ok)])
+        );
+        let readiness = health.readiness(None, None).await;
+        assert!(readiness.is_ready());
+        assert_eq!(readiness.status, "ready");
+        assert!(readiness.last_config_reload.is_none());
+
+        health.set_certificate(validity(0, 1));
+        health.set_config_result(Err("invalid entry".to_owned()));
+        assert_eq!(
+            health.checks().await,
+            BTreeMap::from([
+                ("config", failed("invalid entry")),
+                ("shutdown", CheckStatus::Ok),
+                (
+                    "tls_cert",
+                    failed("certificate expired at Jan  1 00:00:01 1970 +00:00")
+                ),
+            ])
+        );
+        let readiness = health
+            .readiness(Some(1_792_152_600), Some(1_792_152_000))
+            .await;
+        assert_eq!(readiness.status, "unavailable");
+        assert!(readiness.last_config_reload.is_some());
+        assert_eq!(
+            readiness.last_request.as_deref(),
+            Some("2026-10-16T12:10:00Z")
+        );
+        assert_eq!(
+            readiness.last_mutation.as_deref(),
+            Some("2026-10-16T12:00:00Z")
+        );
+
+        health.set_config_result(Ok(()));
+        assert_eq!(health.checks().await["config"], CheckStatus::Ok);
+
+        health.set_shutting_down();
+        assert_eq!(health.checks().await["shutdown"], failed("shutting down"));
+    }
+
+    #[actix_web::test]
+    async fn test_ca_bundle() {
+        let health = Health::new(None);
+        assert!(!health.checks().await.contains_key("ca_bundle"));
+        assert!(
+            health
+                .readiness(None, None)
+                .await
+                .last_ca_bundle_sync
+                .is_none()
+        );
+
+        health.set_ca_bundle_result(Ok(()));
+        assert_eq!(health.checks().await["ca_bundle"], CheckStatus::Ok);
+        assert!(
+            health
+                .readiness(None, None)
+                .await
+                .last_ca_bundle_sync
+                .is_some()
+        );
+
+        health.set_ca_bundle_result(Err("Cannot apply".to_owned()));
+        assert_eq!(
+            health.checks().await["ca_bundle"],
+            CheckStatus::Degraded {
+                message: "Cannot apply".to_owned()
+            }
+        );
+        let readiness = health.readiness(None, None).await;
+        assert_eq!(readiness.status, "degraded");
+        // The last success is kept
+        assert!(readiness.last_ca_bundle_sync.is_some());
+    }
+
+    #[test]
+    fn test_idle_warning() {
+        let hour = 60 * 60;
+        let started = 1_792_152_000;
+        let mut warning = IdleWarning {
+            threshold: Duration::from_secs(hour as u64),
+            started,
+            last: None,
+        };
+        // Not before the process was up for the threshold
+        assert_eq!(warning.due(started + hour / 2, 0), None);
+        assert_eq!(warning.due(started + hour, 0), Some(hour));
+        assert_eq!(warning.due(started + hour + 60, 0), None);
+        // Repeated while idle
+        assert_eq!(warning.due(started + 2 * hour, 0), Some(2 * hour));
+
+        let request = started + 2 * hour + 60;
+        assert_eq!(warning.due(request + 60, request), None);
+        assert_eq!(warning.due(request + hour - 1, request), None);
+        assert_eq!(warning.due(request + hour, request), Some(hour));
+    }
+
+    /// Certificate valid from a day ago for the given duration
+    fn generated_validity(valid_for: time::Duration) -> Validity {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        params.not_before = time::OffsetDateTime::now_utc() - time::Duration::days(1);
+        params.not_after = params.not_before + valid_for;
+        Validity::from_der(params.self_signed(&key).unwrap().der()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_short_lived_certificate() {
+        let health = Health::new(None);
+        let validity = generated_validity(time::Duration::days(2));
+        let expires_in = validity.expires_in(ASN1Time::now());
+        assert!((86_000..=86_400).contains(&expires_in), "{expires_in}");
+        health.set_certificate(validity);
+        assert_eq!(health.checks().await["tls_cert"], CheckStatus::Ok);
+
+        let validity = generated_validity(time::Duration::days(1) - time::Duration::minutes(1));
+        assert!(validity.expires_in(ASN1Time::now()) < 0);
+        health.set_certificate(validity);
+        assert!(matches!(
+            health.checks().await["tls_cert"],
+            CheckStatus::Failed { .. }
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+//! Connection to the Kubernetes API, throttled so that webhook bursts do not flood it
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use kube::{
+    Client, Config,
+    client::ClientBuilder,
+    config::{KubeConfigOptions, Kubeconfig},
+};
+use tower::{Layer, Service};
+
+use crate::rate_limit::RateLimiter;
+
+/// Connects with the given kubeconfig, in cluster or with the default kubeconfig otherwise
+///
+/// Returns the client and a description of the API server and where it was configured. Requests
+/// are throttled to qps per second and burst at once, unless qps is 0.
+pub async fn connect(
+    kubeconfig: Option<&str>,
+    qps: f64,
+    burst: u32,
+) -> Result<(Client, String), String> {
+    let (config, source) = config(kubeconfig).await?;
+    let description = format!("{} via {source}", config.cluster_url);
+    let builder = ClientBuilder::try_from(config).map_err(|err| err.to_string())?;
+    let client = if qps > 0.0 {
+        builder
+            .with_layer(&ThrottleLayer(Arc::new(RateLimiter::new(qps, burst))))
+            .build()
+    } else {
+        builder.build()
+    };
+    Ok((client, description))
+}
+
+/// The config and where it came from
+async fn config(kubeconfig: Option<&str>) -> Result<(Config, String), String> {
+    if let Some(path) = kubeconfig {
+        let file = Kubeconfig::read_from(path).map_err(|err| err.to_string())?;
+        let config = Config::from_custom_kubeconfig(file, &KubeConfigOptions::default())
+            .await
+            .map_err(|err| format!("Invalid kubeconfig {path}: {err}"))?;
+        return Ok((config, format!("GRAVIVOL_KUBECONFIG {path}")));
+    }
+    let in_cluster = match Config::incluster() {
+        Ok(config) => return Ok((config, "in-cluster service account".to_owned())),
+        Err(err) => err,
+    };
+    match Config::from_kubeconfig(&KubeConfigOptions::default()).await {
+        Ok(config) => {
+            let source = match std::env::var("KUBECONFIG") {
+                Ok(path) if !path.is_empty() => format!("KUBECONFIG {path}"),
+                _ => "~/.kube/config".to_owned(),
+            };
+            Ok((config, source))
+        }
+        Err(err) => Err(format!(
+            "not in a cluster ({in_cluster}) and no kubeconfig ({err})"
+        )),
+    }
+}
+
+struct ThrottleLayer(Arc<RateLimiter>);
+
+impl<S> Layer<S> for ThrottleLayer {
+    type Service = Throttle<S>;
+
+    fn layer(&self, inner: S) -> Throttle<S> {
+        Throttle {
+            inner,
+            limiter: self.0.clone(),
+        }
+    }
+}
+
+/// Delays requests beyond the rate of the limiter, rather than failing them
+struct Throttle<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, R> Service<R> for Throttle<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let wait = self.limiter.reserve("kube");
+        // The request is only sent when its future is polled, after the wait
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            if !wait.is_zero() {
+                tracing::trace!("Throttling Kubernetes API request for {wait:?}");
+                tokio::time::sleep(wait).await;
+            }
+            response.await
+        })
+    }
+}
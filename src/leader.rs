@@ -0,0 +1,261 @@
+//! Lease based leader election, so that only one replica runs the background tasks
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+    chrono::{DateTime, Utc},
+};
+use kube::{Api, Client, api::PostParams};
+use tokio::sync::watch;
+
+use crate::metrics::Metrics;
+
+/// Competes for the Lease of the given name as identity, e.g. the pod name
+pub struct Election {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    /// How long the lease is valid after each renewal
+    duration: Duration,
+}
+
+impl Election {
+    pub fn new(
+        client: Client,
+        namespace: &str,
+        name: &str,
+        identity: &str,
+        duration: Duration,
+    ) -> Election {
+        Election {
+            api: Api::namespaced(client, namespace),
+            name: name.to_owned(),
+            identity: identity.to_owned(),
+            duration,
+        }
+    }
+
+    /// Renews or acquires the lease every third of its duration, reporting leadership to the sender
+    ///
+    /// Leadership is given up if the lease could not be renewed for two thirds of its duration,
+    /// before another replica may take it over.
+    pub async fn run(self, leader: watch::Sender<bool>, metrics: Arc<Metrics>) {
+        let deadline = self.duration * 2 / 3;
+        let mut ticker = tokio::time::interval(self.duration / 3);
+        let mut renewed: Option<Instant> = None;
+        metrics.set_leader(false);
+        loop {
+            ticker.tick().await;
+            match self.try_lead().await {
+                Ok(true) => renewed = Some(Instant::now()),
+                Ok(false) => renewed = None,
+                Err(err) => tracing::error!("Cannot renew or acquire lease {}: {err}", self.name),
+            }
+            let leading = renewed.is_some_and(|at| at.elapsed() < deadline);
+            if leading != *leader.borrow() {
+                if leading {
+                    tracing::info!("Acquired lease {} as {}", self.name, self.identity);
+                } else {
+                    tracing::warn!("Lost lease {}, stopping the background tasks", self.name);
+                }
+                metrics.set_leader(leading);
+                leader.send_replace(leading);
+            }
+        }
+    }
+
+    /// Whether the lease is held after the attempt, false if another replica won the race
+    async fn try_lead(&self) -> Result<bool, kube::Error> {
+        let lease = self.api.get_opt(&self.name).await?;
+        let current = lease.as_ref().and_then(|lease| lease.spec.as_ref());
+        let Some(spec) = next_spec(current, &self.identity, Utc::now(), self.duration) else {
+            if let Some(holder) = current.and_then(|spec| spec.holder_identity.as_deref()) {
+                tracing::debug!("Lease {} is held by {holder}", self.name);
+            }
+            return Ok(false);
+        };
+        let result = match lease {
+            // The resource version makes the replace fail if another replica changed the lease
+            Some(lease) => {
+                self.api
+                    .replace(
+                        &self.name,
+                        &PostParams::default(),
+                        &Lease {
+                            metadata: lease.metadata,
+                            spec: Some(spec),
+                        },
+                    )
+                    .await
+            }
+            None => {
+                self.api
+                    .create(
+                        &PostParams::default(),
+                        &Lease {
+                            metadata: ObjectMeta {
+                                name: Some(self.name.clone()),
+                                ..ObjectMeta::default()
+                            },
+                            spec: Some(spec),
+                        },
+                    )
+                    .await
+            }
+        };
+        match result {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(status)) if status.code == 409 => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The lease held by the identity, None if another holder's lease has not expired yet
+fn next_spec(
+    current: Option<&LeaseSpec>,
+    identity: &str,
+    now: DateTime<Utc>,
+    duration: Duration,
+) -> Option<LeaseSpec> {
+    let holder = current.and_then(|spec| spec.holder_identity.as_deref());
+    let renewed = |spec: &LeaseSpec| {
+        spec.renew_time
+            .as_ref()
+            .or(spec.acquire_time.as_ref())
+            .map(|time| time.0)
+    };
+    let lease_seconds = duration.as_secs().try_into().unwrap_or(i32::MAX);
+    match current {
+        Some(spec) if holder == Some(identity) => Some(LeaseSpec {
+            renew_time: Some(MicroTime(now)),
+            lease_duration_seconds: Some(lease_seconds),
+            ..spec.clone()
+        }),
+        Some(spec)
+            if holder.is_some_and(|holder| !holder.is_empty())
+                && renewed(spec).is_some_and(|renewed| {
+                    let valid = i64::from(spec.lease_duration_seconds.unwrap_or(lease_seconds));
+                    now.timestamp() < renewed.timestamp() + valid
+                }) =>
+        {
+            None
+        }
+        _ => Some(LeaseSpec {
+            holder_identity: Some(identity.to_owned()),
+            lease_duration_seconds: Some(lease_seconds),
+            acquire_time: Some(MicroTime(now)),
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(
+                current
+                    .and_then(|spec| spec.lease_transitions)
+                    .map_or(0, |transitions| transitions + 1),
+            ),
+            ..LeaseSpec::default()
+        }),
+    }
+}
+
+/// Runs the task while the receiver reports leadership, dropping it when leadership is lost
+///
+/// The task is started again on regaining leadership.
+pub async fn while_leading<F, Fut>(mut leader: watch::Receiver<bool>, name: &str, task: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        if leader.wait_for(|leading| *leading).await.is_err() {
+            return;
+        }
+        tracing::debug!("Starting {name} as leader");
+        tokio::select! {
+            () = task() => return,
+            lost = leader.wait_for(|leading| !*leading) => {
+                if lost.is_err() {
+                    return;
+                }
+                tracing::info!("Stopped {name} as this replica is no longer leader");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn test_next_spec() {
+        let duration = Duration::from_secs(15);
+        let created = next_spec(None, "gravivol-0", at(100), duration).unwrap();
+        assert_eq!(created.holder_identity.as_deref(), Some("gravivol-0"));
+        assert_eq!(created.lease_duration_seconds, Some(15));
+        assert_eq!(created.lease_transitions, Some(0));
+
+        // Renewed by the holder, kept by others until it expires
+        let renewed = next_spec(Some(&created), "gravivol-0", at(110), duration).unwrap();
+        assert_eq!(renewed.renew_time, Some(MicroTime(at(110))));
+        assert_eq!(renewed.acquire_time, Some(MicroTime(at(100))));
+        assert!(next_spec(Some(&renewed), "gravivol-1", at(124), duration).is_none());
+
+        let acquired = next_spec(Some(&renewed), "gravivol-1", at(125), duration).unwrap();
+        assert_eq!(acquired.holder_identity.as_deref(), Some("gravivol-1"));
+        assert_eq!(acquired.acquire_time, Some(MicroTime(at(125))));
+        assert_eq!(acquired.lease_transitions, Some(1));
+
+        // Released leases are free
+        let released = LeaseSpec {
+            holder_identity: None,
+            ..acquired
+        };
+        assert!(next_spec(Some(&released), "gravivol-0", at(126), duration).is_some());
+    }
+
+    /// Reports the task being dropped
+    struct Stopped(tokio::sync::mpsc::UnboundedSender<&'static str>);
+
+    impl Drop for Stopped {
+        fn drop(&mut self) {
+            let _ = self.0.send("stopped");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_while_leading() {
+        let (sender, leader) = watch::channel(false);
+        let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            while_leading(leader, "test", || {
+                let events = events.clone();
+                async move {
+                    events.send("started").unwrap();
+                    let _stopped = Stopped(events);
+                    std::future::pending::<()>().await
+                }
+            })
+            .await
+        });
+        sender.send_replace(true);
+        assert_eq!(received.recv().await, Some("started"));
+        sender.send_replace(false);
+        assert_eq!(received.recv().await, Some("stopped"));
+        sender.send_replace(true);
+        assert_eq!(received.recv().await, Some("started"));
+
+        // Ends with the election
+        drop(sender);
+        task.await.unwrap();
+        assert_eq!(received.recv().await, Some("stopped"));
+    }
+}
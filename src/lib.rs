@@ -0,0 +1,99 @@
+//! Co-location of pods sharing persistent volume claims, the admission logic of the gravivol
+//! webhook
+//!
+//! A [Controller] answers the [AdmissionReview] of a pod, or of the pod template of a
+//! workload, with a JSON patch. The patch labels the pod with the claims it mounts and adds a
+//! pod affinity to the other pods carrying the labels, so that the scheduler places them on
+//! the same node. The gravivol binary serves it over HTTPS, admission servers of their own can
+//! embed it instead:
+//!
+//! ```
+//! use gravivol::{AdmissionReview, Controller};
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let controller = Controller::new("default/data");
+//! let review: AdmissionReview = serde_json::from_str(r#"{
+//!     "apiVersion": "admission.k8s.io/v1",
+//!     "kind": "AdmissionReview",
+//!     "request": {
+//!         "uid": "705ab4f5",
+//!         "operation": "CREATE",
+//!         "object": {
+//!             "apiVersion": "v1",
+//!             "kind": "Pod",
+//!             "metadata": { "name": "web", "namespace": "default" },
+//!             "spec": {
+//!                 "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }]
+//!             }
+//!         }
+//!     }
+//! }"#).unwrap();
+//! let review = controller.mutate(review).await.unwrap();
+//! let response = review.response().unwrap();
+//! assert!(response.allowed);
+//! assert_eq!(response.patch_type.as_deref(), Some("JSONPatch"));
+//! # });
+//! ```
+//!
+//! The claims to co-locate are configured like with `GRAVIVOL_CONFIG`, see
+//! [Controller::new], or in code with a [ControllerBuilder], further behavior with [Options].
+//! Without a [cluster::Cluster] the controller never looks anything up in the Kubernetes API.
+#![warn(missing_docs)]
+
+pub mod cluster;
+pub mod controller;
+pub mod decisions;
+pub mod metrics;
+pub mod namespaces;
+pub mod settings;
+
+// Serving infrastructure of the binary, public for it but not part of the API
+#[doc(hidden)]
+pub mod activation;
+#[doc(hidden)]
+pub mod audit;
+#[doc(hidden)]
+pub mod audit_log;
+#[doc(hidden)]
+pub mod body_samples;
+#[doc(hidden)]
+pub mod build_info;
+#[doc(hidden)]
+pub mod certificates;
+#[doc(hidden)]
+pub mod events;
+#[doc(hidden)]
+pub mod gate;
+#[doc(hidden)]
+pub mod health;
+#[doc(hidden)]
+pub mod kube_api;
+#[doc(hidden)]
+pub mod leader;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod manifests;
+#[doc(hidden)]
+pub mod permissions;
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+pub mod process;
+#[doc(hidden)]
+pub mod rate_limit;
+#[doc(hidden)]
+pub mod registration;
+#[doc(hidden)]
+pub mod server;
+#[doc(hidden)]
+pub mod simulate;
+#[doc(hidden)]
+pub mod telemetry;
+#[doc(hidden)]
+pub mod tls;
+
+pub use controller::{
+    AdmissionReview, ConfigError, Controller, ControllerBuilder, ControllerError, Kind, Options,
+    Response,
+};
+pub use settings::Settings;
@@ -0,0 +1,170 @@
+//! Log output of gravivol and of the libraries it uses
+
+use opentelemetry_sdk::trace::Tracer;
+use serde::Serialize;
+use tracing::{Dispatch, Level};
+use tracing_subscriber::{
+    EnvFilter, Layer, filter::Targets, fmt::MakeWriter, layer::SubscriberExt,
+};
+
+/// How log lines are written to stdout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, prefixed with the span of the admission request
+    Text,
+    /// One JSON object per line, e.g. for Loki, with the fields of the event and of the spans
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Option<LogFormat> {
+        match name {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Filter configured like env_logger with RUST_LOG, e.g. "info,gravivol=debug"
+pub fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Subscriber writing all events passing the filter in the format, colored text if ansi is set
+///
+/// With a tracer, the spans of gravivol are also exported, regardless of the log filter.
+pub fn dispatch<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+    ansi: bool,
+    tracer: Option<Tracer>,
+) -> Dispatch
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    let layer = match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    let telemetry = tracer.map(|tracer| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_target("gravivol", Level::INFO))
+    });
+    Dispatch::new(
+        tracing_subscriber::registry()
+            .with(telemetry)
+            .with(layer.with_filter(filter)),
+    )
+}
+
+/// Output of a subscriber, for assertions on the log lines
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedLogs {
+    pub fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<'a> MakeWriter<'a> for CapturedLogs {
+    type Writer = CapturedLogs;
+
+    fn make_writer(&'a self) -> CapturedLogs {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::controller::AdmissionReview;
+
+    fn log(format: LogFormat) -> Vec<String> {
+        let logs = CapturedLogs::default();
+        tracing::dispatcher::with_default(
+            &dispatch(format, EnvFilter::new("debug"), logs.clone(), false, None),
+            || {
+                let review: AdmissionReview = serde_json::from_value(json!({
+                    "apiVersion": "admission.k8s.io/v1",
+                    "kind": "AdmissionReview",
+                    "request": {
+                        "uid": "705ab4f5",
+                        "namespace": "default",
+                        "object": { "metadata": { "generateName": "web-" } },
+                    }
+                }))
+                .unwrap();
+                let span = review.span();
+                let _entered = span.enter();
+                span.record("claims", tracing::field::display("data,logs"));
+                let patch = r#"[{"op":"add","path":"/metadata/labels","value":{}}]"#;
+                tracing::debug!(patch, "Patch of Pod default/web");
+            },
+        );
+        logs.lines()
+    }
+
+    #[test]
+    fn test_text() {
+        let lines = log(LogFormat::Text);
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].contains(
+                " DEBUG admission{uid=705ab4f5 namespace=default object=web- claims=data,logs}: gravivol::logging::tests: Patch of Pod default/web patch="
+            ),
+            "{}",
+            lines[0]
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let lines = log(LogFormat::Json);
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert!(line["timestamp"].is_string());
+        assert_eq!(line["level"], "DEBUG");
+        assert_eq!(line["message"], "Patch of Pod default/web");
+        assert_eq!(
+            line["patch"],
+            r#"[{"op":"add","path":"/metadata/labels","value":{}}]"#
+        );
+        assert_eq!(line["span"]["uid"], "705ab4f5");
+        assert_eq!(line["span"]["namespace"], "default");
+        assert_eq!(line["span"]["object"], "web-");
+        assert_eq!(line["span"]["claims"], "data,logs");
+    }
+}
@@ -1,91 +1,574 @@
-use std::{env, error::Error, fs::File, io::BufReader};
+use std::{
+    env,
+    io::{self, IsTerminal},
+    sync::Arc,
+    time::Duration,
+};
 
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder, get,
-    http::{StatusCode, header::ContentType},
-    post, web,
+    App, HttpServer,
+    middleware::{Condition, from_fn},
+    web,
 };
+use tokio::sync::{Semaphore, watch};
+use tracing_subscriber::util::SubscriberInitExt;
+use x509_parser::prelude::ASN1Time;
 
-use rustls::ServerConfig;
-
-use crate::controller::Controller;
-
-mod controller;
-
-fn load_rustls_config() -> Result<ServerConfig, Box<dyn Error>> {
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .unwrap();
+use gravivol::{
+    activation, audit,
+    audit_log::AuditLog,
+    body_samples::BodySamples,
+    build_info, certificates,
+    cluster::{Cluster, KubeCluster},
+    controller::Controller,
+    decisions::Decisions,
+    events::EventRecorder,
+    gate,
+    health::{Health, IdleWarning},
+    kube_api,
+    leader::{self, Election},
+    logging, manifests,
+    metrics::Metrics,
+    namespaces::{self, NamespaceCache, Namespaces},
+    permissions, process,
+    rate_limit::RateLimiter,
+    registration::{self, DeregisterMode, Registration},
+    server::{
+        Limits, access_log, admin_routes, debug_bodies, debug_mutations, listen_tcp, listen_uds,
+        mutate_routes, record_ca_bundle, record_config_load, serve, shutdown_signal, validate,
+    },
+    settings::{Settings, VALIDATE_PATH},
+    simulate, telemetry,
+    tls::{self, CertReloader, CertSource, ClientAuthMode, ExpiryWarning},
+};
 
-    let cert_path =
-        env::var("GRAVIVOL_TLS_CERT_PATH").unwrap_or_else(|_| "/certs/cert.pem".to_string());
-    let key_path =
-        env::var("GRAVIVOL_TLS_KEY_PATH").unwrap_or_else(|_| "/certs/key.pem".to_string());
-    let mut certs_file = BufReader::new(File::open(&cert_path)?);
-    let mut key_file = BufReader::new(File::open(&key_path)?);
+/// Printed for unknown commands
+const USAGE: &str = "\
+Usage: gravivol [<command> [<args>]]
 
-    // load TLS certs and key
-    // to create a self-signed temporary cert for testing:
-    // `openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'`
-    let tls_certs = rustls_pemfile::certs(&mut certs_file).collect::<Result<Vec<_>, _>>()?;
-    let tls_key = rustls_pemfile::pkcs8_private_keys(&mut key_file)
-        .next()
-        .expect("No key in PKCS#1 format found!")?;
+Without a command the webhook is served, configured by the GRAVIVOL_ environment variables.
 
-    // set up TLS config options
-    let tls_config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(tls_certs, rustls::pki_types::PrivateKeyDer::Pkcs8(tls_key))?;
+Commands:
+  simulate       Answer a pod from a file like the webhook would
+  gen-manifests  Print the webhook configuration, service and RBAC objects as YAML
+  gen-cert       Write a self-signed serving certificate and print its caBundle";
 
-    Ok(tls_config)
+/// Runs the command of the arguments, the exit code, None to serve the webhook
+async fn run_command(args: &[String]) -> Option<i32> {
+    let (command, args) = args.split_first()?;
+    let code = match command.as_str() {
+        "simulate" => simulate::run(args).await,
+        "gen-manifests" => manifests::run(args),
+        "gen-cert" => certificates::run(args),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            0
+        }
+        _ => {
+            eprintln!("Unknown command {command}\n\n{USAGE}");
+            2
+        }
+    };
+    Some(code)
 }
 
-#[post("/mutate")]
-async fn mutate(req_body: String, controller: web::Data<Controller>) -> impl Responder {
-    log::debug!("Got: {}", req_body);
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    if let Some(code) = run_command(&env::args().skip(1).collect::<Vec<String>>()).await {
+        std::process::exit(code);
+    }
+    // SAFETY: first thing of the server, before it starts threads
+    let activated = unsafe { activation::listeners() }?;
+    let mut settings = Settings::from_env().expect("Invalid settings");
+    let tracer_provider = telemetry::tracer_provider()
+        .map_err(|err| io::Error::other(format!("Cannot create the OTLP exporter: {err}")))?;
+    logging::dispatch(
+        settings.log_format,
+        logging::env_filter(),
+        io::stdout,
+        io::stdout().is_terminal(),
+        tracer_provider.as_ref().map(telemetry::tracer),
+    )
+    .init();
+    tracing::info!(
+        "Starting gravivol {}, built at {} with {}",
+        build_info::VERSION,
+        build_info::BUILD_INFO.built_at,
+        build_info::BUILD_INFO.rustc_version
+    );
+    let tls = if settings.tls {
+        rustls::crypto::aws_lc_rs::default_provider()
+            .install_default()
+            .unwrap();
+        let source = match &settings.tls_bundle_path {
+            Some(path) => CertSource::Bundle(path.clone()),
+            None => CertSource::Files {
+                cert_path: settings.tls_cert_path.clone(),
+                key_path: settings.tls_key_path.clone(),
+            },
+        };
+        let reloader = CertReloader::new(source)
+            .map_err(|err| io::Error::other(format!("Cannot load TLS config: {err}")))?;
+        Some(Arc::new(reloader))
+    } else {
+        tracing::warn!("TLS is disabled, serving admission requests over plain HTTP");
+        None
+    };
 
-    if let Ok(review) = serde_json::from_str(&req_body) {
-        match controller.mutate(review) {
-            Ok(response) => {
-                log::debug!("Response is OK: {:?}", response);
-                HttpResponse::Ok().json(response)
-            }
-            Err(err) => {
-                log::error!("Respone error: {}", err);
-                HttpResponse::build(StatusCode::BAD_REQUEST)
-                    .insert_header(ContentType::html())
-                    .body(err.to_string())
+    tracing::info!("Got config: '{}'", settings.config);
+    tracing::info!(
+        "Request timeout {}s, keep-alive {}s, client disconnect timeout {}s",
+        settings.request_timeout_secs,
+        settings.keep_alive_secs,
+        settings.client_disconnect_secs
+    );
+
+    // Purely static matching never connects, e.g. to run the webhook outside a cluster
+    let api_features = settings.api_features();
+    let client = if api_features.is_empty() {
+        None
+    } else {
+        let (client, description) = kube_api::connect(
+            settings.kubeconfig.as_deref(),
+            settings.kube_qps,
+            settings.kube_burst,
+        )
+        .await
+        .map_err(|err| {
+            io::Error::other(format!(
+                "{} need the Kubernetes API but {err}; run in a cluster or set GRAVIVOL_KUBECONFIG",
+                api_features.join(", ")
+            ))
+        })?;
+        tracing::info!(
+            "Connected to Kubernetes API {description} for {}",
+            api_features.join(", ")
+        );
+        settings.kube_api = Some(description);
+        Some(client)
+    };
+    let health = web::Data::new(Health::new(client.clone()));
+    let shared_metrics = web::Data::new(
+        Metrics::with_version(build_info::VERSION)
+            .with_namespace_limit(settings.metrics_namespaces)
+            .with_claim_limit(settings.metrics_claims)
+            .with_phase_timing(settings.phase_metrics),
+    );
+    #[cfg(target_os = "linux")]
+    shared_metrics.register(Box::new(process::ProcessCollector::new()));
+    if let Some(client) = &client {
+        let mut permissions = permissions::required_by(&api_features);
+        permissions::probe(client, &mut permissions, &settings.lease_name).await;
+        tracing::info!(
+            "Permissions of the enabled features:\n{}",
+            permissions::table(&permissions)
+        );
+        let denied = permissions::denied_features(&permissions);
+        settings.permissions = permissions;
+        if !denied.is_empty() && settings.rbac_strict {
+            return Err(io::Error::other(format!(
+                "{} lack permissions, see the log above; grant them or disable GRAVIVOL_RBAC_STRICT",
+                denied.join(", ")
+            )));
+        }
+        for feature in denied {
+            tracing::warn!(
+                "Disabling {feature} as its permissions were denied, admission continues without it"
+            );
+            settings.disable(feature);
+            shared_metrics.feature_disabled(feature);
+        }
+    }
+    // Background tasks only run on the replica holding the lease, admission requests on all
+    let (leader_sender, leader) = watch::channel(!settings.leader_election);
+    let background_tasks = settings.background_tasks();
+    let _leader_sender = match &client {
+        Some(client) if settings.leader_election && background_tasks => {
+            let election = Election::new(
+                client.clone(),
+                client.default_namespace(),
+                &settings.lease_name,
+                &settings.pod_name,
+                Duration::from_secs(settings.lease_duration_secs),
+            );
+            tokio::spawn(election.run(leader_sender, shared_metrics.clone().into_inner()));
+            None
+        }
+        // Kept so that the tasks keep running
+        _ => Some(leader_sender),
+    };
+    // The caBundle is updated when the certificate changes
+    let registering = match (&client, &tls) {
+        (Some(client), Some(reloader)) if settings.register_webhook => {
+            let registration = Arc::new(Registration::new(&settings, client));
+            let result =
+                registration::register(client.clone(), &registration, &reloader.chain_pem()).await;
+            record_ca_bundle(&health, &shared_metrics, result);
+            Some((client.clone(), registration, reloader.clone()))
+        }
+        _ => None,
+    };
+    if let Some(reloader) = &tls {
+        health.set_certificate(reloader.validity());
+        let (health, shared_metrics) = (health.clone(), shared_metrics.clone());
+        let mut registered = reloader.chain_pem();
+        let mut syncing: Option<tokio::task::JoinHandle<()>> = None;
+        let ca_sync_dry_run = settings.ca_sync_dry_run;
+        let leader = leader.clone();
+        let mut expiry_warning = ExpiryWarning::new(Duration::from_secs(
+            u64::from(settings.cert_expiry_warning_days) * 24 * 60 * 60,
+        ));
+        tokio::spawn(tls::watch(
+            reloader.clone(),
+            Duration::from_secs(10),
+            move |validity| {
+                health.set_certificate(validity);
+                shared_metrics
+                    .certificate_not_after
+                    .set(validity.not_after().timestamp());
+                shared_metrics
+                    .certificate_expires_in
+                    .set(validity.expires_in(ASN1Time::now()));
+                expiry_warning.check(validity);
+                if let Some((client, registration, reloader)) = &registering {
+                    let ca_bundle = reloader.chain_pem();
+                    if ca_bundle == registered {
+                        return;
+                    }
+                    registered = ca_bundle.clone();
+                    if !*leader.borrow() {
+                        tracing::debug!("Certificate changed, the leader updates the caBundle");
+                        return;
+                    }
+                    // A sync still retrying would apply an outdated caBundle
+                    if let Some(syncing) = syncing.take() {
+                        syncing.abort();
+                    }
+                    let (client, registration) = (client.clone(), registration.clone());
+                    let (health, shared_metrics) = (health.clone(), shared_metrics.clone());
+                    syncing = Some(tokio::spawn(async move {
+                        let result = registration::sync_ca_bundle(
+                            client,
+                            &registration,
+                            &ca_bundle,
+                            ca_sync_dry_run,
+                        )
+                        .await;
+                        if !ca_sync_dry_run {
+                            record_ca_bundle(&health, &shared_metrics, result);
+                        }
+                    }));
+                }
+            },
+        ));
+    }
+    record_config_load(&health, &shared_metrics, &settings.config);
+    let cluster = client.clone().map(|client| -> Arc<dyn Cluster> {
+        Arc::new(KubeCluster::new(
+            client,
+            Duration::from_secs(settings.lookup_cache_secs),
+            settings.lookup_cache_entries,
+            shared_metrics.clone().into_inner(),
+        ))
+    });
+    // Warmed up before serving, pods in namespaces not known yet are handled as selected
+    let namespaces = match &client {
+        Some(client) if settings.controller.namespace_selector.is_some() => {
+            let cache = Arc::new(NamespaceCache::new(shared_metrics.clone().into_inner()));
+            tokio::spawn(cache.clone().run(client.clone()));
+            if !cache.wait_synced(namespaces::SYNC_TIMEOUT).await {
+                tracing::warn!(
+                    "Namespaces not listed within {}s, serving anyway",
+                    namespaces::SYNC_TIMEOUT.as_secs()
+                );
             }
+            Some(cache as Arc<dyn Namespaces>)
         }
-    } else {
-        log::error!("Could not parse AdmissionReview JSON: {}", req_body);
-        HttpResponse::build(StatusCode::BAD_REQUEST)
-            .insert_header(ContentType::html())
-            .body("Failed to parse AdmissionReview from JSON")
+        _ => None,
+    };
+    let events = client
+        .clone()
+        .filter(|_| settings.emit_events || settings.audit_events)
+        .map(|client| Arc::new(EventRecorder::new(client, &settings.pod_name)));
+
+    if settings.controller.scheduling_gate
+        && let Some(client) = client.clone()
+    {
+        tokio::spawn(leader::while_leading(
+            leader.clone(),
+            "scheduling gate",
+            move || gate::run(client.clone(), Duration::from_secs(5)),
+        ));
+    }
+    if settings.audit
+        && let (Some(client), Some(cluster)) = (client.clone(), &cluster)
+    {
+        let mut controller = Controller::new(&settings.config)
+            .with_options(settings.controller.clone())
+            .with_metrics(shared_metrics.clone().into_inner())
+            .with_cluster(cluster.clone());
+        if let Some(namespaces) = &namespaces {
+            controller = controller.with_namespaces(namespaces.clone());
+        }
+        let controller = Arc::new(controller);
+        let (interval, metrics, events) = (
+            Duration::from_secs(settings.audit_interval_secs),
+            shared_metrics.clone().into_inner(),
+            events.clone().filter(|_| settings.audit_events),
+        );
+        tokio::spawn(leader::while_leading(leader.clone(), "audit", move || {
+            audit::run(
+                client.clone(),
+                controller.clone(),
+                interval,
+                metrics.clone(),
+                events.clone(),
+            )
+        }));
     }
-}
 
-#[get("/health")]
-async fn health() -> impl Responder {
-    "OK"
-}
+    if settings.idle_warning_minutes > 0 {
+        let mut idle_warning =
+            IdleWarning::new(Duration::from_secs(settings.idle_warning_minutes * 60));
+        let shared_metrics = shared_metrics.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(IdleWarning::CHECK_EVERY);
+            loop {
+                ticker.tick().await;
+                idle_warning.check(shared_metrics.last_request.get());
+            }
+        });
+    }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    let tls_config = load_rustls_config().expect("Cannot load TLS config");
+    let decisions = settings
+        .debug_endpoints
+        .then(|| web::Data::new(Decisions::new(settings.mutation_history)));
+    let sampling = settings.sample_bodies > 0.0 || !settings.sample_bodies_uids.is_empty();
+    if sampling && !settings.debug_endpoints {
+        tracing::warn!(
+            "Not sampling request bodies, GRAVIVOL_SAMPLE_BODIES requires GRAVIVOL_DEBUG_ENDPOINTS"
+        );
+    }
+    let body_samples = (sampling && settings.debug_endpoints).then(|| {
+        web::Data::new(BodySamples::new(
+            settings.sample_bodies,
+            settings.sample_bodies_uids.clone(),
+            settings.sample_bodies_max_bytes,
+        ))
+    });
+    let audit_log = match &settings.audit_log {
+        Some(path) => {
+            let audit_log = AuditLog::start(
+                path,
+                settings.audit_log_max_bytes,
+                shared_metrics.clone().into_inner(),
+            )
+            .map_err(|err| io::Error::other(format!("Cannot open audit log {path}: {err}")))?;
+            tracing::info!("Appending each decision to the audit log {path}");
+            Some(Arc::new(audit_log))
+        }
+        None => None,
+    };
+    let listeners = if activated.is_empty() {
+        listen_tcp(&settings.bind)?
+    } else {
+        tracing::info!(
+            "Socket activated, serving admission requests on the {} sockets passed by systemd instead of GRAVIVOL_BIND",
+            activated.len()
+        );
+        activated
+    };
+    let admin_listeners = listen_tcp(&settings.admin_bind)?;
+    let settings = web::Data::new(settings);
 
-    let config = env::var("GRAVIVOL_CONFIG").unwrap_or_else(|_| "".to_string());
-    log::info!("Got config: '{config}'");
+    let limits = Limits {
+        body_bytes: settings.max_body_bytes,
+        processing: Duration::from_secs(settings.request_timeout_secs),
+        in_flight: (settings.max_in_flight > 0)
+            .then(|| Arc::new(Semaphore::new(settings.max_in_flight))),
+        rate: (settings.rate_limit > 0.0)
+            .then(|| Arc::new(RateLimiter::new(settings.rate_limit, settings.rate_burst))),
+        json_depth: settings.max_json_depth,
+        volumes: settings.max_volumes,
+    };
+    let server = HttpServer::new({
+        let (settings, shared_metrics, health, decisions, audit_log, body_samples) = (
+            settings.clone(),
+            shared_metrics.clone(),
+            health.clone(),
+            decisions.clone(),
+            audit_log.clone(),
+            body_samples.clone(),
+        );
+        move || {
+            let mut controller = Controller::new(&settings.config)
+                .with_options(settings.controller.clone())
+                .with_metrics(shared_metrics.clone().into_inner());
+            if let Some(cluster) = &cluster {
+                controller = controller.with_cluster(cluster.clone());
+            }
+            if let Some(namespaces) = &namespaces {
+                controller = controller.with_namespaces(namespaces.clone());
+            }
+            if let Some(decisions) = &decisions {
+                controller = controller.with_decisions(decisions.clone().into_inner());
+            }
+            if let Some(audit_log) = &audit_log {
+                controller = controller.with_audit_log(audit_log.clone());
+            }
+            if settings.emit_events
+                && let Some(events) = &events
+            {
+                controller = controller.with_events(events.clone());
+            }
+            App::new()
+                .wrap(Condition::new(settings.access_log, from_fn(access_log)))
+                .app_data(web::Data::new(controller))
+                .app_data(limits.clone())
+                .app_data(shared_metrics.clone())
+                .app_data(health.clone())
+                .app_data(settings.clone())
+                .configure(mutate_routes(&settings.mutate_paths))
+                .configure(|config| {
+                    if settings.validate {
+                        config.route(VALIDATE_PATH, web::post().to(validate));
+                    }
+                    if let Some(body_samples) = &body_samples {
+                        config.app_data(body_samples.clone());
+                    }
+                })
+                .configure(admin_routes)
+        }
+    })
+    .client_request_timeout(Duration::from_secs(settings.request_timeout_secs))
+    .keep_alive(Duration::from_secs(settings.keep_alive_secs))
+    .client_disconnect_timeout(Duration::from_secs(settings.client_disconnect_secs))
+    .shutdown_timeout(settings.shutdown_grace_secs)
+    .disable_signals();
+    // One worker per CPU by default
+    let server = match settings.workers {
+        0 => {
+            shared_metrics.set_workers(
+                std::thread::available_parallelism().map_or(1, |workers| workers.get()),
+            );
+            server
+        }
+        workers => {
+            shared_metrics.set_workers(workers);
+            server.workers(workers)
+        }
+    };
+    let mut server = match tls {
+        Some(reloader) if !listeners.is_empty() => {
+            let tls_config = tls::server_config(
+                reloader,
+                settings.tls_client_ca_path.as_deref(),
+                settings.tls_client_auth,
+                settings.tls_min_version,
+                &settings.tls_ciphers,
+            )
+            .map_err(|err| io::Error::other(format!("Cannot create TLS config: {err}")))?;
+            let log_missing_client_cert = settings.tls_client_ca_path.is_some()
+                && settings.tls_client_auth == ClientAuthMode::Log;
+            let server = server.on_connect(move |connection, _| {
+                tls::log_connection(connection, log_missing_client_cert)
+            });
+            listeners.into_iter().try_fold(server, |server, listener| {
+                server.listen_rustls_0_23(listener, tls_config.clone())
+            })?
+        }
+        _ => listeners.into_iter().try_fold(server, HttpServer::listen)?,
+    };
+    for addr in server.addrs() {
+        tracing::info!("Listening on {addr}");
+    }
+    // Removes the socket file once the server is done
+    let _socket_file = match &settings.bind_uds {
+        Some(path) => {
+            let (listener, socket_file) = listen_uds(path, settings.uds_mode)?;
+            server = server.listen_uds(listener)?;
+            tracing::info!("Listening on {path} with mode {:o}", settings.uds_mode);
+            Some(socket_file)
+        }
+        None => None,
+    };
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(Controller::new(&config)))
-            .service(mutate)
-            .service(health)
+    let admin_server = HttpServer::new({
+        let (settings, shared_metrics, health) =
+            (settings.clone(), shared_metrics.clone(), health.clone());
+        move || {
+            App::new()
+                .app_data(shared_metrics.clone())
+                .app_data(health.clone())
+                .app_data(settings.clone())
+                .configure(admin_routes)
+                .configure(|config| {
+                    // Only on the admin listener, which is not exposed by the service
+                    if let Some(decisions) = &decisions {
+                        config.app_data(decisions.clone()).service(debug_mutations);
+                    }
+                    if let Some(body_samples) = &body_samples {
+                        config.app_data(body_samples.clone()).service(debug_bodies);
+                    }
+                })
+        }
     })
-    .bind_rustls_0_23("[::]:8080", tls_config)?
-    //.bind("[::]:8081")?
-    .run()
-    .await
+    .workers(1)
+    .disable_signals();
+    let admin_server = admin_listeners
+        .into_iter()
+        .try_fold(admin_server, HttpServer::listen)?;
+    for addr in admin_server.addrs() {
+        tracing::info!("Serving probes and metrics on {addr}");
+    }
+
+    // Deregistered before the listeners stop, so that the API server never calls a dead webhook
+    let deregistering = match &client {
+        Some(client)
+            if settings.register_webhook
+                && settings.deregister_on_shutdown != DeregisterMode::Off =>
+        {
+            let registration = Registration::new(&settings, client);
+            Some((
+                client.clone(),
+                settings.deregister_on_shutdown,
+                registration,
+            ))
+        }
+        _ => None,
+    };
+    let pod_name = settings.pod_name.clone();
+    let shutdown = async move {
+        shutdown_signal().await;
+        if let Some((client, mode, registration)) = deregistering {
+            let deregistered = tokio::time::timeout(
+                registration::DEREGISTER_TIMEOUT,
+                registration::deregister(client, mode, &registration, &pod_name),
+            );
+            match deregistered.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::error!("{err}"),
+                Err(_) => tracing::error!(
+                    "Deregistering the webhook timed out after {}s, shutting down anyway",
+                    registration::DEREGISTER_TIMEOUT.as_secs()
+                ),
+            }
+        }
+    };
+    let result = serve(
+        server.run(),
+        admin_server.run(),
+        &health,
+        &shared_metrics,
+        shutdown,
+        Duration::from_secs(settings.shutdown_delay_secs),
+    )
+    .await;
+    // Exports the remaining spans
+    if let Some(provider) = tracer_provider
+        && let Err(err) = provider.shutdown()
+    {
+        tracing::warn!("Cannot export the remaining spans: {err}");
+    }
+    result
 }
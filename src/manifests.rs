@@ -0,0 +1,384 @@
+//! The gen-manifests command, printing the objects to install the webhook without the chart
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Write},
+};
+
+use serde_json::{Map, Value, json};
+
+use crate::{
+    controller::FailureMode,
+    permissions,
+    registration::{DeregisterMode, Registration},
+    settings::Settings,
+};
+
+/// Printed on invalid arguments
+pub const USAGE: &str = "\
+Usage: gravivol gen-manifests --namespace <namespace> --ca-bundle-file <file> [--service <name>]
+                              [--failure-policy Fail|Ignore] [--namespace-selector <key>=<value>]...
+
+Prints the MutatingWebhookConfiguration, the Service and the RBAC objects of the webhook as YAML,
+to apply with kubectl apply -f -. The rules and permissions follow the features enabled by the
+GRAVIVOL_ variables, like those of the webhook run with them, and GRAVIVOL_VALIDATE adds the
+ValidatingWebhookConfiguration.
+
+  --namespace <namespace>             Namespace of the service and of its service account
+  --ca-bundle-file <file>             PEM certificates the API server verifies the webhook with
+  --service <name>                    Name of the service, its service account and the roles,
+                                      GRAVIVOL_WEBHOOK_SERVICE by default
+  --failure-policy Fail|Ignore        Answer of the API server if the webhook fails,
+                                      by GRAVIVOL_FAILURE_MODE by default
+  --namespace-selector <key>=<value>  Only send objects of namespaces with the label, repeatable,
+                                      GRAVIVOL_WEBHOOK_NAMESPACE_SELECTOR by default
+
+The service selects the pods labeled app.kubernetes.io/name=<service>, the bindings the service
+account named like the service.";
+
+/// Invalid arguments, settings or CA bundle
+const EXIT_USAGE: i32 = 2;
+
+/// Port of the webhook without a TCP listener, that of the default GRAVIVOL_BIND
+const DEFAULT_TARGET_PORT: u16 = 8080;
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    service: Option<String>,
+    namespace: String,
+    ca_bundle_file: String,
+    failure_mode: Option<FailureMode>,
+    /// matchLabels of the namespace selector, in the order given
+    namespace_labels: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--service" => parsed.service = Some(value()?),
+                "--namespace" => parsed.namespace = value()?,
+                "--ca-bundle-file" => parsed.ca_bundle_file = value()?,
+                "--failure-policy" => {
+                    parsed.failure_mode = Some(match value()?.as_str() {
+                        "Fail" => FailureMode::Closed,
+                        "Ignore" => FailureMode::Open,
+                        other => {
+                            return Err(format!(
+                                "--failure-policy must be Fail or Ignore but is '{other}'"
+                            ));
+                        }
+                    })
+                }
+                "--namespace-selector" => {
+                    let selector = value()?;
+                    match selector.split_once('=') {
+                        Some((key, value)) if !key.is_empty() => parsed
+                            .namespace_labels
+                            .push((key.to_owned(), value.to_owned())),
+                        _ => {
+                            return Err(format!(
+                                "--namespace-selector must be <key>=<value> but is '{selector}'"
+                            ));
+                        }
+                    }
+                }
+                other => return Err(format!("unknown argument {other}")),
+            }
+        }
+        for (name, value) in [
+            ("--namespace", &parsed.namespace),
+            ("--ca-bundle-file", &parsed.ca_bundle_file),
+        ] {
+            if value.is_empty() {
+                return Err(format!("{name} is required"));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Runs the command with the arguments after gen-manifests, the exit code
+pub fn run(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return 0;
+    }
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    let manifests = Settings::from_env()
+        .map_err(|err| err.to_string())
+        .and_then(|settings| {
+            let ca_bundle = read_ca_bundle(&args.ca_bundle_file)?;
+            to_yaml(&objects(&args, &settings, &ca_bundle))
+        });
+    match manifests {
+        Ok(manifests) => {
+            // A closed stdout is no failure, e.g. when piped into head
+            let _ = write!(io::stdout(), "{manifests}");
+            0
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            EXIT_USAGE
+        }
+    }
+}
+
+/// The PEM file, Err unless it contains a certificate
+fn read_ca_bundle(path: &str) -> Result<String, String> {
+    let ca_bundle = fs::read_to_string(path).map_err(|err| format!("cannot read {path}: {err}"))?;
+    let certs = rustls_pemfile::certs(&mut ca_bundle.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("cannot parse {path}: {err}"))?;
+    if certs.is_empty() {
+        return Err(format!("{path} contains no PEM certificate"));
+    }
+    Ok(ca_bundle)
+}
+
+/// The objects in the order to apply them, the RBAC objects only if a feature needs them
+fn objects(args: &Args, settings: &Settings, ca_bundle: &str) -> Vec<Value> {
+    let service = args
+        .service
+        .clone()
+        .unwrap_or_else(|| settings.webhook_service.clone());
+    let namespace = args.namespace.as_str();
+
+    let mut registration = Registration::in_namespace(settings, namespace.to_owned());
+    registration.service = service.clone();
+    if let Some(failure_mode) = args.failure_mode {
+        registration.failure_mode = failure_mode;
+    }
+    if !args.namespace_labels.is_empty() {
+        let labels: Map<String, Value> = args
+            .namespace_labels
+            .iter()
+            .map(|(key, value)| (key.clone(), json!(value)))
+            .collect();
+        registration.namespace_selector = Some(json!({ "matchLabels": labels }));
+    }
+    let mut configuration = registration.configuration(ca_bundle);
+    // Not deleted or neutralized on shutdown like a configuration gravivol registered itself
+    configuration["metadata"]
+        .as_object_mut()
+        .unwrap()
+        .remove("labels");
+
+    let labels = json!({ "app.kubernetes.io/name": service });
+    let target_port = settings
+        .bind
+        .first()
+        .and_then(|bind| bind.rsplit_once(':')?.1.parse().ok())
+        .unwrap_or(DEFAULT_TARGET_PORT);
+    let mut objects = vec![configuration];
+    if settings.validate {
+        objects.push(registration.validating_configuration(ca_bundle));
+    }
+    objects.push(json!({
+        "apiVersion": "v1",
+        "kind": "Service",
+        "metadata": { "name": service, "namespace": namespace, "labels": labels },
+        "spec": {
+            "ports": [{
+                "name": "http",
+                "port": settings.webhook_port,
+                "targetPort": target_port,
+                "protocol": "TCP",
+            }],
+            "selector": labels,
+        },
+    }));
+    let subjects = json!([{ "kind": "ServiceAccount", "name": service, "namespace": namespace }]);
+
+    let rules = cluster_rules(settings);
+    if !rules.is_empty() {
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "ClusterRole",
+            "metadata": { "name": service, "labels": labels },
+            "rules": rules,
+        }));
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "ClusterRoleBinding",
+            "metadata": { "name": service, "labels": labels },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "ClusterRole",
+                "name": service,
+            },
+            "subjects": subjects,
+        }));
+    }
+
+    if settings.leader_election && settings.background_tasks() {
+        let name = format!("{service}-leader");
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "Role",
+            "metadata": { "name": name, "namespace": namespace, "labels": labels },
+            "rules": [
+                {
+                    "apiGroups": ["coordination.k8s.io"],
+                    "resources": ["leases"],
+                    "verbs": ["create"],
+                },
+                {
+                    "apiGroups": ["coordination.k8s.io"],
+                    "resources": ["leases"],
+                    "resourceNames": [settings.lease_name],
+                    "verbs": ["get", "update"],
+                },
+            ],
+        }));
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "RoleBinding",
+            "metadata": { "name": name, "namespace": namespace, "labels": labels },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "Role",
+                "name": name,
+            },
+            "subjects": subjects,
+        }));
+    }
+    objects
+}
+
+/// Rules granting the permissions of the enabled features in all namespaces, a rule per resource
+fn cluster_rules(settings: &Settings) -> Vec<Value> {
+    let mut verbs: BTreeMap<(&str, &str), BTreeSet<&str>> = BTreeMap::new();
+    // The lease is granted by the Role of the leader
+    for permission in permissions::required_by(&settings.api_features())
+        .into_iter()
+        .filter(|permission| !permission.is_namespaced())
+    {
+        verbs
+            .entry((permission.group, permission.resource))
+            .or_default()
+            .insert(permission.verb);
+    }
+    // Not probed on startup, failing on the audit only
+    if settings.audit && settings.audit_events {
+        verbs
+            .entry(("events.k8s.io", "events"))
+            .or_default()
+            .insert("create");
+    }
+    // Not probed on startup, failing on shutdown only, which then keeps the configuration
+    if settings.register_webhook && settings.deregister_on_shutdown != DeregisterMode::Off {
+        verbs
+            .entry(("discovery.k8s.io", "endpointslices"))
+            .or_default()
+            .insert("list");
+    }
+    verbs
+        .into_iter()
+        .map(|((group, resource), verbs)| {
+            json!({ "apiGroups": [group], "resources": [resource], "verbs": verbs })
+        })
+        .collect()
+}
+
+/// The objects as YAML documents, with their keys sorted
+fn to_yaml(objects: &[Value]) -> Result<String, String> {
+    let documents = objects
+        .iter()
+        .map(serde_norway::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("cannot serialize the manifests: {err}"))?;
+    Ok(documents.join("---\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        Args::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(
+            args(&[
+                "--namespace",
+                "webhooks",
+                "--ca-bundle-file",
+                "cert.pem",
+                "--failure-policy",
+                "Fail",
+                "--namespace-selector",
+                "team=shop",
+                "--namespace-selector",
+                "gravivol=",
+            ]),
+            Ok(Args {
+                service: None,
+                namespace: "webhooks".to_owned(),
+                ca_bundle_file: "cert.pem".to_owned(),
+                failure_mode: Some(FailureMode::Closed),
+                namespace_labels: vec![
+                    ("team".to_owned(), "shop".to_owned()),
+                    ("gravivol".to_owned(), String::new()),
+                ],
+            })
+        );
+        assert_eq!(
+            args(&["--ca-bundle-file", "cert.pem"]),
+            Err("--namespace is required".to_owned())
+        );
+        assert_eq!(
+            args(&["--namespace", "webhooks", "--failure-policy", "Closed"]),
+            Err("--failure-policy must be Fail or Ignore but is 'Closed'".to_owned())
+        );
+        assert_eq!(
+            args(&["--namespace-selector", "=shop"]),
+            Err("--namespace-selector must be <key>=<value> but is '=shop'".to_owned())
+        );
+        assert_eq!(
+            args(&["--service"]),
+            Err("--service needs a value".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_cluster_rules() {
+        let mut settings = Settings::from_env().unwrap();
+        settings.register_webhook = true;
+        settings.deregister_on_shutdown = DeregisterMode::Delete;
+        settings.emit_events = true;
+        assert_eq!(
+            cluster_rules(&settings),
+            vec![
+                json!({ "apiGroups": [""], "resources": ["pods"], "verbs": ["get"] }),
+                json!({
+                    "apiGroups": ["admissionregistration.k8s.io"],
+                    "resources": ["mutatingwebhookconfigurations"],
+                    "verbs": ["create", "delete", "get", "patch"],
+                }),
+                json!({
+                    "apiGroups": ["discovery.k8s.io"],
+                    "resources": ["endpointslices"],
+                    "verbs": ["list"],
+                }),
+                json!({ "apiGroups": ["events.k8s.io"], "resources": ["events"], "verbs": ["create"] }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,883 @@
+//! Prometheus metrics of the webhook and of the controller
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder, core::Collector,
+};
+use serde::Serialize;
+
+/// Why a pod was admitted without being mutated
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The object is not of a configured kind
+    UnhandledKind,
+    /// Mirror pods of static pods are not scheduled
+    MirrorPod,
+    /// Pods owned by a DaemonSet run on every node anyway
+    #[serde(rename = "daemonset")]
+    DaemonSet,
+    /// The pod template already carries the patch for its claims
+    AlreadyMutated,
+    /// The pod mounts no claims at all
+    NoVolumes,
+    /// No claim of the pod is configured for co-location
+    NoClaims,
+    /// The claims annotation of the pod names none of its configured claims
+    OptedOut,
+    /// All configured claims were dropped after a lookup, e.g. for their access modes
+    ClaimsFiltered,
+    /// The namespace does not match the namespace selector
+    NamespaceExcluded,
+    /// The request carries no object, e.g. for DELETE
+    NoObject,
+    /// The patch is larger than the configured maximum
+    PatchTooLarge,
+}
+
+impl SkipReason {
+    /// Value of the reason label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::UnhandledKind => "unhandled_kind",
+            SkipReason::MirrorPod => "mirror_pod",
+            SkipReason::DaemonSet => "daemonset",
+            SkipReason::AlreadyMutated => "already_mutated",
+            SkipReason::NoVolumes => "no_volumes",
+            SkipReason::NoClaims => "no_claims",
+            SkipReason::OptedOut => "opted_out",
+            SkipReason::ClaimsFiltered => "claims_filtered",
+            SkipReason::NamespaceExcluded => "namespace_excluded",
+            SkipReason::NoObject => "no_object",
+            SkipReason::PatchTooLarge => "patch_too_large",
+        }
+    }
+}
+
+/// Steps of handling an admission request, timed by [Metrics::time_phase]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Parsing the AdmissionReview from the body
+    Deserialize,
+    /// Matching the claims of the pod against the config
+    Match,
+    /// All lookups in the Kubernetes API or its caches for one request
+    Lookup,
+    /// Creating the JSON patch
+    PatchBuild,
+    /// Writing the response
+    Serialize,
+}
+
+impl Phase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Deserialize => "deserialize",
+            Phase::Match => "match",
+            Phase::Lookup => "lookup",
+            Phase::PatchBuild => "patch_build",
+            Phase::Serialize => "serialize",
+        }
+    }
+}
+
+/// From 100µs for the phases in memory up to a second, twice the latency budget of a webhook
+const PHASE_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Namespaces labeled in [Metrics::mutated] by default, see [Metrics::with_namespace_limit]
+pub const DEFAULT_NAMESPACE_LIMIT: usize = 50;
+/// Claims labeled in [Metrics::claim_matched] by default, see [Metrics::with_claim_limit]
+pub const DEFAULT_CLAIM_LIMIT: usize = 500;
+/// Clients labeled in [Metrics::rate_limited]
+const CLIENT_LIMIT: usize = 50;
+/// Label of the namespaces, claims and clients beyond the limit, no valid name of either
+const OTHER: &str = "_other";
+
+/// Label values admitted into a metric, the first ones seen up to a limit
+struct BoundedLabels {
+    limit: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl BoundedLabels {
+    fn new(limit: usize) -> BoundedLabels {
+        BoundedLabels {
+            limit,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether the value was seen before or the limit is not reached, returning the values seen
+    fn admit(&self, value: &str) -> (bool, usize) {
+        let mut seen = self.seen.lock().unwrap();
+        let admitted =
+            seen.contains(value) || (seen.len() < self.limit && seen.insert(value.to_owned()));
+        (admitted, seen.len())
+    }
+}
+
+/// Prometheus metrics of the webhook, shared by all workers
+pub struct Metrics {
+    registry: Registry,
+    /// Admission requests processed by the controller
+    pub admission_requests: IntCounter,
+    /// By the operation of the request, see [Metrics::operation]
+    operations: IntCounterVec,
+    /// Patches returned
+    pub pods_mutated: IntCounter,
+    /// Unix time of the last patch returned, 0 before the first
+    pub last_mutation: IntGauge,
+    /// Unix time of the last admission request received, 0 before the first
+    pub last_request: IntGauge,
+    /// By the webhook path the request arrived on
+    requests: IntCounterVec,
+    pods_skipped: IntCounterVec,
+    /// By namespace, up to the namespace limit
+    mutations: IntCounterVec,
+    matched_claims: IntCounter,
+    namespace_labels: BoundedLabels,
+    /// By namespace and claim, up to the claim limit
+    claim_matches: IntCounterVec,
+    claim_labels: BoundedLabels,
+    claim_series: IntGauge,
+    claim_series_limit: IntGauge,
+    /// Patches returned for dry runs
+    pub dry_runs: IntCounter,
+    /// Pods whose existing pod affinity conflicts with the patch
+    pub conflicts: IntCounter,
+    /// Request bodies that are not an AdmissionReview
+    pub parse_failures: IntCounter,
+    rejected_documents: IntCounterVec,
+    /// Lookups in the Kubernetes API that failed, by the check they were for
+    lookup_failures: IntCounterVec,
+    terminating_claims: IntCounter,
+    validation_failures: IntCounterVec,
+    namespace_cache_size: IntGauge,
+    namespace_cache_updated: IntGauge,
+    /// By the kind of object looked up
+    lookup_duration: HistogramVec,
+    cache_lookups: IntCounterVec,
+    cache_evictions: IntCounterVec,
+    /// Requests that failed in the controller
+    pub errors: IntCounter,
+    /// Requests whose processing panicked
+    pub panics: IntCounter,
+    /// By the webhook path the request arrived on
+    pub mutate_duration: HistogramVec,
+    phase_duration: HistogramVec,
+    /// Whether the phases are timed, see [Metrics::with_phase_timing]
+    phase_timing: bool,
+    /// Admission requests being processed
+    pub in_flight: IntGauge,
+    /// Workers of the webhook server, each with a runtime of its own
+    workers: IntGauge,
+    /// Requests refused as too many were processed at once
+    pub shed_requests: IntCounter,
+    /// By client address, up to the client limit
+    rate_limited: IntCounterVec,
+    client_labels: BoundedLabels,
+    /// Unix time the serving certificate expires
+    pub certificate_not_after: IntGauge,
+    /// Seconds until the serving certificate expires
+    pub certificate_expires_in: IntGauge,
+    /// By mode, as of the last successful load
+    config_entries: IntGaugeVec,
+    config_last_load: IntGauge,
+    config_loads: IntCounter,
+    config_load_failures: IntCounterVec,
+    ca_bundle_last_sync: IntGauge,
+    ca_bundle_sync_failures: IntCounter,
+    /// By namespace, as of the last audit
+    unmutated_pods: IntGaugeVec,
+    leader: IntGauge,
+    leader_changes: IntCounter,
+    feature_disabled: IntGaugeVec,
+    audit_log_dropped: IntCounterVec,
+}
+
+impl Metrics {
+    /// Metrics in a registry of their own
+    pub fn new() -> Metrics {
+        Metrics::with_labels(None)
+    }
+
+    /// Metrics labeled with the running build, see [crate::build_info::VERSION]
+    pub fn with_version(version: &str) -> Metrics {
+        Metrics::with_labels(Some(HashMap::from([(
+            "version".to_owned(),
+            version.to_owned(),
+        )])))
+    }
+
+    fn with_labels(labels: Option<HashMap<String, String>>) -> Metrics {
+        let registry = Registry::new_custom(Some("gravivol".to_owned()), labels)
+            .expect("Valid registry prefix and labels");
+        let metrics = Metrics {
+            admission_requests: IntCounter::new(
+                "admission_requests_total",
+                "Admission reviews received",
+            )
+            .unwrap(),
+            operations: IntCounterVec::new(
+                Opts::new(
+                    "admission_operations_total",
+                    "Admission requests by operation",
+                ),
+                &["operation"],
+            )
+            .unwrap(),
+            pods_mutated: IntCounter::new("pods_mutated_total", "Pods or templates patched")
+                .unwrap(),
+            last_mutation: IntGauge::new(
+                "last_mutation_timestamp_seconds",
+                "Time of the last pod or template patched",
+            )
+            .unwrap(),
+            last_request: IntGauge::new(
+                "last_request_timestamp_seconds",
+                "Time of the last admission request received",
+            )
+            .unwrap(),
+            requests: IntCounterVec::new(
+                Opts::new("requests_total", "Requests received on the webhook paths"),
+                &["path"],
+            )
+            .unwrap(),
+            pods_skipped: IntCounterVec::new(
+                Opts::new("pods_skipped_total", "Pods or templates admitted unchanged"),
+                &["reason"],
+            )
+            .unwrap(),
+            mutations: IntCounterVec::new(
+                Opts::new("mutations_total", "Pods or templates patched by namespace"),
+                &["namespace"],
+            )
+            .unwrap(),
+            matched_claims: IntCounter::new(
+                "matched_claims_total",
+                "Claims matched for co-location in admitted pods or templates",
+            )
+            .unwrap(),
+            namespace_labels: BoundedLabels::new(DEFAULT_NAMESPACE_LIMIT),
+            claim_matches: IntCounterVec::new(
+                Opts::new(
+                    "claim_matches_total",
+                    "Patches a claim contributed to, by namespace and claim",
+                ),
+                &["namespace", "claim"],
+            )
+            .unwrap(),
+            claim_labels: BoundedLabels::new(DEFAULT_CLAIM_LIMIT),
+            claim_series: IntGauge::new(
+                "claim_matches_series",
+                "Claims labeled individually in claim_matches_total",
+            )
+            .unwrap(),
+            claim_series_limit: IntGauge::new(
+                "claim_matches_series_limit",
+                "Claims labeled individually in claim_matches_total at most",
+            )
+            .unwrap(),
+            dry_runs: IntCounter::new(
+                "dry_runs_total",
+                "Admission requests with dryRun, patched or not",
+            )
+            .unwrap(),
+            conflicts: IntCounter::new(
+                "affinity_conflicts_total",
+                "Existing pod affinity terms for claims with another topology key",
+            )
+            .unwrap(),
+            parse_failures: IntCounter::new(
+                "parse_failures_total",
+                "Request bodies that are no AdmissionReview",
+            )
+            .unwrap(),
+            rejected_documents: IntCounterVec::new(
+                Opts::new(
+                    "rejected_documents_total",
+                    "Requests not processed as their object exceeds a limit",
+                ),
+                &["limit"],
+            )
+            .unwrap(),
+            lookup_failures: IntCounterVec::new(
+                Opts::new(
+                    "lookup_failures_total",
+                    "Failed lookups in the Kubernetes API, the claims are handled as configured",
+                ),
+                &["check"],
+            )
+            .unwrap(),
+            terminating_claims: IntCounter::new(
+                "terminating_claims_total",
+                "Claims of admitted pods not co-located because they are being deleted",
+            )
+            .unwrap(),
+            validation_failures: IntCounterVec::new(
+                Opts::new(
+                    "validation_failures_total",
+                    "Pods lacking the labels of their claims on validation, denied or warned about",
+                ),
+                &["mode"],
+            )
+            .unwrap(),
+            namespace_cache_size: IntGauge::new(
+                "namespace_cache_size",
+                "Namespaces whose labels and annotations are cached",
+            )
+            .unwrap(),
+            namespace_cache_updated: IntGauge::new(
+                "namespace_cache_last_update_timestamp_seconds",
+                "Time the namespace cache was last listed, watched or changed",
+            )
+            .unwrap(),
+            lookup_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "lookup_duration_seconds",
+                    "Latency of lookups in the Kubernetes API",
+                ),
+                &["resource"],
+            )
+            .unwrap(),
+            cache_lookups: IntCounterVec::new(
+                Opts::new(
+                    "cache_lookups_total",
+                    "Lookups in the caches of objects of the Kubernetes API",
+                ),
+                &["cache", "result"],
+            )
+            .unwrap(),
+            cache_evictions: IntCounterVec::new(
+                Opts::new(
+                    "cache_evictions_total",
+                    "Entries evicted from a full cache before they expired",
+                ),
+                &["cache"],
+            )
+            .unwrap(),
+            errors: IntCounter::new("errors_total", "Requests that failed in the controller")
+                .unwrap(),
+            panics: IntCounter::new("panics_total", "Requests that panicked in the controller")
+                .unwrap(),
+            mutate_duration: HistogramVec::new(
+                HistogramOpts::new("mutate_duration_seconds", "Latency of the mutate handler"),
+                &["path"],
+            )
+            .unwrap(),
+            phase_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "phase_duration_seconds",
+                    "Latency of the phases of handling an admission request",
+                )
+                .buckets(PHASE_BUCKETS.to_vec()),
+                &["phase"],
+            )
+            .unwrap(),
+            phase_timing: true,
+            in_flight: IntGauge::new("requests_in_flight", "Admission requests being processed")
+                .unwrap(),
+            workers: IntGauge::new("workers", "Workers of the webhook server").unwrap(),
+            shed_requests: IntCounter::new(
+                "shed_requests_total",
+                "Requests admitted unchanged as too many were in flight",
+            )
+            .unwrap(),
+            rate_limited: IntCounterVec::new(
+                Opts::new(
+                    "rate_limited_requests_total",
+                    "Requests admitted unchanged as their client exceeded the rate limit",
+                ),
+                &["client"],
+            )
+            .unwrap(),
+            client_labels: BoundedLabels::new(CLIENT_LIMIT),
+            certificate_not_after: IntGauge::new(
+                "certificate_not_after_timestamp_seconds",
+                "Expiry of the served certificate",
+            )
+            .unwrap(),
+            certificate_expires_in: IntGauge::new(
+                "certificate_expiry_seconds",
+                "Seconds until the served certificate expires, negative once expired",
+            )
+            .unwrap(),
+            config_entries: IntGaugeVec::new(
+                Opts::new(
+                    "config_entries",
+                    "Claims configured for co-location by mode, as of the last successful load",
+                ),
+                &["mode"],
+            )
+            .unwrap(),
+            config_last_load: IntGauge::new(
+                "config_last_load_timestamp_seconds",
+                "Time the config was last loaded successfully",
+            )
+            .unwrap(),
+            config_loads: IntCounter::new("config_loads_total", "Loads of the config").unwrap(),
+            config_load_failures: IntCounterVec::new(
+                Opts::new(
+                    "config_load_failures_total",
+                    "Loads of the config that failed, keeping the entries in use",
+                ),
+                &["error"],
+            )
+            .unwrap(),
+            ca_bundle_last_sync: IntGauge::new(
+                "ca_bundle_last_sync_timestamp_seconds",
+                "Time the caBundle of the registered webhook was last updated",
+            )
+            .unwrap(),
+            ca_bundle_sync_failures: IntCounter::new(
+                "ca_bundle_sync_failures_total",
+                "Failed registrations or updates of the caBundle, after their retries",
+            )
+            .unwrap(),
+            unmutated_pods: IntGaugeVec::new(
+                Opts::new(
+                    "audit_unmutated_pods",
+                    "Running pods found by the audit without the labels of their claims",
+                ),
+                &["namespace"],
+            )
+            .unwrap(),
+            leader: IntGauge::new(
+                "leader",
+                "1 while this replica holds the lease and runs the background tasks",
+            )
+            .unwrap(),
+            leader_changes: IntCounter::new(
+                "leader_changes_total",
+                "Times this replica acquired or lost the lease",
+            )
+            .unwrap(),
+            feature_disabled: IntGaugeVec::new(
+                Opts::new(
+                    "feature_disabled",
+                    "1 for features disabled on startup because their permissions were denied",
+                ),
+                &["feature"],
+            )
+            .unwrap(),
+            audit_log_dropped: IntCounterVec::new(
+                Opts::new(
+                    "audit_log_dropped_total",
+                    "Decisions not written to the audit log",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            registry,
+        };
+        for collector in [
+            Box::new(metrics.admission_requests.clone()) as Box<dyn Collector>,
+            Box::new(metrics.operations.clone()),
+            Box::new(metrics.pods_mutated.clone()),
+            Box::new(metrics.last_mutation.clone()),
+            Box::new(metrics.last_request.clone()),
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.pods_skipped.clone()),
+            Box::new(metrics.mutations.clone()),
+            Box::new(metrics.matched_claims.clone()),
+            Box::new(metrics.claim_matches.clone()),
+            Box::new(metrics.claim_series.clone()),
+            Box::new(metrics.claim_series_limit.clone()),
+            Box::new(metrics.dry_runs.clone()),
+            Box::new(metrics.conflicts.clone()),
+            Box::new(metrics.parse_failures.clone()),
+            Box::new(metrics.rejected_documents.clone()),
+            Box::new(metrics.lookup_failures.clone()),
+            Box::new(metrics.terminating_claims.clone()),
+            Box::new(metrics.validation_failures.clone()),
+            Box::new(metrics.namespace_cache_size.clone()),
+            Box::new(metrics.namespace_cache_updated.clone()),
+            Box::new(metrics.lookup_duration.clone()),
+            Box::new(metrics.cache_lookups.clone()),
+            Box::new(metrics.cache_evictions.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.panics.clone()),
+            Box::new(metrics.mutate_duration.clone()),
+            Box::new(metrics.phase_duration.clone()),
+            Box::new(metrics.in_flight.clone()),
+            Box::new(metrics.workers.clone()),
+            Box::new(metrics.shed_requests.clone()),
+            Box::new(metrics.rate_limited.clone()),
+            Box::new(metrics.certificate_not_after.clone()),
+            Box::new(metrics.certificate_expires_in.clone()),
+            Box::new(metrics.config_entries.clone()),
+            Box::new(metrics.config_last_load.clone()),
+            Box::new(metrics.config_loads.clone()),
+            Box::new(metrics.config_load_failures.clone()),
+            Box::new(metrics.ca_bundle_last_sync.clone()),
+            Box::new(metrics.ca_bundle_sync_failures.clone()),
+            Box::new(metrics.unmutated_pods.clone()),
+            Box::new(metrics.leader.clone()),
+            Box::new(metrics.leader_changes.clone()),
+            Box::new(metrics.feature_disabled.clone()),
+            Box::new(metrics.audit_log_dropped.clone()),
+        ] {
+            metrics
+                .registry
+                .register(collector)
+                .expect("Unique metric names");
+        }
+        metrics.claim_series_limit.set(DEFAULT_CLAIM_LIMIT as i64);
+        metrics
+    }
+
+    /// Limits the namespaces labeled individually, later ones are counted as `_other`
+    pub fn with_namespace_limit(mut self, limit: usize) -> Metrics {
+        self.namespace_labels = BoundedLabels::new(limit);
+        self
+    }
+
+    /// Limits the claims labeled individually, later ones are counted as `_other` in `_other`
+    pub fn with_claim_limit(mut self, limit: usize) -> Metrics {
+        self.claim_labels = BoundedLabels::new(limit);
+        self.claim_series_limit.set(limit as i64);
+        self
+    }
+
+    /// Whether to time the phases of requests, without any timer or clock read if not
+    pub fn with_phase_timing(mut self, enabled: bool) -> Metrics {
+        self.phase_timing = enabled;
+        self
+    }
+
+    /// Counts an object admitted unchanged
+    pub fn skipped(&self, reason: SkipReason) {
+        self.pods_skipped
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
+
+    /// Counts a request by its operation, "unknown" for others than those of the
+    /// admission API, e.g. if the request did not say
+    pub fn operation(&self, operation: &str) {
+        let operation = match operation {
+            "CREATE" | "UPDATE" | "DELETE" | "CONNECT" => operation,
+            _ => "unknown",
+        };
+        self.operations.with_label_values(&[operation]).inc();
+    }
+
+    /// Counts a request refused by the limit on e.g. the nesting "depth" or the "volumes"
+    pub fn rejected(&self, limit: &str) {
+        self.rejected_documents.with_label_values(&[limit]).inc();
+    }
+
+    /// Counts a failed lookup for e.g. the "access_modes" of a claim
+    pub fn lookup_failed(&self, check: &str) {
+        self.lookup_failures.with_label_values(&[check]).inc();
+    }
+
+    /// Counts a claim skipped because it has a deletionTimestamp
+    pub fn terminating_claim(&self) {
+        self.terminating_claims.inc();
+    }
+
+    /// Counts a pod failing validation, by the "deny" or "warn" mode it was answered in
+    pub fn validation_failed(&self, mode: &str) {
+        self.validation_failures.with_label_values(&[mode]).inc();
+    }
+
+    /// Records the size of the namespace cache, current as of now
+    pub fn namespace_cache_updated(&self, size: usize) {
+        self.namespace_cache_size.set(size as i64);
+        self.namespace_cache_updated.set(unix_now());
+    }
+
+    /// Times a lookup of e.g. a "claim" in the Kubernetes API until the timer is dropped
+    pub fn time_lookup(&self, resource: &str) -> HistogramTimer {
+        self.lookup_duration
+            .with_label_values(&[resource])
+            .start_timer()
+    }
+
+    /// Times a phase until the timer is dropped, None if phases are not timed
+    pub fn time_phase(&self, phase: Phase) -> Option<HistogramTimer> {
+        self.phase_timing.then(|| {
+            self.phase_duration
+                .with_label_values(&[phase.as_str()])
+                .start_timer()
+        })
+    }
+
+    /// Records a phase timed in parts, e.g. the lookups spread over a request
+    pub fn observe_phase(&self, phase: Phase, duration: Duration) {
+        if self.phase_timing {
+            self.phase_duration
+                .with_label_values(&[phase.as_str()])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Whether phases are timed, so that callers can skip reading the clock
+    pub fn phase_timing(&self) -> bool {
+        self.phase_timing
+    }
+
+    /// Counts a hit or miss in the cache, e.g. of "claims"
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        self.cache_lookups
+            .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+
+    /// Counts an entry dropped from the cache for lack of space
+    pub fn cache_evicted(&self, cache: &str) {
+        self.cache_evictions.with_label_values(&[cache]).inc();
+    }
+
+    /// Replaces the result of the previous audit, namespaces without such pods are dropped
+    pub fn set_unmutated_pods(&self, counts: &HashMap<String, i64>) {
+        self.unmutated_pods.reset();
+        for (namespace, count) in counts {
+            self.unmutated_pods
+                .with_label_values(&[namespace])
+                .set(*count);
+        }
+    }
+
+    /// Records whether this replica leads, counting the changes
+    pub fn set_leader(&self, leading: bool) {
+        let leading = i64::from(leading);
+        if self.leader.get() != leading {
+            self.leader_changes.inc();
+        }
+        self.leader.set(leading);
+    }
+
+    /// Records a feature disabled for lack of permissions, by its setting
+    pub fn feature_disabled(&self, feature: &str) {
+        self.feature_disabled.with_label_values(&[feature]).set(1);
+    }
+
+    /// Counts a decision not written to the audit log, by "overflow" of the queue or write "error"
+    pub fn audit_log_dropped(&self, reason: &str) {
+        self.audit_log_dropped.with_label_values(&[reason]).inc();
+    }
+
+    /// Counts a request over the rate limit by the address of the client, `_other` beyond the
+    /// first clients
+    pub fn rate_limited(&self, client: &str) {
+        let label = match self.client_labels.admit(client) {
+            (true, _) => client,
+            (false, _) => OTHER,
+        };
+        self.rate_limited.with_label_values(&[label]).inc();
+    }
+
+    /// Counts a patched pod or template in the namespace
+    pub fn mutated(&self, namespace: &str) {
+        self.pods_mutated.inc();
+        let label = match self.namespace_labels.admit(namespace) {
+            (true, _) => namespace,
+            (false, _) => OTHER,
+        };
+        self.mutations.with_label_values(&[label]).inc();
+        self.last_mutation.set(unix_now());
+    }
+
+    /// Counts a claim contributing to a patch
+    pub fn claim_matched(&self, namespace: &str, claim: &str) {
+        let (admitted, series) = self.claim_labels.admit(&format!("{namespace}/{claim}"));
+        self.claim_series.set(series as i64);
+        let labels = if admitted {
+            [namespace, claim]
+        } else {
+            [OTHER, OTHER]
+        };
+        self.claim_matches.with_label_values(&labels).inc();
+    }
+
+    /// Counts the claims matched for an admitted pod or template
+    pub fn claims_matched(&self, count: usize) {
+        self.matched_claims.inc_by(count as u64);
+    }
+
+    /// Records a successful load of the config with its entries by mode
+    pub fn config_loaded(&self, entries: &BTreeMap<&str, usize>) {
+        self.config_loads.inc();
+        for (mode, count) in entries {
+            self.config_entries
+                .with_label_values(&[mode])
+                .set(*count as i64);
+        }
+        self.config_last_load.set(unix_now());
+    }
+
+    /// Counts a failed load of the config by the type of error, e.g. an "invalid_entry"
+    pub fn config_load_failed(&self, error: &str) {
+        self.config_loads.inc();
+        self.config_load_failures.with_label_values(&[error]).inc();
+    }
+
+    /// Records a registration of the webhook or an update of its caBundle
+    pub fn ca_bundle_synced(&self, succeeded: bool) {
+        if succeeded {
+            self.ca_bundle_last_sync.set(unix_now());
+        } else {
+            self.ca_bundle_sync_failures.inc();
+        }
+    }
+
+    /// Sets the number of workers of the webhook server
+    pub fn set_workers(&self, workers: usize) {
+        self.workers.set(workers as i64);
+    }
+
+    /// Adds metrics collected elsewhere, e.g. of the process, served with the others
+    pub fn register(&self, collector: Box<dyn Collector>) {
+        self.registry
+            .register(collector)
+            .expect("Unique metric names");
+    }
+
+    /// Counts a request on the webhook path and notes its time, before it is read
+    pub fn request_received(&self, path: &str) {
+        self.requests.with_label_values(&[path]).inc();
+        self.last_request.set(unix_now());
+    }
+
+    /// Counts a request as in flight until the guard is dropped
+    pub fn track_in_flight(&self) -> InFlight {
+        self.in_flight.inc();
+        InFlight(self.in_flight.clone())
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Encodable metrics");
+        String::from_utf8(buffer).expect("UTF-8 metrics")
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+/// Guard of a request counted in [Metrics::in_flight]
+pub struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+        metrics.skipped(SkipReason::MirrorPod);
+        metrics.skipped(SkipReason::MirrorPod);
+        metrics.pods_mutated.inc();
+        let in_flight = metrics.track_in_flight();
+        assert_eq!(metrics.in_flight.get(), 1);
+        drop(in_flight);
+        let text = metrics.encode();
+        assert!(text.contains("gravivol_pods_skipped_total{reason=\"mirror_pod\"} 2\n"));
+        assert!(text.contains("gravivol_pods_mutated_total 1\n"));
+        assert!(!text.contains("gravivol_mutate_duration_seconds"));
+    }
+
+    #[test]
+    fn test_version_label() {
+        let metrics = Metrics::with_version("0.1.1+3e363d7a1b2c");
+        metrics.skipped(SkipReason::MirrorPod);
+        metrics.pods_mutated.inc();
+        let text = metrics.encode();
+        assert!(text.contains(
+            "gravivol_pods_skipped_total{reason=\"mirror_pod\",version=\"0.1.1+3e363d7a1b2c\"} 1\n"
+        ));
+        assert!(text.contains("gravivol_pods_mutated_total{version=\"0.1.1+3e363d7a1b2c\"} 1\n"));
+    }
+
+    #[test]
+    fn test_namespace_limit() {
+        let metrics = Metrics::new().with_namespace_limit(2);
+        for namespace in ["team-a", "team-b", "team-c", "team-a", "team-d"] {
+            metrics.mutated(namespace);
+        }
+        metrics.claims_matched(3);
+        let text = metrics.encode();
+        assert!(text.contains("gravivol_mutations_total{namespace=\"team-a\"} 2\n"));
+        assert!(text.contains("gravivol_mutations_total{namespace=\"team-b\"} 1\n"));
+        assert!(text.contains("gravivol_mutations_total{namespace=\"_other\"} 2\n"));
+        assert!(!text.contains("team-c"));
+        assert!(text.contains("gravivol_pods_mutated_total 5\n"));
+        assert!(text.contains("gravivol_matched_claims_total 3\n"));
+    }
+
+    #[test]
+    fn test_client_limit() {
+        let metrics = Metrics::new();
+        for client in 0..=CLIENT_LIMIT {
+            metrics.rate_limited(&format!("10.0.0.{client}"));
+        }
+        metrics.rate_limited("10.0.0.0");
+        metrics.rate_limited("10.0.1.0");
+        let text = metrics.encode();
+        assert!(text.contains("gravivol_rate_limited_requests_total{client=\"10.0.0.0\"} 2\n"));
+        assert!(text.contains("gravivol_rate_limited_requests_total{client=\"_other\"} 2\n"));
+        assert!(!text.contains(&format!("10.0.0.{CLIENT_LIMIT}\"")));
+    }
+
+    #[test]
+    fn test_claim_limit() {
+        let metrics = Metrics::new().with_claim_limit(2);
+        for (namespace, claim) in [
+            ("default", "data"),
+            ("apps", "data"),
+            ("default", "logs"),
+            ("default", "data"),
+            ("apps", "cache"),
+        ] {
+            metrics.claim_matched(namespace, claim);
+        }
+        let text = metrics.encode();
+        assert!(
+            text.contains("gravivol_claim_matches_total{claim=\"data\",namespace=\"default\"} 2\n")
+        );
+        assert!(
+            text.contains("gravivol_claim_matches_total{claim=\"data\",namespace=\"apps\"} 1\n")
+        );
+        assert!(
+            text.contains(
+                "gravivol_claim_matches_total{claim=\"_other\",namespace=\"_other\"} 2\n"
+            )
+        );
+        assert!(!text.contains("logs"));
+        assert!(text.contains("gravivol_claim_matches_series 2\n"));
+        assert!(text.contains("gravivol_claim_matches_series_limit 2\n"));
+        assert!(
+            Metrics::new()
+                .encode()
+                .contains("gravivol_claim_matches_series_limit 500\n")
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Cache of the labels and annotations of all namespaces, kept up to date by a watch
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use k8s_openapi::{
+    api::core::v1::Namespace,
+    apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement},
+};
+use kube::{
+    Api, Client, ResourceExt,
+    api::{ListParams, WatchEvent, WatchParams},
+};
+use tokio::sync::watch;
+
+use crate::metrics::Metrics;
+
+/// Namespaces listed per request
+const PAGE_SIZE: u32 = 500;
+/// Seconds a watch request lasts before it is renewed from the last resource version
+const WATCH_TIMEOUT_SECS: u32 = 290;
+/// Wait before listing again after the list or watch failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Longest wait for the first list on startup
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Labels and annotations of a namespace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NamespaceMetadata {
+    /// Labels of the namespace
+    pub labels: BTreeMap<String, String>,
+    /// Annotations of the namespace
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl NamespaceMetadata {
+    fn from_namespace(namespace: &Namespace) -> NamespaceMetadata {
+        NamespaceMetadata {
+            labels: namespace.labels().clone(),
+            annotations: namespace.annotations().clone(),
+        }
+    }
+}
+
+/// Read-only view of the namespaces for admission
+pub trait Namespaces: Send + Sync {
+    /// The metadata of the namespace, None if it is not known yet, e.g. created a moment ago or
+    /// before the cache is synced
+    fn get(&self, name: &str) -> Option<Arc<NamespaceMetadata>>;
+}
+
+/// Namespaces of the last list, updated by the events of the watch following it
+pub struct NamespaceCache {
+    snapshot: RwLock<HashMap<String, Arc<NamespaceMetadata>>>,
+    synced: watch::Sender<bool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Namespaces for NamespaceCache {
+    fn get(&self, name: &str) -> Option<Arc<NamespaceMetadata>> {
+        self.snapshot.read().unwrap().get(name).cloned()
+    }
+}
+
+impl NamespaceCache {
+    /// Empty until synced by [NamespaceCache::run]
+    pub fn new(metrics: Arc<Metrics>) -> NamespaceCache {
+        NamespaceCache {
+            snapshot: RwLock::new(HashMap::new()),
+            synced: watch::Sender::new(false),
+            metrics,
+        }
+    }
+
+    /// Waits for the first list at most for the timeout, false if it has not completed yet
+    pub async fn wait_synced(&self, timeout: Duration) -> bool {
+        let mut synced = self.synced.subscribe();
+        tokio::time::timeout(timeout, synced.wait_for(|synced| *synced))
+            .await
+            .is_ok_and(|synced| synced.is_ok())
+    }
+
+    /// Lists the namespaces, then watches them from the resource version of the list
+    ///
+    /// Everything is listed again when the watch expired, e.g. after the API server compacted
+    /// its history, and after errors, keeping the previous snapshot until then.
+    pub async fn run(self: Arc<Self>, client: Client) {
+        let api: Api<Namespace> = Api::all(client);
+        loop {
+            let mut version = match self.list(&api).await {
+                Ok(version) => version,
+                Err(err) => {
+                    tracing::error!("Cannot list namespaces: {err}");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            if let Err(err) = self.watch(&api, &mut version).await {
+                tracing::warn!("Watch of namespaces ended, listing them again: {err}");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    /// Replaces the snapshot, returning the resource version of the list
+    async fn list(&self, api: &Api<Namespace>) -> Result<String, kube::Error> {
+        let mut params = ListParams::default().limit(PAGE_SIZE);
+        let mut snapshot = HashMap::new();
+        loop {
+            let page = api.list(&params).await?;
+            for namespace in &page.items {
+                snapshot.insert(
+                    namespace.name_any(),
+                    Arc::new(NamespaceMetadata::from_namespace(namespace)),
+                );
+            }
+            match page.metadata.continue_ {
+                Some(token) if !token.is_empty() => params = params.continue_token(&token),
+                _ => {
+                    let count = snapshot.len();
+                    *self.snapshot.write().unwrap() = snapshot;
+                    self.updated();
+                    if !self.synced.send_replace(true) {
+                        tracing::info!("Cached the metadata of {count} namespaces");
+                    }
+                    return Ok(page.metadata.resource_version.unwrap_or_default());
+                }
+            }
+        }
+    }
+
+    /// Applies the events until the watch fails or expires
+    async fn watch(&self, api: &Api<Namespace>, version: &mut String) -> Result<(), String> {
+        // Bookmarks are requested by default, keeping the version current in quiet clusters
+        let params = WatchParams::default().timeout(WATCH_TIMEOUT_SECS);
+        loop {
+            let events = api
+                .watch(&params, version)
+                .await
+                .map_err(|err| err.to_string())?;
+            self.updated();
+            let mut events = std::pin::pin!(events);
+            while let Some(event) = events.next().await {
+                match event.map_err(|err| err.to_string())? {
+                    WatchEvent::Error(status) => return Err(status.to_string()),
+                    event => {
+                        if let Some(resource_version) = self.apply(event) {
+                            *version = resource_version;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies the event to the snapshot, returning its resource version
+    fn apply(&self, event: WatchEvent<Namespace>) -> Option<String> {
+        let version = match event {
+            WatchEvent::Added(namespace) | WatchEvent::Modified(namespace) => {
+                let metadata = Arc::new(NamespaceMetadata::from_namespace(&namespace));
+                self.snapshot
+                    .write()
+                    .unwrap()
+                    .insert(namespace.name_any(), metadata);
+                namespace.resource_version()
+            }
+            WatchEvent::Deleted(namespace) => {
+                self.snapshot.write().unwrap().remove(&namespace.name_any());
+                namespace.resource_version()
+            }
+            WatchEvent::Bookmark(bookmark) => Some(bookmark.metadata.resource_version),
+            WatchEvent::Error(_) => None,
+        };
+        self.updated();
+        version
+    }
+
+    /// Reports the size, and that the snapshot is current as of now
+    fn updated(&self) {
+        let size = self.snapshot.read().unwrap().len();
+        self.metrics.namespace_cache_updated(size);
+    }
+}
+
+/// Whether the labels match the selector, like a namespaceSelector of a webhook
+pub fn selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let match_labels = selector.match_labels.iter().flatten();
+    let expressions = selector.match_expressions.iter().flatten();
+    match_labels
+        .into_iter()
+        .all(|(key, value)| labels.get(key) == Some(value))
+        && expressions.into_iter().all(|requirement| {
+            let LabelSelectorRequirement {
+                key,
+                operator,
+                values,
+            } = requirement;
+            let values = values.as_deref().unwrap_or_default();
+            match (operator.as_str(), labels.get(key)) {
+                ("In", Some(value)) => values.contains(value),
+                ("In", None) => false,
+                ("NotIn", Some(value)) => !values.contains(value),
+                ("NotIn", None) => true,
+                ("Exists", found) => found.is_some(),
+                ("DoesNotExist", found) => found.is_none(),
+                // Rejected by the API server, matching nothing like it would
+                _ => false,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use serde_json::json;
+
+    use super::*;
+
+    fn namespace(name: &str, version: &str, labels: &[(&str, &str)]) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.to_owned()),
+                resource_version: Some(version.to_owned()),
+                labels: Some(
+                    labels
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                ),
+                ..ObjectMeta::default()
+            },
+            ..Namespace::default()
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let metrics = Arc::new(Metrics::new());
+        let cache = NamespaceCache::new(metrics.clone());
+        assert_eq!(cache.get("team-a"), None);
+
+        let added = cache.apply(WatchEvent::Added(namespace("team-a", "10", &[])));
+        assert_eq!(added.as_deref(), Some("10"));
+        let modified = cache.apply(WatchEvent::Modified(namespace(
+            "team-a",
+            "11",
+            &[("gravivol", "enabled")],
+        )));
+        assert_eq!(modified.as_deref(), Some("11"));
+        assert_eq!(cache.get("team-a").unwrap().labels["gravivol"], "enabled");
+        assert!(
+            metrics
+                .encode()
+                .contains("gravivol_namespace_cache_size 1\n")
+        );
+
+        cache.apply(WatchEvent::Deleted(namespace("team-a", "12", &[])));
+        assert_eq!(cache.get("team-a"), None);
+        assert!(
+            metrics
+                .encode()
+                .contains("gravivol_namespace_cache_size 0\n")
+        );
+    }
+
+    #[test]
+    fn test_selector_matches() {
+        let labels: BTreeMap<String, String> = [("gravivol", "enabled"), ("team", "a")]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        let matches = |selector: serde_json::Value| {
+            selector_matches(&serde_json::from_value(selector).unwrap(), &labels)
+        };
+        assert!(matches(json!({})));
+        assert!(matches(json!({ "matchLabels": { "gravivol": "enabled" } })));
+        assert!(!matches(
+            json!({ "matchLabels": { "gravivol": "disabled" } })
+        ));
+        assert!(matches(json!({
+            "matchExpressions": [
+                { "key": "team", "operator": "In", "values": ["a", "b"] },
+                { "key": "tier", "operator": "NotIn", "values": ["system"] },
+                { "key": "gravivol", "operator": "Exists" },
+            ],
+        })));
+        assert!(!matches(json!({
+            "matchExpressions": [{ "key": "team", "operator": "DoesNotExist" }],
+        })));
+        assert!(!matches(json!({
+            "matchExpressions": [{ "key": "team", "operator": "Gt", "values": ["1"] }],
+        })));
+    }
+}
@@ -0,0 +1,250 @@
+//! Probing the permissions of the enabled features on startup
+
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::{Api, Client, api::PostParams};
+use serde::Serialize;
+
+/// A verb on a resource in all namespaces, and whether the service account is allowed it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Permission {
+    pub verb: &'static str,
+    /// API group, empty for the core group
+    pub group: &'static str,
+    pub resource: &'static str,
+    /// None if the review failed
+    pub allowed: Option<bool>,
+    /// The settings of the features needing it
+    pub features: Vec<&'static str>,
+}
+
+/// Verbs, API groups and resources a feature needs, by the setting enabling it
+fn required(feature: &str) -> &'static [(&'static str, &'static str, &'static str)] {
+    const CLAIMS: (&str, &str, &str) = ("get", "", "persistentvolumeclaims");
+    match feature {
+        "GRAVIVOL_NODE_AFFINITY" => &[CLAIMS, ("get", "", "persistentvolumes")],
+        "GRAVIVOL_FIRST_POD" => &[("list", "", "pods")],
+        "GRAVIVOL_SCHEDULING_GATE" => &[("list", "", "pods"), ("patch", "", "pods")],
+        "GRAVIVOL_LABEL_VALUE" | "GRAVIVOL_ACCESS_MODES" | "GRAVIVOL_UNBOUND_CLAIMS" => &[CLAIMS],
+        "GRAVIVOL_PROVISIONERS" | "GRAVIVOL_PROVISIONERS_DENY" => &[
+            CLAIMS,
+            ("get", "storage.k8s.io", "storageclasses"),
+            ("list", "storage.k8s.io", "storageclasses"),
+        ],
+        "GRAVIVOL_REGISTER_WEBHOOK" => &[
+            (
+                "get",
+                "admissionregistration.k8s.io",
+                "mutatingwebhookconfigurations",
+            ),
+            (
+                "create",
+                "admissionregistration.k8s.io",
+                "mutatingwebhookconfigurations",
+            ),
+            (
+                "patch",
+                "admissionregistration.k8s.io",
+                "mutatingwebhookconfigurations",
+            ),
+        ],
+        "GRAVIVOL_EMIT_EVENTS" => &[("create", "events.k8s.io", "events"), ("get", "", "pods")],
+        "GRAVIVOL_AUDIT" => &[("list", "", "pods")],
+        "GRAVIVOL_NAMESPACE_SELECTOR" => &[("list", "", "namespaces"), ("watch", "", "namespaces")],
+        "GRAVIVOL_DEREGISTER_ON_SHUTDOWN" => &[(
+            "delete",
+            "admissionregistration.k8s.io",
+            "mutatingwebhookconfigurations",
+        )],
+        "GRAVIVOL_LEADER_ELECTION" => &[
+            ("get", "coordination.k8s.io", "leases"),
+            ("create", "coordination.k8s.io", "leases"),
+            ("update", "coordination.k8s.io", "leases"),
+        ],
+        _ => &[],
+    }
+}
+
+/// The permissions the features need, each once and listing the features needing it
+pub fn required_by(features: &[&'static str]) -> Vec<Permission> {
+    let mut permissions: BTreeMap<_, Vec<&'static str>> = BTreeMap::new();
+    for feature in features {
+        for &(verb, group, resource) in required(feature) {
+            permissions
+                .entry((group, resource, verb))
+                .or_default()
+                .push(feature);
+        }
+    }
+    permissions
+        .into_iter()
+        .map(|((group, resource, verb), features)| Permission {
+            verb,
+            group,
+            resource,
+            allowed: None,
+            features,
+        })
+        .collect()
+}
+
+impl Permission {
+    /// Whether it is granted by a Role in the namespace of gravivol rather than a ClusterRole
+    pub fn is_namespaced(&self) -> bool {
+        self.resource == "leases"
+    }
+}
+
+/// Asks the API server whether the service account is allowed each permission
+///
+/// Leases are reviewed in the namespace of the client, all but create for the lease of the
+/// election only.
+pub async fn probe(client: &Client, permissions: &mut [Permission], lease_name: &str) {
+    let api: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    for permission in permissions {
+        let namespaced = permission.is_namespaced();
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    verb: Some(permission.verb.to_owned()),
+                    group: Some(permission.group.to_owned()),
+                    resource: Some(permission.resource.to_owned()),
+                    namespace: namespaced.then(|| client.default_namespace().to_owned()),
+                    name: (namespaced && permission.verb != "create")
+                        .then(|| lease_name.to_owned()),
+                    ..ResourceAttributes::default()
+                }),
+                ..SelfSubjectAccessReviewSpec::default()
+            },
+            ..SelfSubjectAccessReview::default()
+        };
+        permission.allowed = match api.create(&PostParams::default(), &review).await {
+            Ok(review) => review.status.map(|status| status.allowed),
+            Err(err) => {
+                tracing::warn!(
+                    "Cannot review permission to {} {}: {err}",
+                    permission.verb,
+                    permission.resource
+                );
+                None
+            }
+        };
+    }
+}
+
+/// The features needing a denied permission, in the order of the permissions
+pub fn denied_features(permissions: &[Permission]) -> Vec<&'static str> {
+    let mut features = Vec::new();
+    for permission in permissions {
+        if permission.allowed == Some(false) {
+            for feature in &permission.features {
+                if !features.contains(feature) {
+                    features.push(*feature);
+                }
+            }
+        }
+    }
+    features
+}
+
+/// A table of the permissions for the log, one line each
+pub fn table(permissions: &[Permission]) -> String {
+    let width = permissions
+        .iter()
+        .map(|permission| resource(permission).len())
+        .max()
+        .unwrap_or_default();
+    permissions
+        .iter()
+        .map(|permission| {
+            let allowed = match permission.allowed {
+                Some(true) => "granted",
+                Some(false) => "denied",
+                None => "unknown",
+            };
+            format!(
+                "{:<6} {:<width$} {allowed:<7} {}",
+                permission.verb,
+                resource(permission),
+                permission.features.join(", ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The resource as in kubectl, e.g. storageclasses.storage.k8s.io
+fn resource(permission: &Permission) -> String {
+    if permission.group.is_empty() {
+        permission.resource.to_owned()
+    } else {
+        format!("{}.{}", permission.resource, permission.group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_by() {
+        let leases = required_by(&[
+            "GRAVIVOL_LEADER_ELECTION",
+            "GRAVIVOL_DEREGISTER_ON_SHUTDOWN",
+        ]);
+        assert_eq!(
+            leases
+                .iter()
+                .map(|permission| (
+                    permission.verb,
+                    permission.resource,
+                    permission.is_namespaced()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("delete", "mutatingwebhookconfigurations", false),
+                ("create", "leases", true),
+                ("get", "leases", true),
+                ("update", "leases", true),
+            ]
+        );
+
+        let mut permissions = required_by(&[
+            "GRAVIVOL_NODE_AFFINITY",
+            "GRAVIVOL_ACCESS_MODES",
+            "GRAVIVOL_AUDIT",
+        ]);
+        assert_eq!(
+            permissions
+                .iter()
+                .map(|permission| (
+                    permission.verb,
+                    permission.resource,
+                    permission.features.len()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("get", "persistentvolumeclaims", 2),
+                ("get", "persistentvolumes", 1),
+                ("list", "pods", 1),
+            ]
+        );
+        assert!(denied_features(&permissions).is_empty());
+
+        permissions[0].allowed = Some(false);
+        permissions[1].allowed = Some(true);
+        assert_eq!(
+            denied_features(&permissions),
+            vec!["GRAVIVOL_NODE_AFFINITY", "GRAVIVOL_ACCESS_MODES"]
+        );
+        assert_eq!(
+            table(&permissions),
+            "get    persistentvolumeclaims denied  GRAVIVOL_NODE_AFFINITY, GRAVIVOL_ACCESS_MODES\n\
+             get    persistentvolumes      granted GRAVIVOL_NODE_AFFINITY\n\
+             list   pods                   unknown GRAVIVOL_AUDIT"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Metrics of the process read from /proc/self, like those of other Prometheus clients
+
+use std::fs;
+
+use prometheus::{
+    Counter, Gauge, IntGauge,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+};
+
+/// Resources used by the process, read on each scrape
+pub struct ProcessCollector {
+    cpu: Counter,
+    resident_memory: IntGauge,
+    virtual_memory: IntGauge,
+    open_fds: IntGauge,
+    max_fds: IntGauge,
+    threads: IntGauge,
+    start_time: Gauge,
+    /// Clock ticks per second of the times in /proc
+    ticks: f64,
+    page_size: i64,
+}
+
+/// The fields of /proc/self/stat used, see proc(5)
+#[derive(Debug, PartialEq)]
+struct Stat {
+    /// utime and stime in clock ticks
+    cpu_ticks: u64,
+    threads: i64,
+    /// Ticks after boot
+    start_ticks: u64,
+    virtual_bytes: i64,
+    resident_pages: i64,
+}
+
+impl Stat {
+    fn parse(stat: &str) -> Option<Stat> {
+        // The command name in parentheses may contain spaces and parentheses itself
+        let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+        // Numbered from the state, the third field of proc(5)
+        let field = |number: usize| fields.get(number - 3)?.parse::<u64>().ok();
+        Some(Stat {
+            cpu_ticks: field(14)? + field(15)?,
+            threads: field(20)? as i64,
+            start_ticks: field(22)?,
+            virtual_bytes: field(23)? as i64,
+            resident_pages: field(24)? as i64,
+        })
+    }
+}
+
+impl ProcessCollector {
+    pub fn new() -> ProcessCollector {
+        ProcessCollector {
+            cpu: Counter::new(
+                "process_cpu_seconds_total",
+                "User and system CPU time spent in seconds",
+            )
+            .unwrap(),
+            resident_memory: IntGauge::new(
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes",
+            )
+            .unwrap(),
+            virtual_memory: IntGauge::new(
+                "process_virtual_memory_bytes",
+                "Virtual memory size in bytes",
+            )
+            .unwrap(),
+            open_fds: IntGauge::new("process_open_fds", "Open file descriptors").unwrap(),
+            max_fds: IntGauge::new("process_max_fds", "Limit of open file descriptors").unwrap(),
+            threads: IntGauge::new("process_threads", "OS threads of the process").unwrap(),
+            start_time: Gauge::new(
+                "process_start_time_seconds",
+                "Start time of the process since the Unix epoch in seconds",
+            )
+            .unwrap(),
+            // SAFETY: sysconf only reads configuration values
+            ticks: unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64,
+            page_size: unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as i64,
+        }
+    }
+
+    fn update(&self) {
+        if let Some(stat) = fs::read_to_string("/proc/self/stat")
+            .ok()
+            .as_deref()
+            .and_then(Stat::parse)
+        {
+            let cpu = stat.cpu_ticks as f64 / self.ticks;
+            // Only ever increases, set by the difference to the last scrape
+            if cpu > self.cpu.get() {
+                self.cpu.inc_by(cpu - self.cpu.get());
+            }
+            self.threads.set(stat.threads);
+            self.virtual_memory.set(stat.virtual_bytes);
+            self.resident_memory
+                .set(stat.resident_pages * self.page_size);
+            if let Some(boot_time) = boot_time() {
+                self.start_time
+                    .set(boot_time + stat.start_ticks as f64 / self.ticks);
+            }
+        }
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            self.open_fds.set(fds.count() as i64);
+        }
+        if let Some(limit) = fs::read_to_string("/proc/self/limits")
+            .ok()
+            .as_deref()
+            .and_then(max_open_files)
+        {
+            self.max_fds.set(limit);
+        }
+    }
+}
+
+/// Boot time in seconds since the Unix epoch, from /proc/stat
+fn boot_time() -> Option<f64> {
+    fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The soft limit of "Max open files" in /proc/self/limits, None if unlimited
+fn max_open_files(limits: &str) -> Option<i64> {
+    limits
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl Default for ProcessCollector {
+    fn default() -> Self {
+        ProcessCollector::new()
+    }
+}
+
+impl Collector for ProcessCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.cpu.desc(),
+            self.resident_memory.desc(),
+            self.virtual_memory.desc(),
+            self.open_fds.desc(),
+            self.max_fds.desc(),
+            self.threads.desc(),
+            self.start_time.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.update();
+        [
+            self.cpu.collect(),
+            self.resident_memory.collect(),
+            self.virtual_memory.collect(),
+            self.open_fds.collect(),
+            self.max_fds.collect(),
+            self.threads.collect(),
+            self.start_time.collect(),
+        ]
+        .concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let stat = "4242 (gravivol (worker)) S 1 4242 4242 0 -1 4194560 2515 0 0 0 37 12 0 0 20 0 \
+                    9 0 1183 1402322944 4109 18446744073709551615 1 1 0 0 0 0 0 4096 17102 0 0 0 \
+                    17 3 0 0 0 0 0";
+        assert_eq!(
+            Stat::parse(stat),
+            Some(Stat {
+                cpu_ticks: 49,
+                threads: 9,
+                start_ticks: 1183,
+                virtual_bytes: 1402322944,
+                resident_pages: 4109,
+            })
+        );
+        assert_eq!(Stat::parse("4242 (gravivol"), None);
+
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             127431               127431               processes \n\
+                      Max open files            1048576              1048576              files     \n";
+        assert_eq!(max_open_files(limits), Some(1048576));
+    }
+}
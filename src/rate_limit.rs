@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Sources kept, those with a full bucket and then the least recently seen are forgotten beyond
+const MAX_SOURCES: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per source, e.g. client address, shared by all workers
+pub struct RateLimiter {
+    /// Tokens added per second
+    rate: f64,
+    /// Tokens of a full bucket, the requests allowed at once
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allows rate requests per second and burst at once, as many as rate per second if 0
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        let burst = match burst {
+            0 => rate.ceil().max(1.0),
+            burst => f64::from(burst),
+        };
+        RateLimiter {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token of the source, false if it has none left
+    pub fn try_acquire(&self, source: &str) -> bool {
+        self.try_acquire_at(source, Instant::now())
+    }
+
+    fn try_acquire_at(&self, source: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, source, now);
+        if self.refill(bucket, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes a token of the source even if it has none left, returning how long to wait for it
+    pub fn reserve(&self, source: &str) -> Duration {
+        self.reserve_at(source, Instant::now())
+    }
+
+    fn reserve_at(&self, source: &str, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = self.bucket(&mut buckets, source, now);
+        // Negative while requests wait, refilled before later ones get their turn
+        bucket.tokens = self.refill(bucket, now) - 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+
+    /// The bucket of the source, a full one for a new source after making room for it
+    fn bucket<'a>(
+        &self,
+        buckets: &'a mut HashMap<String, Bucket>,
+        source: &str,
+        now: Instant,
+    ) -> &'a mut Bucket {
+        if buckets.len() >= MAX_SOURCES && !buckets.contains_key(source) {
+            buckets.retain(|_, bucket| self.available(bucket, now) < self.burst);
+            // A flood of addresses must not grow the map, the evicted source gets a full bucket
+            if buckets.len() >= MAX_SOURCES
+                && let Some(oldest) = buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(source, _)| source.clone())
+            {
+                buckets.remove(&oldest);
+            }
+        }
+        buckets.entry(source.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        })
+    }
+
+    /// Tokens available at the time, without updating the bucket
+    fn available(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /// Adds the tokens since the last update, returns the tokens available
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        bucket.tokens = self.available(bucket, now);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at("10.0.0.1", start));
+        }
+        assert!(!limiter.try_acquire_at("10.0.0.1", start));
+        // Other sources have their own bucket
+        assert!(limiter.try_acquire_at("10.0.0.2", start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at("10.0.0.1", later));
+        assert!(!limiter.try_acquire_at("10.0.0.1", later));
+
+        // Refilled up to the burst only
+        let idle = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at("10.0.0.1", idle));
+        }
+        assert!(!limiter.try_acquire_at("10.0.0.1", idle));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let limiter = RateLimiter::new(2.0, 2);
+        let start = Instant::now();
+        assert_eq!(limiter.reserve_at("kube", start), Duration::ZERO);
+        assert_eq!(limiter.reserve_at("kube", start), Duration::ZERO);
+        // Queued behind each other
+        assert_eq!(
+            limiter.reserve_at("kube", start),
+            Duration::from_millis(500)
+        );
+        assert_eq!(limiter.reserve_at("kube", start), Duration::from_secs(1));
+        assert_eq!(
+            limiter.reserve_at("kube", start + Duration::from_secs(2)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_max_sources() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let start = Instant::now();
+        for source in 0..MAX_SOURCES {
+            let now = start + Duration::from_micros(source as u64);
+            assert!(limiter.try_acquire_at(&source.to_string(), now));
+        }
+        let now = start + Duration::from_millis(100);
+        assert!(limiter.try_acquire_at("new", now));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_SOURCES);
+        // The least recently seen source is forgotten
+        assert!(!buckets.contains_key("0"));
+        assert!(buckets.contains_key("1"));
+    }
+
+    #[test]
+    fn test_default_burst() {
+        assert_eq!(RateLimiter::new(2.5, 0).burst, 3.0);
+        assert_eq!(RateLimiter::new(0.5, 0).burst, 1.0);
+    }
+}
@@ -0,0 +1,655 @@
+use std::time::Duration;
+
+use base64::prelude::*;
+use k8s_openapi::api::{
+    admissionregistration::v1::MutatingWebhookConfiguration, discovery::v1::EndpointSlice,
+};
+use kube::{
+    Api, Client,
+    api::{DeleteParams, ListParams, Patch, PatchParams, Preconditions},
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::{
+    controller::{FailureMode, Kind},
+    settings::{Settings, VALIDATE_PATH},
+};
+
+/// Name of the MutatingWebhookConfiguration and of its webhook
+pub const CONFIGURATION_NAME: &str = "gravivol";
+const WEBHOOK_NAME: &str = "gravivol.fonona.net";
+/// Name of the webhook of the ValidatingWebhookConfiguration
+const VALIDATING_WEBHOOK_NAME: &str = "validate.gravivol.fonona.net";
+/// Field manager of the server-side apply, owning the fields gravivol sets
+const FIELD_MANAGER: &str = "gravivol";
+/// Label marking the configuration as created by gravivol instead of a manifest
+pub const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+/// Label of the EndpointSlices of a service
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+/// Attempts to patch the caBundle before giving up until the certificate changes again
+const SYNC_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each further one
+const SYNC_BACKOFF: Duration = Duration::from_secs(1);
+/// Time the shutdown waits for the configuration to be deregistered
+pub const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What happens to the registered configuration on shutdown
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeregisterMode {
+    /// Keep it, the API server keeps calling the webhook
+    Off,
+    /// Delete it
+    Delete,
+    /// Set its failurePolicy to Ignore, until it is registered again on startup
+    Ignore,
+}
+
+impl DeregisterMode {
+    pub fn from_name(name: &str) -> Option<DeregisterMode> {
+        match name {
+            "off" => Some(DeregisterMode::Off),
+            "delete" => Some(DeregisterMode::Delete),
+            "ignore" => Some(DeregisterMode::Ignore),
+            _ => None,
+        }
+    }
+}
+
+/// Where the API server sends admission requests and which ones
+pub struct Registration {
+    pub service: String,
+    pub namespace: String,
+    pub port: u16,
+    pub path: String,
+    pub timeout_secs: u32,
+    pub failure_mode: FailureMode,
+    /// LabelSelector of the namespaces whose objects are sent, all if None
+    pub namespace_selector: Option<Value>,
+    pub kinds: Vec<Kind>,
+    pub label_pvcs: bool,
+}
+
+impl Registration {
+    /// Registration of the settings, in the namespace of the client unless configured
+    pub fn new(settings: &Settings, client: &Client) -> Registration {
+        let namespace = settings
+            .webhook_namespace
+            .clone()
+            .unwrap_or_else(|| client.default_namespace().to_owned());
+        Registration::in_namespace(settings, namespace)
+    }
+
+    /// Registration of the settings for the service in the namespace
+    pub fn in_namespace(settings: &Settings, namespace: String) -> Registration {
+        let mut kinds: Vec<Kind> = settings.controller.kinds.iter().copied().collect();
+        kinds.sort_by_key(|kind| kind.to_string());
+        Registration {
+            service: settings.webhook_service.clone(),
+            namespace,
+            port: settings.webhook_port,
+            path: settings.mutate_paths[0].clone(),
+            timeout_secs: settings.webhook_timeout_secs,
+            failure_mode: settings.controller.failure_mode,
+            namespace_selector: settings.webhook_namespace_selector.clone(),
+            kinds,
+            label_pvcs: settings.controller.label_pvcs,
+        }
+    }
+
+    /// Rules for the configured kinds, grouped by API group like in the chart
+    fn rules(&self) -> Vec<Value> {
+        let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+        let mut add = |group, resource| match groups.iter_mut().find(|(name, _)| *name == group) {
+            Some((_, resources)) => resources.push(resource),
+            None => groups.push((group, vec![resource])),
+        };
+        for kind in &self.kinds {
+            match kind {
+                Kind::Pod => add("", "pods"),
+                Kind::Deployment => add("apps", "deployments"),
+                Kind::StatefulSet => add("apps", "statefulsets"),
+                Kind::DaemonSet => add("apps", "daemonsets"),
+                Kind::Job => add("batch", "jobs"),
+                Kind::CronJob => add("batch", "cronjobs"),
+            }
+        }
+        if self.label_pvcs {
+            add("", "persistentvolumeclaims");
+        }
+        groups
+            .into_iter()
+            .map(|(group, resources)| {
+                json!({
+                    "apiGroups": [group],
+                    "apiVersions": ["v1"],
+                    "resources": resources,
+                    "operations": ["CREATE"],
+                    "scope": "Namespaced",
+                })
+            })
+            .collect()
+    }
+
+    /// The MutatingWebhookConfiguration trusting the CA bundle in PEM
+    pub fn configuration(&self, ca_bundle: &str) -> Value {
+        let mut webhook = json!({
+            "name": WEBHOOK_NAME,
+            "clientConfig": {
+                "service": {
+                    "namespace": self.namespace,
+                    "name": self.service,
+                    "path": self.path,
+                    "port": self.port,
+                },
+                "caBundle": BASE64_STANDARD.encode(ca_bundle),
+            },
+            "rules": self.rules(),
+            "sideEffects": "None",
+            "admissionReviewVersions": ["v1", "v1beta1"],
+            "failurePolicy": match self.failure_mode {
+                FailureMode::Open => "Ignore",
+                FailureMode::Closed => "Fail",
+            },
+            "timeoutSeconds": self.timeout_secs,
+        });
+        if let Some(selector) = &self.namespace_selector {
+            webhook["namespaceSelector"] = selector.clone();
+        }
+        json!({
+            "apiVersion": "admissionregistration.k8s.io/v1",
+            "kind": "MutatingWebhookConfiguration",
+            "metadata": {
+                "name": CONFIGURATION_NAME,
+                "labels": { MANAGED_BY_LABEL: "gravivol" },
+            },
+            "webhooks": [webhook],
+        })
+    }
+
+    /// The ValidatingWebhookConfiguration of /validate trusting the CA bundle in PEM, like the
+    /// chart's
+    ///
+    /// It has no namespaceSelector, so that it catches pods the mutating webhook did not see, and
+    /// ignores failures, so that pods are not blocked while gravivol is unavailable.
+    pub fn validating_configuration(&self, ca_bundle: &str) -> Value {
+        json!({
+            "apiVersion": "admissionregistration.k8s.io/v1",
+            "kind": "ValidatingWebhookConfiguration",
+            "metadata": { "name": CONFIGURATION_NAME },
+            "webhooks": [{
+                "name": VALIDATING_WEBHOOK_NAME,
+                "clientConfig": {
+                    "service": {
+                        "namespace": self.namespace,
+                        "name": self.service,
+                        "path": VALIDATE_PATH,
+                        "port": self.port,
+                    },
+                    "caBundle": BASE64_STANDARD.encode(ca_bundle),
+                },
+                "rules": [{
+                    "apiGroups": [""],
+                    "apiVersions": ["v1"],
+                    "resources": ["pods"],
+                    "operations": ["CREATE"],
+                    "scope": "Namespaced",
+                }],
+                "sideEffects": "None",
+                "admissionReviewVersions": ["v1", "v1beta1"],
+                "failurePolicy": "Ignore",
+                "timeoutSeconds": self.timeout_secs,
+            }],
+        })
+    }
+}
+
+/// Creates or updates the MutatingWebhookConfiguration by server-side apply
+///
+/// Applying the same configuration again changes nothing.
+pub async fn register(
+    client: Client,
+    registration: &Registration,
+    ca_bundle: &str,
+) -> Result<(), String> {
+    let api: Api<MutatingWebhookConfiguration> = Api::all(client);
+    let configuration = registration.configuration(ca_bundle);
+    match api
+        .patch(
+            CONFIGURATION_NAME,
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(&configuration),
+        )
+        .await
+    {
+        Ok(_) => {
+            tracing::info!(
+                "Registered MutatingWebhookConfiguration {CONFIGURATION_NAME} for service {}/{} on {}",
+                registration.namespace,
+                registration.service,
+                registration.path
+            );
+            Ok(())
+        }
+        Err(err) => Err(describe_error(&err)),
+    }
+}
+
+/// Strategic merge patch of the caBundle, merged into the webhook of the same name
+fn ca_bundle_patch(ca_bundle: &str) -> Value {
+    json!({
+        "webhooks": [{
+            "name": WEBHOOK_NAME,
+            "clientConfig": { "caBundle": BASE64_STANDARD.encode(ca_bundle) },
+        }],
+    })
+}
+
+/// Wait before the given retry, counted from 1
+fn backoff(retry: u32) -> Duration {
+    SYNC_BACKOFF * 2u32.pow(retry - 1)
+}
+
+/// Whether the API server may accept the same request later
+fn is_transient(err: &kube::Error) -> bool {
+    match err {
+        kube::Error::Api(status) => matches!(status.code, 409 | 429 | 500..),
+        _ => true,
+    }
+}
+
+/// Patches the caBundle of the registered configuration, retrying transient errors with backoff
+///
+/// The whole configuration is applied again if it was deleted. A dry run only logs the patch.
+pub async fn sync_ca_bundle(
+    client: Client,
+    registration: &Registration,
+    ca_bundle: &str,
+    dry_run: bool,
+) -> Result<(), String> {
+    let patch = ca_bundle_patch(ca_bundle);
+    if dry_run {
+        tracing::info!(
+            "Dry run, not patching MutatingWebhookConfiguration {CONFIGURATION_NAME} with {patch}"
+        );
+        return Ok(());
+    }
+    let api: Api<MutatingWebhookConfiguration> = Api::all(client.clone());
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_owned()),
+        ..PatchParams::default()
+    };
+    let mut attempt = 1;
+    loop {
+        match api
+            .patch(CONFIGURATION_NAME, &params, &Patch::Strategic(&patch))
+            .await
+        {
+            Ok(_) => {
+                tracing::info!(
+                    "Updated the caBundle of MutatingWebhookConfiguration {CONFIGURATION_NAME}"
+                );
+                return Ok(());
+            }
+            Err(kube::Error::Api(status)) if status.code == 404 => {
+                tracing::warn!(
+                    "MutatingWebhookConfiguration {CONFIGURATION_NAME} was deleted, registering it again"
+                );
+                return register(client, registration, ca_bundle).await;
+            }
+            Err(err) if attempt < SYNC_ATTEMPTS && is_transient(&err) => {
+                let delay = backoff(attempt);
+                tracing::warn!("{}, retrying in {}s", describe_error(&err), delay.as_secs());
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(describe_error(&err)),
+        }
+    }
+}
+
+/// Deletes or neutralizes the configuration on shutdown, if it carries the managed-by label
+/// and no other pod serves the webhook
+///
+/// A configuration installed by a manifest, e.g. the chart, is left alone, and so is one still
+/// served by the new replicas of a rolling update.
+pub async fn deregister(
+    client: Client,
+    mode: DeregisterMode,
+    registration: &Registration,
+    pod_name: &str,
+) -> Result<(), String> {
+    let describe = |err: kube::Error| {
+        format!("Cannot deregister MutatingWebhookConfiguration {CONFIGURATION_NAME}: {err}")
+    };
+    let slices: Api<EndpointSlice> = Api::namespaced(client.clone(), &registration.namespace);
+    let params =
+        ListParams::default().labels(&format!("{SERVICE_NAME_LABEL}={}", registration.service));
+    let slices = slices.list(&params).await.map_err(describe)?;
+    if other_endpoint_ready(&slices.items, pod_name) {
+        tracing::info!(
+            "Not deregistering MutatingWebhookConfiguration {CONFIGURATION_NAME}, other pods of service {}/{} are ready",
+            registration.namespace,
+            registration.service
+        );
+        return Ok(());
+    }
+    let api: Api<MutatingWebhookConfiguration> = Api::all(client);
+    let Some(configuration) = api.get_opt(CONFIGURATION_NAME).await.map_err(describe)? else {
+        return Ok(());
+    };
+    if !is_managed(&configuration) {
+        tracing::info!(
+            "Not deregistering MutatingWebhookConfiguration {CONFIGURATION_NAME}, it is not managed by gravivol"
+        );
+        return Ok(());
+    }
+    match mode {
+        DeregisterMode::Off => {}
+        DeregisterMode::Delete => {
+            // Not a configuration created again in the meantime
+            let params = DeleteParams {
+                preconditions: Some(Preconditions {
+                    uid: configuration.metadata.uid,
+                    resource_version: None,
+                }),
+                ..DeleteParams::default()
+            };
+            api.delete(CONFIGURATION_NAME, &params)
+                .await
+                .map_err(describe)?;
+            tracing::info!("Deleted MutatingWebhookConfiguration {CONFIGURATION_NAME}");
+        }
+        DeregisterMode::Ignore => {
+            let params = PatchParams {
+                field_manager: Some(FIELD_MANAGER.to_owned()),
+                ..PatchParams::default()
+            };
+            api.patch(
+                CONFIGURATION_NAME,
+                &params,
+                &Patch::Strategic(&ignore_patch()),
+            )
+            .await
+            .map_err(describe)?;
+            tracing::info!(
+                "Set the failurePolicy of MutatingWebhookConfiguration {CONFIGURATION_NAME} to Ignore"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether an endpoint of another pod is ready, receiving the admission requests after this one
+///
+/// A terminating pod's own endpoint is not ready anymore. An endpoint of unknown readiness
+/// counts as ready, like for the service.
+fn other_endpoint_ready(slices: &[EndpointSlice], pod_name: &str) -> bool {
+    slices
+        .iter()
+        .flat_map(|slice| &slice.endpoints)
+        .filter(|endpoint| {
+            endpoint
+                .target_ref
+                .as_ref()
+                .and_then(|target| target.name.as_deref())
+                != Some(pod_name)
+        })
+        .any(|endpoint| {
+            endpoint
+                .conditions
+                .as_ref()
+                .and_then(|conditions| conditions.ready)
+                != Some(false)
+        })
+}
+
+/// Whether gravivol created the configuration, see [Registration::configuration]
+fn is_managed(configuration: &MutatingWebhookConfiguration) -> bool {
+    configuration
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(MANAGED_BY_LABEL))
+        .is_some_and(|managed_by| managed_by == "gravivol")
+}
+
+/// Strategic merge patch of the failurePolicy, merged into the webhook of the same name
+fn ignore_patch() -> Value {
+    json!({
+        "webhooks": [{ "name": WEBHOOK_NAME, "failurePolicy": "Ignore" }],
+    })
+}
+
+/// Message of a failed registration, naming the missing permission if it was forbidden
+fn describe_error(err: &kube::Error) -> String {
+    match err {
+        kube::Error::Api(status) if status.code == 403 => format!(
+            "Not allowed to apply MutatingWebhookConfiguration {CONFIGURATION_NAME}, the service account needs get, create and patch on mutatingwebhookconfigurations.admissionregistration.k8s.io: {}",
+            status.message
+        ),
+        err => format!("Cannot apply MutatingWebhookConfiguration {CONFIGURATION_NAME}: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn registration() -> Registration {
+        Registration {
+            service: "gravivol".to_owned(),
+            namespace: "kube-system".to_owned(),
+            port: 443,
+            path: "/mutate".to_owned(),
+            timeout_secs: 10,
+            failure_mode: FailureMode::Open,
+            namespace_selector: None,
+            kinds: vec![Kind::Pod],
+            label_pvcs: false,
+        }
+    }
+
+    #[test]
+    fn test_configuration() {
+        let configuration = registration().configuration("-----BEGIN CERTIFICATE-----\n");
+        assert_eq!(
+            configuration,
+            json!({
+                "apiVersion": "admissionregistration.k8s.io/v1",
+                "kind": "MutatingWebhookConfiguration",
+                "metadata": {
+                    "name": "gravivol",
+                    "labels": { "app.kubernetes.io/managed-by": "gravivol" },
+                },
+                "webhooks": [{
+                    "name": "gravivol.fonona.net",
+                    "clientConfig": {
+                        "service": {
+                            "namespace": "kube-system",
+                            "name": "gravivol",
+                            "path": "/mutate",
+                            "port": 443,
+                        },
+                        "caBundle": "LS0tLS1CRUdJTiBDRVJUSUZJQ0FURS0tLS0tCg==",
+                    },
+                    "rules": [{
+                        "apiGroups": [""],
+                        "apiVersions": ["v1"],
+                        "resources": ["pods"],
+                        "operations": ["CREATE"],
+                        "scope": "Namespaced",
+                    }],
+                    "sideEffects": "None",
+                    "admissionReviewVersions": ["v1", "v1beta1"],
+                    "failurePolicy": "Ignore",
+                    "timeoutSeconds": 10,
+                }],
+            })
+        );
+        // A valid object for the API server
+        serde_json::from_value::<MutatingWebhookConfiguration>(configuration).unwrap();
+    }
+
+    #[test]
+    fn test_rules() {
+        let selector = json!({ "matchLabels": { "gravivol": "enabled" } });
+        let registration = Registration {
+            failure_mode: FailureMode::Closed,
+            namespace_selector: Some(selector.clone()),
+            kinds: vec![
+                Kind::CronJob,
+                Kind::Deployment,
+                Kind::Pod,
+                Kind::StatefulSet,
+            ],
+            label_pvcs: true,
+            ..registration()
+        };
+        let webhook = &registration.configuration("")["webhooks"][0];
+        let resources: HashSet<(String, String)> = webhook["rules"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|rule| {
+                let group = rule["apiGroups"][0].as_str().unwrap().to_owned();
+                rule["resources"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(move |resource| (group.clone(), resource.as_str().unwrap().to_owned()))
+            })
+            .collect();
+        assert_eq!(webhook["rules"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            resources,
+            HashSet::from(
+                [
+                    ("", "pods"),
+                    ("", "persistentvolumeclaims"),
+                    ("apps", "deployments"),
+                    ("apps", "statefulsets"),
+                    ("batch", "cronjobs"),
+                ]
+                .map(|(group, resource)| (group.to_owned(), resource.to_owned()))
+            )
+        );
+        assert_eq!(webhook["failurePolicy"], "Fail");
+        assert_eq!(webhook["namespaceSelector"], selector);
+    }
+
+    #[test]
+    fn test_ca_bundle_patch() {
+        assert_eq!(
+            ca_bundle_patch("-----BEGIN CERTIFICATE-----\n"),
+            json!({
+                "webhooks": [{
+                    "name": "gravivol.fonona.net",
+                    "clientConfig": { "caBundle": "LS0tLS1CRUdJTiBDRVJUSUZJQ0FURS0tLS0tCg==" },
+                }],
+            })
+        );
+        assert_eq!(
+            (1..SYNC_ATTEMPTS).map(backoff).collect::<Vec<_>>(),
+            [1, 2, 4, 8].map(Duration::from_secs)
+        );
+    }
+
+    #[test]
+    fn test_is_managed() {
+        let mut configuration: MutatingWebhookConfiguration =
+            serde_json::from_value(registration().configuration("")).unwrap();
+        assert!(is_managed(&configuration));
+        // Installed by the chart
+        configuration.metadata.labels = Some(
+            [(MANAGED_BY_LABEL.to_owned(), "Helm".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        assert!(!is_managed(&configuration));
+        configuration.metadata.labels = None;
+        assert!(!is_managed(&configuration));
+        assert_eq!(
+            ignore_patch(),
+            json!({ "webhooks": [{ "name": "gravivol.fonona.net", "failurePolicy": "Ignore" }] })
+        );
+    }
+
+    /// EndpointSlice of the service with an endpoint per pod name and readiness
+    fn slice(endpoints: &[(&str, Option<bool>)]) -> EndpointSlice {
+        serde_json::from_value(json!({
+            "metadata": { "labels": { SERVICE_NAME_LABEL: "gravivol" } },
+            "addressType": "IPv4",
+            "endpoints": endpoints
+                .iter()
+                .map(|(pod, ready)| json!({
+                    "addresses": ["10.0.0.1"],
+                    "conditions": { "ready": ready },
+                    "targetRef": { "kind": "Pod", "name": pod },
+                }))
+                .collect::<Vec<_>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_other_endpoint_ready() {
+        // A rolling update: the new pod is ready before the old one terminates
+        let rolling = [
+            vec![slice(&[("old", Some(true))])],
+            vec![slice(&[("old", Some(true)), ("new", Some(false))])],
+            vec![slice(&[("old", Some(false)), ("new", Some(true))])],
+        ];
+        assert!(!other_endpoint_ready(&rolling[0], "old"));
+        assert!(!other_endpoint_ready(&rolling[1], "old"));
+        // The old pod keeps the configuration for the new one
+        assert!(other_endpoint_ready(&rolling[2], "old"));
+        // Replaced only after stopping, it registers the configuration again on startup
+        assert!(!other_endpoint_ready(
+            &[slice(&[("old", Some(false))])],
+            "old"
+        ));
+        // The deployment is deleted, all pods terminate
+        let deleted = [slice(&[("a", Some(false))]), slice(&[("b", Some(false))])];
+        assert!(!other_endpoint_ready(&deleted, "a"));
+        assert!(!other_endpoint_ready(&[], "a"));
+        // Unknown readiness counts as ready
+        assert!(other_endpoint_ready(&[slice(&[("b", None)])], "a"));
+    }
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(
+            serde_json::from_value(json!({ "status": "Failure", "message": "", "code": code }))
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&api_error(409)));
+        assert!(is_transient(&api_error(429)));
+        assert!(is_transient(&api_error(503)));
+        assert!(!is_transient(&api_error(403)));
+        assert!(!is_transient(&api_error(422)));
+    }
+
+    #[test]
+    fn test_describe_error() {
+        let forbidden = kube::Error::Api(
+            serde_json::from_value(json!({
+                "status": "Failure",
+                "message": "mutatingwebhookconfigurations.admissionregistration.k8s.io \"gravivol\" is forbidden",
+                "reason": "Forbidden",
+                "code": 403,
+            }))
+            .unwrap(),
+        );
+        assert_eq!(
+            describe_error(&forbidden),
+            "Not allowed to apply MutatingWebhookConfiguration gravivol, the service account needs get, create and patch on mutatingwebhookconfigurations.admissionregistration.k8s.io: mutatingwebhookconfigurations.admissionregistration.k8s.io \"gravivol\" is forbidden"
+        );
+    }
+}
@@ -0,0 +1,2648 @@
+//! The HTTP layer of the binary: the admission and admin handlers, the limits of the
+//! requests, the listeners and the graceful shutdown
+
+use std::{
+    any::Any,
+    fs::{self, Permissions},
+    io::{self, Read},
+    net::{Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixListener,
+    },
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, Responder,
+    body::{BodySize, MessageBody},
+    dev::{Server, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    get,
+    http::{
+        StatusCode,
+        header::{self, ContentType},
+    },
+    middleware::Next,
+    mime, routes,
+    web::{self, BufMut, BytesMut},
+};
+use flate2::read::GzDecoder;
+use futures_util::{FutureExt, StreamExt};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::Semaphore,
+};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    body_samples::BodySamples,
+    build_info,
+    controller::{
+        AdmissionReview, Controller, ControllerError, config_entries_by_mode, count_volumes,
+        exceeds_depth, request_uid,
+    },
+    decisions::Decisions,
+    health::{CheckStatus, Health},
+    metrics::{Metrics, Phase},
+    rate_limit::RateLimiter,
+    settings::{DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_JSON_DEPTH, DEFAULT_MAX_VOLUMES, Settings},
+    telemetry,
+};
+
+/// Message passed to panic!, or of a failed expect() or unwrap()
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown panic payload", String::as_str),
+    }
+}
+
+/// Limits of /mutate requests, see [Settings]
+#[derive(Clone)]
+pub struct Limits {
+    pub body_bytes: usize,
+    pub processing: Duration,
+    /// Permits for the requests processed at once, shared by all workers, None if unlimited
+    pub in_flight: Option<Arc<Semaphore>>,
+    /// Buckets of the clients, shared by all workers, None if unlimited
+    pub rate: Option<Arc<RateLimiter>>,
+    /// Nesting depth of the body, 0 for no limit
+    pub json_depth: usize,
+    /// Volumes of the pod, 0 for no limit
+    pub volumes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            body_bytes: DEFAULT_MAX_BODY_BYTES,
+            processing: Duration::from_secs(8),
+            in_flight: None,
+            rate: None,
+            json_depth: DEFAULT_MAX_JSON_DEPTH,
+            volumes: DEFAULT_MAX_VOLUMES,
+        }
+    }
+}
+
+/// Answers without processing the request with the review and a warning for the client, or
+/// with the status if the body has no uid
+fn refuse(
+    req: &HttpRequest,
+    body: &[u8],
+    review: Option<AdmissionReview>,
+    message: &str,
+    status: StatusCode,
+) -> HttpResponse {
+    note_admission(req, body.len(), review.as_ref());
+    match review {
+        Some(mut review) => {
+            review.add_warning(format!("gravivol: {message}, the object was not mutated"));
+            HttpResponse::Ok().json(review)
+        }
+        None => HttpResponse::build(status).body(message.to_owned()),
+    }
+}
+
+/// Reads the body up to the limit, Err with the bytes read so far if it is longer
+async fn read_body(
+    mut payload: web::Payload,
+    limit: usize,
+) -> Result<Result<BytesMut, BytesMut>, PayloadError> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            body.extend_from_slice(&chunk[..limit - body.len()]);
+            return Ok(Err(body));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Ok(body))
+}
+
+/// Decompresses a body sent with `Content-Encoding: gzip`, the limit applies to the result
+///
+/// Ok(Err) with the bytes decompressed up to the limit if it is exceeded, Err with the body as
+/// received and a message if the encoding is not supported or the body cannot be decompressed.
+fn decode_body(
+    req: &HttpRequest,
+    body: BytesMut,
+    limit: usize,
+) -> Result<Result<BytesMut, BytesMut>, (BytesMut, String)> {
+    let Some(encoding) = req.headers().get(header::CONTENT_ENCODING) else {
+        return Ok(Ok(body));
+    };
+    let encoding = String::from_utf8_lossy(encoding.as_bytes());
+    let encoding = encoding.trim();
+    if encoding.eq_ignore_ascii_case("identity") {
+        return Ok(Ok(body));
+    }
+    if !encoding.eq_ignore_ascii_case("gzip") && !encoding.eq_ignore_ascii_case("x-gzip") {
+        let message = format!("unsupported content encoding {encoding}, expected gzip or identity");
+        return Err((body, message));
+    }
+    // Read one byte past the limit to tell a body of exactly the limit from a longer one,
+    // without inflating more of a zip bomb
+    let mut decoded = BytesMut::new().writer();
+    match io::copy(
+        &mut GzDecoder::new(&body[..]).take(limit as u64 + 1),
+        &mut decoded,
+    ) {
+        Ok(_) => {
+            let mut decoded = decoded.into_inner();
+            if decoded.len() > limit {
+                decoded.truncate(limit);
+                Ok(Err(decoded))
+            } else {
+                Ok(Ok(decoded))
+            }
+        }
+        Err(err) => Err((body, format!("cannot decompress request body: {err}"))),
+    }
+}
+
+/// Accepts JSON media types like application/json or application/merge-patch+json
+///
+/// A request without content type is taken as JSON.
+fn check_content_type(req: &HttpRequest) -> Result<(), String> {
+    let unsupported =
+        |received: &str| format!("unsupported content type {received}, expected application/json");
+    match req.mime_type() {
+        Ok(None) => Ok(()),
+        Ok(Some(mime)) if mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON) => {
+            Ok(())
+        }
+        Ok(Some(mime)) => Err(unsupported(mime.essence_str())),
+        Err(_) => {
+            let received = req
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .map(|value| value.as_bytes());
+            Err(unsupported(&String::from_utf8_lossy(
+                received.unwrap_or_default(),
+            )))
+        }
+    }
+}
+
+/// What the webhook does with the admission requests of a path
+#[derive(Clone, Copy)]
+enum Webhook {
+    Mutate,
+    Validate,
+}
+
+/// Handler of the webhook paths, see [mutate_routes]
+async fn mutate(
+    req: HttpRequest,
+    payload: web::Payload,
+    controller: web::Data<Controller>,
+) -> actix_web::Result<HttpResponse> {
+    let span = tracing::info_span!("mutate", path = %req.path(), otel.kind = "server");
+    // Continues the trace of the API server
+    if req.headers().contains_key("traceparent") {
+        let _ = span.set_parent(telemetry::remote_context(req.headers()));
+    }
+    admit(req, payload, controller, Webhook::Mutate)
+        .instrument(span)
+        .await
+}
+
+/// Handler of the validating webhook, refusing pods that bypassed the mutating one
+pub async fn validate(
+    req: HttpRequest,
+    payload: web::Payload,
+    controller: web::Data<Controller>,
+) -> actix_web::Result<HttpResponse> {
+    let span = tracing::info_span!("validate", path = %req.path(), otel.kind = "server");
+    if req.headers().contains_key("traceparent") {
+        let _ = span.set_parent(telemetry::remote_context(req.headers()));
+    }
+    admit(req, payload, controller, Webhook::Validate)
+        .instrument(span)
+        .await
+}
+
+async fn admit(
+    req: HttpRequest,
+    payload: web::Payload,
+    controller: web::Data<Controller>,
+    webhook: Webhook,
+) -> actix_web::Result<HttpResponse> {
+    let metrics = controller.metrics();
+    let path = req.path();
+    let _timer = metrics
+        .mutate_duration
+        .with_label_values(&[path])
+        .start_timer();
+    let _in_flight = metrics.track_in_flight();
+    metrics.request_received(path);
+    let limits = req.app_data::<Limits>().cloned().unwrap_or_default();
+    // Tags the logs of the request once it is parsed
+    let mut span = tracing::Span::none();
+
+    let body = match read_body(payload, limits.body_bytes).await? {
+        Ok(body) => decode_body(&req, body, limits.body_bytes),
+        Err(prefix) => Ok(Err(prefix)),
+    };
+    let (req_body, result) = match body {
+        Ok(Ok(body)) => {
+            // Clients on the Unix socket share a bucket
+            let client = req
+                .peer_addr()
+                .map_or_else(|| "unix".to_owned(), |addr| addr.ip().to_string());
+            if let Some(rate) = &limits.rate
+                && !rate.try_acquire(&client)
+            {
+                metrics.rate_limited(&client);
+                let message = "rate limit exceeded";
+                tracing::warn!("Shedding request of {client} on {path}: {message}");
+                // Allowed in any failure mode so that a client in a loop blocks no pods
+                return Ok(refuse(
+                    &req,
+                    &body,
+                    controller
+                        .failure_review(&body, message)
+                        .map(AdmissionReview::allowed),
+                    message,
+                    StatusCode::TOO_MANY_REQUESTS,
+                ));
+            }
+            let _permit = match limits.in_flight.map(Semaphore::try_acquire_owned) {
+                Some(Err(_)) => {
+                    metrics.shed_requests.inc();
+                    let message = "too many requests in flight";
+                    tracing::warn!("Shedding request on {path}: {message}");
+                    // Admitted or denied without mutation, like on a failure
+                    return Ok(refuse(
+                        &req,
+                        &body,
+                        controller.failure_review(&body, message),
+                        message,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    ));
+                }
+                permit => permit,
+            };
+            // Before serde and json_patch spend time on a crafted or corrupted object
+            if limits.json_depth > 0 && exceeds_depth(&body, limits.json_depth) {
+                metrics.rejected("depth");
+                let message = format!(
+                    "request body nests deeper than {} levels",
+                    limits.json_depth
+                );
+                tracing::warn!("Refusing request on {path}: {message}");
+                return Ok(refuse(
+                    &req,
+                    &body,
+                    controller.failure_review(&body, &message),
+                    &message,
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+            // Counted without building the volumes, before the whole review is parsed
+            if limits.volumes > 0
+                && let Some(count) = count_volumes(&body)
+                && count > limits.volumes
+            {
+                metrics.rejected("volumes");
+                let message = format!("pod has {count} volumes, more than {}", limits.volumes);
+                tracing::warn!("Refusing request on {path}: {message}");
+                return Ok(refuse(
+                    &req,
+                    &body,
+                    controller.failure_review(&body, &message),
+                    &message,
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+            // Kept with redacted env for /debug/bodies if sampled
+            if let Some(samples) = req.app_data::<web::Data<BodySamples>>() {
+                samples.offer(&body);
+            }
+            // Parsed in place, the body is not logged as pods may carry secrets
+            let parsed = tracing::info_span!("parse").in_scope(|| {
+                let _timer = metrics.time_phase(Phase::Deserialize);
+                check_content_type(&req).and_then(|()| {
+                    serde_json::from_slice::<AdmissionReview>(&body)
+                        .map_err(|err| format!("cannot parse AdmissionReview: {err}"))
+                })
+            });
+            let result = match parsed {
+                Ok(review) => {
+                    let uid = review.uid().unwrap_or_default().to_owned();
+                    span = review.span();
+                    span.in_scope(|| {
+                        tracing::debug!("Got request {uid} of {} bytes on {path}", body.len())
+                    });
+                    // A panic, e.g. of an expect(), is answered like an error instead of with an
+                    // empty 500, the controller holds no state that it could leave inconsistent
+                    let processing = AssertUnwindSafe(async {
+                        match webhook {
+                            Webhook::Mutate => controller.mutate(review).await,
+                            Webhook::Validate => controller.validate(review).await,
+                        }
+                    })
+                    .catch_unwind();
+                    let processed = tokio::time::timeout(limits.processing, processing)
+                        .instrument(span.clone())
+                        .await;
+                    let _entered = span.enter();
+                    match processed {
+                        Ok(Ok(result)) => result.inspect_err(|_| metrics.errors.inc()),
+                        Ok(Err(panic)) => {
+                            metrics.errors.inc();
+                            metrics.panics.inc();
+                            tracing::error!(
+                                "Panic processing request {uid}: {}",
+                                panic_message(&*panic)
+                            );
+                            Err(ControllerError::Internal(format!(
+                                "internal error processing request {uid}"
+                            )))
+                        }
+                        Err(_) => {
+                            metrics.errors.inc();
+                            Err(ControllerError::Internal(format!(
+                                "request {uid} not processed within {}s",
+                                limits.processing.as_secs_f64()
+                            )))
+                        }
+                    }
+                }
+                Err(message) => {
+                    metrics.parse_failures.inc();
+                    Err(ControllerError::InvalidReview(message))
+                }
+            };
+            (body, result)
+        }
+        // The uid comes early in the body and is found in the part read
+        Ok(Err(prefix)) => (
+            prefix,
+            Err(ControllerError::InvalidReview(format!(
+                "request body exceeds the limit of {} bytes",
+                limits.body_bytes
+            ))),
+        ),
+        // Answered with a review if the body is readable as is despite its encoding
+        Err((body, message)) => {
+            metrics.parse_failures.inc();
+            (body, Err(ControllerError::InvalidReview(message)))
+        }
+    };
+    let _entered = span.enter();
+    let review = match result {
+        Ok(response) => {
+            tracing::debug!(?response, "Response is OK");
+            Ok(response)
+        }
+        Err(err) => {
+            let uid = request_uid(&req_body).unwrap_or_default();
+            tracing::error!("Cannot process request {uid} on {path}: {err}");
+            // Answered with a review as long as the uid is known, so that the failure mode applies
+            controller
+                .error_review(&req_body, &err)
+                .ok_or_else(|| err.to_string())
+        }
+    };
+    note_admission(&req, req_body.len(), review.as_ref().ok());
+    Ok(match review {
+        Ok(review) => tracing::info_span!("serialize").in_scope(|| {
+            let _timer = metrics.time_phase(Phase::Serialize);
+            HttpResponse::Ok().json(review)
+        }),
+        Err(message) => HttpResponse::build(StatusCode::BAD_REQUEST)
+            .insert_header(ContentType::html())
+            .body(message),
+    })
+}
+
+/// What the handler learned about an admission request, for the access log
+struct Admission {
+    /// Bytes read, less than the body if it exceeds the limit
+    request_bytes: usize,
+    uid: Option<String>,
+    patched: bool,
+}
+
+fn note_admission(req: &HttpRequest, request_bytes: usize, review: Option<&AdmissionReview>) {
+    req.extensions_mut().insert(Admission {
+        request_bytes,
+        uid: review.and_then(AdmissionReview::uid).map(str::to_owned),
+        patched: review.is_some_and(AdmissionReview::has_patch),
+    });
+}
+
+/// Logs one line per request, without the body, with the outcome of admission requests
+pub async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let start = Instant::now();
+    let (method, path) = (req.method().clone(), req.path().to_owned());
+    let response = next.call(req).await?;
+    let response_bytes = match response.response().body().size() {
+        BodySize::Sized(size) => size,
+        BodySize::None | BodySize::Stream => 0,
+    };
+    let extensions = response.request().extensions();
+    let admission = extensions.get::<Admission>();
+    tracing::info!(
+        target: "gravivol::access",
+        %method,
+        %path,
+        status = response.status().as_u16(),
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+        request_bytes = admission.map_or(0, |admission| admission.request_bytes),
+        response_bytes,
+        uid = %admission.and_then(|admission| admission.uid.as_deref()).unwrap_or_default(),
+        patched = admission.is_some_and(|admission| admission.patched),
+        "{method} {path} {}",
+        response.status().as_u16()
+    );
+    drop(extensions);
+    Ok(response)
+}
+
+/// Registers the mutate handler on each webhook path, e.g. one per rule
+pub fn mutate_routes(paths: &[String]) -> impl Fn(&mut web::ServiceConfig) + '_ {
+    move |config| {
+        for path in paths {
+            config.route(path, web::post().to(mutate));
+        }
+    }
+}
+
+#[get("/metrics")]
+async fn prometheus_metrics(metrics: web::Data<Metrics>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.encode())
+}
+
+#[routes]
+#[get("/livez")]
+#[get("/health")]
+async fn livez() -> impl Responder {
+    "OK"
+}
+
+#[get("/readyz")]
+async fn readyz(health: web::Data<Health>, metrics: Option<web::Data<Metrics>>) -> impl Responder {
+    // 0 before the first
+    let timestamp = |gauge: &prometheus::IntGauge| Some(gauge.get()).filter(|time| *time > 0);
+    let readiness = health
+        .readiness(
+            metrics
+                .as_ref()
+                .and_then(|metrics| timestamp(&metrics.last_request)),
+            metrics
+                .as_ref()
+                .and_then(|metrics| timestamp(&metrics.last_mutation)),
+        )
+        .await;
+    for (check, status) in &readiness.checks {
+        if let CheckStatus::Failed { message } = status {
+            tracing::warn!("Readiness check {check} failed: {message}");
+        }
+    }
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[get("/version")]
+async fn version() -> impl Responder {
+    HttpResponse::Ok().json(build_info::BUILD_INFO)
+}
+
+#[get("/configz")]
+async fn configz(settings: web::Data<Settings>) -> impl Responder {
+    HttpResponse::Ok().json(settings.get_ref())
+}
+
+#[derive(Deserialize)]
+struct MutationsQuery {
+    namespace: Option<String>,
+}
+
+/// Recent decisions of all workers, newest first
+#[get("/debug/mutations")]
+pub async fn debug_mutations(
+    decisions: web::Data<Decisions>,
+    query: web::Query<MutationsQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(decisions.recent(query.namespace.as_deref()))
+}
+
+/// Sampled request bodies of all workers, newest first
+#[get("/debug/bodies")]
+pub async fn debug_bodies(samples: web::Data<BodySamples>) -> impl Responder {
+    HttpResponse::Ok().json(samples.recent())
+}
+
+/// Probes, metrics, the effective configuration and the build, also served on the admin listener
+pub fn admin_routes(config: &mut web::ServiceConfig) {
+    config
+        .service(livez)
+        .service(readyz)
+        .service(prometheus_metrics)
+        .service(configz)
+        .service(version);
+}
+
+fn resolve(bind: &str) -> io::Result<Vec<SocketAddr>> {
+    bind.to_socket_addrs()
+        .map(Iterator::collect)
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Cannot resolve bind address {bind}: {err}"),
+            )
+        })
+}
+
+/// The IPv4 wildcard address to listen on instead of the IPv6 one when the host has no IPv6,
+/// None for other addresses or errors
+fn ipv4_fallback(addr: SocketAddr, err: &io::Error) -> Option<SocketAddr> {
+    match addr {
+        SocketAddr::V6(v6)
+            if v6.ip().is_unspecified() && err.raw_os_error() == Some(libc::EAFNOSUPPORT) =>
+        {
+            Some(SocketAddr::from((Ipv4Addr::UNSPECIFIED, v6.port())))
+        }
+        _ => None,
+    }
+}
+
+/// Pending connections of each listener, the default of actix-web
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Binds the address with SO_REUSEADDR like actix-web, so that a restart does not wait for the
+/// connections of the previous run in TIME_WAIT
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Listens on all addresses of each bind address, failing with the one that cannot be bound
+pub fn listen_tcp(binds: &[String]) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for bind in binds {
+        for addr in resolve(bind)? {
+            let listener = bind_tcp(addr)
+                .or_else(|err| match ipv4_fallback(addr, &err) {
+                    Some(fallback) => {
+                        tracing::warn!(
+                            "IPv6 is not supported, listening on {fallback} instead of {addr}"
+                        );
+                        bind_tcp(fallback)
+                    }
+                    None => Err(err),
+                })
+                .map_err(|err| {
+                    io::Error::new(err.kind(), format!("Cannot listen on {bind}: {err}"))
+                })?;
+            listeners.push(listener);
+        }
+    }
+    Ok(listeners)
+}
+
+/// Socket file of the Unix listener, removed when dropped on shutdown
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            tracing::warn!("Cannot remove socket {}: {err}", self.0.display());
+        }
+    }
+}
+
+/// Listens on the Unix socket at path with the given permissions
+///
+/// A socket file left over by a previous run is replaced.
+pub fn listen_uds(path: &str, mode: u32) -> io::Result<(UnixListener, SocketFile)> {
+    let context =
+        |err: io::Error| io::Error::new(err.kind(), format!("Cannot listen on {path}: {err}"));
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path).map_err(context)?;
+    }
+    let listener = UnixListener::bind(path).map_err(context)?;
+    let socket_file = SocketFile(path.into());
+    fs::set_permissions(path, Permissions::from_mode(mode)).map_err(context)?;
+    Ok((listener, socket_file))
+}
+
+/// Loads the config, reporting its entries or the failure to load it
+pub fn record_config_load(health: &Health, metrics: &Metrics, config: &str) {
+    let result = config_entries_by_mode(config);
+    match &result {
+        Ok(entries) => metrics.config_loaded(entries),
+        // The only error of a config from the environment, more come with other sources
+        Err(_) => metrics.config_load_failed("invalid_entry"),
+    }
+    health.set_config_result(result.map(|_| ()));
+}
+
+/// Reports the result of registering the webhook or updating its caBundle
+pub fn record_ca_bundle(health: &Health, metrics: &Metrics, result: Result<(), String>) {
+    if let Err(err) = &result {
+        tracing::error!("{err}");
+    }
+    metrics.ca_bundle_synced(result.is_ok());
+    health.set_ca_bundle_result(result);
+}
+
+/// Completes on SIGTERM, e.g. from the kubelet, or SIGINT
+pub async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Got SIGTERM"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Got SIGINT"),
+    }
+}
+
+/// Runs the servers until shutdown, then fails readiness and drains the in-flight requests
+///
+/// New requests are accepted for the delay after readiness fails, until the endpoint is removed
+/// from the service. The admin server keeps answering the probes until the webhook server has
+/// stopped.
+pub async fn serve(
+    server: Server,
+    admin_server: Server,
+    health: &Health,
+    metrics: &Metrics,
+    shutdown: impl Future<Output = ()>,
+    delay: Duration,
+) -> io::Result<()> {
+    let (handle, admin_handle) = (server.handle(), admin_server.handle());
+    let (mut server, mut admin_server) = (tokio::spawn(server), tokio::spawn(admin_server));
+    // Whichever server stops first, e.g. on a bind error, takes the other one down
+    tokio::select! {
+        result = &mut server => {
+            admin_handle.stop(true).await;
+            return result?;
+        }
+        result = &mut admin_server => {
+            handle.stop(true).await;
+            return result?;
+        }
+        () = shutdown => {}
+    }
+
+    health.set_shutting_down();
+    if !delay.is_zero() {
+        tracing::info!(
+            "Shutting down, accepting requests for {}s until the endpoint is removed",
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+    }
+    let draining = metrics.in_flight.get();
+    tracing::info!("Shutting down, draining {draining} admission requests");
+    handle.stop(true).await;
+    let result = server.await?;
+    tracing::info!(
+        "Drained {} admission requests",
+        draining - metrics.in_flight.get()
+    );
+    admin_handle.stop(true).await;
+    admin_server.await??;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        io::Write,
+    };
+
+    use actix_web::{
+        App, HttpServer,
+        body::to_bytes,
+        middleware::{Condition, from_fn},
+        test,
+    };
+    use base64::prelude::*;
+    use flate2::{Compression, write::GzEncoder};
+    use k8s_openapi::api::{
+        core::v1::{PersistentVolume, PersistentVolumeClaim},
+        storage::v1::StorageClass,
+    };
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use serde_json::{Value, json};
+    use socket2::SockRef;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        cluster::{Cluster, LookupError},
+        controller::{FailureMode, LabelValue, Options, ValidateMode, validate_config},
+        logging::{self, CapturedLogs},
+        process,
+        settings::VALIDATE_PATH,
+    };
+
+    async fn post_mutate(body: &str) -> (StatusCode, Vec<u8>) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("")))
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let request = test::TestRequest::post()
+            .uri("/mutate")
+            .set_payload(body.to_owned())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        let status = response.status();
+        (
+            status,
+            to_bytes(response.into_body()).await.unwrap().to_vec(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_json() {
+        let (status, body) = post_mutate(
+            r#"{"apiVersion": "admission.k8s.io/v1", "kind": "AdmissionReview", "request": {"uid": "705ab4f5", "object": {"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let review: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert_eq!(review["response"]["allowed"], true);
+        assert_eq!(review["response"]["status"]["code"], 400);
+        assert!(
+            review["response"]["warnings"][0]
+                .as_str()
+                .unwrap()
+                .contains("cannot parse AdmissionReview")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_object_uid() {
+        for request in [
+            json!({
+                "uid": "705ab4f5",
+                "object": { "apiVersion": "v1", "kind": "Pod", "metadata": { "labels": 5 } },
+            }),
+            json!({
+                "uid": "705ab4f5",
+                "object": { "apiVersion": "v1", "kind": "Pod" },
+                "dryRun": "yes",
+            }),
+        ] {
+            let (status, body) = post_mutate(
+                &json!({
+                    "apiVersion": "admission.k8s.io/v1",
+                    "kind": "AdmissionReview",
+                    "request": request,
+                })
+                .to_string(),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            let review: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(review["response"]["uid"], "705ab4f5", "{request}");
+            assert_eq!(review["response"]["allowed"], true);
+            assert_eq!(review["response"]["status"]["code"], 400);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_delete() {
+        let (status, body) = post_mutate(
+            &json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "0df28fbd-5f5f-11e8-bc74-36e6bb280816",
+                    "kind": { "group": "", "version": "v1", "kind": "Pod" },
+                    "resource": { "group": "", "version": "v1", "resource": "pods" },
+                    "requestKind": { "group": "", "version": "v1", "kind": "Pod" },
+                    "requestResource": { "group": "", "version": "v1", "resource": "pods" },
+                    "name": "web-0",
+                    "namespace": "default",
+                    "operation": "DELETE",
+                    "userInfo": {
+                        "username": "system:serviceaccount:kube-system:statefulset-controller",
+                        "uid": "8e4f8f5a-c5c3-4b9c-9d5e-2f1d0a1b2c3d",
+                        "groups": ["system:serviceaccounts", "system:authenticated"],
+                    },
+                    "object": null,
+                    "oldObject": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": {
+                            "name": "web-0",
+                            "namespace": "default",
+                            "uid": "5b6c1f0e-2f9a-4d4e-8a47-3c1b2a9d8e7f",
+                            "labels": { "default.gravivol.fonona.net/data": "true" },
+                        },
+                        "spec": {
+                            "containers": [{ "name": "web", "image": "nginx" }],
+                            "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                        },
+                    },
+                    "dryRun": false,
+                    "options": {
+                        "kind": "DeleteOptions",
+                        "apiVersion": "meta.k8s.io/v1",
+                        "gracePeriodSeconds": 30,
+                        "propagationPolicy": "Background",
+                    },
+                },
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let review: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            review["response"],
+            json!({ "uid": "0df28fbd-5f5f-11e8-bc74-36e6bb280816", "allowed": true })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_missing_request() {
+        let (status, _) =
+            post_mutate(r#"{"apiVersion": "admission.k8s.io/v1", "kind": "AdmissionReview"}"#)
+                .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_wrong_kind() {
+        let (status, body) = post_mutate(
+            &json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionRequest",
+                "request": {
+                    "uid": "705ab4f5",
+                    "object": { "apiVersion": "v1", "kind": "Pod", "metadata": {}, "spec": {} },
+                }
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let review: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(review["apiVersion"], "admission.k8s.io/v1");
+        assert_eq!(review["kind"], "AdmissionRequest");
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert_eq!(review["response"]["allowed"], false);
+        assert_eq!(review["response"]["status"]["code"], 400);
+    }
+
+    #[actix_web::test]
+    async fn test_content_type() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("")))
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [] },
+                },
+            }
+        })
+        .to_string();
+        for (content_type, status) in [
+            ("application/json", None),
+            ("application/json; charset=utf-8", None),
+            ("application/vnd.kubernetes+json", None),
+            (
+                "application/yaml",
+                Some(
+                    "gravivol: unsupported content type application/yaml, expected application/json",
+                ),
+            ),
+            (
+                "text/plain; charset=utf-8",
+                Some("gravivol: unsupported content type text/plain, expected application/json"),
+            ),
+            (
+                "json",
+                Some("gravivol: unsupported content type json, expected application/json"),
+            ),
+        ] {
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .insert_header((header::CONTENT_TYPE, content_type))
+                .set_payload(body.clone())
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK, "{content_type}");
+            let review: Value =
+                serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+            assert_eq!(review["response"]["uid"], "705ab4f5", "{content_type}");
+            assert_eq!(
+                review["response"]["status"]["message"].as_str(),
+                status,
+                "{content_type}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_body_limit() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("")))
+                .app_data(Limits {
+                    body_bytes: 1024,
+                    ..Default::default()
+                })
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        // Keys in the order of the API server, the uid before the object
+        let body = format!(
+            r#"{{"apiVersion":"admission.k8s.io/v1","kind":"AdmissionReview","request":{{"uid":"705ab4f5","object":{}}}}}"#,
+            json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "web", "namespace": "default" },
+                "spec": { "containers": [{ "name": "web", "args": ["x".repeat(4096)] }] },
+            })
+        );
+        let request = test::TestRequest::post()
+            .uri("/mutate")
+            .set_payload(body)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let review: Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert_eq!(review["response"]["allowed"], true);
+        assert_eq!(
+            review["response"]["status"]["message"],
+            "gravivol: request body exceeds the limit of 1024 bytes"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_content_encoding() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("default/data")))
+                .app_data(Limits {
+                    body_bytes: 4096,
+                    ..Default::default()
+                })
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let body = |args: &str| {
+            json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "705ab4f5",
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "name": "web", "namespace": "default" },
+                        "spec": {
+                            "containers": [{ "name": "web", "args": [args] }],
+                            "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                        },
+                    },
+                }
+            })
+            .to_string()
+        };
+        let gzip = |body: String| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let post = |encoding: &str, body: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/mutate")
+                .insert_header((header::CONTENT_ENCODING, encoding))
+                .set_payload(body)
+                .to_request()
+        };
+
+        for request in [
+            post("gzip", gzip(body("web"))),
+            post("identity", body("web").into_bytes()),
+        ] {
+            let review: Value = test::call_and_read_body_json(&app, request).await;
+            assert_eq!(review["response"]["uid"], "705ab4f5");
+            let patch = BASE64_STANDARD
+                .decode(review["response"]["patch"].as_str().unwrap())
+                .unwrap();
+            let patch: Value = serde_json::from_slice(&patch).unwrap();
+            assert_eq!(patch[2]["path"], "/spec/affinity", "{patch}");
+        }
+
+        // The limit applies to the decompressed size, the compressed body is far below it
+        // Keys in the order of the API server, the uid before the object
+        let bomb = gzip(format!(
+            r#"{{"apiVersion":"admission.k8s.io/v1","kind":"AdmissionReview","request":{{"uid":"705ab4f5","object":{}}}}}"#,
+            json!({ "apiVersion": "v1", "kind": "Pod", "spec": { "x": "x".repeat(1 << 20) } })
+        ));
+        assert!(bomb.len() < 4096);
+        let review: Value = test::call_and_read_body_json(&app, post("gzip", bomb)).await;
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert!(review["response"]["patch"].is_null());
+        assert_eq!(
+            review["response"]["status"]["message"],
+            "gravivol: request body exceeds the limit of 4096 bytes"
+        );
+
+        let review: Value =
+            test::call_and_read_body_json(&app, post("br", body("web").into_bytes())).await;
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert_eq!(
+            review["response"]["status"]["message"],
+            "gravivol: unsupported content encoding br, expected gzip or identity"
+        );
+        let response = test::call_service(&app, post("br", gzip(body("web")))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            to_bytes(response.into_body()).await.unwrap(),
+            "unsupported content encoding br, expected gzip or identity"
+        );
+        let response = test::call_service(&app, post("gzip", body("web").into_bytes())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_document_limits() {
+        let controller = web::Data::new(Controller::new("default/data").with_options(Options {
+            failure_mode: FailureMode::Closed,
+            ..Default::default()
+        }));
+        let app = test::init_service(
+            App::new()
+                .app_data(controller.clone())
+                .app_data(Limits {
+                    volumes: 2,
+                    ..Default::default()
+                })
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let post = |body: String| {
+            test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body)
+                .to_request()
+        };
+        let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        let deep = format!(
+            r#"{{"apiVersion":"admission.k8s.io/v1","kind":"AdmissionReview","request":{{"uid":"705ab4f5","object":{{"apiVersion":"v1","kind":"Pod","spec":{{"x":{nested}}}}}}}}}"#
+        );
+        let review: Value = test::call_and_read_body_json(&app, post(deep)).await;
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert_eq!(review["response"]["allowed"], false);
+        assert_eq!(
+            review["response"]["status"]["message"],
+            "gravivol: request body nests deeper than 64 levels"
+        );
+        assert_eq!(
+            review["response"]["warnings"][0],
+            "gravivol: request body nests deeper than 64 levels, the object was not mutated"
+        );
+
+        let response = test::call_service(&app, post(nested)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let volumes: Vec<Value> = ["a", "b", "data"]
+            .iter()
+            .map(|name| json!({ "name": name, "persistentVolumeClaim": { "claimName": name } }))
+            .collect();
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "9c1e77d0",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [], "volumes": volumes },
+                },
+            }
+        });
+        let review: Value = test::call_and_read_body_json(&app, post(body.to_string())).await;
+        assert_eq!(review["response"]["uid"], "9c1e77d0");
+        assert_eq!(review["response"]["allowed"], false);
+        assert!(review["response"].get("patch").is_none());
+        assert_eq!(
+            review["response"]["status"]["message"],
+            "gravivol: pod has 3 volumes, more than 2"
+        );
+
+        let metrics = controller.metrics().encode();
+        assert!(metrics.contains("gravivol_rejected_documents_total{limit=\"depth\"} 2\n"));
+        assert!(metrics.contains("gravivol_rejected_documents_total{limit=\"volumes\"} 1\n"));
+        assert!(metrics.contains("gravivol_admission_requests_total 0\n"));
+    }
+
+    /// Cluster that never answers in time
+    struct SlowCluster(Duration);
+
+    #[async_trait::async_trait]
+    impl Cluster for SlowCluster {
+        async fn get_claim(
+            &self,
+            _namespace: &str,
+            _name: &str,
+            _dry_run: bool,
+        ) -> Result<Option<PersistentVolumeClaim>, LookupError> {
+            tokio::time::sleep(self.0).await;
+            Ok(None)
+        }
+
+        async fn get_volume(
+            &self,
+            _name: &str,
+            _dry_run: bool,
+        ) -> Result<Option<PersistentVolume>, LookupError> {
+            tokio::time::sleep(self.0).await;
+            Ok(None)
+        }
+
+        async fn has_pods(
+            &self,
+            _namespace: &str,
+            _label_selector: &str,
+            _dry_run: bool,
+        ) -> Result<bool, LookupError> {
+            tokio::time::sleep(self.0).await;
+            Ok(true)
+        }
+
+        async fn get_storage_class(
+            &self,
+            _name: Option<&str>,
+            _dry_run: bool,
+        ) -> Result<Option<StorageClass>, LookupError> {
+            tokio::time::sleep(self.0).await;
+            Ok(None)
+        }
+    }
+
+    /// Fails like an expect() in the controller would
+    struct PanickingCluster;
+
+    #[async_trait::async_trait]
+    impl Cluster for PanickingCluster {
+        async fn get_claim(
+            &self,
+            _namespace: &str,
+            _name: &str,
+            _dry_run: bool,
+        ) -> Result<Option<PersistentVolumeClaim>, LookupError> {
+            panic!("claim without uid");
+        }
+
+        async fn get_volume(
+            &self,
+            _name: &str,
+            _dry_run: bool,
+        ) -> Result<Option<PersistentVolume>, LookupError> {
+            panic!("volume without name");
+        }
+
+        async fn has_pods(
+            &self,
+            _namespace: &str,
+            _label_selector: &str,
+            _dry_run: bool,
+        ) -> Result<bool, LookupError> {
+            panic!("pod without name");
+        }
+
+        async fn get_storage_class(
+            &self,
+            _name: Option<&str>,
+            _dry_run: bool,
+        ) -> Result<Option<StorageClass>, LookupError> {
+            panic!("storage class without provisioner");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_panic() {
+        let shared_metrics = Arc::new(Metrics::new());
+        let controller = Controller::new("default/data")
+            .with_options(Options {
+                label_value: LabelValue::Uid,
+                failure_mode: FailureMode::Closed,
+                ..Default::default()
+            })
+            .with_metrics(shared_metrics.clone())
+            .with_cluster(Arc::new(PanickingCluster));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(controller))
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        });
+        for _ in 0..2 {
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body.to_string())
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let review: Value =
+                serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+            assert_eq!(review["kind"], "AdmissionReview");
+            assert_eq!(review["response"]["uid"], "705ab4f5");
+            assert_eq!(review["response"]["allowed"], false);
+            assert_eq!(review["response"]["status"]["code"], 500);
+            assert_eq!(
+                review["response"]["status"]["message"],
+                "gravivol: internal error processing request 705ab4f5"
+            );
+        }
+        let text = shared_metrics.encode();
+        assert!(text.contains("gravivol_panics_total 2\n"), "{text}");
+        assert_eq!(shared_metrics.in_flight.get(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_panic_message() {
+        let message =
+            |f: fn()| panic_message(&*std::panic::catch_unwind(f).unwrap_err()).to_owned();
+        assert_eq!(message(|| panic!("static")), "static");
+        assert_eq!(message(|| panic!("formatted {}", 1)), "formatted 1");
+        assert_eq!(
+            message(|| std::panic::panic_any(1)),
+            "unknown panic payload"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_request_span() {
+        let logs = CapturedLogs::default();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(logs.clone())
+                .with_ansi(false)
+                .with_max_level(tracing::Level::DEBUG)
+                .finish(),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("default/data")))
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        for (uid, name) in [("705ab4f5", "web"), ("8a1c0d2e", "db")] {
+            let body = json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": uid,
+                    "namespace": "default",
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "name": name, "namespace": "default" },
+                        "spec": {
+                            "containers": [],
+                            "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                        },
+                    },
+                }
+            });
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body.to_string())
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, request).await.status(),
+                StatusCode::OK
+            );
+        }
+
+        let lines = logs.lines();
+        for (uid, name) in [("705ab4f5", "web"), ("8a1c0d2e", "db")] {
+            let tagged: Vec<_> = lines
+                .iter()
+                .filter(|line| {
+                    line.contains(&format!(
+                        "admission{{uid={uid} namespace=default object={name}"
+                    ))
+                })
+                .collect();
+            // Logged by the controller
+            assert!(
+                tagged
+                    .iter()
+                    .any(|line| line.contains("uses matching PVC data")),
+                "{lines:#?}"
+            );
+            assert!(
+                tagged
+                    .iter()
+                    .any(|line| line.contains("claims=data") && line.contains("Response is OK")),
+                "{lines:#?}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_access_log() {
+        let logs = CapturedLogs::default();
+        let _subscriber = tracing::dispatcher::set_default(&logging::dispatch(
+            logging::LogFormat::Json,
+            tracing_subscriber::EnvFilter::new("gravivol::access=info"),
+            logs.clone(),
+            false,
+            None,
+        ));
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default", "annotations": { "token": "s3cr3t" } },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        })
+        .to_string();
+        for enabled in [true, false] {
+            let app = test::init_service(
+                App::new()
+                    .wrap(Condition::new(enabled, from_fn(access_log)))
+                    .app_data(web::Data::new(Controller::new("default/data")))
+                    .route("/mutate", web::post().to(mutate)),
+            )
+            .await;
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body.clone())
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let lines = logs.lines();
+        assert_eq!(lines.len(), 1, "{lines:#?}");
+        assert!(!lines[0].contains("s3cr3t"));
+        let line: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(line["target"], "gravivol::access");
+        assert_eq!(line["message"], "POST /mutate 200");
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["path"], "/mutate");
+        assert_eq!(line["status"], 200);
+        assert!(line["latency_ms"].as_f64().unwrap() > 0.0);
+        assert_eq!(line["request_bytes"], body.len());
+        assert!(line["response_bytes"].as_u64().unwrap() > 0);
+        assert_eq!(line["uid"], "705ab4f5");
+        assert_eq!(line["patched"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_trace_export() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _subscriber = tracing::dispatcher::set_default(&logging::dispatch(
+            logging::LogFormat::Text,
+            tracing_subscriber::EnvFilter::new("off"),
+            io::sink,
+            false,
+            Some(telemetry::tracer(&provider)),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("default/data")))
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "namespace": "default",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        });
+        let request = test::TestRequest::post()
+            .uri("/mutate")
+            .insert_header((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ))
+            .set_payload(body.to_string())
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::OK
+        );
+        provider.force_flush().unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let span = |name| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("no span {name} in {spans:#?}"))
+        };
+        let handler = span("mutate");
+        assert_eq!(
+            handler.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(handler.parent_span_id.to_string(), "00f067aa0ba902b7");
+        let admission = span("admission");
+        assert_eq!(admission.parent_span_id, handler.span_context.span_id());
+        assert_eq!(span("parse").parent_span_id, handler.span_context.span_id());
+        for name in ["match", "patch_build", "serialize"] {
+            assert_eq!(span(name).parent_span_id, admission.span_context.span_id());
+        }
+        let attribute = |key| {
+            admission
+                .attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.clone())
+        };
+        assert_eq!(attribute("namespace"), Some("default".into()));
+        assert_eq!(attribute("claim_count"), Some(1.into()));
+        assert!(
+            matches!(attribute("patch_bytes"), Some(opentelemetry::Value::I64(bytes)) if bytes > 0)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_processing_timeout() {
+        let controller = Controller::new("default/data")
+            .with_options(Options {
+                label_value: LabelValue::Uid,
+                ..Default::default()
+            })
+            .with_cluster(Arc::new(SlowCluster(Duration::from_secs(60))));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(controller))
+                .app_data(Limits {
+                    processing: Duration::from_millis(50),
+                    ..Default::default()
+                })
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        });
+        let request = test::TestRequest::post()
+            .uri("/mutate")
+            .set_payload(body.to_string())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let review: Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(review["response"]["allowed"], true);
+        assert_eq!(
+            review["response"]["status"]["message"],
+            "gravivol: request 705ab4f5 not processed within 0.05s"
+        );
+    }
+
+    /// Sends a request over a new connection, returning the whole response
+    async fn send(addr: SocketAddr, request_line: &str, body: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "{request_line} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    thread_local! {
+        /// Bytes allocated by the thread so far
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Counts the allocations of each thread
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[actix_web::test]
+    async fn test_body_copies() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("")))
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [{ "name": "web", "args": ["x".repeat(1 << 20)] }] },
+                },
+            }
+        })
+        .to_string();
+        let size = body.len();
+        let request = test::TestRequest::post()
+            .uri("/mutate")
+            .set_payload(body)
+            .to_request();
+
+        let before = ALLOCATED.with(Cell::get);
+        let response = test::call_service(&app, request).await;
+        let allocated = ALLOCATED.with(Cell::get) - before;
+        assert_eq!(response.status(), StatusCode::OK);
+        // The collected body and the parsed review, each a little over the size, but neither a
+        // String copy of the body nor a clone of the object
+        assert!(allocated < size * 5 / 2, "{allocated} bytes for {size}");
+    }
+
+    #[actix_web::test]
+    async fn test_max_in_flight() {
+        let shared_metrics = Arc::new(Metrics::new());
+        let controller = Controller::new("default/data")
+            .with_options(Options {
+                label_value: LabelValue::Uid,
+                ..Default::default()
+            })
+            .with_metrics(shared_metrics.clone())
+            .with_cluster(Arc::new(SlowCluster(Duration::from_millis(200))));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(controller))
+                .app_data(Limits {
+                    in_flight: Some(Arc::new(Semaphore::new(1))),
+                    ..Default::default()
+                })
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let request = |uid: &str| {
+            let body = json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": uid,
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "name": "web", "namespace": "default" },
+                        "spec": {
+                            "containers": [],
+                            "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                        },
+                    },
+                }
+            });
+            test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let (first, second) = tokio::join!(
+            test::call_service(&app, request("first")),
+            test::call_service(&app, request("second"))
+        );
+        let review = |response: actix_web::dev::ServiceResponse| async {
+            serde_json::from_slice::<Value>(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+        };
+        let (first, second) = (review(first).await, review(second).await);
+        assert_eq!(first["response"]["uid"], "first");
+        assert!(first["response"]["patch"].is_string());
+        assert_eq!(second["response"]["uid"], "second");
+        assert_eq!(second["response"]["allowed"], true);
+        assert!(second["response"]["patch"].is_null());
+        assert_eq!(
+            second["response"]["warnings"][0],
+            "gravivol: too many requests in flight, the object was not mutated"
+        );
+        assert!(
+            shared_metrics
+                .encode()
+                .contains("gravivol_shed_requests_total 1\n")
+        );
+
+        // Permits are returned
+        let response = review(test::call_service(&app, request("third")).await).await;
+        assert!(response["response"]["patch"].is_string());
+        assert_eq!(shared_metrics.in_flight.get(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit() {
+        let controller = web::Data::new(Controller::new("default/data").with_options(Options {
+            failure_mode: FailureMode::Closed,
+            ..Default::default()
+        }));
+        let app = test::init_service(
+            App::new()
+                .app_data(controller.clone())
+                .app_data(Limits {
+                    rate: Some(Arc::new(RateLimiter::new(0.01, 2))),
+                    ..Default::default()
+                })
+                .route("/mutate", web::post().to(mutate)),
+        )
+        .await;
+        let request = |uid: &str, client: &str| {
+            let body = json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": uid,
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "name": "web", "namespace": "default" },
+                        "spec": {
+                            "containers": [],
+                            "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                        },
+                    },
+                }
+            });
+            test::TestRequest::post()
+                .uri("/mutate")
+                .peer_addr(client.parse().unwrap())
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        for uid in ["1", "2"] {
+            let review: Value =
+                test::call_and_read_body_json(&app, request(uid, "10.0.0.1:40000")).await;
+            assert!(review["response"]["patch"].is_string(), "{review}");
+        }
+        for uid in ["3", "4"] {
+            let review: Value =
+                test::call_and_read_body_json(&app, request(uid, "10.0.0.1:40001")).await;
+            assert_eq!(review["response"]["uid"], uid);
+            assert_eq!(review["response"]["allowed"], true);
+            assert!(review["response"]["patch"].is_null());
+            assert_eq!(
+                review["response"]["warnings"][0],
+                "gravivol: rate limit exceeded, the object was not mutated"
+            );
+        }
+        let review: Value =
+            test::call_and_read_body_json(&app, request("5", "10.0.0.2:40000")).await;
+        assert!(review["response"]["patch"].is_string());
+
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/mutate")
+                .peer_addr("10.0.0.1:40002".parse().unwrap())
+                .set_payload("{}")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let metrics = controller.metrics().encode();
+        assert!(
+            metrics.contains("gravivol_rate_limited_requests_total{client=\"10.0.0.1\"} 3\n"),
+            "{metrics}"
+        );
+        assert!(!metrics.contains("client=\"10.0.0.2\""));
+    }
+
+    #[actix_web::test]
+    async fn test_plain_http() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .app_data(web::Data::new(Controller::new("")))
+                .route("/mutate", web::post().to(mutate))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [] },
+                },
+            }
+        })
+        .to_string();
+        let response = send(addr, "POST /mutate", &body).await;
+        handle.stop(true).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let review: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert_eq!(review["response"]["allowed"], true);
+    }
+
+    // The plain test attribute, shadowed by actix_web::test
+    #[std::prelude::v1::test]
+    fn test_ipv4_fallback() {
+        let unsupported = io::Error::from_raw_os_error(libc::EAFNOSUPPORT);
+        assert_eq!(
+            ipv4_fallback("[::]:8080".parse().unwrap(), &unsupported),
+            Some("0.0.0.0:8080".parse().unwrap())
+        );
+        assert_eq!(
+            ipv4_fallback("[::1]:8080".parse().unwrap(), &unsupported),
+            None
+        );
+        assert_eq!(
+            ipv4_fallback("0.0.0.0:8080".parse().unwrap(), &unsupported),
+            None
+        );
+        let in_use = io::Error::from_raw_os_error(libc::EADDRINUSE);
+        assert_eq!(ipv4_fallback("[::]:8080".parse().unwrap(), &in_use), None);
+    }
+
+    #[std::prelude::v1::test]
+    fn test_listen_tcp() {
+        let listeners = listen_tcp(&["127.0.0.1:0".to_owned(), "localhost:0".to_owned()]).unwrap();
+        assert!(listeners.len() >= 2);
+        for listener in &listeners {
+            assert!(SockRef::from(listener).reuse_address().unwrap());
+        }
+
+        let taken = listeners[0].local_addr().unwrap().to_string();
+        let err = listen_tcp(&["127.0.0.1:0".to_owned(), taken.clone()]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(
+            err.to_string()
+                .starts_with(&format!("Cannot listen on {taken}: ")),
+            "{err}"
+        );
+        assert!(listen_tcp(&[]).unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("gravivol-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        // A socket file left over by a crashed run
+        drop(UnixListener::bind(path).unwrap());
+        let (listener, socket_file) = listen_uds(path, 0o600).unwrap();
+        assert_eq!(
+            fs::metadata(path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let server = HttpServer::new(|| {
+            App::new()
+                .app_data(web::Data::new(Controller::new("default/data")))
+                .route("/mutate", web::post().to(mutate))
+        })
+        .workers(1)
+        .listen_uds(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        })
+        .to_string();
+        let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST /mutate HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        handle.stop(true).await;
+        drop(socket_file);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let review: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert!(review["response"]["patch"].is_string());
+        assert!(fs::symlink_metadata(path).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_graceful_shutdown() {
+        let shared_metrics = web::Data::new(Metrics::new());
+        let health = web::Data::new(Health::new(None));
+        let server = HttpServer::new({
+            let shared_metrics = shared_metrics.clone();
+            move || {
+                let controller = Controller::new("default/data")
+                    .with_options(Options {
+                        label_value: LabelValue::Uid,
+                        ..Default::default()
+                    })
+                    .with_metrics(shared_metrics.clone().into_inner())
+                    .with_cluster(Arc::new(SlowCluster(Duration::from_millis(500))));
+                App::new()
+                    .app_data(web::Data::new(controller))
+                    .route("/mutate", web::post().to(mutate))
+            }
+        })
+        .workers(1)
+        .shutdown_timeout(5)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let admin_server = HttpServer::new({
+            let health = health.clone();
+            move || App::new().app_data(health.clone()).service(readyz)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let (addr, admin_addr) = (server.addrs()[0], admin_server.addrs()[0]);
+        let (server, admin_server) = (server.run(), admin_server.run());
+        let (shutdown, shutdown_received) = tokio::sync::oneshot::channel();
+        let serving = tokio::spawn({
+            let (health, shared_metrics) = (health.clone(), shared_metrics.clone());
+            async move {
+                serve(
+                    server,
+                    admin_server,
+                    &health,
+                    &shared_metrics,
+                    async {
+                        shutdown_received.await.unwrap();
+                    },
+                    Duration::ZERO,
+                )
+                .await
+            }
+        });
+
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        })
+        .to_string();
+        let slow_request = tokio::spawn(async move { send(addr, "POST /mutate", &body).await });
+        while shared_metrics.in_flight.get() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let readiness = send(admin_addr, "GET /readyz", "").await;
+        assert!(
+            readiness.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{readiness}"
+        );
+
+        let response = slow_request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let review: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(review["response"]["uid"], "705ab4f5");
+        assert!(review["response"]["status"].is_null(), "{review}");
+        serving.await.unwrap().unwrap();
+        assert_eq!(shared_metrics.in_flight.get(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_shutdown_delay() {
+        let shared_metrics = web::Data::new(Metrics::new());
+        let health = web::Data::new(Health::new(None));
+        let server = HttpServer::new(|| {
+            App::new()
+                .app_data(web::Data::new(Controller::new("")))
+                .route("/mutate", web::post().to(mutate))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let admin_server = HttpServer::new({
+            let health = health.clone();
+            move || App::new().app_data(health.clone()).service(readyz)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let (addr, admin_addr) = (server.addrs()[0], admin_server.addrs()[0]);
+        let (server, admin_server) = (server.run(), admin_server.run());
+        let (shutdown, shutdown_received) = tokio::sync::oneshot::channel();
+        let delay = Duration::from_millis(300);
+        let serving = tokio::spawn({
+            let (health, shared_metrics) = (health.clone(), shared_metrics.clone());
+            async move {
+                serve(
+                    server,
+                    admin_server,
+                    &health,
+                    &shared_metrics,
+                    async {
+                        shutdown_received.await.unwrap();
+                    },
+                    delay,
+                )
+                .await
+            }
+        });
+
+        let started = Instant::now();
+        shutdown.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let readiness = send(admin_addr, "GET /readyz", "").await;
+        assert!(
+            readiness.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{readiness}"
+        );
+        // Still accepted while the endpoint is being removed
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "9c1e77d0",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [] },
+                },
+            }
+        })
+        .to_string();
+        let response = send(addr, "POST /mutate", &body).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+        serving.await.unwrap().unwrap();
+        assert!(started.elapsed() >= delay);
+    }
+
+    #[actix_web::test]
+    async fn test_mutate_paths() {
+        let shared_metrics = Arc::new(Metrics::new());
+        let paths = ["/mutate/pods".to_owned(), "/mutate/workloads".to_owned()];
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    Controller::new("").with_metrics(shared_metrics.clone()),
+                ))
+                .configure(mutate_routes(&paths)),
+        )
+        .await;
+        let body = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [] },
+                },
+            }
+        })
+        .to_string();
+        for (uri, status) in [
+            ("/mutate/pods", StatusCode::OK),
+            ("/mutate/workloads", StatusCode::OK),
+            ("/mutate/pods", StatusCode::OK),
+            ("/mutate", StatusCode::NOT_FOUND),
+            ("/mutate/jobs", StatusCode::NOT_FOUND),
+        ] {
+            let request = test::TestRequest::post()
+                .uri(uri)
+                .set_payload(body.clone())
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "{uri}");
+        }
+
+        let text = shared_metrics.encode();
+        assert!(text.contains("gravivol_mutate_duration_seconds_count{path=\"/mutate/pods\"} 2\n"));
+        assert!(
+            text.contains("gravivol_mutate_duration_seconds_count{path=\"/mutate/workloads\"} 1\n")
+        );
+        assert!(text.contains("gravivol_requests_total{path=\"/mutate/pods\"} 2\n"));
+        assert!(text.contains("gravivol_requests_total{path=\"/mutate/workloads\"} 1\n"));
+        assert!(!text.contains("gravivol_requests_total{path=\"/mutate/jobs\"}"));
+        assert!(text.contains("gravivol_admission_requests_total 3\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_validate() {
+        let validate_with = |validate_mode| async move {
+            let controller = Controller::new("default/data").with_options(Options {
+                validate_mode,
+                ..Options::default()
+            });
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(controller))
+                    .route(VALIDATE_PATH, web::post().to(validate)),
+            )
+            .await
+        };
+        let review = |labels: Value| {
+            json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "705ab4f5",
+                    "namespace": "default",
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "name": "web", "labels": labels },
+                        "spec": {
+                            "containers": [],
+                            "volumes": [{
+                                "name": "data",
+                                "persistentVolumeClaim": { "claimName": "data" },
+                            }],
+                        },
+                    },
+                }
+            })
+            .to_string()
+        };
+        let post = |body: String| {
+            test::TestRequest::post()
+                .uri(VALIDATE_PATH)
+                .set_payload(body)
+                .to_request()
+        };
+
+        // Denied without the label of its claim
+        let app = validate_with(ValidateMode::Deny).await;
+        let denied: Value =
+            test::call_and_read_body_json(&app, post(review(json!({ "app": "web" })))).await;
+        assert_eq!(denied["response"]["uid"], "705ab4f5");
+        assert_eq!(denied["response"]["allowed"], false);
+        assert_eq!(denied["response"]["status"]["code"], 403);
+        assert_eq!(
+            denied["response"]["status"]["message"],
+            "gravivol: pod default/web mounts claims data without the labels default.gravivol.fonona.net/data of their co-location, it bypassed the mutating webhook"
+        );
+        assert!(denied["response"]["patch"].is_null());
+
+        // Passes with it
+        let passed: Value = test::call_and_read_body_json(
+            &app,
+            post(review(
+                json!({ "default.gravivol.fonona.net/data": "true" }),
+            )),
+        )
+        .await;
+        assert_eq!(passed["response"]["allowed"], true);
+        assert!(passed["response"]["status"].is_null());
+        assert!(passed["response"]["warnings"].is_null());
+
+        // Allowed with a warning
+        let app = validate_with(ValidateMode::Warn).await;
+        let warned: Value =
+            test::call_and_read_body_json(&app, post(review(json!({ "app": "web" })))).await;
+        assert_eq!(warned["response"]["allowed"], true);
+        assert!(warned["response"]["status"].is_null());
+        assert_eq!(
+            warned["response"]["warnings"][0]
+                .as_str()
+                .unwrap()
+                .split(" without")
+                .next(),
+            Some("gravivol: pod default/web mounts claims data")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_admin_routes() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Metrics::new()))
+                .app_data(web::Data::new(Health::new(None)))
+                .app_data(web::Data::new(Settings::from_env().unwrap()))
+                .configure(admin_routes),
+        )
+        .await;
+        for uri in ["/livez", "/readyz", "/metrics", "/configz", "/version"] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+
+        let request = test::TestRequest::post()
+            .uri("/mutate")
+            .set_payload("{}")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_debug_mutations() {
+        let decisions = web::Data::new(Decisions::new(10));
+        let controller =
+            Controller::new("default/data").with_decisions(decisions.clone().into_inner());
+        let review = |uid: &str, namespace: &str, volumes: Value| {
+            serde_json::from_value::<AdmissionReview>(json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": uid,
+                    "namespace": namespace,
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "generateName": "web-", "namespace": namespace },
+                        "spec": {
+                            "containers": [{ "name": "web", "env": [{ "name": "TOKEN", "value": "s3cr3t" }] }],
+                            "volumes": volumes,
+                        },
+                    },
+                }
+            }))
+            .unwrap()
+        };
+        let claim = json!([{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }]);
+        controller
+            .mutate(review("705ab4f5", "default", claim))
+            .await
+            .unwrap();
+        controller
+            .mutate(review("9c1e77d0", "apps", json!([])))
+            .await
+            .unwrap();
+
+        let app = test::init_service(App::new().app_data(decisions).service(debug_mutations)).await;
+        let request = test::TestRequest::get()
+            .uri("/debug/mutations")
+            .to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        assert!(!String::from_utf8_lossy(&body).contains("s3cr3t"));
+        let mutations: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(mutations[0]["uid"], "9c1e77d0");
+        assert_eq!(mutations[0]["skipReason"], "no_volumes");
+        assert_eq!(mutations[0]["patchOps"], 0);
+        assert_eq!(mutations[1]["uid"], "705ab4f5");
+        assert_eq!(mutations[1]["namespace"], "default");
+        assert_eq!(mutations[1]["name"], "web-");
+        assert_eq!(mutations[1]["claims"], json!(["data"]));
+        assert!(mutations[1].get("skipReason").is_none());
+        assert!(mutations[1]["patchOps"].as_u64().unwrap() > 0);
+
+        let request = test::TestRequest::get()
+            .uri("/debug/mutations?namespace=apps")
+            .to_request();
+        let mutations: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(mutations.as_array().unwrap().len(), 1);
+        assert_eq!(mutations[0]["uid"], "9c1e77d0");
+    }
+
+    #[actix_web::test]
+    async fn test_debug_bodies() {
+        let samples = web::Data::new(BodySamples::new(0.0, vec!["debug-".to_owned()], 1 << 20));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Controller::new("default/data")))
+                .app_data(samples.clone())
+                .route("/mutate", web::post().to(mutate))
+                .service(debug_bodies),
+        )
+        .await;
+        for uid in ["705ab4f5", "debug-9c1e77d0"] {
+            let body = json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": uid,
+                    "object": {
+                        "apiVersion": "v1",
+                        "kind": "Pod",
+                        "metadata": { "name": "web", "namespace": "default" },
+                        "spec": {
+                            "containers": [{ "name": "web", "env": [{ "name": "TOKEN", "value": "s3cr3t" }] }],
+                        },
+                    },
+                }
+            });
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body.to_string())
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, request).await.status(),
+                StatusCode::OK
+            );
+        }
+
+        let request = test::TestRequest::get().uri("/debug/bodies").to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        assert!(!String::from_utf8_lossy(&body).contains("s3cr3t"));
+        let bodies: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(bodies.as_array().unwrap().len(), 1);
+        assert_eq!(bodies[0]["uid"], "debug-9c1e77d0");
+        assert_eq!(
+            bodies[0]["body"]["request"]["object"]["metadata"]["name"],
+            "web"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_version() {
+        let app = test::init_service(App::new().service(version)).await;
+        let request = test::TestRequest::get().uri("/version").to_request();
+        let info: Value = test::call_and_read_body_json(&app, request).await;
+        let object = info.as_object().unwrap();
+        let mut keys: Vec<_> = object.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["built_at", "git_commit", "rustc_version", "version"]);
+        assert!(
+            object
+                .values()
+                .all(|value| value.as_str().is_some_and(|s| !s.is_empty()))
+        );
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(
+            info["rustc_version"]
+                .as_str()
+                .unwrap()
+                .starts_with("rustc ")
+        );
+        assert!(
+            build_info::VERSION.ends_with(info["git_commit"].as_str().unwrap()),
+            "{}",
+            build_info::VERSION
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_probes() {
+        let health = web::Data::new(Health::new(None));
+        let app = test::init_service(
+            App::new()
+                .app_data(health.clone())
+                .service(livez)
+                .service(readyz),
+        )
+        .await;
+        let get = |uri| test::TestRequest::get().uri(uri).to_request();
+
+        for uri in ["/livez", "/health", "/readyz"] {
+            let response = test::call_service(&app, get(uri)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+
+        let response = test::call_service(&app, get("/readyz")).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let mut body: Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        // Whole seconds, a slow machine may have crossed one
+        let uptime = body["uptimeSeconds"].take();
+        assert!(
+            uptime.as_u64().is_some_and(|uptime| uptime < 10),
+            "{uptime}"
+        );
+        assert_eq!(
+            body,
+            json!({
+                "status": "ready",
+                "checks": { "config": { "status": "ok" }, "shutdown": { "status": "ok" } },
+                "uptimeSeconds": null,
+                "lastConfigReload": null,
+                "lastRequest": null,
+                "lastMutation": null,
+            })
+        );
+
+        health.set_config_result(validate_config("myvol"));
+        let response = test::call_service(&app, get("/readyz")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["checks"]["config"]["status"], "failed");
+        assert!(
+            body["checks"]["config"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("myvol")
+        );
+        assert_eq!(body["checks"]["shutdown"], json!({ "status": "ok" }));
+        assert!(body["lastConfigReload"].as_str().unwrap().ends_with('Z'));
+
+        let response = test::call_service(&app, get("/livez")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[actix_web::test]
+    async fn test_process_metrics() {
+        let metrics = web::Data::new(Metrics::new());
+        metrics.register(Box::new(process::ProcessCollector::new()));
+        metrics.set_workers(4);
+        let app =
+            test::init_service(App::new().app_data(metrics).service(prometheus_metrics)).await;
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let body =
+            String::from_utf8(test::call_and_read_body(&app, request).await.to_vec()).unwrap();
+        for family in [
+            "gravivol_process_cpu_seconds_total",
+            "gravivol_process_resident_memory_bytes",
+            "gravivol_process_virtual_memory_bytes",
+            "gravivol_process_open_fds",
+            "gravivol_process_max_fds",
+            "gravivol_process_threads",
+            "gravivol_process_start_time_seconds",
+            "gravivol_requests_in_flight",
+        ] {
+            assert!(
+                body.contains(&format!("# TYPE {family} ")),
+                "{family} missing"
+            );
+        }
+        assert!(body.contains("gravivol_workers 4\n"));
+        assert!(!body.contains("gravivol_process_resident_memory_bytes 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_config_metrics() {
+        let (health, metrics) = (Health::new(None), Metrics::new());
+        record_config_load(
+            &health,
+            &metrics,
+            "default/data,default/logs:spread,apps/db",
+        );
+        let text = metrics.encode();
+        assert!(text.contains("gravivol_config_entries{mode=\"affinity\"} 2\n"));
+        assert!(text.contains("gravivol_config_entries{mode=\"spread\"} 1\n"));
+        assert!(text.contains("gravivol_config_entries{mode=\"preferred\"} 0\n"));
+        assert!(!text.contains("gravivol_config_last_load_timestamp_seconds 0\n"));
+
+        // A failed load keeps the entries in use
+        record_config_load(&health, &metrics, "default/data,myvol");
+        let text = metrics.encode();
+        assert!(text.contains("gravivol_config_loads_total 2\n"));
+        assert!(text.contains("gravivol_config_load_failures_total{error=\"invalid_entry\"} 1\n"));
+        assert!(text.contains("gravivol_config_entries{mode=\"affinity\"} 2\n"));
+        assert_eq!(
+            health.checks().await["config"],
+            CheckStatus::Failed {
+                message: "config entries not in the format <namespace>/<claim name>[:affinity[:replace]|preferred[:<weight 1-100>]|anti-affinity[:<weight 1-100>]|spread[:<max skew>][:DoNotSchedule|ScheduleAnyway][:<topology key>]]: myvol".to_owned()
+            }
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let shared_metrics = Arc::new(Metrics::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(
+                    Controller::new("").with_metrics(shared_metrics.clone()),
+                ))
+                .app_data(web::Data::from(shared_metrics))
+                .route("/mutate", web::post().to(mutate))
+                .service(prometheus_metrics),
+        )
+        .await;
+        let pod = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": { "containers": [] },
+                },
+            }
+        });
+        for body in [pod.to_string(), pod.to_string(), "{".to_owned()] {
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .set_payload(body)
+                .to_request();
+            test::call_service(&app, request).await;
+        }
+
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body =
+            String::from_utf8(to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+        assert!(body.contains("gravivol_admission_requests_total 2\n"));
+        assert!(body.contains("gravivol_pods_skipped_total{reason=\"no_volumes\"} 2\n"));
+        assert!(body.contains("gravivol_parse_failures_total 1\n"));
+        assert!(body.contains("gravivol_errors_total 0\n"));
+        assert!(body.contains("gravivol_mutate_duration_seconds_count{path=\"/mutate\"} 3\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_phase_metrics() {
+        let metrics_app = |phase_timing: bool| {
+            let shared_metrics = Arc::new(Metrics::new().with_phase_timing(phase_timing));
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(
+                        Controller::new("default/data").with_metrics(shared_metrics.clone()),
+                    ))
+                    .app_data(web::Data::from(shared_metrics))
+                    .route("/mutate", web::post().to(mutate))
+                    .service(prometheus_metrics),
+            )
+        };
+        let pod = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web", "namespace": "default" },
+                    "spec": {
+                        "containers": [],
+                        "volumes": [{ "name": "data", "persistentVolumeClaim": { "claimName": "data" } }],
+                    },
+                },
+            }
+        });
+        let mutate_and_scrape = async |app| {
+            let request = test::TestRequest::post()
+                .uri("/mutate")
+                .set_json(&pod)
+                .to_request();
+            let review: Value = test::call_and_read_body_json(&app, request).await;
+            assert!(review["response"]["patch"].is_string());
+            let request = test::TestRequest::get().uri("/metrics").to_request();
+            String::from_utf8(test::call_and_read_body(&app, request).await.to_vec()).unwrap()
+        };
+
+        let body = mutate_and_scrape(metrics_app(true).await).await;
+        for phase in ["deserialize", "match", "lookup", "patch_build", "serialize"] {
+            assert!(
+                body.contains(&format!(
+                    "gravivol_phase_duration_seconds_count{{phase=\"{phase}\"}} 1\n"
+                )),
+                "{phase} not observed"
+            );
+        }
+        assert!(
+            body.contains("gravivol_phase_duration_seconds_bucket{phase=\"match\",le=\"0.0001\"}")
+        );
+        assert!(body.contains("gravivol_mutate_duration_seconds_count{path=\"/mutate\"} 1\n"));
+
+        let body = mutate_and_scrape(metrics_app(false).await).await;
+        assert!(!body.contains("gravivol_phase_duration_seconds"));
+        assert!(body.contains("gravivol_mutate_duration_seconds_count{path=\"/mutate\"} 1\n"));
+    }
+}
@@ -0,0 +1,942 @@
+//! Settings of the webhook and the options of the controller, read from GRAVIVOL_ variables
+
+use std::{collections::HashSet, env, error::Error, net::SocketAddr, str::FromStr};
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    controller::{
+        AccessMode, FailureMode, FirstPodMode, Kind, LabelValue, NodeAffinityMode, Options,
+        PatchMode, SpreadOptions, UnboundClaimMode, ValidateMode, WhenUnsatisfiable,
+        is_valid_label_key,
+    },
+    logging::LogFormat,
+    metrics::{DEFAULT_CLAIM_LIMIT, DEFAULT_NAMESPACE_LIMIT},
+    permissions::Permission,
+    registration::DeregisterMode,
+    tls::{self, ClientAuthMode, TlsVersion},
+};
+
+/// Like the object size limit of the API server
+pub const DEFAULT_MAX_BODY_BYTES: usize = 3 * 1024 * 1024;
+/// Pods nest about 20 levels deep including managedFields, serde_json stops at 128
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 64;
+/// Most volumes of a pod processed by default
+pub const DEFAULT_MAX_VOLUMES: usize = 1000;
+const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_SAMPLE_BODIES_MAX_BYTES: usize = 10 * 1024 * 1024;
+/// Path of the validating webhook
+pub const VALIDATE_PATH: &str = "/validate";
+
+/// Settings of gravivol, read from the environment
+#[derive(Clone, Debug, Serialize)]
+pub struct Settings {
+    /// Comma separated list of PVCs to handle
+    pub config: String,
+    /// PEM file of the certificate chain
+    pub tls_cert_path: String,
+    /// PEM file of the private key
+    pub tls_key_path: String,
+    /// PEM file with the certificate chain and the key, used instead of the two paths
+    pub tls_bundle_path: Option<String>,
+    /// CA bundle to verify client certificates with, e.g. of the API server
+    pub tls_client_ca_path: Option<String>,
+    /// Whether clients must present a certificate
+    pub tls_client_auth: ClientAuthMode,
+    /// Oldest TLS version accepted
+    pub tls_min_version: TlsVersion,
+    /// Cipher suites offered to clients, empty for the defaults of rustls
+    pub tls_ciphers: Vec<String>,
+    /// Text or JSON lines
+    pub log_format: LogFormat,
+    /// Log a line per request with status, latency and the outcome of the admission
+    pub access_log: bool,
+    /// Serve /debug endpoints, e.g. the recent mutations, on the admin listener
+    pub debug_endpoints: bool,
+    /// Decisions kept for /debug/mutations
+    pub mutation_history: usize,
+    /// Namespaces labeled individually in the metrics of mutations
+    pub metrics_namespaces: usize,
+    /// Claims labeled individually in the metrics of mutations
+    pub metrics_claims: usize,
+    /// Time the phases of each admission request
+    pub phase_metrics: bool,
+    /// JSON Lines file to append each decision with its patch to
+    pub audit_log: Option<String>,
+    /// Size from which the audit log is rotated, 0 for never
+    pub audit_log_max_bytes: u64,
+    /// Share of the request bodies kept for /debug/bodies, 0 to 1
+    pub sample_bodies: f64,
+    /// Request uids whose bodies are always kept, by prefix
+    pub sample_bodies_uids: Vec<String>,
+    /// Total size of the kept bodies, the oldest are dropped for new ones
+    pub sample_bodies_max_bytes: usize,
+    /// Days before the expiry of the served certificate to start warning daily
+    pub cert_expiry_warning_days: u32,
+    /// Minutes without admission requests after which a warning is logged, 0 for never
+    pub idle_warning_minutes: u64,
+    /// Serve plain HTTP when TLS is terminated elsewhere, e.g. by a service mesh
+    pub tls: bool,
+    /// Addresses to listen on as `<host>:<port>`, the host may be a name to resolve, empty if
+    /// only listening on the Unix socket
+    pub bind: Vec<String>,
+    /// Unix socket to listen on with plain HTTP, e.g. for a proxy on the same host
+    pub bind_uds: Option<String>,
+    /// Permissions of the Unix socket file
+    pub uds_mode: u32,
+    /// Plain HTTP addresses for probes, metrics and the effective configuration
+    pub admin_bind: Vec<String>,
+    /// Paths the webhook is served on, e.g. one per rule of the webhook configuration
+    pub mutate_paths: Vec<String>,
+    /// Serve the validating webhook on /validate
+    pub validate: bool,
+    /// Number of HTTP workers, 0 for one per CPU
+    pub workers: usize,
+    /// Requests processed at once before further ones are shed, 0 for no limit
+    pub max_in_flight: usize,
+    /// Requests per second of a client before further ones are shed, 0 for no limit
+    pub rate_limit: f64,
+    /// Requests of a client at once within the rate limit, 0 for the requests of a second
+    pub rate_burst: u32,
+    /// Largest admission request body read, the API server limits objects to 3 MiB
+    pub max_body_bytes: usize,
+    /// Deepest nesting of objects and arrays in a request body processed, 0 for no limit
+    pub max_json_depth: usize,
+    /// Most volumes of a pod processed, 0 for no limit
+    pub max_volumes: usize,
+    /// Seconds to receive a request and to process it, below the webhook timeoutSeconds
+    pub request_timeout_secs: u64,
+    /// Seconds an idle connection is kept open for the next request
+    pub keep_alive_secs: u64,
+    /// Seconds to wait for the client to close the connection after a response
+    pub client_disconnect_secs: u64,
+    /// Seconds to keep accepting requests on termination after readiness fails
+    pub shutdown_delay_secs: u64,
+    /// Seconds to finish in-flight requests on termination
+    pub shutdown_grace_secs: u64,
+    /// Seconds lookups in the Kubernetes API are cached
+    pub lookup_cache_secs: u64,
+    /// Objects of each kind cached from the Kubernetes API, 0 to not cache
+    pub lookup_cache_entries: usize,
+    /// Create or update the MutatingWebhookConfiguration on startup and certificate reloads
+    pub register_webhook: bool,
+    /// Service the registered webhook points at
+    pub webhook_service: String,
+    /// Namespace of the service, that of the service account if None
+    pub webhook_namespace: Option<String>,
+    /// Port of the service
+    pub webhook_port: u16,
+    /// Seconds the API server waits for the webhook, 1 to 30
+    pub webhook_timeout_secs: u32,
+    /// LabelSelector of the namespaces whose objects the webhook gets, all if None
+    pub webhook_namespace_selector: Option<Value>,
+    /// Log the caBundle patch on certificate changes instead of applying it
+    pub ca_sync_dry_run: bool,
+    /// What happens to the registered configuration on shutdown
+    pub deregister_on_shutdown: DeregisterMode,
+    /// Create an event on each pod that got a patch
+    pub emit_events: bool,
+    /// Name of the gravivol pod, reported on events
+    pub pod_name: String,
+    /// Periodically report running pods that lack the labels of their claims
+    pub audit: bool,
+    /// Seconds between the audits
+    pub audit_interval_secs: u64,
+    /// Create an event on each pod the audit reports
+    pub audit_events: bool,
+    /// Run the background tasks only on the replica holding the lease instead of on all
+    pub leader_election: bool,
+    /// Lease in the namespace of the service account
+    pub lease_name: String,
+    /// Seconds a lease is held without renewal
+    pub lease_duration_secs: u64,
+    /// Kubeconfig used instead of the service account, e.g. to run outside the cluster
+    pub kubeconfig: Option<String>,
+    /// Requests per second to the Kubernetes API, 0 for no limit
+    pub kube_qps: f64,
+    /// Requests to the Kubernetes API allowed at once, as many as kube_qps if 0
+    pub kube_burst: u32,
+    /// The API server and how it was found, set on connecting
+    pub kube_api: Option<String>,
+    /// Refuse to start if a feature lacks permissions, instead of disabling it
+    pub rbac_strict: bool,
+    /// Permissions of the enabled features, set after probing them on startup
+    pub permissions: Vec<Permission>,
+    /// Features disabled on startup because their permissions were denied
+    pub disabled_features: Vec<&'static str>,
+    /// Options of the controller
+    pub controller: Options,
+}
+
+impl Settings {
+    /// Reads the settings, failing on invalid values
+    pub fn from_env() -> Result<Settings, Box<dyn Error>> {
+        let defaults = Options::default();
+        let settings = Settings {
+            config: env::var("GRAVIVOL_CONFIG").unwrap_or_else(|_| "".to_string()),
+            tls_cert_path: env::var("GRAVIVOL_TLS_CERT_PATH")
+                .unwrap_or_else(|_| "/certs/cert.pem".to_string()),
+            tls_key_path: env::var("GRAVIVOL_TLS_KEY_PATH")
+                .unwrap_or_else(|_| "/certs/key.pem".to_string()),
+            tls_bundle_path: env::var("GRAVIVOL_TLS_BUNDLE_PATH")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            tls_client_ca_path: env::var("GRAVIVOL_TLS_CLIENT_CA_PATH")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            tls_client_auth: env_choice(
+                "GRAVIVOL_TLS_CLIENT_AUTH",
+                ClientAuthMode::Require,
+                ClientAuthMode::from_name,
+                "require or log",
+            )?,
+            tls_min_version: env_choice(
+                "GRAVIVOL_TLS_MIN_VERSION",
+                TlsVersion::Tls12,
+                TlsVersion::from_name,
+                "1.2 or 1.3",
+            )?,
+            tls_ciphers: match env::var("GRAVIVOL_TLS_CIPHERS") {
+                Ok(value) => parse_ciphers(&value)?,
+                Err(_) => Vec::new(),
+            },
+            log_format: env_choice(
+                "GRAVIVOL_LOG_FORMAT",
+                LogFormat::Text,
+                LogFormat::from_name,
+                "text or json",
+            )?,
+            access_log: env_bool("GRAVIVOL_ACCESS_LOG", true)?,
+            debug_endpoints: env_bool("GRAVIVOL_DEBUG_ENDPOINTS", false)?,
+            mutation_history: env_number("GRAVIVOL_MUTATION_HISTORY", 100, "entries")?,
+            metrics_namespaces: env_number(
+                "GRAVIVOL_METRICS_NAMESPACES",
+                DEFAULT_NAMESPACE_LIMIT,
+                "namespaces",
+            )?,
+            metrics_claims: env_number("GRAVIVOL_METRICS_CLAIMS", DEFAULT_CLAIM_LIMIT, "claims")?,
+            phase_metrics: env_bool("GRAVIVOL_PHASE_METRICS", true)?,
+            audit_log: env::var("GRAVIVOL_AUDIT_LOG")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            audit_log_max_bytes: env_number(
+                "GRAVIVOL_AUDIT_LOG_MAX_BYTES",
+                DEFAULT_AUDIT_LOG_MAX_BYTES,
+                "bytes",
+            )?,
+            sample_bodies: match env_number::<f64>("GRAVIVOL_SAMPLE_BODIES", 0.0, "share")? {
+                rate if (0.0..=1.0).contains(&rate) => rate,
+                rate => {
+                    return Err(format!(
+                        "GRAVIVOL_SAMPLE_BODIES must be a share of the requests from 0 to 1 but is '{rate}'"
+                    )
+                    .into());
+                }
+            },
+            sample_bodies_uids: match env::var("GRAVIVOL_SAMPLE_BODIES_UIDS") {
+                Ok(value) => value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+            sample_bodies_max_bytes: env_number(
+                "GRAVIVOL_SAMPLE_BODIES_MAX_BYTES",
+                DEFAULT_SAMPLE_BODIES_MAX_BYTES,
+                "bytes",
+            )?,
+            cert_expiry_warning_days: env_number("GRAVIVOL_CERT_EXPIRY_WARNING_DAYS", 14, "days")?,
+            idle_warning_minutes: env_number("GRAVIVOL_IDLE_WARNING_MINUTES", 360, "minutes")?,
+            tls: env_choice(
+                "GRAVIVOL_TLS",
+                true,
+                |name| match name {
+                    "enabled" => Some(true),
+                    "disabled" => Some(false),
+                    _ => None,
+                },
+                "enabled or disabled",
+            )? && !env_bool("GRAVIVOL_INSECURE_HTTP", false)?,
+            bind: match env::var("GRAVIVOL_BIND") {
+                Ok(value) => parse_binds("GRAVIVOL_BIND", &value)?,
+                Err(_) => vec!["[::]:8080".to_string()],
+            },
+            bind_uds: env::var("GRAVIVOL_BIND_UDS")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            uds_mode: match env::var("GRAVIVOL_UDS_MODE") {
+                Ok(value) => parse_mode(&value)?,
+                Err(_) => 0o660,
+            },
+            admin_bind: match env::var("GRAVIVOL_ADMIN_BIND") {
+                Ok(value) => match parse_binds("GRAVIVOL_ADMIN_BIND", &value)? {
+                    binds if binds.is_empty() => {
+                        return Err("GRAVIVOL_ADMIN_BIND must not be empty".into());
+                    }
+                    binds => binds,
+                },
+                Err(_) => vec!["[::]:8081".to_string()],
+            },
+            mutate_paths: match env::var("GRAVIVOL_MUTATE_PATH") {
+                Ok(value) => parse_paths(&value)?,
+                Err(_) => vec!["/mutate".to_owned()],
+            },
+            validate: env_bool("GRAVIVOL_VALIDATE", false)?,
+            workers: env_number("GRAVIVOL_WORKERS", 0, "workers")?,
+            max_in_flight: env_number("GRAVIVOL_MAX_INFLIGHT", 0, "requests")?,
+            rate_limit: match env_number::<f64>("GRAVIVOL_RATE_LIMIT", 0.0, "requests per second")?
+            {
+                rate if rate >= 0.0 && rate.is_finite() => rate,
+                rate => {
+                    return Err(format!(
+                        "GRAVIVOL_RATE_LIMIT must be a number of requests per second but is '{rate}'"
+                    )
+                    .into());
+                }
+            },
+            rate_burst: env_number("GRAVIVOL_RATE_BURST", 0, "requests")?,
+            max_body_bytes: env_number("GRAVIVOL_MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES, "bytes")?,
+            max_json_depth: env_number(
+                "GRAVIVOL_MAX_JSON_DEPTH",
+                DEFAULT_MAX_JSON_DEPTH,
+                "levels",
+            )?,
+            max_volumes: env_number("GRAVIVOL_MAX_VOLUMES", DEFAULT_MAX_VOLUMES, "volumes")?,
+            request_timeout_secs: env_number("GRAVIVOL_REQUEST_TIMEOUT", 8, "seconds")?,
+            keep_alive_secs: env_number("GRAVIVOL_KEEPALIVE", 30, "seconds")?,
+            client_disconnect_secs: env_number("GRAVIVOL_CLIENT_DISCONNECT", 2, "seconds")?,
+            shutdown_delay_secs: env_number("GRAVIVOL_SHUTDOWN_DELAY", 5, "seconds")?,
+            shutdown_grace_secs: env_number("GRAVIVOL_SHUTDOWN_GRACE", 10, "seconds")?,
+            lookup_cache_secs: env_number("GRAVIVOL_LOOKUP_CACHE_TTL", 30, "seconds")?,
+            lookup_cache_entries: env_number("GRAVIVOL_LOOKUP_CACHE_ENTRIES", 10_000, "entries")?,
+            register_webhook: env_bool("GRAVIVOL_REGISTER_WEBHOOK", false)?,
+            webhook_service: env::var("GRAVIVOL_WEBHOOK_SERVICE")
+                .ok()
+                .filter(|service| !service.is_empty())
+                .unwrap_or_else(|| "gravivol".to_owned()),
+            webhook_namespace: env::var("GRAVIVOL_WEBHOOK_NAMESPACE")
+                .ok()
+                .filter(|namespace| !namespace.is_empty()),
+            webhook_port: env_number("GRAVIVOL_WEBHOOK_PORT", 443, "port")?,
+            webhook_timeout_secs: match env_number("GRAVIVOL_WEBHOOK_TIMEOUT", 10, "seconds")? {
+                timeout @ 1..=30 => timeout,
+                timeout => {
+                    return Err(format!(
+                        "GRAVIVOL_WEBHOOK_TIMEOUT must be 1 to 30 seconds but is {timeout}"
+                    )
+                    .into());
+                }
+            },
+            webhook_namespace_selector: match env::var("GRAVIVOL_WEBHOOK_NAMESPACE_SELECTOR") {
+                Ok(value) if !value.trim().is_empty() => Some(parse_label_selector(
+                    "GRAVIVOL_WEBHOOK_NAMESPACE_SELECTOR",
+                    &value,
+                )?),
+                _ => None,
+            },
+            ca_sync_dry_run: env_bool("GRAVIVOL_CA_SYNC_DRY_RUN", false)?,
+            deregister_on_shutdown: env_choice(
+                "GRAVIVOL_DEREGISTER_ON_SHUTDOWN",
+                DeregisterMode::Off,
+                DeregisterMode::from_name,
+                "off, delete or ignore",
+            )?,
+            emit_events: env_bool("GRAVIVOL_EMIT_EVENTS", false)?,
+            pod_name: ["GRAVIVOL_POD_NAME", "HOSTNAME"]
+                .into_iter()
+                .find_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))
+                .unwrap_or_else(|| "gravivol".to_owned()),
+            audit: env_bool("GRAVIVOL_AUDIT", false)?,
+            audit_interval_secs: match env_number("GRAVIVOL_AUDIT_INTERVAL", 600, "seconds")? {
+                0 => return Err("GRAVIVOL_AUDIT_INTERVAL must be at least 1 second".into()),
+                secs => secs,
+            },
+            audit_events: env_bool("GRAVIVOL_AUDIT_EVENTS", false)?,
+            leader_election: env_bool("GRAVIVOL_LEADER_ELECTION", true)?,
+            kubeconfig: env::var("GRAVIVOL_KUBECONFIG")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            kube_qps: match env_number::<f64>("GRAVIVOL_KUBE_QPS", 50.0, "requests per second")? {
+                qps if qps >= 0.0 && qps.is_finite() => qps,
+                qps => {
+                    return Err(format!(
+                        "GRAVIVOL_KUBE_QPS must be a number of requests per second but is '{qps}'"
+                    )
+                    .into());
+                }
+            },
+            kube_burst: env_number("GRAVIVOL_KUBE_BURST", 100, "requests")?,
+            kube_api: None,
+            rbac_strict: env_bool("GRAVIVOL_RBAC_STRICT", false)?,
+            permissions: Vec::new(),
+            disabled_features: Vec::new(),
+            lease_name: env::var("GRAVIVOL_LEASE_NAME")
+                .ok()
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "gravivol".to_owned()),
+            lease_duration_secs: match env_number("GRAVIVOL_LEASE_DURATION", 15, "seconds")? {
+                secs @ 3.. => secs,
+                secs => {
+                    return Err(format!(
+                        "GRAVIVOL_LEASE_DURATION must be at least 3 seconds but is {secs}"
+                    )
+                    .into());
+                }
+            },
+            controller: Options {
+                stamp_annotation: env_bool("GRAVIVOL_STAMP_ANNOTATION", defaults.stamp_annotation)?,
+                kinds: match env::var("GRAVIVOL_KINDS") {
+                    Ok(value) => parse_kinds(&value)?,
+                    Err(_) => defaults.kinds,
+                },
+                label_pvcs: env_bool("GRAVIVOL_LABEL_PVCS", defaults.label_pvcs)?,
+                patch_mode: env_choice(
+                    "GRAVIVOL_PATCH",
+                    defaults.patch_mode,
+                    PatchMode::from_name,
+                    "labels, affinity or both",
+                )?,
+                node_affinity: env_choice(
+                    "GRAVIVOL_NODE_AFFINITY",
+                    defaults.node_affinity,
+                    NodeAffinityMode::from_name,
+                    "off, add or replace",
+                )?,
+                first_pod: env_choice(
+                    "GRAVIVOL_FIRST_POD",
+                    defaults.first_pod,
+                    FirstPodMode::from_name,
+                    "off, anchor or preferred",
+                )?,
+                guarded_patch: env_bool("GRAVIVOL_GUARDED_PATCH", defaults.guarded_patch)?,
+                skip_daemonsets: env_bool("GRAVIVOL_SKIP_DAEMONSETS", defaults.skip_daemonsets)?,
+                volume_scan_limit: env_number(
+                    "GRAVIVOL_VOLUME_SCAN_LIMIT",
+                    defaults.volume_scan_limit,
+                    "volumes",
+                )?,
+                max_patch_bytes: env_number(
+                    "GRAVIVOL_MAX_PATCH_BYTES",
+                    defaults.max_patch_bytes,
+                    "bytes",
+                )?,
+                label_value: env_choice(
+                    "GRAVIVOL_LABEL_VALUE",
+                    defaults.label_value,
+                    LabelValue::from_name,
+                    "true or uid",
+                )?,
+                group_by_owner: env_bool("GRAVIVOL_GROUP_BY_OWNER", defaults.group_by_owner)?,
+                replace_affinity_namespaces: match env::var("GRAVIVOL_REPLACE_AFFINITY_NAMESPACES")
+                {
+                    Ok(value) => value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|namespace| !namespace.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                    Err(_) => defaults.replace_affinity_namespaces,
+                },
+                access_modes: match env::var("GRAVIVOL_ACCESS_MODES") {
+                    Ok(value) => parse_access_modes(&value)?,
+                    Err(_) => defaults.access_modes,
+                },
+                provisioners: match env::var("GRAVIVOL_PROVISIONERS") {
+                    Ok(value) => parse_provisioners(&value),
+                    Err(_) => defaults.provisioners,
+                },
+                provisioners_deny: match env::var("GRAVIVOL_PROVISIONERS_DENY") {
+                    Ok(value) => parse_provisioners(&value),
+                    Err(_) => defaults.provisioners_deny,
+                },
+                unbound_claims: env_choice(
+                    "GRAVIVOL_UNBOUND_CLAIMS",
+                    defaults.unbound_claims,
+                    UnboundClaimMode::from_name,
+                    "off, skip or preferred",
+                )?,
+                failure_mode: env_choice(
+                    "GRAVIVOL_FAILURE_MODE",
+                    defaults.failure_mode,
+                    FailureMode::from_name,
+                    "open or closed",
+                )?,
+                namespace_selector: match env::var("GRAVIVOL_NAMESPACE_SELECTOR") {
+                    Ok(value) if !value.trim().is_empty() => Some(serde_json::from_value(
+                        parse_label_selector("GRAVIVOL_NAMESPACE_SELECTOR", &value)?,
+                    )?),
+                    _ => None,
+                },
+                validate_mode: env_choice(
+                    "GRAVIVOL_VALIDATE_MODE",
+                    defaults.validate_mode,
+                    ValidateMode::from_name,
+                    "deny or warn",
+                )?,
+                scheduling_gate: env_bool("GRAVIVOL_SCHEDULING_GATE", defaults.scheduling_gate)?,
+                preferred_weight: match env::var("GRAVIVOL_PREFERRED_WEIGHT") {
+                    Ok(value) => parse_weight("GRAVIVOL_PREFERRED_WEIGHT", &value)?,
+                    Err(_) => defaults.preferred_weight,
+                },
+                anti_affinity_weight: match env::var("GRAVIVOL_ANTI_AFFINITY_WEIGHT") {
+                    Ok(value) => parse_weight("GRAVIVOL_ANTI_AFFINITY_WEIGHT", &value)?,
+                    Err(_) => defaults.anti_affinity_weight,
+                },
+                spread: SpreadOptions {
+                    max_skew: match env::var("GRAVIVOL_SPREAD_MAX_SKEW") {
+                        Ok(value) => parse_max_skew(&value)?,
+                        Err(_) => defaults.spread.max_skew,
+                    },
+                    when_unsatisfiable: env_choice(
+                        "GRAVIVOL_SPREAD_WHEN_UNSATISFIABLE",
+                        defaults.spread.when_unsatisfiable,
+                        WhenUnsatisfiable::from_name,
+                        "DoNotSchedule or ScheduleAnyway",
+                    )?,
+                    topology_key: match env::var("GRAVIVOL_SPREAD_TOPOLOGY_KEY") {
+                        Ok(value) if is_valid_label_key(&value) => value,
+                        Ok(value) => {
+                            return Err(format!(
+                                "GRAVIVOL_SPREAD_TOPOLOGY_KEY must be a label key but is '{value}'"
+                            )
+                            .into());
+                        }
+                        Err(_) => defaults.spread.topology_key,
+                    },
+                },
+            },
+        };
+        settings.check_listeners()?;
+        if settings.register_webhook && !settings.tls {
+            return Err(
+                "GRAVIVOL_REGISTER_WEBHOOK requires TLS, the API server only calls webhooks over HTTPS"
+                    .into(),
+            );
+        }
+        if settings.validate
+            && settings
+                .mutate_paths
+                .iter()
+                .any(|path| path == VALIDATE_PATH)
+        {
+            return Err(format!(
+                "GRAVIVOL_MUTATE_PATH must not contain {VALIDATE_PATH} with GRAVIVOL_VALIDATE"
+            )
+            .into());
+        }
+        if settings.deregister_on_shutdown != DeregisterMode::Off && !settings.register_webhook {
+            return Err(
+                "GRAVIVOL_DEREGISTER_ON_SHUTDOWN requires GRAVIVOL_REGISTER_WEBHOOK, it only removes the registered configuration"
+                    .into(),
+            );
+        }
+        Ok(settings)
+    }
+
+    /// The settings of the enabled features that need the Kubernetes API
+    pub fn api_features(&self) -> Vec<&'static str> {
+        let mut features = self.controller.api_features();
+        for (enabled, setting) in [
+            (self.register_webhook, "GRAVIVOL_REGISTER_WEBHOOK"),
+            (self.emit_events, "GRAVIVOL_EMIT_EVENTS"),
+            (self.audit, "GRAVIVOL_AUDIT"),
+            (
+                self.register_webhook && self.deregister_on_shutdown == DeregisterMode::Delete,
+                "GRAVIVOL_DEREGISTER_ON_SHUTDOWN",
+            ),
+            (
+                self.leader_election && self.background_tasks(),
+                "GRAVIVOL_LEADER_ELECTION",
+            ),
+        ] {
+            if enabled {
+                features.push(setting);
+            }
+        }
+        features
+    }
+
+    /// Whether tasks run besides admission that only the leader runs with leader election
+    pub fn background_tasks(&self) -> bool {
+        self.controller.scheduling_gate || self.audit || self.register_webhook
+    }
+
+    /// Turns off the feature enabled by the setting, handling requests as if its lookups failed
+    pub fn disable(&mut self, feature: &'static str) {
+        let controller = &mut self.controller;
+        match feature {
+            "GRAVIVOL_NODE_AFFINITY" => controller.node_affinity = NodeAffinityMode::Off,
+            "GRAVIVOL_FIRST_POD" => controller.first_pod = FirstPodMode::Off,
+            "GRAVIVOL_SCHEDULING_GATE" => controller.scheduling_gate = false,
+            "GRAVIVOL_LABEL_VALUE" => controller.label_value = LabelValue::True,
+            "GRAVIVOL_ACCESS_MODES" => controller.access_modes.clear(),
+            "GRAVIVOL_UNBOUND_CLAIMS" => controller.unbound_claims = UnboundClaimMode::Off,
+            "GRAVIVOL_PROVISIONERS" => controller.provisioners.clear(),
+            "GRAVIVOL_PROVISIONERS_DENY" => controller.provisioners_deny.clear(),
+            "GRAVIVOL_NAMESPACE_SELECTOR" => controller.namespace_selector = None,
+            "GRAVIVOL_REGISTER_WEBHOOK" => self.register_webhook = false,
+            "GRAVIVOL_EMIT_EVENTS" => self.emit_events = false,
+            "GRAVIVOL_AUDIT" => self.audit = false,
+            "GRAVIVOL_DEREGISTER_ON_SHUTDOWN" => self.deregister_on_shutdown = DeregisterMode::Off,
+            // Each replica then runs the background tasks
+            "GRAVIVOL_LEADER_ELECTION" => self.leader_election = false,
+            _ => return,
+        }
+        self.disabled_features.push(feature);
+    }
+
+    /// There is a listener, and TLS is disabled if one is the Unix socket
+    fn check_listeners(&self) -> Result<(), Box<dyn Error>> {
+        match (self.bind.first(), &self.bind_uds) {
+            (None, None) => {
+                Err("GRAVIVOL_BIND may only be empty when GRAVIVOL_BIND_UDS is set".into())
+            }
+            (_, Some(_)) if self.tls => Err(
+                "GRAVIVOL_BIND_UDS serves plain HTTP and requires GRAVIVOL_TLS=disabled or GRAVIVOL_INSECURE_HTTP"
+                    .into(),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn env_bool(name: &str, default: bool) -> Result<bool, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => parse_bool(name, &value),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A value out of a fixed set of names
+fn env_choice<T>(
+    name: &str,
+    default: T,
+    from_name: fn(&str) -> Option<T>,
+    expected: &str,
+) -> Result<T, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => from_name(&value)
+            .ok_or_else(|| format!("{name} must be {expected} but is '{value}'").into()),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A non-negative number, e.g. of bytes or days
+fn env_number<T: FromStr>(name: &str, default: T, unit: &str) -> Result<T, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{name} must be a number of {unit} but is '{value}'").into()),
+        Err(_) => Ok(default),
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, Box<dyn Error>> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(format!("{name} must be true or false but is '{value}'").into()),
+    }
+}
+
+fn parse_max_skew(value: &str) -> Result<u32, Box<dyn Error>> {
+    match value.parse() {
+        Ok(max_skew) if max_skew > 0 => Ok(max_skew),
+        _ => Err(
+            format!("GRAVIVOL_SPREAD_MAX_SKEW must be a positive number but is '{value}'").into(),
+        ),
+    }
+}
+
+fn parse_weight(name: &str, value: &str) -> Result<u32, Box<dyn Error>> {
+    match value.parse() {
+        Ok(weight) if (1..=100).contains(&weight) => Ok(weight),
+        _ => Err(format!("{name} must be between 1 and 100 but is '{value}'").into()),
+    }
+}
+
+/// An IPv4 or bracketed IPv6 address or a host name, followed by a port
+fn parse_bind(name: &str, value: &str) -> Result<String, Box<dyn Error>> {
+    let is_host_name = |host: &str| {
+        !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    };
+    let valid = value.parse::<SocketAddr>().is_ok()
+        || value
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| is_host_name(host) && port.parse::<u16>().is_ok());
+    if valid {
+        Ok(value.to_owned())
+    } else {
+        Err(format!("{name} must be <host>:<port> but is '{value}'").into())
+    }
+}
+
+/// Comma separated list of addresses as for [parse_bind], may be empty
+fn parse_binds(name: &str, value: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|bind| !bind.is_empty())
+        .map(|bind| parse_bind(name, bind))
+        .collect()
+}
+
+/// Octal file permissions, e.g. "0660"
+fn parse_mode(value: &str) -> Result<u32, Box<dyn Error>> {
+    match u32::from_str_radix(value.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("GRAVIVOL_UDS_MODE must be octal permissions but is '{value}'").into()),
+    }
+}
+
+/// Comma separated list of absolute URL paths, e.g. "/mutate/pods,/mutate/workloads"
+fn parse_paths(value: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let paths: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if paths.is_empty() {
+        return Err("GRAVIVOL_MUTATE_PATH must name at least one path".into());
+    }
+    match paths.iter().find(|path| !path.starts_with('/')) {
+        Some(path) => Err(format!("GRAVIVOL_MUTATE_PATH: path '{path}' must start with /").into()),
+        None => Ok(paths),
+    }
+}
+
+/// A LabelSelector in JSON, e.g. {"matchLabels":{"gravivol":"enabled"}}
+fn parse_label_selector(name: &str, value: &str) -> Result<Value, Box<dyn Error>> {
+    let selector: Value = serde_json::from_str(value)
+        .map_err(|err| format!("{name} must be a LabelSelector in JSON: {err}"))?;
+    serde_json::from_value::<LabelSelector>(selector.clone())
+        .map_err(|err| format!("{name} must be a LabelSelector in JSON: {err}"))?;
+    Ok(selector)
+}
+
+/// Comma separated list of cipher suite names, e.g. "TLS13_AES_256_GCM_SHA384"
+fn parse_ciphers(value: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let known = tls::cipher_suite_names();
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            if known.contains(&name) {
+                Ok(name.to_owned())
+            } else {
+                Err(format!(
+                    "GRAVIVOL_TLS_CIPHERS: unknown cipher suite '{name}', valid are {}",
+                    known.join(", ")
+                )
+                .into())
+            }
+        })
+        .collect()
+}
+
+/// Comma separated list of provisioners, e.g. "rancher.io/local-path,openebs.io/local"
+fn parse_provisioners(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|provisioner| !provisioner.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Comma separated list of access modes, e.g. "ReadWriteOnce,ReadWriteOncePod"
+fn parse_access_modes(value: &str) -> Result<HashSet<AccessMode>, Box<dyn Error>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            AccessMode::from_name(name).ok_or_else(|| {
+                format!("GRAVIVOL_ACCESS_MODES: unknown access mode '{name}'").into()
+            })
+        })
+        .collect()
+}
+
+/// Comma separated list of kinds, e.g. "Pod,Deployment"
+fn parse_kinds(value: &str) -> Result<HashSet<Kind>, Box<dyn Error>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            Kind::from_name(name)
+                .ok_or_else(|| format!("GRAVIVOL_KINDS: unknown kind '{name}'").into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bool() {
+        assert!(parse_bool("X", "true").unwrap());
+        assert!(parse_bool("X", "On").unwrap());
+        assert!(!parse_bool("X", "false").unwrap());
+        assert!(!parse_bool("X", "0").unwrap());
+        assert_eq!(
+            parse_bool("X", "maybe").unwrap_err().to_string(),
+            "X must be true or false but is 'maybe'"
+        );
+    }
+
+    #[test]
+    fn test_parse_label_selector() {
+        assert_eq!(
+            parse_label_selector("X", r#"{"matchLabels":{"gravivol":"enabled"}}"#).unwrap(),
+            serde_json::json!({ "matchLabels": { "gravivol": "enabled" } })
+        );
+        assert!(parse_label_selector("X", "gravivol=enabled").is_err());
+        assert!(parse_label_selector("X", r#"{"matchLabels":["gravivol"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_kinds() {
+        assert_eq!(
+            parse_kinds("Pod, Deployment,").unwrap(),
+            HashSet::from([Kind::Pod, Kind::Deployment])
+        );
+        assert!(parse_kinds("Pod,ReplicaSet").is_err());
+    }
+
+    #[test]
+    fn test_parse_access_modes() {
+        assert_eq!(
+            parse_access_modes("ReadWriteOnce, ReadWriteOncePod").unwrap(),
+            HashSet::from([AccessMode::ReadWriteOnce, AccessMode::ReadWriteOncePod])
+        );
+        assert!(parse_access_modes("").unwrap().is_empty());
+        assert!(parse_access_modes("ReadWriteOnce,RWX").is_err());
+    }
+
+    #[test]
+    fn test_parse_weight() {
+        assert_eq!(parse_weight("X", "100").unwrap(), 100);
+        assert!(parse_weight("X", "0").is_err());
+        assert!(parse_weight("X", "101").is_err());
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        for value in ["[::]:8080", "0.0.0.0:443", "[::1]:8443", "localhost:8080"] {
+            assert_eq!(parse_bind("X", value).unwrap(), value);
+        }
+        for value in [
+            "::1:8080",
+            "localhost",
+            ":8080",
+            "localhost:http",
+            "host:70000",
+        ] {
+            assert!(parse_bind("X", value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn test_parse_binds() {
+        assert_eq!(
+            parse_binds("X", "0.0.0.0:8080, [::1]:8080,").unwrap(),
+            vec!["0.0.0.0:8080", "[::1]:8080"]
+        );
+        assert!(parse_binds("X", "").unwrap().is_empty());
+        assert_eq!(
+            parse_binds("X", "0.0.0.0:8080,::1:8080")
+                .unwrap_err()
+                .to_string(),
+            "X must be <host>:<port> but is '::1:8080'"
+        );
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0o600").unwrap(), 0o600);
+        assert_eq!(parse_mode("777").unwrap(), 0o777);
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("0680").is_err());
+    }
+
+    #[test]
+    fn test_check_listeners() {
+        let settings = |tls, bind: Option<&str>, bind_uds: Option<&str>| Settings {
+            tls,
+            bind: bind.map(str::to_owned).into_iter().collect(),
+            bind_uds: bind_uds.map(str::to_owned),
+            ..Settings::from_env().unwrap()
+        };
+        assert!(
+            settings(true, Some("[::]:8080"), None)
+                .check_listeners()
+                .is_ok()
+        );
+        assert!(
+            settings(false, None, Some("/run/gravivol.sock"))
+                .check_listeners()
+                .is_ok()
+        );
+        assert!(
+            settings(false, Some("[::]:8080"), Some("/run/gravivol.sock"))
+                .check_listeners()
+                .is_ok()
+        );
+        assert!(
+            settings(true, None, Some("/run/gravivol.sock"))
+                .check_listeners()
+                .is_err()
+        );
+        assert!(settings(false, None, None).check_listeners().is_err());
+    }
+
+    #[test]
+    fn test_parse_paths() {
+        assert_eq!(
+            parse_paths("/mutate/pods, /mutate/workloads").unwrap(),
+            vec!["/mutate/pods", "/mutate/workloads"]
+        );
+        assert!(parse_paths(" , ").is_err());
+        assert!(parse_paths("/mutate,pods").is_err());
+    }
+
+    #[test]
+    fn test_parse_ciphers() {
+        assert_eq!(
+            parse_ciphers("TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256").unwrap(),
+            vec!["TLS13_AES_256_GCM_SHA384", "TLS13_CHACHA20_POLY1305_SHA256"]
+        );
+        assert!(parse_ciphers("").unwrap().is_empty());
+        let err = parse_ciphers("TLS13_AES_256_GCM_SHA384,TLS_RSA_WITH_RC4_128_MD5")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("GRAVIVOL_TLS_CIPHERS: unknown cipher suite 'TLS_RSA_WITH_RC4_128_MD5', valid are TLS13_"),
+            "{err}"
+        );
+        assert!(
+            err.contains("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_max_skew() {
+        assert_eq!(parse_max_skew("2").unwrap(), 2);
+        assert!(parse_max_skew("0").is_err());
+        assert!(parse_max_skew("-1").is_err());
+    }
+}
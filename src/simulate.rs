@@ -0,0 +1,335 @@
+//! The simulate command, answering a pod from a file like the webhook would, without a cluster
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use json_patch::Patch;
+use serde_json::{Value, json};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    controller::{AdmissionReview, Controller, Options, validate_config},
+    decisions::Decisions,
+    logging::{self, LogFormat},
+    settings::Settings,
+};
+
+/// Printed on invalid arguments
+pub const USAGE: &str = "\
+Usage: gravivol simulate [--config <entries or file>] [--pod <file> | --review <file>] [--apply]
+
+Answers the pod or the AdmissionReview like the webhook would and prints the patch, the skip
+reason and the warnings as JSON. Without --pod and --review, an AdmissionReview is read from
+stdin. Files may be YAML or JSON.
+
+  --config <entries or file>  Claims to co-locate like GRAVIVOL_CONFIG, which is the default
+  --pod <file>                A pod, or a workload of the kinds in GRAVIVOL_KINDS
+  --review <file>             An AdmissionReview, - for stdin
+  --apply                     Also print the object with the patch applied
+
+The other GRAVIVOL_ variables configure the controller like the webhook. Nothing is looked up in
+the Kubernetes API, the checks needing it are skipped.";
+
+/// The controller failed to answer, the webhook would have failed the request
+const EXIT_FAILED: i32 = 1;
+/// Invalid arguments, config or input
+const EXIT_USAGE: i32 = 2;
+
+/// Namespace of a pod without one, like kubectl without a namespace in the context
+const DEFAULT_NAMESPACE: &str = "default";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    /// The entries, or a file with them
+    config: Option<String>,
+    pod: Option<String>,
+    /// - for stdin, the default without a pod
+    review: Option<String>,
+    apply: bool,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--config" => parsed.config = Some(value()?),
+                "--pod" => parsed.pod = Some(value()?),
+                "--review" => parsed.review = Some(value()?),
+                "--apply" => parsed.apply = true,
+                other => return Err(format!("unknown argument {other}")),
+            }
+        }
+        if parsed.pod.is_some() && parsed.review.is_some() {
+            return Err("only one of --pod and --review can be given".to_owned());
+        }
+        Ok(parsed)
+    }
+}
+
+/// Runs the command with the arguments after simulate, the exit code
+pub async fn run(args: &[String]) -> i32 {
+    // Only warnings by default, the output is the answer
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    let _logging = tracing::dispatcher::set_default(&logging::dispatch(
+        LogFormat::Text,
+        filter,
+        io::stderr,
+        false,
+        None,
+    ));
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return 0;
+    }
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    let prepared = Settings::from_env()
+        .map_err(|err| err.to_string())
+        .and_then(|settings| {
+            let config = match &args.config {
+                Some(config) => read_config(config)?,
+                None => settings.config,
+            };
+            validate_config(&config)?;
+            Ok((config, settings.controller, read_review(&args)?))
+        });
+    let (config, options, review) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            eprintln!("{err}");
+            return EXIT_USAGE;
+        }
+    };
+    match simulate(&config, options, review, args.apply).await {
+        Ok(output) => {
+            // A closed stdout is no failure, e.g. when piped into head
+            let _ = writeln!(io::stdout(), "{output:#}");
+            0
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            EXIT_FAILED
+        }
+    }
+}
+
+/// The entries of the config, read from the file if one exists at the path
+///
+/// A file may have an entry per line as well, # starts a comment.
+fn read_config(config: &str) -> Result<String, String> {
+    if !Path::new(config).is_file() {
+        return Ok(config.to_owned());
+    }
+    let text = fs::read_to_string(config).map_err(|err| format!("cannot read {config}: {err}"))?;
+    let entries: Vec<&str> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    Ok(entries.join(","))
+}
+
+/// The review to answer as JSON, the pod wrapped into a CREATE request
+fn read_review(args: &Args) -> Result<Value, String> {
+    let Some(path) = &args.pod else {
+        return read_document(args.review.as_deref().unwrap_or("-"));
+    };
+    let pod = read_document(path)?;
+    if !pod.is_object() {
+        return Err(format!("{path} does not contain an object"));
+    }
+    let namespace = pod["metadata"]["namespace"]
+        .as_str()
+        .unwrap_or(DEFAULT_NAMESPACE)
+        .to_owned();
+    Ok(json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "simulate",
+            "operation": "CREATE",
+            "namespace": namespace,
+            "object": pod,
+        },
+    }))
+}
+
+/// A YAML or JSON document from the file, - for stdin
+fn read_document(path: &str) -> Result<Value, String> {
+    let text = if path == "-" {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|err| format!("cannot read stdin: {err}"))?;
+        text
+    } else {
+        fs::read_to_string(path).map_err(|err| format!("cannot read {path}: {err}"))?
+    };
+    // JSON is YAML as well
+    serde_norway::from_str(&text).map_err(|err| format!("cannot parse {path}: {err}"))
+}
+
+/// Answers the review, the output of the command, Err if the controller failed
+async fn simulate(
+    config: &str,
+    options: Options,
+    review: Value,
+    apply: bool,
+) -> Result<Value, String> {
+    let decisions = Arc::new(Decisions::new(1));
+    let controller = Controller::new(config)
+        .with_options(options)
+        .with_decisions(decisions.clone());
+    let object = review["request"]["object"].clone();
+    let review: AdmissionReview =
+        serde_json::from_value(review).map_err(|err| format!("invalid AdmissionReview: {err}"))?;
+    let answered = controller
+        .mutate(review)
+        .await
+        .map_err(|err| format!("the webhook would fail the request: {err}"))?;
+    let response = answered
+        .response()
+        .ok_or("the webhook would answer without a response")?;
+
+    let patch: Value = match &response.patch {
+        Some(patch) => BASE64_STANDARD
+            .decode(patch)
+            .map_err(|err| err.to_string())
+            .and_then(|patch| serde_json::from_slice(&patch).map_err(|err| err.to_string()))
+            .map_err(|err| format!("cannot decode the patch: {err}"))?,
+        None => json!([]),
+    };
+    let mut output = json!({
+        "allowed": response.allowed,
+        "patch": patch,
+        "warnings": response.warnings,
+    });
+    if let Some(skip_reason) = decisions
+        .recent(None)
+        .first()
+        .and_then(|decision| decision.skip_reason)
+    {
+        output["skipReason"] = json!(skip_reason.as_str());
+    }
+    if let Some(status) = &response.status {
+        output["status"] = json!(status);
+    }
+    if apply {
+        let patch: Patch = serde_json::from_value(output["patch"].clone())
+            .map_err(|err| format!("cannot read the patch: {err}"))?;
+        let mut patched = object;
+        json_patch::patch(&mut patched, &patch)
+            .map_err(|err| format!("cannot apply the patch: {err}"))?;
+        output["patched"] = patched;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        Args::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(
+            args(&["--config", "default/data", "--pod", "pod.yaml", "--apply"]),
+            Ok(Args {
+                config: Some("default/data".to_owned()),
+                pod: Some("pod.yaml".to_owned()),
+                review: None,
+                apply: true,
+            })
+        );
+        assert_eq!(args(&[]), Ok(Args::default()));
+        assert_eq!(
+            args(&["--config"]),
+            Err("--config needs a value".to_owned())
+        );
+        assert_eq!(
+            args(&["--pod", "pod.yaml", "--review", "-"]),
+            Err("only one of --pod and --review can be given".to_owned())
+        );
+        assert_eq!(
+            args(&["--dry-run"]),
+            Err("unknown argument --dry-run".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_read_config() {
+        let path = std::env::temp_dir().join(format!("gravivol-config-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# Databases\ndefault/data,default/logs:spread\n\napps/cache # shared\n",
+        )
+        .unwrap();
+        let config = read_config(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            config,
+            Ok("default/data,default/logs:spread,apps/cache".to_owned())
+        );
+        assert_eq!(
+            read_config("default/data,apps/cache"),
+            Ok("default/data,apps/cache".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_simulate() {
+        let review = json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "simulate",
+                "namespace": "default",
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "metadata": { "name": "web" },
+                    "spec": { "volumes": [{ "name": "logs", "persistentVolumeClaim": { "claimName": "logs" } }] },
+                },
+            },
+        });
+        let output = simulate("default/data", Options::default(), review, true)
+            .await
+            .unwrap();
+        assert_eq!(output["allowed"], true);
+        assert_eq!(output["patch"], json!([]));
+        assert_eq!(output["skipReason"], "no_claims");
+        assert_eq!(output["patched"]["metadata"], json!({ "name": "web" }));
+
+        let err = simulate(
+            "",
+            Options::default(),
+            json!({ "kind": "AdmissionReview" }),
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.starts_with("invalid AdmissionReview"), "{err}");
+    }
+}
@@ -0,0 +1,92 @@
+//! Export of the spans of admission requests with OpenTelemetry
+//!
+//! Configured with the standard variables, e.g. OTEL_EXPORTER_OTLP_ENDPOINT. Without an endpoint
+//! no exporter is created and spans are only used for logging.
+
+use std::env;
+
+use actix_web::http::header::HeaderMap;
+use opentelemetry::{
+    Context,
+    propagation::{Extractor, TextMapPropagator},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, Tracer},
+};
+
+/// Whether an OTLP endpoint for traces is configured and the SDK is not disabled
+fn export_configured() -> bool {
+    let set = |name| env::var(name).is_ok_and(|value| !value.is_empty());
+    (set("OTEL_EXPORTER_OTLP_ENDPOINT") || set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"))
+        && !env::var("OTEL_SDK_DISABLED").is_ok_and(|value| value.eq_ignore_ascii_case("true"))
+}
+
+/// Provider exporting spans with OTLP over HTTP, None if no endpoint is configured
+pub fn tracer_provider() -> Result<Option<SdkTracerProvider>, ExporterBuildError> {
+    if !export_configured() {
+        return Ok(None);
+    }
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let mut resource = Resource::builder();
+    if env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name("gravivol");
+    }
+    Ok(Some(
+        SdkTracerProvider::builder()
+            .with_resource(resource.build())
+            .with_batch_exporter(exporter)
+            .build(),
+    ))
+}
+
+pub fn tracer(provider: &SdkTracerProvider) -> Tracer {
+    provider.tracer("gravivol")
+}
+
+/// Context of the traceparent header, e.g. of the API server, empty without it
+pub fn remote_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use opentelemetry::trace::TraceContextExt;
+
+    use super::*;
+
+    #[test]
+    fn test_remote_context() {
+        let mut headers = HeaderMap::new();
+        assert!(!remote_context(&headers).span().span_context().is_valid());
+
+        headers.insert(
+            HeaderName::from_static("traceparent"),
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        let context = remote_context(&headers);
+        let span_context = context.span().span_context().clone();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+    }
+}
@@ -0,0 +1,841 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::BufReader,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::rt::net::TcpStream;
+use base64::prelude::*;
+use rustls::{
+    DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig, SignatureScheme,
+    SupportedProtocolVersion,
+    client::danger::HandshakeSignatureValid,
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, UnixTime},
+    server::{
+        ClientHello, ResolvesServerCert, WebPkiClientVerifier,
+        danger::{ClientCertVerified, ClientCertVerifier},
+    },
+    sign::CertifiedKey,
+};
+use rustls_pemfile::Item;
+use serde::Serialize;
+use x509_parser::prelude::ASN1Time;
+
+use crate::health::Validity;
+
+fn open(path: &str) -> Result<File, String> {
+    File::open(path).map_err(|err| format!("Cannot open {path}: {err}"))
+}
+
+/// Loads the first private key of the file in PKCS#8, PKCS#1 or SEC1 format
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    let mut key_file = BufReader::new(open(path)?);
+    rustls_pemfile::private_key(&mut key_file)?.ok_or_else(|| {
+        format!(
+            "No private key found in {path}, tried PKCS#8 (BEGIN PRIVATE KEY), PKCS#1 (BEGIN RSA PRIVATE KEY) and SEC1 (BEGIN EC PRIVATE KEY)"
+        )
+        .into()
+    })
+}
+
+/// Loads the certificate chain followed by exactly one private key from one file
+fn load_bundle(
+    path: &str,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Box<dyn Error>> {
+    let mut certs = Vec::new();
+    let mut keys = Vec::new();
+    for item in rustls_pemfile::read_all(&mut BufReader::new(open(path)?)) {
+        match item? {
+            Item::X509Certificate(cert) => certs.push(cert),
+            Item::Pkcs1Key(key) => keys.push(PrivateKeyDer::Pkcs1(key)),
+            Item::Pkcs8Key(key) => keys.push(PrivateKeyDer::Pkcs8(key)),
+            Item::Sec1Key(key) => keys.push(PrivateKeyDer::Sec1(key)),
+            _ => {}
+        }
+    }
+    if certs.is_empty() {
+        return Err(format!("No certificates found in bundle {path}").into());
+    }
+    match keys.len() {
+        0 => Err(format!("No key found in bundle {path}").into()),
+        1 => Ok((certs, keys.remove(0))),
+        count => Err(format!("Multiple keys found in bundle {path}: {count}").into()),
+    }
+}
+
+/// Where the certificate chain and the key are read from
+#[derive(Clone, Debug)]
+pub enum CertSource {
+    Files {
+        cert_path: String,
+        key_path: String,
+    },
+    /// One PEM file with the chain, leaf first, and the key
+    Bundle(String),
+}
+
+impl CertSource {
+    fn paths(&self) -> Vec<&str> {
+        match self {
+            CertSource::Files {
+                cert_path,
+                key_path,
+            } => vec![cert_path, key_path],
+            CertSource::Bundle(path) => vec![path],
+        }
+    }
+
+    /// Modification times of the files
+    fn modified(&self) -> Option<Vec<SystemTime>> {
+        self.paths()
+            .into_iter()
+            .map(|path| fs::metadata(path).ok()?.modified().ok())
+            .collect()
+    }
+
+    /// Loads the certificate chain and the key, checking that they belong together
+    fn load(&self) -> Result<(CertifiedKey, Validity), Box<dyn Error>> {
+        // to create a self-signed temporary cert for testing:
+        // `gravivol gen-cert --dns localhost --out-dir .`
+        let (tls_certs, tls_key) = match self {
+            CertSource::Files {
+                cert_path,
+                key_path,
+            } => {
+                let mut certs_file = BufReader::new(open(cert_path)?);
+                let tls_certs =
+                    rustls_pemfile::certs(&mut certs_file).collect::<Result<Vec<_>, _>>()?;
+                if tls_certs.is_empty() {
+                    return Err(format!("No certificates found in {cert_path}").into());
+                }
+                (tls_certs, load_private_key(key_path)?)
+            }
+            CertSource::Bundle(path) => load_bundle(path)?,
+        };
+        let validity = Validity::from_der(&tls_certs[0])?;
+
+        let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&tls_key)?;
+        let certified_key = CertifiedKey::new(tls_certs, signing_key);
+        certified_key.keys_match()?;
+        Ok((certified_key, validity))
+    }
+}
+
+impl fmt::Display for CertSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertSource::Files {
+                cert_path,
+                key_path,
+            } => write!(f, "{cert_path} with key {key_path}"),
+            CertSource::Bundle(path) => write!(f, "bundle {path}"),
+        }
+    }
+}
+
+/// Serves the certificate of the files, swapped when they change
+#[derive(Debug)]
+pub struct CertReloader {
+    source: CertSource,
+    current: RwLock<Arc<CertifiedKey>>,
+    validity: RwLock<Validity>,
+    /// Modification times of the last load attempt
+    attempted: Mutex<Option<Vec<SystemTime>>>,
+}
+
+impl CertReloader {
+    pub fn new(source: CertSource) -> Result<CertReloader, Box<dyn Error>> {
+        let attempted = source.modified();
+        let (certified_key, validity) = source.load()?;
+        Ok(CertReloader {
+            source,
+            current: RwLock::new(Arc::new(certified_key)),
+            validity: RwLock::new(validity),
+            attempted: Mutex::new(attempted),
+        })
+    }
+
+    /// The served certificate chain in PEM, e.g. for the caBundle of the webhook configuration
+    pub fn chain_pem(&self) -> String {
+        let mut pem = String::new();
+        for cert in &self.current.read().unwrap().cert {
+            pem.push_str("-----BEGIN CERTIFICATE-----\n");
+            let encoded = BASE64_STANDARD.encode(cert);
+            for line in encoded.as_bytes().chunks(64) {
+                pem.push_str(std::str::from_utf8(line).expect("Base64 is ASCII"));
+                pem.push('\n');
+            }
+            pem.push_str("-----END CERTIFICATE-----\n");
+        }
+        pem
+    }
+
+    /// Validity of the served certificate
+    pub fn validity(&self) -> Validity {
+        *self.validity.read().unwrap()
+    }
+
+    /// Loads the files if they changed since the last attempt, returning the new validity
+    ///
+    /// The served certificate is kept if the files cannot be loaded, e.g. while only one of
+    /// them has been written yet. They are loaded again once they change again.
+    pub fn reload(&self) -> Option<Validity> {
+        let modified = self.source.modified()?;
+        {
+            let mut attempted = self.attempted.lock().unwrap();
+            if attempted.as_ref() == Some(&modified) {
+                return None;
+            }
+            *attempted = Some(modified);
+        }
+        match self.source.load() {
+            Ok((certified_key, validity)) => {
+                *self.current.write().unwrap() = Arc::new(certified_key);
+                *self.validity.write().unwrap() = validity;
+                tracing::info!(
+                    "Reloaded the certificate {}, valid until {}",
+                    self.source,
+                    validity.not_after()
+                );
+                Some(validity)
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Cannot reload the certificate {}, keeping the current one: {err}",
+                    self.source
+                );
+                None
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Handling of client certificates that are missing or not issued by the client CA
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Fail the handshake
+    Require,
+    /// Accept the connection with a warning, for the rollout
+    Log,
+}
+
+impl ClientAuthMode {
+    pub fn from_name(name: &str) -> Option<ClientAuthMode> {
+        match name {
+            "require" => Some(ClientAuthMode::Require),
+            "log" => Some(ClientAuthMode::Log),
+            _ => None,
+        }
+    }
+}
+
+/// Lowest TLS version accepted from clients
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn from_name(name: &str) -> Option<TlsVersion> {
+        match name {
+            "1.2" => Some(TlsVersion::Tls12),
+            "1.3" => Some(TlsVersion::Tls13),
+            _ => None,
+        }
+    }
+
+    fn protocol_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self {
+            TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+fn crypto_provider() -> CryptoProvider {
+    rustls::crypto::aws_lc_rs::default_provider()
+}
+
+/// Names of the cipher suites that can be allowed, e.g. TLS13_AES_256_GCM_SHA384
+pub fn cipher_suite_names() -> Vec<&'static str> {
+    crypto_provider()
+        .cipher_suites
+        .iter()
+        .filter_map(|suite| suite.suite().as_str())
+        .collect()
+}
+
+/// Accepts any client, logging those without a valid certificate
+#[derive(Debug)]
+struct LoggingClientCertVerifier(Arc<dyn ClientCertVerifier>);
+
+impl ClientCertVerifier for LoggingClientCertVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.0.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.0
+            .verify_client_cert(end_entity, intermediates, now)
+            .or_else(|err| {
+                tracing::warn!("Accepting a client with an invalid certificate: {err}");
+                Ok(ClientCertVerified::assertion())
+            })
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+fn client_cert_verifier(
+    ca_path: &str,
+    mode: ClientAuthMode,
+) -> Result<Arc<dyn ClientCertVerifier>, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_path)?)) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        return Err(format!("No CA certificate found in {ca_path}").into());
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+    Ok(match mode {
+        ClientAuthMode::Require => verifier,
+        ClientAuthMode::Log => Arc::new(LoggingClientCertVerifier(verifier)),
+    })
+}
+
+/// Server config, verifying client certificates if a client CA is given
+///
+/// Only the named cipher suites are offered unless ciphers is empty.
+pub fn server_config(
+    reloader: Arc<CertReloader>,
+    client_ca_path: Option<&str>,
+    client_auth: ClientAuthMode,
+    min_version: TlsVersion,
+    ciphers: &[String],
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let mut provider = crypto_provider();
+    if !ciphers.is_empty() {
+        provider.cipher_suites.retain(|suite| {
+            suite
+                .suite()
+                .as_str()
+                .is_some_and(|name| ciphers.iter().any(|cipher| cipher == name))
+        });
+    }
+    // Fails if none of the allowed cipher suites can be used with the versions
+    let builder = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(min_version.protocol_versions())?;
+    let builder = match client_ca_path {
+        Some(ca_path) => {
+            builder.with_client_cert_verifier(client_cert_verifier(ca_path, client_auth)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_cert_resolver(reloader))
+}
+
+/// Logs the negotiated protocol at debug level and, if asked, connections without client
+/// certificate, for HttpServer::on_connect
+pub fn log_connection(connection: &dyn Any, log_missing_client_cert: bool) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (tcp, tls) = stream.get_ref();
+        let peer = || {
+            tcp.peer_addr()
+                .map_or_else(|_| "unknown peer".to_owned(), |addr| addr.to_string())
+        };
+        if let (Some(version), Some(suite)) =
+            (tls.protocol_version(), tls.negotiated_cipher_suite())
+        {
+            tracing::debug!(
+                "Negotiated {version:?} with {:?} for {}",
+                suite.suite(),
+                peer()
+            );
+        }
+        if log_missing_client_cert && tls.peer_certificates().is_none() {
+            tracing::warn!("Accepting a client without certificate from {}", peer());
+        }
+    }
+}
+
+/// Periodically reloads the changed certificate files, reporting the validity of the served one
+pub async fn watch(
+    reloader: Arc<CertReloader>,
+    interval: Duration,
+    mut on_tick: impl FnMut(Validity),
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        reloader.reload();
+        on_tick(reloader.validity());
+    }
+}
+
+/// Warns about the upcoming expiry of the served certificate, at most once a day
+pub struct ExpiryWarning {
+    threshold: Duration,
+    last: Option<Instant>,
+}
+
+impl ExpiryWarning {
+    const EVERY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn new(threshold: Duration) -> ExpiryWarning {
+        ExpiryWarning {
+            threshold,
+            last: None,
+        }
+    }
+
+    pub fn check(&mut self, validity: Validity) {
+        let expires_in = validity.expires_in(ASN1Time::now());
+        if !self.due(expires_in, Instant::now()) {
+            return;
+        }
+        if expires_in < 0 {
+            tracing::error!("The served certificate expired at {}", validity.not_after());
+        } else {
+            tracing::warn!(
+                "The served certificate expires in {} days at {}",
+                expires_in / (24 * 60 * 60),
+                validity.not_after()
+            );
+        }
+    }
+
+    /// Whether a certificate expiring in the given seconds is to be warned about now
+    fn due(&mut self, expires_in: i64, now: Instant) -> bool {
+        if expires_in > self.threshold.as_secs() as i64 {
+            // Renewed, a later certificate warns right away again
+            self.last = None;
+            return false;
+        }
+        match self.last {
+            Some(last) if now.duration_since(last) < ExpiryWarning::EVERY => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A directory for the files of a test, removed on drop
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir().join(format!("gravivol-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.0.join(file).to_str().unwrap().to_owned()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn generate(name: &str) -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        (certified.cert.pem(), certified.signing_key.serialize_pem())
+    }
+
+    fn files(cert_path: &str, key_path: &str) -> CertSource {
+        CertSource::Files {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+        }
+    }
+
+    fn served_cert(reloader: &CertReloader) -> Vec<u8> {
+        reloader.current.read().unwrap().cert[0].to_vec()
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TestDir::new("reload");
+        let (cert_path, key_path) = (dir.path("cert.pem"), dir.path("key.pem"));
+        let (first_cert, first_key) = generate("first.example.com");
+        fs::write(&cert_path, &first_cert).unwrap();
+        fs::write(&key_path, &first_key).unwrap();
+
+        let reloader = CertReloader::new(files(&cert_path, &key_path)).unwrap();
+        let served = served_cert(&reloader);
+        assert!(reloader.reload().is_none());
+
+        // Only the certificate written yet, the old one is kept
+        let (second_cert, second_key) = generate("second.example.com");
+        fs::write(&cert_path, &second_cert).unwrap();
+        assert!(reloader.reload().is_none());
+        assert_eq!(served_cert(&reloader), served);
+
+        fs::write(&key_path, &second_key).unwrap();
+        assert!(reloader.reload().is_some());
+        assert_ne!(served_cert(&reloader), served);
+
+        // A partially written certificate
+        fs::write(&cert_path, &first_cert[..first_cert.len() / 2]).unwrap();
+        let served = served_cert(&reloader);
+        assert!(reloader.reload().is_none());
+        assert_eq!(served_cert(&reloader), served);
+
+        // A key file without key
+        fs::write(&cert_path, &second_cert).unwrap();
+        fs::write(&key_path, "").unwrap();
+        assert!(reloader.reload().is_none());
+        assert_eq!(served_cert(&reloader), served);
+    }
+
+    /// CA and certificates of the server and a client signed by it
+    struct Pki {
+        ca: String,
+        server: (String, String),
+        client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+        other_client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+    }
+
+    fn generate_pki() -> Pki {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .signed_by(&server_key, &issuer)
+            .unwrap();
+        let client = |issuer| {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["kube-apiserver".to_owned()])
+                .unwrap()
+                .signed_by(&key, issuer)
+                .unwrap();
+            (
+                cert.der().clone(),
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )
+        };
+        let other_ca_key = KeyPair::generate().unwrap();
+        let mut other_ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        other_ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Pki {
+            ca: ca.pem(),
+            server: (server.pem(), server_key.serialize_pem()),
+            client: client(&issuer),
+            other_client: client(&Issuer::new(other_ca_params, other_ca_key)),
+        }
+    }
+
+    /// Status line of a GET request for /livez over TLS
+    async fn get_livez(
+        addr: std::net::SocketAddr,
+        ca: &str,
+        client_cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> std::io::Result<String> {
+        get_livez_with(addr, ca, client_cert, rustls::DEFAULT_VERSIONS).await
+    }
+
+    async fn get_livez_with(
+        addr: std::net::SocketAddr,
+        ca: &str,
+        client_cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> std::io::Result<String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut ca.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = rustls::ClientConfig::builder_with_protocol_versions(versions)
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let tcp = tokio::net::TcpStream::connect(addr).await?;
+        let mut stream = connector
+            .connect("localhost".try_into().unwrap(), tcp)
+            .await?;
+        stream
+            .write_all(b"GET /livez HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response.lines().next().unwrap_or_default().to_owned())
+    }
+
+    #[actix_web::test]
+    async fn test_client_auth() {
+        use actix_web::{App, HttpServer, get};
+
+        #[get("/livez")]
+        async fn livez() -> &'static str {
+            "OK"
+        }
+
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let pki = generate_pki();
+        let dir = TestDir::new("client-auth");
+        let (cert_path, key_path, ca_path) = (
+            dir.path("cert.pem"),
+            dir.path("key.pem"),
+            dir.path("ca.pem"),
+        );
+        fs::write(&cert_path, &pki.server.0).unwrap();
+        fs::write(&key_path, &pki.server.1).unwrap();
+        fs::write(&ca_path, &pki.ca).unwrap();
+        let reloader = Arc::new(CertReloader::new(files(&cert_path, &key_path)).unwrap());
+
+        for mode in [ClientAuthMode::Require, ClientAuthMode::Log] {
+            let config = server_config(
+                reloader.clone(),
+                Some(&ca_path),
+                mode,
+                TlsVersion::Tls12,
+                &[],
+            )
+            .unwrap();
+            let server = HttpServer::new(|| App::new().service(livez))
+                .workers(1)
+                .on_connect(move |connection, _| {
+                    log_connection(connection, mode == ClientAuthMode::Log)
+                })
+                .bind_rustls_0_23("127.0.0.1:0", config)
+                .unwrap();
+            let addr = server.addrs()[0];
+            let server = server.run();
+            let handle = server.handle();
+            tokio::spawn(server);
+
+            let identity = |(cert, key): &(CertificateDer<'static>, PrivateKeyDer<'static>)| {
+                Some((cert.clone(), key.clone_key()))
+            };
+            let ok = Some("HTTP/1.1 200 OK".to_owned());
+            let with_cert = get_livez(addr, &pki.ca, identity(&pki.client)).await;
+            assert_eq!(with_cert.ok(), ok);
+            let without_cert = get_livez(addr, &pki.ca, None).await.ok();
+            let other_cert = get_livez(addr, &pki.ca, identity(&pki.other_client))
+                .await
+                .ok();
+            match mode {
+                ClientAuthMode::Require => {
+                    assert_ne!(without_cert, ok);
+                    assert_ne!(other_cert, ok);
+                }
+                ClientAuthMode::Log => {
+                    assert_eq!(without_cert, ok);
+                    assert_eq!(other_cert, ok);
+                }
+            }
+            handle.stop(true).await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_min_version() {
+        use actix_web::{App, HttpServer, get};
+
+        #[get("/livez")]
+        async fn livez() -> &'static str {
+            "OK"
+        }
+
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let pki = generate_pki();
+        let dir = TestDir::new("min-version");
+        let (cert_path, key_path) = (dir.path("cert.pem"), dir.path("key.pem"));
+        fs::write(&cert_path, &pki.server.0).unwrap();
+        fs::write(&key_path, &pki.server.1).unwrap();
+        let reloader = Arc::new(CertReloader::new(files(&cert_path, &key_path)).unwrap());
+        let ciphers = ["TLS13_AES_256_GCM_SHA384".to_owned()];
+        let config = server_config(
+            reloader.clone(),
+            None,
+            ClientAuthMode::Require,
+            TlsVersion::Tls13,
+            &ciphers,
+        )
+        .unwrap();
+        let server = HttpServer::new(|| App::new().service(livez))
+            .workers(1)
+            .bind_rustls_0_23("127.0.0.1:0", config)
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let tls13 = get_livez_with(addr, &pki.ca, None, &[&rustls::version::TLS13]).await;
+        assert_eq!(tls13.unwrap(), "HTTP/1.1 200 OK");
+        let tls12 = get_livez_with(addr, &pki.ca, None, &[&rustls::version::TLS12]).await;
+        assert!(tls12.is_err(), "{tls12:?}");
+        handle.stop(true).await;
+
+        // No allowed cipher suite for TLS 1.3
+        let ciphers = ["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_owned()];
+        assert!(
+            server_config(
+                reloader,
+                None,
+                ClientAuthMode::Require,
+                TlsVersion::Tls13,
+                &ciphers
+            )
+            .is_err()
+        );
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_key_formats() {
+        for (cert, key, format) in [
+            ("rsa-cert.pem", "rsa-pkcs8.pem", "Pkcs8"),
+            ("rsa-cert.pem", "rsa-pkcs1.pem", "Pkcs1"),
+            ("ec-cert.pem", "ec-sec1.pem", "Sec1"),
+        ] {
+            let tls_key = load_private_key(&fixture(key)).unwrap();
+            assert!(format!("{tls_key:?}").starts_with(format), "{key}");
+            assert!(files(&fixture(cert), &fixture(key)).load().is_ok(), "{key}");
+        }
+
+        let err = load_private_key(&fixture("rsa-cert.pem")).unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "No private key found in {}",
+            fixture("rsa-cert.pem")
+        )));
+    }
+
+    #[test]
+    fn test_bundle() {
+        let bundle = fixture("bundle.pem");
+        let (certified_key, _) = CertSource::Bundle(bundle.clone()).load().unwrap();
+        let chain: Vec<_> =
+            rustls_pemfile::certs(&mut BufReader::new(File::open(&bundle).unwrap()))
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(certified_key.cert, chain);
+
+        // The chain is written back as the certificates of the bundle
+        let reloader = CertReloader::new(CertSource::Bundle(bundle.clone())).unwrap();
+        let pem = reloader.chain_pem();
+        let parsed: Vec<_> = rustls_pemfile::certs(&mut pem.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parsed, chain);
+        assert!(pem.lines().all(|line| line.len() <= 64));
+
+        let dir = TestDir::new("bundle");
+        let contents = fs::read_to_string(&bundle).unwrap();
+        let (certs, key) = contents.split_at(contents.find("-----BEGIN EC").unwrap());
+        for (name, contents, error) in [
+            ("no-certs.pem", key.to_owned(), "No certificates found"),
+            ("no-key.pem", certs.to_owned(), "No key found"),
+            (
+                "two-keys.pem",
+                format!(
+                    "{contents}{}",
+                    fs::read_to_string(fixture("ec-sec1.pem")).unwrap()
+                ),
+                "Multiple keys found",
+            ),
+        ] {
+            let path = dir.path(name);
+            fs::write(&path, contents).unwrap();
+            let err = CertSource::Bundle(path).load().unwrap_err();
+            assert!(err.to_string().starts_with(error), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let day = 24 * 60 * 60;
+        let mut warning = ExpiryWarning::new(Duration::from_secs(14 * day as u64));
+        let now = Instant::now();
+        assert!(!warning.due(20 * day, now));
+        assert!(warning.due(13 * day, now));
+        assert!(!warning.due(13 * day, now + Duration::from_secs(60)));
+        assert!(warning.due(12 * day, now + ExpiryWarning::EVERY));
+        assert!(!warning.due(60 * day, now + ExpiryWarning::EVERY));
+        assert!(warning.due(-1, now + ExpiryWarning::EVERY));
+    }
+
+    #[test]
+    fn test_mismatched_key() {
+        let dir = TestDir::new("mismatched-key");
+        let (cert_path, key_path) = (dir.path("cert.pem"), dir.path("key.pem"));
+        let (cert, _) = generate("first.example.com");
+        let (_, key) = generate("second.example.com");
+        fs::write(&cert_path, cert).unwrap();
+        fs::write(&key_path, key).unwrap();
+        assert!(CertReloader::new(files(&cert_path, &key_path)).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! The server on a socket passed like systemd does on socket activation
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+
+#[test]
+fn test_passed_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (addr, fd) = (listener.local_addr().unwrap(), listener.as_raw_fd());
+    // The shell keeps its pid for gravivol, which systemd passes as LISTEN_PID
+    let mut command = Command::new("sh");
+    command
+        .args([
+            "-c",
+            "LISTEN_PID=$$ exec \"$0\"",
+            env!("CARGO_BIN_EXE_gravivol"),
+        ])
+        .env_clear()
+        .envs([
+            ("LISTEN_FDS", "1"),
+            ("LISTEN_FDNAMES", "webhook"),
+            ("GRAVIVOL_TLS", "disabled"),
+            ("GRAVIVOL_ADMIN_BIND", "127.0.0.1:0"),
+        ])
+        .stdout(Stdio::null());
+    // SAFETY: dup2 and fcntl are async-signal-safe, the descriptor stays open in the test
+    unsafe {
+        command.pre_exec(move || {
+            // Only descriptors without close-on-exec are inherited, as duplicates are
+            let result = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            match result {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    let mut child = command.spawn().unwrap();
+
+    let body = json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "705ab4f5",
+            "object": {
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "web", "namespace": "default" },
+                "spec": { "containers": [] },
+            },
+        }
+    })
+    .to_string();
+    // Queued on the socket until the server accepts it
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "POST /mutate HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    let read = stream.read_to_string(&mut response);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    read.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let review: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(review["response"]["uid"], "705ab4f5");
+    assert_eq!(review["response"]["allowed"], true);
+}
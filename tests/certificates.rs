@@ -0,0 +1,60 @@
+//! The gen-cert command run like from a shell, into a temporary directory
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use base64::prelude::*;
+
+/// Runs gravivol gen-cert without the environment of the test
+fn gen_cert(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gravivol"))
+        .arg("gen-cert")
+        .args(args)
+        .env_clear()
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_overwrite() {
+    let out_dir: PathBuf =
+        std::env::temp_dir().join(format!("gravivol-certificates-{}", std::process::id()));
+    let args = [
+        "--dns",
+        "gravivol.webhooks.svc",
+        "--out-dir",
+        out_dir.to_str().unwrap(),
+        "--days",
+        "7",
+    ];
+    let read = |file| fs::read_to_string(out_dir.join(file)).unwrap();
+
+    let output = gen_cert(&args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let ca_bundle = String::from_utf8(output.stdout).unwrap();
+    let cert = read("cert.pem");
+    assert_eq!(
+        BASE64_STANDARD.decode(ca_bundle.trim_end()).unwrap(),
+        cert.as_bytes()
+    );
+
+    // Existing files are kept
+    let refused = gen_cert(&args);
+    assert_eq!(refused.status.code(), Some(2));
+    assert!(refused.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("use --force to overwrite it"));
+    assert_eq!(read("cert.pem"), cert);
+
+    let forced = gen_cert(&[&args[..], &["--force"]].concat());
+    let replaced = read("cert.pem");
+    let _ = fs::remove_dir_all(&out_dir);
+    assert!(forced.status.success());
+    assert_ne!(replaced, cert);
+}
@@ -0,0 +1,194 @@
+//! The API of the library as used by admission servers embedding it
+
+use std::collections::HashSet;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use gravivol::{
+    AdmissionReview, ConfigError, Controller, ControllerError, Kind, Options, controller::ClaimMode,
+};
+use serde_json::{Value, json};
+
+fn review(object: Value) -> AdmissionReview {
+    serde_json::from_value(json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "705ab4f5",
+            "operation": "CREATE",
+            "object": object,
+        },
+    }))
+    .unwrap()
+}
+
+fn pod(claims: &[&str]) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": { "name": "web", "namespace": "default" },
+        "spec": {
+            "containers": [{ "name": "web", "image": "nginx" }],
+            "volumes": claims
+                .iter()
+                .map(|claim| json!({ "name": claim, "persistentVolumeClaim": { "claimName": claim } }))
+                .collect::<Vec<Value>>(),
+        },
+    })
+}
+
+fn patch_of(review: &AdmissionReview) -> Value {
+    let patch = review.response().unwrap().patch.as_deref().unwrap();
+    serde_json::from_slice(&BASE64_STANDARD.decode(patch).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_mutate() {
+    let controller = Controller::new("default/data");
+    let answered = controller
+        .mutate(review(pod(&["data", "logs"])))
+        .await
+        .unwrap();
+    assert!(answered.has_patch());
+    let response = answered.response().unwrap();
+    assert_eq!(response.uid, "705ab4f5");
+    assert!(response.allowed);
+    assert_eq!(response.patch_type.as_deref(), Some("JSONPatch"));
+    assert_eq!(
+        response.warnings,
+        ["gravivol: claim logs is not configured for co-location"]
+    );
+
+    let mut patched = pod(&["data", "logs"]);
+    let patch: json_patch::Patch = serde_json::from_value(patch_of(&answered)).unwrap();
+    json_patch::patch(&mut patched, &patch).unwrap();
+    assert_eq!(
+        patched["metadata"]["labels"]["default.gravivol.fonona.net/data"],
+        "true"
+    );
+    assert_eq!(
+        patched["spec"]["affinity"]["podAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+            [0]["labelSelector"]["matchLabels"],
+        json!({ "default.gravivol.fonona.net/data": "true" })
+    );
+
+    // The review as returned to the API server
+    let returned = serde_json::to_value(&answered).unwrap();
+    assert_eq!(returned["apiVersion"], "admission.k8s.io/v1");
+    assert_eq!(returned["kind"], "AdmissionReview");
+    assert!(returned.get("request").is_none());
+}
+
+#[tokio::test]
+async fn test_builder() {
+    let controller = Controller::builder()
+        .add_pvc_with_mode("default", "data", ClaimMode::Preferred(Some(50)))
+        .topology_key("topology.kubernetes.io/zone")
+        .build()
+        .unwrap();
+    let answered = controller.mutate(review(pod(&["data"]))).await.unwrap();
+    let affinity = patch_of(&answered)
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|operation| operation["path"] == "/spec/affinity")
+        .unwrap()["value"]
+        .clone();
+    assert_eq!(
+        affinity["podAffinity"]["preferredDuringSchedulingIgnoredDuringExecution"],
+        json!([{
+            "podAffinityTerm": {
+                "labelSelector": { "matchLabels": { "default.gravivol.fonona.net/data": "true" } },
+                "topologyKey": "topology.kubernetes.io/zone",
+            },
+            "weight": 50,
+        }])
+    );
+
+    let err = Controller::builder()
+        .add_pvc("default", "data")
+        .ignore_namespace("kube_system")
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(err, ConfigError::IgnoredNamespace("kube_system".to_owned()));
+    assert_eq!(err.field(), "ignore_namespace");
+}
+
+#[tokio::test]
+async fn test_options() {
+    let options = Options {
+        kinds: HashSet::from([Kind::Pod, Kind::Deployment]),
+        stamp_annotation: false,
+        ..Options::default()
+    };
+    let from_config = Controller::new("").with_options(options.clone());
+    let built = Controller::builder().options(options).build().unwrap();
+    for controller in [from_config, built] {
+        options_applied(controller).await;
+    }
+}
+
+async fn options_applied(controller: Controller) {
+    let deployment = json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": { "name": "web", "namespace": "default" },
+        "spec": { "template": pod(&["data"]) },
+    });
+    let answered = controller.mutate(review(deployment)).await.unwrap();
+    let patch = patch_of(&answered);
+    let paths: Vec<&str> = patch
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|operation| operation["path"].as_str().unwrap())
+        .collect();
+    assert!(
+        paths.contains(&"/spec/template/metadata/labels"),
+        "{paths:?}"
+    );
+    assert!(!paths.iter().any(|path| path.contains("annotations")));
+    assert_eq!(Kind::from_name("StatefulSet"), Some(Kind::StatefulSet));
+}
+
+#[tokio::test]
+async fn test_unchanged() {
+    let controller = Controller::new("default/data");
+    let answered = controller.mutate(review(pod(&["logs"]))).await.unwrap();
+    assert!(!answered.has_patch());
+    assert!(answered.response().unwrap().allowed);
+}
+
+#[tokio::test]
+async fn test_errors() {
+    let controller = Controller::new("default/data");
+    let body = br#"{"apiVersion":"admission.k8s.io/v1","kind":"AdmissionReview"}"#;
+    let err = controller
+        .mutate(serde_json::from_slice(body).unwrap())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ControllerError::MissingRequest));
+
+    let mut object = pod(&["data"]);
+    object["spec"]["volumes"] = json!("data");
+    let body = serde_json::to_vec(&json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": { "uid": "705ab4f5", "object": object },
+    }))
+    .unwrap();
+    let err = controller
+        .mutate(serde_json::from_slice(&body).unwrap())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, ControllerError::InvalidObject { .. }),
+        "{err}"
+    );
+
+    // Answered with the error, failing open by default
+    let answered = controller.error_review(&body, &err).unwrap();
+    let response = answered.response().unwrap();
+    assert!(response.allowed);
+    assert_eq!(response.status.as_ref().unwrap().code, 400);
+}
@@ -0,0 +1,154 @@
+//! The gen-manifests command compared to the golden files in tests/fixtures/manifests
+
+use std::process::{Command, Output};
+
+use k8s_openapi::{
+    api::{
+        admissionregistration::v1::{MutatingWebhookConfiguration, ValidatingWebhookConfiguration},
+        core::v1::Service,
+        rbac::v1::{ClusterRole, ClusterRoleBinding, Role, RoleBinding},
+    },
+    serde::{Serialize, de::DeserializeOwned},
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Runs gravivol gen-manifests without the environment of the test
+fn gen_manifests(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gravivol"))
+        .arg("gen-manifests")
+        .args(args)
+        .env_clear()
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+/// Asserts the output is the golden file, a valid manifest of each object
+fn assert_golden(output: Output, golden: &str) {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let manifests = String::from_utf8(output.stdout).unwrap();
+    let expected = std::fs::read_to_string(fixture(golden)).unwrap();
+    assert_eq!(manifests, expected, "{golden} differs");
+
+    for document in serde_norway::Deserializer::from_str(&manifests) {
+        let object = Value::deserialize(document).unwrap();
+        match object["kind"].as_str().unwrap() {
+            "MutatingWebhookConfiguration" => {
+                assert_schema::<MutatingWebhookConfiguration>(&object)
+            }
+            "ValidatingWebhookConfiguration" => {
+                assert_schema::<ValidatingWebhookConfiguration>(&object)
+            }
+            "Service" => assert_schema::<Service>(&object),
+            "ClusterRole" => assert_schema::<ClusterRole>(&object),
+            "ClusterRoleBinding" => assert_schema::<ClusterRoleBinding>(&object),
+            "Role" => assert_schema::<Role>(&object),
+            "RoleBinding" => assert_schema::<RoleBinding>(&object),
+            kind => panic!("unexpected kind {kind}"),
+        }
+    }
+}
+
+/// Asserts the object is of the type, with no field it lacks
+fn assert_schema<T: Serialize + DeserializeOwned>(object: &Value) {
+    let typed: T = serde_json::from_value(object.clone()).unwrap();
+    assert_eq!(&serde_json::to_value(typed).unwrap(), object);
+}
+
+#[test]
+fn test_default() {
+    let output = gen_manifests(
+        &[
+            "--namespace",
+            "webhooks",
+            "--ca-bundle-file",
+            &fixture("rsa-cert.pem"),
+        ],
+        &[],
+    );
+    assert_golden(output, "manifests/default.yaml");
+}
+
+#[test]
+fn test_validate() {
+    let output = gen_manifests(
+        &[
+            "--namespace",
+            "webhooks",
+            "--ca-bundle-file",
+            &fixture("rsa-cert.pem"),
+            "--namespace-selector",
+            "team=shop",
+        ],
+        &[("GRAVIVOL_VALIDATE", "true")],
+    );
+    assert_golden(output, "manifests/validate.yaml");
+}
+
+#[test]
+fn test_features() {
+    let output = gen_manifests(
+        &[
+            "--service",
+            "gravivol-shop",
+            "--namespace",
+            "webhooks",
+            "--ca-bundle-file",
+            &fixture("rsa-cert.pem"),
+            "--failure-policy",
+            "Fail",
+            "--namespace-selector",
+            "team=shop",
+            "--namespace-selector",
+            "gravivol=enabled",
+        ],
+        &[
+            ("GRAVIVOL_REGISTER_WEBHOOK", "true"),
+            ("GRAVIVOL_DEREGISTER_ON_SHUTDOWN", "delete"),
+            ("GRAVIVOL_EMIT_EVENTS", "true"),
+            ("GRAVIVOL_KINDS", "Pod,Deployment,StatefulSet"),
+            ("GRAVIVOL_LABEL_PVCS", "true"),
+            ("GRAVIVOL_WEBHOOK_PORT", "8443"),
+            ("GRAVIVOL_BIND", "0.0.0.0:9443"),
+        ],
+    );
+    assert_golden(output, "manifests/features.yaml");
+}
+
+#[test]
+fn test_failures() {
+    for (args, message) in [
+        (
+            vec!["--ca-bundle-file", "cert.pem"],
+            "--namespace is required",
+        ),
+        (
+            vec!["--namespace", "webhooks", "--ca-bundle-file", "missing.pem"],
+            "cannot read missing.pem",
+        ),
+        (
+            vec![
+                "--namespace",
+                "webhooks",
+                "--ca-bundle-file",
+                &fixture("rsa-pkcs8.pem"),
+            ],
+            "contains no PEM certificate",
+        ),
+    ] {
+        let output = gen_manifests(&args, &[]);
+        assert_eq!(output.status.code(), Some(2), "{args:?}");
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "{args:?}: {stderr}");
+    }
+}
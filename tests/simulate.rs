@@ -0,0 +1,142 @@
+//! The simulate command run like from a shell, on the files in tests/fixtures/simulate
+
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+use serde_json::{Value, json};
+
+fn fixture(name: &str) -> String {
+    format!(
+        "{}/tests/fixtures/simulate/{name}",
+        env!("CARGO_MANIFEST_DIR")
+    )
+}
+
+/// Runs gravivol simulate without the environment of the test, with the input on stdin
+fn simulate(args: &[&str], env: &[(&str, &str)], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_gravivol"))
+        .arg("simulate")
+        .args(args)
+        .env_clear()
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout_json(output: &Output) -> Value {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_pod() {
+    let output = simulate(
+        &[
+            "--config",
+            &fixture("config"),
+            "--pod",
+            &fixture("pod.yaml"),
+            "--apply",
+        ],
+        &[("GRAVIVOL_STAMP_ANNOTATION", "false")],
+        b"",
+    );
+    let output = stdout_json(&output);
+    assert_eq!(output["allowed"], true);
+    assert_eq!(output["warnings"], json!([]));
+    assert!(output.get("skipReason").is_none());
+    assert_eq!(output["patch"].as_array().unwrap().len(), 3);
+
+    let patched = &output["patched"];
+    assert_eq!(
+        patched["metadata"]["labels"],
+        json!({
+            "app": "web",
+            "shop.gravivol.fonona.net/cache": "true",
+            "shop.gravivol.fonona.net/data": "true",
+        })
+    );
+    assert!(patched["metadata"].get("annotations").is_none());
+    let pod_affinity = &patched["spec"]["affinity"]["podAffinity"];
+    assert_eq!(
+        pod_affinity["requiredDuringSchedulingIgnoredDuringExecution"][0]["labelSelector"],
+        json!({ "matchLabels": { "shop.gravivol.fonona.net/data": "true" } })
+    );
+    assert_eq!(
+        pod_affinity["preferredDuringSchedulingIgnoredDuringExecution"][0]["weight"],
+        50
+    );
+}
+
+#[test]
+fn test_config_from_env() {
+    let output = simulate(
+        &["--pod", &fixture("pod.yaml")],
+        &[("GRAVIVOL_CONFIG", "shop/cache")],
+        b"",
+    );
+    let output = stdout_json(&output);
+    assert_eq!(
+        output["warnings"],
+        json!(["gravivol: claim data is not configured for co-location"])
+    );
+    assert!(output.get("patched").is_none());
+}
+
+#[test]
+fn test_review_on_stdin() {
+    let review = std::fs::read(fixture("review.json")).unwrap();
+    let output = stdout_json(&simulate(&["--config", "shop/data"], &[], &review));
+    assert_eq!(
+        output,
+        json!({
+            "allowed": true,
+            "patch": [],
+            "skipReason": "no_volumes",
+            "warnings": [],
+        })
+    );
+
+    let from_file = simulate(
+        &["--config", "shop/data", "--review", &fixture("review.json")],
+        &[],
+        b"",
+    );
+    assert_eq!(stdout_json(&from_file), output);
+}
+
+#[test]
+fn test_failures() {
+    // Failing the request like the webhook, with the reason
+    let output = simulate(&["--pod", &fixture("invalid-pod.yaml")], &[], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot read Pod shop/web"), "{stderr}");
+
+    // Invalid arguments, config and input
+    for (args, message) in [
+        (vec!["--pod"], "--pod needs a value"),
+        (vec!["--config", "shop"], "config entries not in the format"),
+        (vec!["--pod", "missing.yaml"], "cannot read missing.yaml"),
+    ] {
+        let output = simulate(&args, &[], b"");
+        assert_eq!(output.status.code(), Some(2), "{args:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "{args:?}: {stderr}");
+    }
+    let output = simulate(&[], &[], b"kind: [");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot parse -"));
+}